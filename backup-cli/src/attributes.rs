@@ -0,0 +1,205 @@
+//! Collects what a restore needs besides a file's contents.
+
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+use std::ptr;
+
+use backuplib::rpc::{EntryType, ExtendedAttribute, PosixAttributes, Timestamp};
+
+/// Largest buffer we'll offer `getpwuid_r` and `getgrgid_r` before giving
+/// up on a name.
+const MAX_NAME_BUFFER: usize = 1024 * 1024;
+
+/// Says what `path` is, given its `fs::symlink_metadata`. Sockets only
+/// exist while a program listens on them, so they are `None`.
+pub fn entry_type(path: &Path, metadata: &fs::Metadata) -> io::Result<Option<EntryType>> {
+    let file_type = metadata.file_type();
+    let entry_type = if file_type.is_file() {
+        EntryType::Regular
+    }
+    else if file_type.is_dir() {
+        EntryType::Directory
+    }
+    else if file_type.is_symlink() {
+        EntryType::Symlink { target: fs::read_link(path)?.as_os_str().as_bytes().to_vec() }
+    }
+    else if file_type.is_fifo() {
+        EntryType::Fifo
+    }
+    else if file_type.is_char_device() {
+        EntryType::CharDevice { major: libc::major(metadata.rdev()) as u32, minor: libc::minor(metadata.rdev()) as u32 }
+    }
+    else if file_type.is_block_device() {
+        EntryType::BlockDevice { major: libc::major(metadata.rdev()) as u32, minor: libc::minor(metadata.rdev()) as u32 }
+    }
+    else {
+        return Ok(None);
+    };
+    Ok(Some(entry_type))
+}
+
+/// Reads the attributes of `path` itself. For a symlink, those are the
+/// link's, not its target's.
+pub fn posix_attributes(path: &Path) -> io::Result<PosixAttributes> {
+    let metadata = fs::symlink_metadata(path)?;
+    Ok(PosixAttributes {
+        mode: metadata.mode(),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        user_name: user_name(metadata.uid()),
+        group_name: group_name(metadata.gid()),
+        accessed: Timestamp::new(metadata.atime(), metadata.atime_nsec() as u32),
+        changed: Timestamp::new(metadata.ctime(), metadata.ctime_nsec() as u32),
+        xattrs: xattrs(path)?,
+    })
+}
+
+fn user_name(uid: u32) -> Option<String> {
+    let mut buffer = vec![0 as libc::c_char; 1024];
+    loop {
+        let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+        let mut result = ptr::null_mut();
+        let error = unsafe {
+            libc::getpwuid_r(uid, &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result)
+        };
+        if error == libc::ERANGE && buffer.len() < MAX_NAME_BUFFER {
+            let len = buffer.len() * 2;
+            buffer.resize(len, 0);
+            continue;
+        }
+        if error != 0 || result.is_null() {
+            return None;
+        }
+        return Some(unsafe { CStr::from_ptr(passwd.pw_name) }.to_string_lossy().into_owned());
+    }
+}
+
+fn group_name(gid: u32) -> Option<String> {
+    let mut buffer = vec![0 as libc::c_char; 1024];
+    loop {
+        let mut group: libc::group = unsafe { mem::zeroed() };
+        let mut result = ptr::null_mut();
+        let error = unsafe {
+            libc::getgrgid_r(gid, &mut group, buffer.as_mut_ptr(), buffer.len(), &mut result)
+        };
+        if error == libc::ERANGE && buffer.len() < MAX_NAME_BUFFER {
+            let len = buffer.len() * 2;
+            buffer.resize(len, 0);
+            continue;
+        }
+        if error != 0 || result.is_null() {
+            return None;
+        }
+        return Some(unsafe { CStr::from_ptr(group.gr_name) }.to_string_lossy().into_owned());
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn xattrs(path: &Path) -> io::Result<Vec<ExtendedAttribute>> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let names = match read_xattr_buffer(|buffer, size| unsafe {
+        libc::llistxattr(path.as_ptr(), buffer as *mut libc::c_char, size)
+    }) {
+        Ok(names) => names,
+        Err(ref error) if error.raw_os_error() == Some(libc::ENOTSUP) => return Ok(Vec::new()),
+        Err(error) => return Err(error),
+    };
+
+    let mut xattrs = Vec::new();
+    for name in names.split(|&byte| byte == 0).filter(|name| !name.is_empty()) {
+        let c_name = CString::new(name)?;
+        let value = match read_xattr_buffer(|buffer, size| unsafe {
+            libc::lgetxattr(path.as_ptr(), c_name.as_ptr(), buffer as *mut libc::c_void, size)
+        }) {
+            Ok(value) => value,
+            // Removed since we listed it
+            Err(ref error) if error.raw_os_error() == Some(libc::ENODATA) => continue,
+            Err(error) => return Err(error),
+        };
+        xattrs.push(ExtendedAttribute {
+            name: name.to_vec(),
+            value: value,
+        });
+    }
+    Ok(xattrs)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn xattrs(_path: &Path) -> io::Result<Vec<ExtendedAttribute>> {
+    Ok(Vec::new())
+}
+
+/// Asks `read` for the size of the data, then reads it. Tries again if the
+/// data grew in between.
+#[cfg(target_os = "linux")]
+fn read_xattr_buffer<F>(read: F) -> io::Result<Vec<u8>>
+    where F: Fn(*mut u8, usize) -> isize,
+{
+    loop {
+        let size = read(ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buffer = vec![0; size as usize];
+        let read_size = read(buffer.as_mut_ptr(), buffer.len());
+        if read_size < 0 {
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() == Some(libc::ERANGE) {
+                continue;
+            }
+            return Err(error);
+        }
+        buffer.truncate(read_size as usize);
+        return Ok(buffer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::os::unix::fs::{self as unix_fs, MetadataExt, PermissionsExt};
+
+    use backuplib::rpc::EntryType;
+
+    use super::{entry_type, posix_attributes};
+
+    #[test]
+    fn test_posix_attributes() {
+        let path = env::temp_dir().join(format!("backup-cli-attributes-{}", std::process::id()));
+        File::create(&path).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        let attributes = posix_attributes(&path).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(attributes.mode & 0o7777, 0o640);
+        assert_eq!(attributes.uid, metadata.uid());
+        assert_eq!(attributes.gid, metadata.gid());
+        assert_eq!(attributes.changed.seconds, metadata.ctime());
+    }
+
+    #[test]
+    fn test_entry_type() {
+        let dir = env::temp_dir().join(format!("backup-cli-entry-type-{}", std::process::id()));
+        fs::create_dir(&dir).unwrap();
+        let link = dir.join("link");
+        unix_fs::symlink("missing", &link).unwrap();
+
+        let dir_type = entry_type(&dir, &fs::symlink_metadata(&dir).unwrap()).unwrap();
+        let link_type = entry_type(&link, &fs::symlink_metadata(&link).unwrap()).unwrap();
+        let null_type = entry_type("/dev/null".as_ref(), &fs::symlink_metadata("/dev/null").unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(dir_type, Some(EntryType::Directory));
+        assert_eq!(link_type, Some(EntryType::Symlink { target: b"missing".to_vec() }));
+        assert_eq!(null_type, Some(EntryType::CharDevice { major: 1, minor: 3 }));
+    }
+}
@@ -0,0 +1,100 @@
+use std::cmp;
+use std::io::{self, Read};
+
+use backuplib::hash::ContentHasher;
+use fastcdc::v2020::{self, StreamCDC};
+
+/// A content-defined chunk of a file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chunk {
+    pub offset: u64,
+    pub len: u64,
+    /// SHA-256 of the chunk's data.
+    pub hash: Vec<u8>,
+}
+
+impl Chunk {
+    pub fn end(&self) -> u64 {
+        self.offset + self.len
+    }
+}
+
+/// Cuts everything in `reader` into chunks of about `avg_size` bytes. Cut
+/// points depend on the data around them, so an insertion only changes the
+/// chunks next to it.
+pub fn cut<R>(reader: R, avg_size: u64) -> io::Result<Vec<Chunk>>
+    where R: Read,
+{
+    let avg_size = clamp(avg_size, v2020::AVERAGE_MIN, v2020::AVERAGE_MAX);
+    let min_size = clamp(avg_size as u64 / 4, v2020::MINIMUM_MIN, v2020::MINIMUM_MAX);
+    let max_size = clamp(avg_size as u64 * 4, v2020::MAXIMUM_MIN, v2020::MAXIMUM_MAX);
+
+    StreamCDC::new(reader, min_size, avg_size, max_size)
+        .map(|chunk| {
+            let chunk = chunk.map_err(io::Error::from)?;
+            let mut hasher = ContentHasher::new();
+            hasher.update(&chunk.data);
+            Ok(Chunk {
+                offset: chunk.offset,
+                len: chunk.length as u64,
+                hash: hasher.finish(),
+            })
+        })
+        .collect()
+}
+
+fn clamp(size: u64, min: u32, max: u32) -> u32 {
+    cmp::min(cmp::max(size, min as u64), max as u64) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::cut;
+
+    /// Bytes that don't repeat, so cut points fall all over the place.
+    fn test_data(len: usize) -> Vec<u8> {
+        let mut state: u32 = 12345;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn test_chunks_cover_data() {
+        let data = test_data(200_000);
+
+        let chunks = cut(Cursor::new(&data), 4096).unwrap();
+        assert!(chunks.len() > 1);
+        let mut offset = 0;
+        for chunk in &chunks {
+            assert_eq!(chunk.offset, offset);
+            assert!(chunk.len <= 4 * 4096);
+            offset = chunk.end();
+        }
+        assert_eq!(offset, data.len() as u64);
+    }
+
+    #[test]
+    fn test_insertion_keeps_later_chunks() {
+        let data = test_data(200_000);
+        let mut changed = data[..1000].to_vec();
+        changed.extend_from_slice(b"inserted");
+        changed.extend_from_slice(&data[1000..]);
+
+        let hashes: Vec<Vec<u8>> = cut(Cursor::new(&data), 4096).unwrap()
+            .into_iter()
+            .map(|chunk| chunk.hash)
+            .collect();
+        let changed_chunks = cut(Cursor::new(&changed), 4096).unwrap();
+        let kept = changed_chunks.iter().filter(|chunk| hashes.contains(&chunk.hash)).count();
+        assert!(kept >= changed_chunks.len() - 2);
+    }
+
+    #[test]
+    fn test_empty_input() {
+        assert_eq!(cut(Cursor::new(Vec::new()), 4096).unwrap(), Vec::new());
+    }
+}
@@ -7,6 +7,104 @@ pub mod yaml_reader;
 #[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct Configuration {
     pub backup_paths: Vec<PathBuf>,
+    #[serde(default = "default_server_host")]
+    pub server_host: String,
+    #[serde(default = "default_server_port")]
+    pub server_port: u16,
+    /// Identifies this client to the server. Can be left out when
+    /// `tls.certificate` is set instead.
+    pub api_key: Option<String>,
+    /// Connect over TLS instead of plaintext.
+    pub tls: Option<TlsConfiguration>,
+    /// Bytes of a file sent per chunk. With servers that deduplicate chunks,
+    /// the average size of the content-defined chunks files are cut into.
+    #[serde(default = "default_chunk_size")]
+    pub chunk_size: u64,
+    /// How many chunks may await the server's answer at once. Only used
+    /// with servers that can't take a whole file in one upload stream.
+    #[serde(default = "default_upload_window")]
+    pub upload_window: usize,
+    /// Compress chunks with zstd if the server takes them that way.
+    #[serde(default = "default_compress")]
+    pub compress: bool,
+    /// Encrypt file contents, names and attributes before they leave this
+    /// machine.
+    pub encryption: Option<EncryptionConfiguration>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct TlsConfiguration {
+    /// PEM file with extra CA certificates to trust besides the system ones.
+    pub ca_bundle: Option<PathBuf>,
+    /// SHA-256 fingerprint of the server certificate, in hex. When set, only
+    /// that certificate is accepted.
+    pub pinned_certificate: Option<String>,
+    /// PEM file with a client certificate, followed by any intermediates, to
+    /// identify this client with.
+    pub certificate: Option<PathBuf>,
+    /// PEM file with the client certificate's private key.
+    pub private_key: Option<PathBuf>,
+}
+
+/// How to encrypt. The key comes from exactly one of `key_file`,
+/// `passphrase` and `repository_key_file`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct EncryptionConfiguration {
+    /// File holding the 32 byte key.
+    pub key_file: Option<PathBuf>,
+    /// Passphrase to derive the key from. With `repository_key_file`, the
+    /// passphrase of one of its key slots instead.
+    pub passphrase: Option<String>,
+    /// File holding the random salt `passphrase` is stretched with. Made on
+    /// first use. Only for `passphrase` on its own.
+    pub salt_file: Option<PathBuf>,
+    /// Key file made by `backup-cli key init`, holding the key once per
+    /// passphrase. Asks for the passphrase unless `passphrase` is set.
+    pub repository_key_file: Option<PathBuf>,
+    #[serde(default)]
+    pub mode: EncryptionMode,
+    /// File holding the 32 byte secret shared by every client whose files
+    /// should deduplicate against each other. Only for convergent mode.
+    pub repository_secret_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EncryptionMode {
+    /// Files are encrypted under this client's key alone. The server learns
+    /// nothing but their sizes, and can't deduplicate them.
+    Private,
+    /// File contents are encrypted chunk by chunk under keys derived from
+    /// the chunks and the repository secret, so the server can deduplicate
+    /// them between clients. It learns which chunks are equal, though, and
+    /// anyone with the secret can tell whether a file they have is stored.
+    Convergent,
+}
+
+impl Default for EncryptionMode {
+    fn default() -> EncryptionMode {
+        EncryptionMode::Private
+    }
+}
+
+fn default_server_host() -> String {
+    "127.0.0.1".into()
+}
+
+fn default_server_port() -> u16 {
+    8000
+}
+
+fn default_chunk_size() -> u64 {
+    64 * 1024
+}
+
+fn default_upload_window() -> usize {
+    8
+}
+
+fn default_compress() -> bool {
+    true
 }
 
 pub trait ConfigReader {
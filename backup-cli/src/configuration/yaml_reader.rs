@@ -31,7 +31,7 @@ mod tests {
     use std::io::Cursor;
 
     use super::YamlReader;
-    use crate::configuration::{Configuration, ConfigReader};
+    use crate::configuration::{Configuration, ConfigReader, EncryptionConfiguration, EncryptionMode, TlsConfiguration};
 
     #[test]
     fn test_read_proper_config() {
@@ -40,17 +40,184 @@ mod tests {
               - foo
               - bar
               - baz
+            api_key: secret
         "#);
         let mut config_reader = YamlReader::new(static_config);
 
         let config_result = config_reader.read_config();
         let config_should_be = Configuration {
             backup_paths: vec!["foo".into(), "bar".into(), "baz".into()],
+            server_host: "127.0.0.1".into(),
+            server_port: 8000,
+            api_key: Some("secret".into()),
+            tls: None,
+            chunk_size: 64 * 1024,
+            upload_window: 8,
+            compress: true,
+            encryption: None,
         };
 
         assert_eq!(config_result.unwrap(), config_should_be);
     }
 
+    #[test]
+    fn test_read_tls_config() {
+        let static_config = Cursor::new(r#"
+            backup_paths:
+              - foo
+            server_host: backup.example.com
+            server_port: 8443
+            api_key: secret
+            tls:
+              ca_bundle: ca.pem
+              pinned_certificate: "00:11:22"
+        "#);
+        let mut config_reader = YamlReader::new(static_config);
+
+        let config_result = config_reader.read_config();
+        let config_should_be = Configuration {
+            backup_paths: vec!["foo".into()],
+            server_host: "backup.example.com".into(),
+            server_port: 8443,
+            api_key: Some("secret".into()),
+            tls: Some(TlsConfiguration {
+                ca_bundle: Some("ca.pem".into()),
+                pinned_certificate: Some("00:11:22".into()),
+                certificate: None,
+                private_key: None,
+            }),
+            chunk_size: 64 * 1024,
+            upload_window: 8,
+            compress: true,
+            encryption: None,
+        };
+
+        assert_eq!(config_result.unwrap(), config_should_be);
+    }
+
+    #[test]
+    fn test_read_client_certificate_config() {
+        let static_config = Cursor::new(r#"
+            backup_paths:
+              - foo
+            tls:
+              certificate: client.pem
+              private_key: client.key
+        "#);
+        let mut config_reader = YamlReader::new(static_config);
+
+        let config_result = config_reader.read_config();
+        let config_should_be = Configuration {
+            backup_paths: vec!["foo".into()],
+            server_host: "127.0.0.1".into(),
+            server_port: 8000,
+            api_key: None,
+            tls: Some(TlsConfiguration {
+                ca_bundle: None,
+                pinned_certificate: None,
+                certificate: Some("client.pem".into()),
+                private_key: Some("client.key".into()),
+            }),
+            chunk_size: 64 * 1024,
+            upload_window: 8,
+            compress: true,
+            encryption: None,
+        };
+
+        assert_eq!(config_result.unwrap(), config_should_be);
+    }
+
+    #[test]
+    fn test_read_chunk_settings() {
+        let static_config = Cursor::new(r#"
+            backup_paths:
+              - foo
+            api_key: secret
+            chunk_size: 1048576
+            upload_window: 32
+            compress: false
+        "#);
+        let mut config_reader = YamlReader::new(static_config);
+
+        let config = config_reader.read_config().unwrap();
+
+        assert_eq!(config.chunk_size, 1024 * 1024);
+        assert_eq!(config.upload_window, 32);
+        assert!(!config.compress);
+    }
+
+    #[test]
+    fn test_read_encryption_config() {
+        let static_config = Cursor::new(r#"
+            backup_paths:
+              - foo
+            api_key: secret
+            encryption:
+              key_file: backup.key
+        "#);
+        let mut config_reader = YamlReader::new(static_config);
+
+        let config = config_reader.read_config().unwrap();
+
+        assert_eq!(config.encryption, Some(EncryptionConfiguration {
+            key_file: Some("backup.key".into()),
+            passphrase: None,
+            salt_file: None,
+            repository_key_file: None,
+            mode: EncryptionMode::Private,
+            repository_secret_file: None,
+        }));
+    }
+
+    #[test]
+    fn test_read_convergent_encryption_config() {
+        let static_config = Cursor::new(r#"
+            backup_paths:
+              - foo
+            api_key: secret
+            encryption:
+              passphrase: hunter2
+              salt_file: repository.salt
+              mode: convergent
+              repository_secret_file: repository.key
+        "#);
+        let mut config_reader = YamlReader::new(static_config);
+
+        let config = config_reader.read_config().unwrap();
+
+        assert_eq!(config.encryption, Some(EncryptionConfiguration {
+            key_file: None,
+            passphrase: Some("hunter2".into()),
+            salt_file: Some("repository.salt".into()),
+            repository_key_file: None,
+            mode: EncryptionMode::Convergent,
+            repository_secret_file: Some("repository.key".into()),
+        }));
+    }
+
+    #[test]
+    fn test_read_repository_key_config() {
+        let static_config = Cursor::new(r#"
+            backup_paths:
+              - foo
+            api_key: secret
+            encryption:
+              repository_key_file: repository-keys.yml
+        "#);
+        let mut config_reader = YamlReader::new(static_config);
+
+        let config = config_reader.read_config().unwrap();
+
+        assert_eq!(config.encryption, Some(EncryptionConfiguration {
+            key_file: None,
+            passphrase: None,
+            salt_file: None,
+            repository_key_file: Some("repository-keys.yml".into()),
+            mode: EncryptionMode::Private,
+            repository_secret_file: None,
+        }));
+    }
+
     #[test]
     fn test_read_improper_config() {
         let static_config = Cursor::new(r#"
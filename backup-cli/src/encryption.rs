@@ -0,0 +1,214 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use backuplib::convergent;
+use backuplib::crypto::{self, Argon2Params, Key};
+use backuplib::hash::content_hash;
+use backuplib::hex::to_hex;
+use backuplib::rpc::{EntryType, FileMetadata};
+
+use crate::chunker::{self, Chunk};
+use crate::configuration::{EncryptionConfiguration, EncryptionMode};
+use crate::key_file::{prompt_passphrase, KeyFile};
+
+const SALT_LEN: usize = 16;
+
+/// Argon2id cost for a passphrase on its own. Nothing stores it, so it
+/// can't change without changing the key.
+const PASSPHRASE_PARAMS: Argon2Params = Argon2Params {
+    memory_kib: 64 * 1024,
+    iterations: 3,
+    lanes: 4,
+};
+
+/// Tells apart the temporary files of one run.
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// The keys to encrypt with, see `EncryptionConfiguration`.
+#[derive(Clone, Debug)]
+pub struct Encryption {
+    /// Encrypts names, and in private mode file contents too.
+    pub key: Key,
+    /// Only set in convergent mode.
+    pub repository_secret: Option<Key>,
+}
+
+/// Gets the keys `config` points to.
+pub fn load(config: &EncryptionConfiguration) -> Result<Encryption, String> {
+    let key = match (&config.key_file, &config.passphrase, &config.repository_key_file) {
+        (Some(key_file), None, None) => read_key(key_file)?,
+        (None, Some(passphrase), None) => {
+            let salt_file = config.salt_file.as_ref()
+                .ok_or("encryption.passphrase needs encryption.salt_file")?;
+            Key::from_passphrase(passphrase.as_bytes(), &read_or_create_salt(salt_file)?, &PASSPHRASE_PARAMS)
+                .map_err(|err| err.to_string())?
+        }
+        (None, passphrase, Some(repository_key_file)) => {
+            let key_file = KeyFile::read(repository_key_file)?;
+            let passphrase = match passphrase {
+                Some(passphrase) => passphrase.clone(),
+                None => prompt_passphrase("Passphrase")?,
+            };
+            key_file.unlock(&passphrase)?.1
+        }
+        _ => {
+            return Err("set one of encryption.key_file, encryption.passphrase and encryption.repository_key_file".into());
+        }
+    };
+    let repository_secret = match (config.mode, &config.repository_secret_file) {
+        (EncryptionMode::Private, None) => None,
+        (EncryptionMode::Convergent, Some(secret_file)) => Some(read_key(secret_file)?),
+        (EncryptionMode::Private, Some(_)) => {
+            return Err("encryption.repository_secret_file is only for convergent mode".into());
+        }
+        (EncryptionMode::Convergent, None) => {
+            return Err("convergent mode needs encryption.repository_secret_file".into());
+        }
+    };
+    Ok(Encryption {
+        key: key,
+        repository_secret: repository_secret,
+    })
+}
+
+fn read_key(path: &Path) -> Result<Key, String> {
+    let bytes = fs::read(path)
+        .map_err(|err| format!("can't read {}: {}", path.display(), err))?;
+    Key::from_slice(&bytes)
+        .ok_or_else(|| format!("{} must hold exactly {} bytes", path.display(), crypto::KEY_LEN))
+}
+
+/// Reads the salt in `path`, or makes a random one there if there's no
+/// such file yet. The same salt has to be used on every run, or the same
+/// passphrase wouldn't give the same key.
+fn read_or_create_salt(path: &Path) -> Result<Vec<u8>, String> {
+    let mut salt = [0; SALT_LEN];
+    crypto::random_bytes(&mut salt);
+    let created = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .and_then(|mut file| {
+            file.write_all(&salt)?;
+            file.sync_all()
+        });
+    match created {
+        Ok(()) => Ok(salt.to_vec()),
+        Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            let salt = fs::read(path)
+                .map_err(|err| format!("can't read {}: {}", path.display(), err))?;
+            if salt.len() != SALT_LEN {
+                return Err(format!("{} must hold exactly {} bytes", path.display(), SALT_LEN));
+            }
+            Ok(salt)
+        }
+        Err(err) => Err(format!("can't create {}: {}", path.display(), err)),
+    }
+}
+
+/// Encrypts the name of `file_data`, and its target if it's a symlink. The
+/// same name always encrypts the same way, so the server can still tell
+/// whether it has a file. Ownership, permissions and extended attributes
+/// are sealed away too.
+pub fn encrypt_metadata(key: &Key, mut file_data: FileMetadata) -> io::Result<FileMetadata> {
+    let name = crypto::encrypt_name(key, file_data.file_name.as_bytes())?;
+    file_data.file_name = to_hex(&name);
+    if let EntryType::Symlink { ref mut target } = file_data.entry_type {
+        *target = crypto::encrypt_name(key, target)?;
+    }
+    file_data.posix_attributes = match file_data.posix_attributes {
+        Some(attributes) => Some(crypto::encrypt_attributes(key, attributes)?),
+        None => None,
+    };
+    Ok(file_data)
+}
+
+/// Encrypts `file`, which is stored under `name`, into a temporary file.
+/// Uploads read from that copy, so a resumed upload sends the same
+/// ciphertext the content hash is of.
+pub fn encrypted_copy(file: &mut File, key: &Key, name: &str) -> io::Result<File> {
+    let mut copy = temp_file()?;
+    file.seek(SeekFrom::Start(0))?;
+    crypto::encrypt_file(&mut *file, &mut copy, key, name.as_bytes())?;
+    copy.seek(SeekFrom::Start(0))?;
+    Ok(copy)
+}
+
+/// Like `encrypted_copy`, but cuts `file` into content-defined chunks of
+/// about `chunk_size` bytes and encrypts them convergently. Also returns
+/// the chunks of the copy, for deduplicating. The manifest at the end goes
+/// as one more chunk.
+pub fn convergent_copy(file: &mut File, key: &Key, name: &str, repository_secret: &Key, chunk_size: u64)
+    -> io::Result<(File, Vec<Chunk>)>
+{
+    file.seek(SeekFrom::Start(0))?;
+    let plaintext_chunks = chunker::cut(&*file, chunk_size)?;
+
+    let mut copy = temp_file()?;
+    let mut chunks = Vec::with_capacity(plaintext_chunks.len() + 1);
+    let mut entries = Vec::with_capacity(plaintext_chunks.len());
+    let mut offset = 0;
+    let mut add_chunk = |copy: &mut File, sealed: &[u8]| -> io::Result<()> {
+        copy.write_all(sealed)?;
+        chunks.push(Chunk {
+            offset: offset,
+            len: sealed.len() as u64,
+            hash: content_hash(sealed)?,
+        });
+        offset += sealed.len() as u64;
+        Ok(())
+    };
+    for plaintext_chunk in plaintext_chunks {
+        let mut data = vec![0; plaintext_chunk.len as usize];
+        file.seek(SeekFrom::Start(plaintext_chunk.offset))?;
+        file.read_exact(&mut data)?;
+        let (sealed, entry) = convergent::seal_chunk(repository_secret, &data)?;
+        add_chunk(&mut copy, &sealed)?;
+        entries.push(entry);
+    }
+    add_chunk(&mut copy, &convergent::seal_manifest(key, name.as_bytes(), &entries)?)?;
+
+    copy.seek(SeekFrom::Start(0))?;
+    Ok((copy, chunks))
+}
+
+/// Opens a new temporary file, which goes away once closed.
+fn temp_file() -> io::Result<File> {
+    let file_number = TEMP_FILES.fetch_add(1, Ordering::SeqCst);
+    let path = env::temp_dir().join(format!("backup-cli-{}-{}", process::id(), file_number));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    // Nothing is left behind even if we crash
+    fs::remove_file(&path)?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::{read_or_create_salt, SALT_LEN};
+
+    #[test]
+    fn test_salt_made_once() {
+        let path = env::temp_dir().join(format!("backup-cli-salt-{}", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let salt = read_or_create_salt(&path).unwrap();
+        assert_eq!(salt.len(), SALT_LEN);
+        assert_eq!(read_or_create_salt(&path).unwrap(), salt);
+
+        fs::write(&path, b"short").unwrap();
+        assert!(read_or_create_salt(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,7 +1,7 @@
 use std::collections::HashSet;
 use std::fs;
 use std::io::Error as IoError;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use walkdir::{WalkDir, IntoIter as WalkerIter};
 
@@ -35,6 +35,21 @@ impl<I> FileScanner<I>
     }
 }
 
+/// Canonicalizes all but the last component of `path`, so a symlink stays
+/// a symlink instead of turning into its target.
+fn canonicalize_parent(path: &Path) -> Result<PathBuf, IoError> {
+    let file_name = match path.file_name() {
+        Some(file_name) => file_name,
+        // Ends in `..` or is a root, so there's no symlink to keep
+        None => return fs::canonicalize(path),
+    };
+    let parent = match path.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    Ok(fs::canonicalize(parent)?.join(file_name))
+}
+
 impl<I> Iterator for FileScanner<I>
     where I: Iterator<Item = PathBuf>,
 {
@@ -46,7 +61,7 @@ impl<I> Iterator for FileScanner<I>
             match walker.next() {
                 Some(Ok(entry)) => {
                     // If we have a proper entry, canonicalize and check if it's excluded.
-                    let canonical = match fs::canonicalize(entry.path()) {
+                    let canonical = match canonicalize_parent(entry.path()) {
                         Ok(path) => path,
                         // If the path can't be canonicalized, pass up the error.
                         Err(e) => return Some(Err(e)),
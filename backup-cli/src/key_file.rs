@@ -0,0 +1,373 @@
+//! Repository key files. The repository key everything is encrypted with
+//! is stored once per key slot, wrapped by a key derived from that slot's
+//! passphrase with Argon2id. Adding or removing a slot, or changing its
+//! passphrase, only rewrites this file: the repository key stays the same.
+
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use backuplib::crypto::{self, Argon2Params, Key, NONCE_LEN};
+use backuplib::hex::{from_hex, to_hex};
+use serde_derive::{Deserialize, Serialize};
+
+const SALT_LEN: usize = 16;
+
+// Most a key slot may cost, so a key file can't make unlocking it take all
+// the memory there is, or forever: 16 times the default memory and passes.
+const MAX_MEMORY_KIB: u32 = 1024 * 1024;
+const MAX_ITERATIONS: u32 = 48;
+const MAX_LANES: u32 = 16;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct KeyFile {
+    pub slots: Vec<KeySlot>,
+}
+
+/// One copy of the repository key, for one passphrase.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct KeySlot {
+    pub name: String,
+    /// Argon2id salt, in hex.
+    pub salt: String,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub lanes: u32,
+    /// Nonce followed by the sealed repository key, in hex.
+    pub wrapped_key: String,
+}
+
+impl KeyFile {
+    pub fn read(path: &Path) -> Result<KeyFile, String> {
+        let file = File::open(path).map_err(|err| format!("can't read {}: {}", path.display(), err))?;
+        serde_yaml::from_reader(file).map_err(|err| format!("can't parse {}: {}", path.display(), err))
+    }
+
+    /// Replaces `path` with this key file. A crash halfway leaves the old
+    /// one in place.
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        // Next to `path` under a name of its own, whatever its extension
+        let temp_path = PathBuf::from(format!("{}.tmp", path.display()));
+        let write = || -> io::Result<()> {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&temp_path)?;
+            serde_yaml::to_writer(&mut file, self)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+            file.sync_all()?;
+            fs::rename(&temp_path, path)?;
+            // The rename itself only lasts once the directory is synced
+            let dir = match path.parent() {
+                Some(dir) if dir != Path::new("") => dir,
+                _ => Path::new("."),
+            };
+            File::open(dir)?.sync_all()
+        };
+        write().map_err(|err| format!("can't write {}: {}", path.display(), err))
+    }
+
+    /// Gets the repository key out of the slot `passphrase` is for. Also
+    /// returns that slot's name. Slots that are corrupt are skipped, and
+    /// only reported if no slot opens.
+    pub fn unlock(&self, passphrase: &str) -> Result<(String, Key), String> {
+        let mut error = None;
+        for slot in &self.slots {
+            match slot.unlock(passphrase) {
+                Ok(Some(key)) => return Ok((slot.name.clone(), key)),
+                Ok(None) => (),
+                Err(err) => {
+                    error.get_or_insert(err);
+                }
+            }
+        }
+        Err(error.unwrap_or_else(|| "the passphrase doesn't open any key slot".into()))
+    }
+
+    /// Adds a slot called `name` that opens with `passphrase`.
+    pub fn add_slot(&mut self, name: &str, passphrase: &str, key: &Key, params: &Argon2Params) -> Result<(), String> {
+        if self.slots.iter().any(|slot| slot.name == name) {
+            return Err(format!("there's a key slot called {} already", name));
+        }
+        self.slots.push(KeySlot::new(name, passphrase, key, params)?);
+        Ok(())
+    }
+
+    /// Removes the slot called `name`. The last slot can't be removed,
+    /// since the repository key would be lost with it.
+    pub fn remove_slot(&mut self, name: &str) -> Result<(), String> {
+        let index = self.slot_index(name)?;
+        if self.slots.len() == 1 {
+            return Err("can't remove the only key slot".into());
+        }
+        self.slots.remove(index);
+        Ok(())
+    }
+
+    /// Makes the slot called `name` open with `passphrase` instead.
+    pub fn change_passphrase(&mut self, name: &str, passphrase: &str, key: &Key, params: &Argon2Params)
+        -> Result<(), String>
+    {
+        let index = self.slot_index(name)?;
+        self.slots[index] = KeySlot::new(name, passphrase, key, params)?;
+        Ok(())
+    }
+
+    fn slot_index(&self, name: &str) -> Result<usize, String> {
+        self.slots.iter()
+            .position(|slot| slot.name == name)
+            .ok_or_else(|| format!("there's no key slot called {}", name))
+    }
+}
+
+impl KeySlot {
+    fn new(name: &str, passphrase: &str, key: &Key, params: &Argon2Params) -> Result<KeySlot, String> {
+        check_params(params).map_err(|err| format!("key slot {}: {}", name, err))?;
+        let mut salt = [0; SALT_LEN];
+        crypto::random_bytes(&mut salt);
+        let wrapping_key = Key::from_passphrase(passphrase.as_bytes(), &salt, params)
+            .map_err(|err| err.to_string())?;
+
+        let nonce = crypto::random_nonce();
+        let mut wrapped_key = nonce.to_vec();
+        wrapped_key.extend(crypto::seal(&wrapping_key, &nonce, name.as_bytes(), key.as_bytes())
+            .map_err(|err| err.to_string())?);
+        Ok(KeySlot {
+            name: name.to_string(),
+            salt: to_hex(&salt),
+            memory_kib: params.memory_kib,
+            iterations: params.iterations,
+            lanes: params.lanes,
+            wrapped_key: to_hex(&wrapped_key),
+        })
+    }
+
+    /// Gets the repository key out of this slot, if `passphrase` is its
+    /// passphrase.
+    fn unlock(&self, passphrase: &str) -> Result<Option<Key>, String> {
+        let corrupt = || format!("key slot {} is corrupt", self.name);
+        let salt = from_hex(&self.salt).ok_or_else(corrupt)?;
+        let wrapped_key = from_hex(&self.wrapped_key).ok_or_else(corrupt)?;
+        if wrapped_key.len() < NONCE_LEN {
+            return Err(corrupt());
+        }
+        let params = Argon2Params {
+            memory_kib: self.memory_kib,
+            iterations: self.iterations,
+            lanes: self.lanes,
+        };
+        check_params(&params).map_err(|err| format!("key slot {}: {}", self.name, err))?;
+        let wrapping_key = Key::from_passphrase(passphrase.as_bytes(), &salt, &params)
+            .map_err(|err| format!("key slot {}: {}", self.name, err))?;
+
+        let (nonce, sealed) = wrapped_key.split_at(NONCE_LEN);
+        match crypto::open(&wrapping_key, nonce.try_into().unwrap(), self.name.as_bytes(), sealed) {
+            Ok(key) => Key::from_slice(&key).map(Some).ok_or_else(corrupt),
+            // Most likely another slot's passphrase
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// Fails unless `params` are within what Argon2 takes and at most the
+/// `MAX_` bounds.
+fn check_params(params: &Argon2Params) -> Result<(), String> {
+    if params.lanes < 1 || params.lanes > MAX_LANES {
+        return Err(format!("Argon2 lanes must be between 1 and {}", MAX_LANES));
+    }
+    if params.iterations < 1 || params.iterations > MAX_ITERATIONS {
+        return Err(format!("Argon2 iterations must be between 1 and {}", MAX_ITERATIONS));
+    }
+    // Argon2 needs 8 KiB for each lane
+    if params.memory_kib < 8 * params.lanes || params.memory_kib > MAX_MEMORY_KIB {
+        return Err(format!("Argon2 memory must be between {} and {} KiB", 8 * params.lanes, MAX_MEMORY_KIB));
+    }
+    Ok(())
+}
+
+/// Asks for a passphrase on the terminal, without echoing it. Reads a line
+/// from stdin as it is if that's no terminal.
+pub fn prompt_passphrase(prompt: &str) -> Result<String, String> {
+    eprint!("{}: ", prompt);
+    io::stderr().flush().map_err(|err| err.to_string())?;
+
+    let echo_off = EchoOff::new();
+    let mut passphrase = String::new();
+    let read = io::stdin().lock().read_line(&mut passphrase);
+    if echo_off.is_some() {
+        eprintln!();
+    }
+    drop(echo_off);
+    read.map_err(|err| format!("can't read passphrase: {}", err))?;
+
+    let passphrase = passphrase.trim_end_matches(&['\r', '\n'][..]).to_string();
+    if passphrase.is_empty() {
+        return Err("no passphrase given".into());
+    }
+    Ok(passphrase)
+}
+
+/// Turns terminal echo off for as long as it lives.
+struct EchoOff(libc::termios);
+
+impl EchoOff {
+    fn new() -> Option<EchoOff> {
+        unsafe {
+            let mut termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return None;
+            }
+            let mut silent = termios;
+            silent.c_lflag &= !libc::ECHO;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &silent) != 0 {
+                return None;
+            }
+            Some(EchoOff(termios))
+        }
+    }
+}
+
+impl Drop for EchoOff {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0);
+        }
+    }
+}
+
+/// A random recovery key, to use as a slot's passphrase.
+pub fn generate_recovery_key() -> String {
+    let mut bytes = [0; 20];
+    crypto::random_bytes(&mut bytes);
+    to_hex(&bytes)
+        .as_bytes()
+        .chunks(8)
+        .map(|group| String::from_utf8_lossy(group).into_owned())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use backuplib::crypto::{Argon2Params, Key};
+
+    use super::{generate_recovery_key, KeyFile, MAX_ITERATIONS, MAX_LANES, MAX_MEMORY_KIB};
+
+    /// Cheap enough for tests.
+    const PARAMS: Argon2Params = Argon2Params {
+        memory_kib: 64,
+        iterations: 1,
+        lanes: 1,
+    };
+
+    #[test]
+    fn test_slots_open_the_same_key() {
+        let key = Key::generate();
+        let mut key_file = KeyFile::default();
+        key_file.add_slot("laptop", "first passphrase", &key, &PARAMS).unwrap();
+        key_file.add_slot("recovery", "second passphrase", &key, &PARAMS).unwrap();
+        assert!(key_file.add_slot("laptop", "third passphrase", &key, &PARAMS).is_err());
+
+        assert_eq!(key_file.unlock("first passphrase").unwrap(), ("laptop".to_string(), key.clone()));
+        assert_eq!(key_file.unlock("second passphrase").unwrap(), ("recovery".to_string(), key.clone()));
+        assert!(key_file.unlock("wrong passphrase").is_err());
+
+        // Survives a round trip through YAML
+        let yaml = serde_yaml::to_string(&key_file).unwrap();
+        let read: KeyFile = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(read, key_file);
+    }
+
+    #[test]
+    fn test_remove_and_change_passphrase() {
+        let key = Key::generate();
+        let mut key_file = KeyFile::default();
+        key_file.add_slot("laptop", "first passphrase", &key, &PARAMS).unwrap();
+        key_file.add_slot("recovery", "second passphrase", &key, &PARAMS).unwrap();
+
+        key_file.change_passphrase("laptop", "new passphrase", &key, &PARAMS).unwrap();
+        assert!(key_file.unlock("first passphrase").is_err());
+        assert_eq!(key_file.unlock("new passphrase").unwrap().1, key);
+
+        key_file.remove_slot("recovery").unwrap();
+        assert!(key_file.unlock("second passphrase").is_err());
+        assert!(key_file.remove_slot("recovery").is_err());
+        assert!(key_file.remove_slot("laptop").is_err());
+        assert_eq!(key_file.unlock("new passphrase").unwrap().1, key);
+    }
+
+    #[test]
+    fn test_slot_bound_to_its_name() {
+        let key = Key::generate();
+        let mut key_file = KeyFile::default();
+        key_file.add_slot("laptop", "passphrase", &key, &PARAMS).unwrap();
+        key_file.slots[0].name = "renamed".into();
+        assert!(key_file.unlock("passphrase").is_err());
+    }
+
+    #[test]
+    fn test_slot_cost_bounded() {
+        let key = Key::generate();
+        let mut key_file = KeyFile::default();
+        let too_much_memory = Argon2Params {
+            memory_kib: MAX_MEMORY_KIB + 1,
+            ..PARAMS
+        };
+        assert!(key_file.add_slot("laptop", "passphrase", &key, &too_much_memory).is_err());
+        key_file.add_slot("laptop", "passphrase", &key, &PARAMS).unwrap();
+
+        // A key file asking for too much isn't even tried
+        key_file.slots[0].iterations = MAX_ITERATIONS + 1;
+        assert!(key_file.unlock("passphrase").unwrap_err().contains("iterations"));
+        key_file.slots[0].iterations = PARAMS.iterations;
+        key_file.slots[0].lanes = 0;
+        assert!(key_file.unlock("passphrase").unwrap_err().contains("lanes"));
+        key_file.slots[0].lanes = MAX_LANES + 1;
+        assert!(key_file.unlock("passphrase").is_err());
+        key_file.slots[0].lanes = PARAMS.lanes;
+        key_file.slots[0].memory_kib = u32::max_value();
+        assert!(key_file.unlock("passphrase").unwrap_err().contains("memory"));
+    }
+
+    #[test]
+    fn test_corrupt_slot_skipped() {
+        let key = Key::generate();
+        let mut key_file = KeyFile::default();
+        key_file.add_slot("laptop", "first passphrase", &key, &PARAMS).unwrap();
+        key_file.add_slot("recovery", "second passphrase", &key, &PARAMS).unwrap();
+        key_file.slots[0].salt = "not hex".into();
+
+        assert_eq!(key_file.unlock("second passphrase").unwrap(), ("recovery".to_string(), key));
+        assert!(key_file.unlock("first passphrase").unwrap_err().contains("corrupt"));
+    }
+
+    #[test]
+    fn test_write_keeps_extension_apart() {
+        let dir = env::temp_dir().join(format!("backup-cli-key-file-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("repository.key");
+        // Where writing `repository.key` through `with_extension` would go
+        fs::write(dir.join("repository.tmp"), "not a key file").unwrap();
+
+        let mut key_file = KeyFile::default();
+        key_file.add_slot("laptop", "passphrase", &Key::generate(), &PARAMS).unwrap();
+        key_file.write(&path).unwrap();
+        assert_eq!(KeyFile::read(&path).unwrap(), key_file);
+        assert_eq!(fs::read_to_string(dir.join("repository.tmp")).unwrap(), "not a key file");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_recovery_key() {
+        let recovery_key = generate_recovery_key();
+        assert_eq!(recovery_key.len(), 5 * 8 + 4);
+        assert_eq!(recovery_key.split('-').count(), 5);
+        assert_ne!(generate_recovery_key(), recovery_key);
+    }
+}
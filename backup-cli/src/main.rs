@@ -1,94 +1,734 @@
+use std::cmp;
+use std::collections::HashSet;
 use std::env;
 use std::io::{Read, Seek, SeekFrom};
-use std::fs::File;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process;
 use std::sync::Arc;
-use std::time::SystemTime;
 
 use backuplib::grpc::ClientStubExt;
 use backuplib::rpc::*;
+use backuplib::compression;
+use backuplib::crypto::{Argon2Params, Key};
+use backuplib::delta::{self, DeltaOp};
+use backuplib::hash::content_hash;
 use backuplib::client::BaacupClient;
+use backuplib::tls::{parse_fingerprint, TlsConnector};
+use backuplib::tls_api::{TlsConnector as _, TlsConnectorBuilder as _};
 use futures::Future;
 use futures::future::{self, Loop, Either};
+use futures::stream::{self, Stream};
 
+use chunker::Chunk;
+use configuration::{Configuration, ConfigReader, TlsConfiguration};
+use configuration::yaml_reader::YamlReader;
+use encryption::Encryption;
+use file_scanner::FileScanner;
+use key_file::{generate_recovery_key, prompt_passphrase, KeyFile};
+
+mod attributes;
+mod chunker;
 mod configuration;
+mod encryption;
 mod file_scanner;
+mod key_file;
 
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
+/// How many times in a row a chunk is resent after a retryable error.
+const MAX_CHUNK_RETRIES: u32 = 3;
+
+/// Smaller files are sent whole rather than as a delta against the
+/// server's copy, which would take an extra round trip.
+const DELTA_MIN_SIZE: u64 = 64 * 1024;
+
+/// How files are cut up for uploading, see `Configuration`.
+#[derive(Clone, Copy, Debug)]
+struct ChunkSettings {
+    chunk_size: u64,
+    upload_window: usize,
+    /// What to ask the server to take chunks compressed with.
+    compression: Compression,
+}
+
 fn main() {
     backuplib::print_hello();
     println!("backup-cli v{} using backuplib v{}", VERSION, backuplib::VERSION);
 
-    let filename = env::args().skip(1).next().unwrap();
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("key") {
+        if let Err(err) = key_command(&args[1..]) {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let mut args = args.into_iter();
+    let config_path = args.next().unwrap();
+    let backup_path = PathBuf::from(args.next().unwrap());
+
+    let config_file = File::open(&config_path).expect("could not open config file");
+    let config = YamlReader::new(config_file).read_config().expect("could not read config file");
+    if config.chunk_size == 0 || config.upload_window == 0 {
+        panic!("chunk_size and upload_window must be at least 1");
+    }
+    let chunk_settings = ChunkSettings {
+        chunk_size: config.chunk_size,
+        upload_window: config.upload_window,
+        compression: if config.compress { Compression::Zstd } else { Compression::None },
+    };
+
+    let encryption = config.encryption.as_ref().map(|encryption| {
+        encryption::load(encryption).unwrap_or_else(|err| panic!("could not load encryption keys: {}", err))
+    });
+
+    // Make client
+    let client = Arc::new(connect(&config));
+
+    // Everything below backup_path, or just backup_path if it's no directory
+    let entries = FileScanner::new(vec![backup_path], Vec::new())
+        .expect("could not scan files")
+        .filter_map(|entry| entry.map_err(|err| println!("Can't scan: {}", err)).ok());
 
-    tokio::run(upload_file(filename)
+    tokio::run(client.negotiate(VERSION)
+        .and_then(move |server| {
+            println!("Connected to backupd v{} (protocol {})", server.software_version, server.protocol_version);
+            // A run that fails part of the way leaves its snapshot uncommitted
+            let snapshot = if server.supports(Capability::Snapshots) {
+                Either::A(client.begin_snapshot(hostname()).map(Some))
+            }
+            else {
+                Either::B(future::ok(None))
+            };
+            snapshot.map(move |snapshot_id| (client, server, snapshot_id))
+        })
+        .and_then(move |(client, server, snapshot_id)| {
+            let entry_server = server.clone();
+            let entry_encryption = encryption.clone();
+            let batch_client = client.clone();
+            stream::iter_ok(entries.filter_map(move |path| entry_metadata(&entry_server, entry_encryption.as_ref(), path)))
+                .chunks(MAX_FILES_ARE_UPLOADED)
+                .for_each(move |batch| {
+                    let file_names: Vec<String> = batch.iter()
+                        .map(|(_path, file_data)| file_data.file_name.clone())
+                        .collect();
+                    let snapshot_client = batch_client.clone();
+                    upload_batch(batch_client.clone(), server.clone(), chunk_settings, encryption.clone(), batch)
+                        .and_then(move |()| match snapshot_id {
+                            Some(snapshot_id) => Either::A(snapshot_client.add_to_snapshot(snapshot_id, file_names)),
+                            None => Either::B(future::ok(())),
+                        })
+                })
+                .and_then(move |()| match snapshot_id {
+                    // The connection goes down with the last client
+                    Some(snapshot_id) => Either::A(client.commit_snapshot(snapshot_id)
+                        .map(move |snapshot| {
+                            println!("Committed snapshot {} of {} with {} files.", snapshot.id, snapshot.host, snapshot.file_count);
+                            drop(client);
+                        })),
+                    None => Either::B(future::ok(())),
+                })
+        })
         .map_err(|err| println!("Error: {}", err)));
 }
 
-fn upload_file(filename: String) -> impl Future<Item = (), Error = String> {
-    // Open file
-    let file = File::open(&filename).unwrap();
-    let metadata = file.metadata().unwrap();
-    let file_size = metadata.len();
-    let modified = metadata.modified().unwrap()
-        .duration_since(SystemTime::UNIX_EPOCH).unwrap()
-        .as_secs();
+/// Name of the host being backed up, for its snapshots.
+fn hostname() -> String {
+    let mut name = [0u8; 256];
+    if unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) } != 0 {
+        return "unknown".into();
+    }
+    let len = name.iter().position(|&byte| byte == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
 
-    // Make client
-    let client = BaacupClient::new_plain("127.0.0.1", 8000, Default::default()).unwrap();
-    let client = Arc::new(client);
+const KEY_USAGE: &str = "usage: backup-cli key init <key file> [slot]
+       backup-cli key add <key file> <slot> [--recovery]
+       backup-cli key remove <key file> <slot>
+       backup-cli key change-passphrase <key file>";
+
+/// Manages the key slots of a repository key file. None of it touches the
+/// repository key itself, so nothing has to be encrypted again.
+fn key_command(args: &[String]) -> Result<(), String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let params = Argon2Params::default();
+    match args[..] {
+        ["init", path] | ["init", path, _] => {
+            let path = Path::new(path);
+            if path.exists() {
+                return Err(format!("{} exists already", path.display()));
+            }
+            let name = args.get(2).cloned().unwrap_or("default");
+            let mut key_file = KeyFile::default();
+            key_file.add_slot(name, &new_passphrase()?, &Key::generate(), &params)?;
+            key_file.write(path)?;
+            println!("Created {} with key slot {}.", path.display(), name);
+        }
+        ["add", path, name] | ["add", path, name, "--recovery"] => {
+            let path = Path::new(path);
+            let mut key_file = KeyFile::read(path)?;
+            let (_, key) = key_file.unlock(&prompt_passphrase("Passphrase of any key slot")?)?;
+            let passphrase = if args.len() == 4 {
+                let recovery_key = generate_recovery_key();
+                println!("Recovery key for key slot {}: {}", name, recovery_key);
+                println!("Keep it somewhere safe. It won't be shown again.");
+                recovery_key
+            } else {
+                new_passphrase()?
+            };
+            key_file.add_slot(name, &passphrase, &key, &params)?;
+            key_file.write(path)?;
+            println!("Added key slot {}.", name);
+        }
+        ["remove", path, name] => {
+            let path = Path::new(path);
+            let mut key_file = KeyFile::read(path)?;
+            // Only someone who can open the key may take slots away
+            key_file.unlock(&prompt_passphrase("Passphrase of any key slot")?)?;
+            key_file.remove_slot(name)?;
+            key_file.write(path)?;
+            println!("Removed key slot {}.", name);
+        }
+        ["change-passphrase", path] => {
+            let path = Path::new(path);
+            let mut key_file = KeyFile::read(path)?;
+            let (name, key) = key_file.unlock(&prompt_passphrase("Current passphrase")?)?;
+            key_file.change_passphrase(&name, &new_passphrase()?, &key, &params)?;
+            key_file.write(path)?;
+            println!("Changed the passphrase of key slot {}.", name);
+        }
+        _ => return Err(KEY_USAGE.into()),
+    }
+    Ok(())
+}
+
+/// Asks for a new passphrase twice.
+fn new_passphrase() -> Result<String, String> {
+    let passphrase = prompt_passphrase("New passphrase")?;
+    if prompt_passphrase("Repeat the new passphrase")? != passphrase {
+        return Err("the passphrases don't match".into());
+    }
+    Ok(passphrase)
+}
+
+fn connect(config: &Configuration) -> BaacupClient {
+    let client = match config.tls {
+        Some(ref tls) => connect_tls(config, tls),
+        None => BaacupClient::new_plain(&config.server_host, config.server_port, Default::default()).unwrap(),
+    };
+
+    match config.api_key {
+        Some(ref api_key) => client.with_api_key(api_key.clone()),
+        None => client,
+    }
+}
+
+fn connect_tls(config: &Configuration, tls: &TlsConfiguration) -> BaacupClient {
+
+    let mut connector = TlsConnector::builder().unwrap();
+    if let Some(ref ca_bundle) = tls.ca_bundle {
+        let ca_bundle = fs::read(ca_bundle).expect("could not read CA bundle");
+        connector.add_root_certificates_pem(&ca_bundle).expect("invalid CA bundle");
+    }
+    if let Some(ref fingerprint) = tls.pinned_certificate {
+        let fingerprint = parse_fingerprint(fingerprint)
+            .expect("pinned_certificate must be a hex SHA-256 fingerprint");
+        connector.pin_certificate(fingerprint);
+    }
+    match (&tls.certificate, &tls.private_key) {
+        (Some(certificate), Some(private_key)) => {
+            let certificate = fs::read(certificate).expect("could not read client certificate");
+            let private_key = fs::read(private_key).expect("could not read client private key");
+            connector.set_client_certificate(&certificate, &private_key)
+                .expect("invalid client certificate or private key");
+        }
+        (None, None) => {}
+        _ => panic!("tls.certificate and tls.private_key must be set together"),
+    }
+
+    BaacupClient::new_tls(&config.server_host, config.server_port, connector.build().unwrap(), Default::default())
+        .expect("could not connect to server")
+}
+
+/// Uploads a batch of entries, asking the server which of them it has
+/// in one go if it can.
+fn upload_batch(client: Arc<BaacupClient>, server: Hello, chunk_settings: ChunkSettings, encryption: Option<Encryption>,
+                batch: Vec<(PathBuf, FileMetadata)>)
+    -> impl Future<Item = (), Error = BaacupError>
+{
+    let files: Vec<FileMetadata> = batch.iter()
+        .map(|(_path, file_data)| file_data.clone())
+        .collect();
+    let statuses = if server.supports(Capability::BatchStatus) {
+        BaacupFuture::new(client.files_are_uploaded(files))
+    }
+    else {
+        let status_client = client.clone();
+        BaacupFuture::new(stream::iter_ok(files)
+            .and_then(move |file_data| status_client.file_is_uploaded(file_data))
+            .collect())
+    };
+
+    statuses.and_then(move |statuses| {
+        stream::iter_ok(batch.into_iter().zip(statuses))
+            .for_each(move |((path, file_data), upload_status)| {
+                upload_entry(client.clone(), server.clone(), chunk_settings, encryption.clone(), path, file_data, upload_status)
+            })
+    })
+}
+
+/// Gets what to tell the server about whatever kind of entry `path` is,
+/// encrypted if `encryption` is set. Entries we can't read or the server
+/// can't store are skipped.
+fn entry_metadata(server: &Hello, encryption: Option<&Encryption>, path: PathBuf) -> Option<(PathBuf, FileMetadata)> {
+    let filename = path.to_string_lossy().into_owned();
+    let metadata = match fs::symlink_metadata(&path) {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            println!("Can't read {}: {}", filename, err);
+            return None;
+        }
+    };
+    let entry_type = match attributes::entry_type(&path, &metadata) {
+        Ok(Some(entry_type)) => entry_type,
+        Ok(None) => {
+            println!("Skipping socket {}", filename);
+            return None;
+        }
+        Err(err) => {
+            println!("Can't read {}: {}", filename, err);
+            return None;
+        }
+    };
+    if !entry_type.has_contents() && !server.supports(Capability::EntryTypes) {
+        println!("Skipping {}: the server only stores regular files", filename);
+        return None;
+    }
+    let posix_attributes = if server.supports(Capability::PosixAttributes) {
+        attributes::posix_attributes(&path)
+            .map_err(|err| println!("Can't read attributes of {}: {}", filename, err))
+            .ok()
+    }
+    else {
+        None
+    };
 
-    // Get a token
     let file_data = FileMetadata {
-        file_name: filename.into(),
-        // TODO: Make last_modified a u64 instead
-        last_modified: modified as u32,
-        file_size: file_size,
+        file_name: filename,
+        last_modified: metadata.modified().unwrap().into(),
+        file_size: if entry_type.has_contents() { metadata.len() } else { 0 },
+        content_hash: None,
+        posix_attributes: posix_attributes,
+        entry_type: entry_type,
     };
-    client.file_is_uploaded(file_data.clone())
-        .and_then(move |is_uploaded| {
-            if !is_uploaded {
-                Either::A(
-                    client.init_upload(file_data)
-                        .and_then(move |token| {
-                            future::loop_fn((file, client), move |(mut file, client)| {
-                                // Get file head
-                                client.get_head(token)
-                                    .and_then(move |offset| {
-                                        // Read from file
-                                        let mut buffer = [0; 1024];
-                                        file.seek(SeekFrom::Start(offset)).unwrap();
-                                        let bytes = file.read(&mut buffer).unwrap();
-                                        if bytes == 0 {
-                                            return Either::A(future::ok(Loop::Break(())));
-                                        }
-                                        let buffer_vec = buffer[..bytes].to_vec();
-
-                                        // Upload data
-                                        let file_chunk = FileChunk {
-                                            token: token,
-                                            offset: offset,
-                                            data: buffer_vec,
-                                        };
-                                        Either::B(client.upload_chunk(file_chunk)
-                                            .and_then(move |checksum| {
-                                                if checksum != 0 {
-                                                    panic!("Bad upload_resp");
-                                                }
-
-                                                // Check if we've finished uploading.
-                                                if bytes as u64 + offset == file_size {
-                                                    return Ok(Loop::Break(()));
-                                                }
-                                                Ok(Loop::Continue((file, client)))
-                                            }))
-                                    })
-                            })
-                        })
-                )
+    match encryption {
+        Some(encryption) => match encryption::encrypt_metadata(&encryption.key, file_data) {
+            Ok(file_data) => Some((path, file_data)),
+            Err(err) => {
+                println!("Can't encrypt {}: {}", path.display(), err);
+                None
             }
-            else {
-                Either::B(future::err("file is up to date".into()))
+        },
+        None => Some((path, file_data)),
+    }
+}
+
+/// Uploads an entry the server said `upload_status` about.
+fn upload_entry(client: Arc<BaacupClient>, server: Hello, chunk_settings: ChunkSettings, encryption: Option<Encryption>, path: PathBuf,
+                file_data: FileMetadata, upload_status: UploadStatus)
+    -> BaacupFuture<()>
+{
+    if file_data.entry_type.has_contents() {
+        BaacupFuture::new(upload_file(client, server, chunk_settings, encryption, path, file_data, upload_status))
+    }
+    else if !upload_status.is_uploaded {
+        // The server has all there is once it knows the metadata
+        BaacupFuture::new(client.init_upload(file_data).map(|_token| ()))
+    }
+    else {
+        BaacupFuture::new(Ok(()))
+    }
+}
+
+fn upload_file(client: Arc<BaacupClient>, server: Hello, chunk_settings: ChunkSettings, encryption: Option<Encryption>, path: PathBuf,
+               mut file_data: FileMetadata, upload_status: UploadStatus)
+    -> impl Future<Item = (), Error = BaacupError>
+{
+    if !upload_status.is_uploaded {
+        // The file may have gone, or become unreadable, since the walk
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) => {
+                println!("Skipping {}: can't read it: {}", path.display(), err);
+                return Either::B(future::ok(()));
+            }
+        };
+        // The server only ever gets the encrypted copy
+        let encrypted = encryption.is_some();
+        let mut convergent_chunks = None;
+        if let Some(encryption) = encryption {
+            let copy = match encryption.repository_secret {
+                Some(ref repository_secret) => {
+                    encryption::convergent_copy(&mut file, &encryption.key, &file_data.file_name, repository_secret,
+                                                chunk_settings.chunk_size)
+                        .map(|(copy, chunks)| (copy, Some(chunks)))
+                }
+                None => encryption::encrypted_copy(&mut file, &encryption.key, &file_data.file_name).map(|copy| (copy, None)),
+            };
+            let copy = copy.and_then(|(copy, chunks)| {
+                let copy_len = copy.metadata()?.len();
+                Ok((copy, chunks, copy_len))
+            });
+            match copy {
+                Ok((copy, chunks, copy_len)) => {
+                    file = copy;
+                    file_data.file_size = copy_len;
+                    convergent_chunks = chunks;
+                }
+                Err(err) => {
+                    let message = format!("Can't encrypt {}: {}", path.display(), err);
+                    return Either::B(future::err(BaacupError::Internal(message)));
+                }
+            }
+        }
+        let file_size = file_data.file_size;
+        // Ciphertext doesn't compress, and only convergent ciphertext
+        // shares anything with other files or versions
+        let compression = if encrypted { Compression::None } else { chunk_settings.compression };
+
+        // Let the server check it received the whole file intact
+        if server.supports(Capability::ContentHash) {
+            match content_hash(&mut file) {
+                Ok(hash) => file_data.content_hash = Some(hash),
+                Err(err) => {
+                    let message = format!("Can't hash {}: {}", path.display(), err);
+                    return Either::B(future::err(BaacupError::Internal(message)));
+                }
+            }
+        }
+        let verify_checksums = server.supports(Capability::Checksums);
+        let dedup = server.supports(Capability::Dedup) && server.supports(Capability::UploadStream);
+
+        // The signature has to be of the version this upload replaces
+        let use_delta = !encrypted
+            && server.supports(Capability::Delta)
+            && server.supports(Capability::UploadStream)
+            && file_size >= DELTA_MIN_SIZE;
+        let signature = if use_delta {
+            Either::A(previous_signature(&client, file_data.file_name.clone()))
+        }
+        else {
+            Either::B(future::ok(None))
+        };
+
+        Either::A(signature
+            .and_then(move |signature| client.init_compressed_upload(file_data, compression)
+                .map(move |upload| (client, upload, signature)))
+            .and_then(move |(client, upload, signature)| {
+                if let Some(signature) = signature {
+                    BaacupFuture::new(stream_delta_file(client, upload, file, signature, chunk_settings))
+                }
+                else if let Some(chunks) = convergent_chunks.filter(|_| dedup) {
+                    BaacupFuture::new(stream_chunks(client, upload, file, chunks))
+                }
+                else if dedup && !encrypted {
+                    BaacupFuture::new(stream_dedup_file(client, upload, file, chunk_settings))
+                }
+                else if server.supports(Capability::UploadStream) {
+                    BaacupFuture::new(stream_file(client, upload, file, file_size, chunk_settings))
+                }
+                else {
+                    BaacupFuture::new(send_chunks(client, upload, file, file_size, chunk_settings, verify_checksums))
+                }
+            }))
+    }
+    else {
+        println!("{} is up to date.", path.display());
+        Either::B(future::ok(()))
+    }
+}
+
+/// Streams `file` to `upload`, going on from the server's head after a
+/// failure.
+fn stream_file(client: Arc<BaacupClient>, upload: Upload, file: File, file_size: u64, chunk_settings: ChunkSettings)
+    -> impl Future<Item = (), Error = BaacupError>
+{
+    let token = upload.token;
+    // A new upload starts out empty
+    future::loop_fn((client, 0, 0), move |(client, offset, retries)| {
+        let chunks = read_chunks(file.try_clone().unwrap(), offset, file_size, chunk_settings.chunk_size)
+            .map(move |(_offset, data)| {
+                let (data, compression) = compression::compress(data, upload.compression);
+                DataChunk::compressed(data, compression)
+            });
+        client.upload_stream(StreamHeader::new(token, offset), BaacupStream::new(chunks))
+            .then(move |stream_result| {
+                match stream_result {
+                    Ok(_) => Either::A(future::ok(Loop::Break(()))),
+                    Err(ref error) if error.is_retryable() && retries < MAX_CHUNK_RETRIES => {
+                        Either::B(resync(client, token, offset, retries, error))
+                    }
+                    Err(error) => Either::A(future::err(error)),
+                }
+            })
+    })
+}
+
+/// Gets the signature of the server's copy of `file_name`, if it has one
+/// worth diffing against.
+fn previous_signature(client: &Arc<BaacupClient>, file_name: String)
+    -> impl Future<Item = Option<Signature>, Error = BaacupError>
+{
+    client.get_signature(file_name)
+        .then(|signature_result| {
+            match signature_result {
+                Ok(ref signature) if signature.block_size == 0 || signature.blocks.is_empty() => Ok(None),
+                Ok(signature) => Ok(Some(signature)),
+                // A new file
+                Err(BaacupError::NotFound) => Ok(None),
+                Err(error) => Err(error),
             }
         })
 }
+
+/// Streams `file` to `upload` as a delta against the version of it the
+/// server has, which `signature` describes. Whatever didn't change is copied
+/// on the server instead of sent.
+fn stream_delta_file(client: Arc<BaacupClient>, upload: Upload, file: File, signature: Signature, chunk_settings: ChunkSettings)
+    -> impl Future<Item = (), Error = BaacupError>
+{
+    let token = upload.token;
+    let ops = (&file).seek(SeekFrom::Start(0))
+        .and_then(|_| delta::diff(&file, &signature))
+        .map_err(|err| BaacupError::Internal(format!("Can't read file: {}", err)));
+    future::result(ops).and_then(move |ops| {
+        let pieces = delta_pieces(&ops, chunk_settings.chunk_size);
+        // A new upload starts out empty
+        future::loop_fn((client, 0, 0), move |(client, offset, retries)| {
+            // The first of these may be partly uploaded already
+            let remaining: Vec<(u64, DeltaOp)> = pieces.iter()
+                .filter(|&&(start, op)| start + op.output_len() > offset)
+                .cloned()
+                .collect();
+            let mut file = file.try_clone().unwrap();
+            let chunks = stream::iter_ok(remaining).and_then(move |(start, op)| {
+                let skip = offset.saturating_sub(start);
+                match op {
+                    DeltaOp::Copy { offset: copy_offset, len } => {
+                        Ok(DataChunk::copy(CopyRange { offset: copy_offset + skip, len: len - skip }))
+                    }
+                    DeltaOp::Literal { len } => {
+                        let data = read_at(&mut file, start + skip, len - skip)?;
+                        let (data, compression) = compression::compress(data, upload.compression);
+                        Ok(DataChunk::compressed(data, compression))
+                    }
+                }
+            });
+            client.upload_stream(StreamHeader::new(token, offset), BaacupStream::new(chunks))
+                .then(move |stream_result| {
+                    match stream_result {
+                        Ok(_) => Either::A(future::ok(Loop::Break(()))),
+                        Err(ref error) if error.is_retryable() && retries < MAX_CHUNK_RETRIES => {
+                            Either::B(resync(client, token, offset, retries, error))
+                        }
+                        Err(error) => Either::A(future::err(error)),
+                    }
+                })
+        })
+    })
+}
+
+/// Splits `ops` into pieces that fit in a chunk each, along with where in
+/// the file each one starts.
+fn delta_pieces(ops: &[DeltaOp], chunk_size: u64) -> Vec<(u64, DeltaOp)> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    for &op in ops {
+        let max_len = match op {
+            DeltaOp::Copy { .. } => MAX_COPY_LEN,
+            DeltaOp::Literal { .. } => chunk_size,
+        };
+        let mut done = 0;
+        while done < op.output_len() {
+            let len = cmp::min(max_len, op.output_len() - done);
+            let piece = match op {
+                DeltaOp::Copy { offset, .. } => DeltaOp::Copy { offset: offset + done, len: len },
+                DeltaOp::Literal { .. } => DeltaOp::Literal { len: len },
+            };
+            pieces.push((start + done, piece));
+            done += len;
+        }
+        start += op.output_len();
+    }
+    pieces
+}
+
+/// Streams `file` to `upload` as content-defined chunks of about
+/// `chunk_size` bytes. Chunks the server already has, from any file, are
+/// only referred to by their hash.
+fn stream_dedup_file(client: Arc<BaacupClient>, upload: Upload, file: File, chunk_settings: ChunkSettings)
+    -> impl Future<Item = (), Error = BaacupError>
+{
+    let chunks = (&file).seek(SeekFrom::Start(0))
+        .and_then(|_| chunker::cut(&file, chunk_settings.chunk_size))
+        .map_err(|err| BaacupError::Internal(format!("Can't read file: {}", err)));
+    future::result(chunks).and_then(move |chunks| stream_chunks(client, upload, file, chunks))
+}
+
+/// Streams `file` to `upload` as `chunks`, which cover all of it. Chunks
+/// the server already has, from any file, are only referred to by their
+/// hash.
+fn stream_chunks(client: Arc<BaacupClient>, upload: Upload, file: File, chunks: Vec<Chunk>)
+    -> impl Future<Item = (), Error = BaacupError>
+{
+    let token = upload.token;
+    // A new upload starts out empty
+    future::loop_fn((client, 0, 0), move |(client, offset, retries)| {
+        // The first of these may be partly uploaded already
+        let remaining: Vec<Chunk> = chunks.iter()
+            .filter(|chunk| chunk.end() > offset)
+            .cloned()
+            .collect();
+        let mut file = file.try_clone().unwrap();
+        let stream_client = client.clone();
+        missing_chunks(&client, &remaining)
+            .and_then(move |mut missing| {
+                let data_chunks = stream::iter_ok(remaining).and_then(move |chunk| {
+                    if chunk.offset < offset {
+                        let data = read_at(&mut file, offset, chunk.end() - offset)?;
+                        let (data, compression) = compression::compress(data, upload.compression);
+                        return Ok(DataChunk::compressed(data, compression));
+                    }
+                    // Only the first copy of a new chunk carries its data
+                    if !missing.remove(&chunk.hash) {
+                        return Ok(DataChunk::new(Vec::new()).with_chunk_hash(chunk.hash));
+                    }
+                    let data = read_at(&mut file, chunk.offset, chunk.len)?;
+                    let (data, compression) = compression::compress(data, upload.compression);
+                    Ok(DataChunk::compressed(data, compression).with_chunk_hash(chunk.hash))
+                });
+                stream_client.upload_stream(StreamHeader::new(token, offset), BaacupStream::new(data_chunks))
+            })
+            .then(move |stream_result| {
+                match stream_result {
+                    Ok(_) => Either::A(future::ok(Loop::Break(()))),
+                    Err(ref error) if error.is_retryable() && retries < MAX_CHUNK_RETRIES => {
+                        Either::B(resync(client, token, offset, retries, error))
+                    }
+                    Err(error) => Either::A(future::err(error)),
+                }
+            })
+    })
+}
+
+/// Asks the server which of the hashes of `chunks` it has no chunk for.
+fn missing_chunks(client: &Arc<BaacupClient>, chunks: &[Chunk]) -> impl Future<Item = HashSet<Vec<u8>>, Error = BaacupError> {
+    let hashes: HashSet<Vec<u8>> = chunks.iter().map(|chunk| chunk.hash.clone()).collect();
+    let hashes: Vec<Vec<u8>> = hashes.into_iter().collect();
+    let batches: Vec<Vec<Vec<u8>>> = hashes.chunks(MAX_HAS_CHUNKS).map(<[Vec<u8>]>::to_vec).collect();
+    let client = client.clone();
+    stream::iter_ok(batches)
+        .and_then(move |batch| {
+            client.has_chunks(batch.clone())
+                .map(move |has_chunks| (batch, has_chunks))
+        })
+        .fold(HashSet::new(), |mut missing, (batch, has_chunks)| {
+            missing.extend(batch.into_iter()
+                .zip(has_chunks)
+                .filter(|&(_, has_chunk)| !has_chunk)
+                .map(|(hash, _)| hash));
+            Ok::<_, BaacupError>(missing)
+        })
+}
+
+/// Sends `file` to `upload` one request per chunk, for servers without
+/// upload streams. Up to `upload_window` chunks are on their way at once,
+/// and the server puts them in order.
+fn send_chunks(client: Arc<BaacupClient>, upload: Upload, file: File, file_size: u64,
+               chunk_settings: ChunkSettings, verify_checksums: bool)
+    -> impl Future<Item = (), Error = BaacupError>
+{
+    let token = upload.token;
+    // A new upload starts out empty
+    future::loop_fn((client, 0, 0), move |(client, offset, retries)| {
+        let chunk_client = client.clone();
+        read_chunks(file.try_clone().unwrap(), offset, file_size, chunk_settings.chunk_size)
+            .map(move |(offset, data)| {
+                let (data, compression) = compression::compress(data, upload.compression);
+                let file_chunk = FileChunk::compressed(token, offset, data, compression);
+                let sent_checksum = file_chunk.checksum;
+                chunk_client.upload_chunk(file_chunk)
+                    .and_then(move |checksum| {
+                        // Already written, so sending it again won't help
+                        if verify_checksums && checksum != sent_checksum {
+                            let message = format!("server stored the chunk at {} with a different checksum", offset);
+                            return Err(BaacupError::Internal(message));
+                        }
+                        Ok(())
+                    })
+            })
+            .buffered(chunk_settings.upload_window)
+            .for_each(|()| Ok(()))
+            .then(move |upload_result| {
+                match upload_result {
+                    Ok(()) => Either::A(future::ok(Loop::Break(()))),
+                    // Nothing past a failed chunk gets written before it,
+                    // so go on from wherever the server's head is
+                    Err(ref error) if error.is_retryable() && retries < MAX_CHUNK_RETRIES => {
+                        Either::B(resync(client, token, offset, retries, error))
+                    }
+                    Err(error) => Either::A(future::err(error)),
+                }
+            })
+    })
+}
+
+/// Finds out where to go on from after a failed attempt that started at
+/// `offset`. Only attempts that got nothing written count as retries.
+fn resync(client: Arc<BaacupClient>, token: UploadToken, offset: u64, retries: u32, error: &BaacupError)
+    -> impl Future<Item = Loop<(), (Arc<BaacupClient>, u64, u32)>, Error = BaacupError>
+{
+    // A bad offset already tells us the server's head
+    let head = match *error {
+        BaacupError::BadOffset { expected } => Either::A(future::ok(expected)),
+        _ => Either::B(client.get_head(token)),
+    };
+    head
+        .map(move |head| {
+            let retries = if head > offset { 0 } else { retries + 1 };
+            Loop::Continue((client, head, retries))
+        })
+}
+
+/// Reads exactly `len` bytes of `file` at `offset`.
+fn read_at(file: &mut File, offset: u64, len: u64) -> Result<Vec<u8>, BaacupError> {
+    let mut data = vec![0; len as usize];
+    file.seek(SeekFrom::Start(offset))
+        .and_then(|_| file.read_exact(&mut data))
+        .map_err(|err| BaacupError::Internal(format!("Can't read file: {}", err)))?;
+    Ok(data)
+}
+
+/// Reads `file` from `offset` up to `file_size` in chunks of `chunk_size`,
+/// each with the offset it starts at.
+fn read_chunks(mut file: File, offset: u64, file_size: u64, chunk_size: u64)
+    -> impl Stream<Item = (u64, Vec<u8>), Error = BaacupError>
+{
+    stream::unfold(offset, move |offset| {
+        if offset >= file_size {
+            return None;
+        }
+        let mut data = Vec::new();
+        let read_result = file.seek(SeekFrom::Start(offset))
+            .and_then(|_| (&mut file).take(cmp::min(chunk_size, file_size - offset)).read_to_end(&mut data))
+            .map_err(|err| BaacupError::Internal(format!("Can't read file: {}", err)));
+        match read_result {
+            // The file got shorter since we looked
+            Ok(0) => None,
+            Ok(bytes) => Some(future::ok(((offset, data), offset + bytes as u64))),
+            Err(error) => Some(future::err(error)),
+        }
+    })
+}
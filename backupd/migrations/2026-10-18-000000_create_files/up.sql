@@ -0,0 +1,6 @@
+-- Databases created before migrations were introduced already have this table.
+CREATE TABLE IF NOT EXISTS files (
+    id TEXT NOT NULL PRIMARY KEY,
+    filename TEXT NOT NULL,
+    last_modified BIGINT NOT NULL
+);
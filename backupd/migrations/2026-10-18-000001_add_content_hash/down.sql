@@ -0,0 +1,10 @@
+-- SQLite can't drop columns, so copy everything else into a new table.
+CREATE TABLE files_without_content_hash (
+    id TEXT NOT NULL PRIMARY KEY,
+    filename TEXT NOT NULL,
+    last_modified BIGINT NOT NULL
+);
+INSERT INTO files_without_content_hash (id, filename, last_modified)
+    SELECT id, filename, last_modified FROM files;
+DROP TABLE files;
+ALTER TABLE files_without_content_hash RENAME TO files;
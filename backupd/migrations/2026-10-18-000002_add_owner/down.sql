@@ -0,0 +1,11 @@
+-- SQLite can't drop columns, so copy everything else into a new table.
+CREATE TABLE files_without_owner (
+    id TEXT NOT NULL PRIMARY KEY,
+    filename TEXT NOT NULL,
+    last_modified BIGINT NOT NULL,
+    content_hash BLOB
+);
+INSERT INTO files_without_owner (id, filename, last_modified, content_hash)
+    SELECT id, filename, last_modified, content_hash FROM files;
+DROP TABLE files;
+ALTER TABLE files_without_owner RENAME TO files;
@@ -0,0 +1,2 @@
+-- Files uploaded before clients had to authenticate don't belong to anyone.
+ALTER TABLE files ADD COLUMN owner TEXT NOT NULL DEFAULT '';
@@ -0,0 +1,10 @@
+-- Uploads in progress, so clients can resume them after a restart.
+CREATE TABLE upload_sessions (
+    token BLOB NOT NULL PRIMARY KEY,
+    owner TEXT NOT NULL,
+    filename TEXT NOT NULL,
+    last_modified BIGINT NOT NULL,
+    file_size BIGINT NOT NULL,
+    content_hash BLOB,
+    last_used BIGINT NOT NULL
+);
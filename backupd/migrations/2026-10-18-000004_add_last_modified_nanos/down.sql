@@ -0,0 +1,26 @@
+-- SQLite can't drop columns, so copy everything else into new tables.
+CREATE TABLE files_without_nanos (
+    id TEXT NOT NULL PRIMARY KEY,
+    filename TEXT NOT NULL,
+    last_modified BIGINT NOT NULL,
+    content_hash BLOB,
+    owner TEXT NOT NULL DEFAULT ''
+);
+INSERT INTO files_without_nanos (id, filename, last_modified, content_hash, owner)
+    SELECT id, filename, last_modified, content_hash, owner FROM files;
+DROP TABLE files;
+ALTER TABLE files_without_nanos RENAME TO files;
+
+CREATE TABLE upload_sessions_without_nanos (
+    token BLOB NOT NULL PRIMARY KEY,
+    owner TEXT NOT NULL,
+    filename TEXT NOT NULL,
+    last_modified BIGINT NOT NULL,
+    file_size BIGINT NOT NULL,
+    content_hash BLOB,
+    last_used BIGINT NOT NULL
+);
+INSERT INTO upload_sessions_without_nanos
+    SELECT token, owner, filename, last_modified, file_size, content_hash, last_used FROM upload_sessions;
+DROP TABLE upload_sessions;
+ALTER TABLE upload_sessions_without_nanos RENAME TO upload_sessions;
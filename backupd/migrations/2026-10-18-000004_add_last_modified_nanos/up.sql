@@ -0,0 +1,3 @@
+-- last_modified stays in seconds; older rows only had second precision.
+ALTER TABLE files ADD COLUMN last_modified_nanos INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE upload_sessions ADD COLUMN last_modified_nanos INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+DROP TABLE file_xattrs;
+DROP TABLE file_attributes;
@@ -0,0 +1,21 @@
+-- POSIX attributes of the files that came with any.
+CREATE TABLE file_attributes (
+    file_id TEXT NOT NULL PRIMARY KEY REFERENCES files (id),
+    mode INTEGER NOT NULL,
+    uid BIGINT NOT NULL,
+    gid BIGINT NOT NULL,
+    user_name TEXT,
+    group_name TEXT,
+    accessed BIGINT NOT NULL,
+    accessed_nanos INTEGER NOT NULL,
+    changed BIGINT NOT NULL,
+    changed_nanos INTEGER NOT NULL
+);
+
+CREATE TABLE file_xattrs (
+    file_id TEXT NOT NULL REFERENCES files (id),
+    name BLOB NOT NULL,
+    -- NULL for empty values, which diesel can't read back.
+    value BLOB,
+    PRIMARY KEY (file_id, name)
+);
@@ -0,0 +1,13 @@
+-- SQLite can't drop columns, so copy everything else into a new table.
+CREATE TABLE files_without_entry_types (
+    id TEXT NOT NULL PRIMARY KEY,
+    filename TEXT NOT NULL,
+    last_modified BIGINT NOT NULL,
+    content_hash BLOB,
+    owner TEXT NOT NULL DEFAULT '',
+    last_modified_nanos INTEGER NOT NULL DEFAULT 0
+);
+INSERT INTO files_without_entry_types (id, filename, last_modified, content_hash, owner, last_modified_nanos)
+    SELECT id, filename, last_modified, content_hash, owner, last_modified_nanos FROM files;
+DROP TABLE files;
+ALTER TABLE files_without_entry_types RENAME TO files;
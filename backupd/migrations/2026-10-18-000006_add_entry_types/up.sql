@@ -0,0 +1,5 @@
+-- 0 is a regular file; see entry_type_columns in sqlite_db/mod.rs.
+ALTER TABLE files ADD COLUMN entry_type INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE files ADD COLUMN symlink_target BLOB;
+ALTER TABLE files ADD COLUMN device_major INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE files ADD COLUMN device_minor INTEGER NOT NULL DEFAULT 0;
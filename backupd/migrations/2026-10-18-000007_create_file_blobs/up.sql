@@ -0,0 +1,13 @@
+-- Where each stretch of a file's data is stored in its data file, so blobs
+-- can be kept compressed. Files without rows are stored as they are.
+CREATE TABLE file_blobs (
+    file_id TEXT NOT NULL REFERENCES files (id),
+    -- Where the blob goes in the file, in uncompressed bytes.
+    file_offset BIGINT NOT NULL,
+    len BIGINT NOT NULL,
+    stored_offset BIGINT NOT NULL,
+    stored_len BIGINT NOT NULL,
+    -- 0 is uncompressed; see the compression constants in sqlite_db/mod.rs.
+    compression INTEGER NOT NULL,
+    PRIMARY KEY (file_id, file_offset)
+);
@@ -0,0 +1,17 @@
+-- SQLite can't drop columns, so copy everything else into a new table.
+CREATE TABLE file_blobs_without_chunks (
+    file_id TEXT NOT NULL REFERENCES files (id),
+    file_offset BIGINT NOT NULL,
+    len BIGINT NOT NULL,
+    stored_offset BIGINT NOT NULL,
+    stored_len BIGINT NOT NULL,
+    compression INTEGER NOT NULL,
+    PRIMARY KEY (file_id, file_offset)
+);
+INSERT INTO file_blobs_without_chunks (file_id, file_offset, len, stored_offset, stored_len, compression)
+    SELECT file_id, file_offset, len, stored_offset, stored_len, compression FROM file_blobs
+    WHERE chunk_hash IS NULL;
+DROP TABLE file_blobs;
+ALTER TABLE file_blobs_without_chunks RENAME TO file_blobs;
+
+DROP TABLE chunks;
@@ -0,0 +1,12 @@
+-- Chunks shared between files and clients. Each one is stored in
+-- chunks/<hex of hash>.
+CREATE TABLE chunks (
+    hash BLOB NOT NULL PRIMARY KEY,
+    len BIGINT NOT NULL,
+    stored_len BIGINT NOT NULL,
+    compression INTEGER NOT NULL
+);
+
+-- Blobs with a chunk_hash are that chunk rather than part of the file's
+-- data file.
+ALTER TABLE file_blobs ADD COLUMN chunk_hash BLOB REFERENCES chunks (hash);
@@ -0,0 +1 @@
+DROP TABLE previous_blobs;
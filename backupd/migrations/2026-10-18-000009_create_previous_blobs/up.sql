@@ -0,0 +1,13 @@
+-- Blobs of the version of a file that a new upload replaced, kept until the
+-- upload finishes so delta uploads can copy from it. Its data file is
+-- <id>.previous; files without rows here are stored there as they are.
+CREATE TABLE previous_blobs (
+    file_id TEXT NOT NULL REFERENCES files (id),
+    file_offset BIGINT NOT NULL,
+    len BIGINT NOT NULL,
+    stored_offset BIGINT NOT NULL,
+    stored_len BIGINT NOT NULL,
+    compression INTEGER NOT NULL,
+    chunk_hash BLOB REFERENCES chunks (hash),
+    PRIMARY KEY (file_id, file_offset)
+);
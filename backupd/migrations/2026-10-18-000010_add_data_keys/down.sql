@@ -0,0 +1,58 @@
+-- SQLite can't drop columns, so copy everything else into new tables.
+CREATE TABLE files_without_data_keys (
+    id TEXT NOT NULL PRIMARY KEY,
+    filename TEXT NOT NULL,
+    last_modified BIGINT NOT NULL,
+    content_hash BLOB,
+    owner TEXT NOT NULL DEFAULT '',
+    last_modified_nanos INTEGER NOT NULL DEFAULT 0,
+    entry_type INTEGER NOT NULL DEFAULT 0,
+    symlink_target BLOB,
+    device_major INTEGER NOT NULL DEFAULT 0,
+    device_minor INTEGER NOT NULL DEFAULT 0
+);
+INSERT INTO files_without_data_keys (id, filename, last_modified, content_hash, owner, last_modified_nanos, entry_type, symlink_target, device_major, device_minor)
+    SELECT id, filename, last_modified, content_hash, owner, last_modified_nanos, entry_type, symlink_target, device_major, device_minor FROM files;
+DROP TABLE files;
+ALTER TABLE files_without_data_keys RENAME TO files;
+
+CREATE TABLE chunks_without_data_keys (
+    hash BLOB NOT NULL PRIMARY KEY,
+    len BIGINT NOT NULL,
+    stored_len BIGINT NOT NULL,
+    compression INTEGER NOT NULL
+);
+INSERT INTO chunks_without_data_keys (hash, len, stored_len, compression)
+    SELECT hash, len, stored_len, compression FROM chunks;
+DROP TABLE chunks;
+ALTER TABLE chunks_without_data_keys RENAME TO chunks;
+
+CREATE TABLE file_blobs_without_nonces (
+    file_id TEXT NOT NULL REFERENCES files (id),
+    file_offset BIGINT NOT NULL,
+    len BIGINT NOT NULL,
+    stored_offset BIGINT NOT NULL,
+    stored_len BIGINT NOT NULL,
+    compression INTEGER NOT NULL,
+    chunk_hash BLOB REFERENCES chunks (hash),
+    PRIMARY KEY (file_id, file_offset)
+);
+INSERT INTO file_blobs_without_nonces (file_id, file_offset, len, stored_offset, stored_len, compression, chunk_hash)
+    SELECT file_id, file_offset, len, stored_offset, stored_len, compression, chunk_hash FROM file_blobs;
+DROP TABLE file_blobs;
+ALTER TABLE file_blobs_without_nonces RENAME TO file_blobs;
+
+CREATE TABLE previous_blobs_without_nonces (
+    file_id TEXT NOT NULL REFERENCES files (id),
+    file_offset BIGINT NOT NULL,
+    len BIGINT NOT NULL,
+    stored_offset BIGINT NOT NULL,
+    stored_len BIGINT NOT NULL,
+    compression INTEGER NOT NULL,
+    chunk_hash BLOB REFERENCES chunks (hash),
+    PRIMARY KEY (file_id, file_offset)
+);
+INSERT INTO previous_blobs_without_nonces (file_id, file_offset, len, stored_offset, stored_len, compression, chunk_hash)
+    SELECT file_id, file_offset, len, stored_offset, stored_len, compression, chunk_hash FROM previous_blobs;
+DROP TABLE previous_blobs;
+ALTER TABLE previous_blobs_without_nonces RENAME TO previous_blobs;
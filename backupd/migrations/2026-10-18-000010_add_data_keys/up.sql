@@ -0,0 +1,9 @@
+-- Encryption at rest: the data keys of files and shared chunks, wrapped by
+-- the storage key. NULL for anything stored before it was configured.
+ALTER TABLE files ADD COLUMN data_key BLOB;
+ALTER TABLE chunks ADD COLUMN data_key BLOB;
+
+-- Nonce each encrypted blob of a data file was sealed with. Blobs that are
+-- shared chunks use their chunk's data key instead.
+ALTER TABLE file_blobs ADD COLUMN nonce BLOB;
+ALTER TABLE previous_blobs ADD COLUMN nonce BLOB;
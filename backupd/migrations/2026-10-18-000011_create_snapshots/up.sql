@@ -0,0 +1,29 @@
+-- Backup runs. A snapshot is only visible to restores once it's committed,
+-- which sets finished and file_count.
+CREATE TABLE snapshots (
+    id BLOB NOT NULL PRIMARY KEY,
+    owner TEXT NOT NULL,
+    host TEXT NOT NULL,
+    started BIGINT NOT NULL,
+    started_nanos INTEGER NOT NULL,
+    finished BIGINT,
+    finished_nanos INTEGER,
+    file_count BIGINT NOT NULL
+);
+
+-- The files of each snapshot, as they were stored when they were added to
+-- it. The columns are as in files.
+CREATE TABLE snapshot_files (
+    snapshot_id BLOB NOT NULL REFERENCES snapshots (id),
+    filename TEXT NOT NULL,
+    file_id TEXT NOT NULL REFERENCES files (id),
+    last_modified BIGINT NOT NULL,
+    last_modified_nanos INTEGER NOT NULL,
+    file_size BIGINT NOT NULL,
+    content_hash BLOB NOT NULL,
+    entry_type INTEGER NOT NULL,
+    symlink_target BLOB,
+    device_major INTEGER NOT NULL,
+    device_minor INTEGER NOT NULL,
+    PRIMARY KEY (snapshot_id, filename)
+);
@@ -0,0 +1,12 @@
+-- SQLite can't drop columns, so copy everything else into a new table.
+CREATE TABLE chunks_without_nonces (
+    hash BLOB NOT NULL PRIMARY KEY,
+    len BIGINT NOT NULL,
+    stored_len BIGINT NOT NULL,
+    compression INTEGER NOT NULL,
+    data_key BLOB
+);
+INSERT INTO chunks_without_nonces (hash, len, stored_len, compression, data_key)
+    SELECT hash, len, stored_len, compression, data_key FROM chunks;
+DROP TABLE chunks;
+ALTER TABLE chunks_without_nonces RENAME TO chunks;
@@ -0,0 +1,3 @@
+-- Nonce each encrypted chunk was sealed with. NULL for chunks sealed before
+-- nonces were stored, which used an all-zero one under a key of their own.
+ALTER TABLE chunks ADD COLUMN nonce BLOB;
@@ -0,0 +1,62 @@
+-- Versions other than the previous one of each file are lost, and so is
+-- which version a snapshot had. SQLite can't drop columns, so copy
+-- everything else into new tables.
+CREATE TABLE previous_blobs (
+    file_id TEXT NOT NULL REFERENCES files (id),
+    file_offset BIGINT NOT NULL,
+    len BIGINT NOT NULL,
+    stored_offset BIGINT NOT NULL,
+    stored_len BIGINT NOT NULL,
+    compression INTEGER NOT NULL,
+    chunk_hash BLOB REFERENCES chunks (hash),
+    nonce BLOB,
+    PRIMARY KEY (file_id, file_offset)
+);
+INSERT INTO previous_blobs (file_id, file_offset, len, stored_offset, stored_len, compression, chunk_hash, nonce)
+    SELECT file_versions.file_id, file_offset, len, stored_offset, stored_len, compression, chunk_hash, nonce
+    FROM version_blobs
+    JOIN file_versions ON file_versions.id = version_blobs.version_id
+    WHERE file_versions.data_path = file_versions.file_id || '.previous';
+
+DROP TABLE version_xattrs;
+DROP TABLE version_attributes;
+DROP TABLE version_blobs;
+DROP TABLE file_versions;
+
+CREATE TABLE files_without_versions (
+    id TEXT NOT NULL PRIMARY KEY,
+    filename TEXT NOT NULL,
+    last_modified BIGINT NOT NULL,
+    content_hash BLOB,
+    owner TEXT NOT NULL DEFAULT '',
+    last_modified_nanos INTEGER NOT NULL DEFAULT 0,
+    entry_type INTEGER NOT NULL DEFAULT 0,
+    symlink_target BLOB,
+    device_major INTEGER NOT NULL DEFAULT 0,
+    device_minor INTEGER NOT NULL DEFAULT 0,
+    data_key BLOB
+);
+INSERT INTO files_without_versions (id, filename, last_modified, content_hash, owner, last_modified_nanos, entry_type, symlink_target, device_major, device_minor, data_key)
+    SELECT id, filename, last_modified, content_hash, owner, last_modified_nanos, entry_type, symlink_target, device_major, device_minor, data_key FROM files;
+DROP TABLE files;
+ALTER TABLE files_without_versions RENAME TO files;
+
+CREATE TABLE snapshot_files_without_versions (
+    snapshot_id BLOB NOT NULL REFERENCES snapshots (id),
+    filename TEXT NOT NULL,
+    file_id TEXT NOT NULL REFERENCES files (id),
+    last_modified BIGINT NOT NULL,
+    last_modified_nanos INTEGER NOT NULL,
+    file_size BIGINT NOT NULL,
+    content_hash BLOB NOT NULL,
+    entry_type INTEGER NOT NULL,
+    symlink_target BLOB,
+    device_major INTEGER NOT NULL,
+    device_minor INTEGER NOT NULL,
+    PRIMARY KEY (snapshot_id, filename)
+);
+INSERT INTO snapshot_files_without_versions
+    SELECT snapshot_id, filename, file_id, last_modified, last_modified_nanos, file_size, content_hash, entry_type, symlink_target, device_major, device_minor
+    FROM snapshot_files;
+DROP TABLE snapshot_files;
+ALTER TABLE snapshot_files_without_versions RENAME TO snapshot_files;
@@ -0,0 +1,66 @@
+-- Every upload of a file makes a new version of it. The current version's
+-- data stays in files, file_blobs and file_attributes; a version an upload
+-- replaced is kept in file_versions while a delta upload may copy from it
+-- or a snapshot has it. Its data file is data_path in the data directory.
+ALTER TABLE files ADD COLUMN version_id TEXT NOT NULL DEFAULT '';
+UPDATE files SET version_id = id;
+ALTER TABLE files ADD COLUMN previous_version_id TEXT;
+
+CREATE TABLE file_versions (
+    id TEXT NOT NULL PRIMARY KEY,
+    file_id TEXT NOT NULL REFERENCES files (id),
+    data_path TEXT NOT NULL
+);
+
+-- The columns are as in file_blobs.
+CREATE TABLE version_blobs (
+    version_id TEXT NOT NULL REFERENCES file_versions (id),
+    file_offset BIGINT NOT NULL,
+    len BIGINT NOT NULL,
+    stored_offset BIGINT NOT NULL,
+    stored_len BIGINT NOT NULL,
+    compression INTEGER NOT NULL,
+    chunk_hash BLOB REFERENCES chunks (hash),
+    nonce BLOB,
+    PRIMARY KEY (version_id, file_offset)
+);
+
+-- Previous versions become versions of their own.
+INSERT INTO file_versions (id, file_id, data_path)
+    SELECT DISTINCT 'previous-' || file_id, file_id, file_id || '.previous' FROM previous_blobs;
+UPDATE files SET previous_version_id = 'previous-' || id
+    WHERE id IN (SELECT file_id FROM previous_blobs);
+INSERT INTO version_blobs (version_id, file_offset, len, stored_offset, stored_len, compression, chunk_hash, nonce)
+    SELECT 'previous-' || file_id, file_offset, len, stored_offset, stored_len, compression, chunk_hash, nonce
+    FROM previous_blobs;
+DROP TABLE previous_blobs;
+
+-- Attributes of the versions in snapshots, as in file_attributes and
+-- file_xattrs.
+CREATE TABLE version_attributes (
+    version_id TEXT NOT NULL PRIMARY KEY,
+    mode INTEGER NOT NULL,
+    uid BIGINT NOT NULL,
+    gid BIGINT NOT NULL,
+    user_name TEXT,
+    group_name TEXT,
+    accessed BIGINT NOT NULL,
+    accessed_nanos INTEGER NOT NULL,
+    changed BIGINT NOT NULL,
+    changed_nanos INTEGER NOT NULL
+);
+
+CREATE TABLE version_xattrs (
+    version_id TEXT NOT NULL,
+    name BLOB NOT NULL,
+    value BLOB,
+    PRIMARY KEY (version_id, name)
+);
+
+-- Snapshots so far have the current version of their files.
+ALTER TABLE snapshot_files ADD COLUMN version_id TEXT NOT NULL DEFAULT '';
+UPDATE snapshot_files SET version_id = file_id;
+INSERT INTO version_attributes
+    SELECT * FROM file_attributes WHERE file_id IN (SELECT file_id FROM snapshot_files);
+INSERT INTO version_xattrs
+    SELECT * FROM file_xattrs WHERE file_id IN (SELECT file_id FROM snapshot_files);
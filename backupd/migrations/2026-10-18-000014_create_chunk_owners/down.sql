@@ -0,0 +1 @@
+DROP TABLE chunk_owners;
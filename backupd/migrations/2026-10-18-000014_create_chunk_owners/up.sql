@@ -0,0 +1,21 @@
+-- Clients that uploaded the data of each shared chunk. Only they may refer
+-- to it by its hash, so knowing a hash doesn't give away anyone else's
+-- data.
+CREATE TABLE chunk_owners (
+    hash BLOB NOT NULL REFERENCES chunks (hash),
+    owner TEXT NOT NULL,
+    PRIMARY KEY (hash, owner)
+);
+
+-- Clients whose files have a chunk already keep it
+INSERT OR IGNORE INTO chunk_owners (hash, owner)
+    SELECT file_blobs.chunk_hash, files.owner
+    FROM file_blobs
+    JOIN files ON files.id = file_blobs.file_id
+    WHERE file_blobs.chunk_hash IS NOT NULL;
+INSERT OR IGNORE INTO chunk_owners (hash, owner)
+    SELECT version_blobs.chunk_hash, files.owner
+    FROM version_blobs
+    JOIN file_versions ON file_versions.id = version_blobs.version_id
+    JOIN files ON files.id = file_versions.file_id
+    WHERE version_blobs.chunk_hash IS NOT NULL;
@@ -2,11 +2,47 @@ use std::path::PathBuf;
 
 use serde_derive::Deserialize;
 
-mod yaml_reader;
+pub mod yaml_reader;
 
 #[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct Configuration {
     pub storage_path: PathBuf,
+    /// Serve over TLS instead of plaintext.
+    pub tls: Option<TlsConfiguration>,
+    /// Clients allowed to connect. Requests without one of their API keys
+    /// or client certificates are rejected.
+    #[serde(default)]
+    pub clients: Vec<ClientConfiguration>,
+    /// File with the 32-byte key that stored files are encrypted with at
+    /// rest. Without it, they're stored as they are.
+    pub storage_key_file: Option<PathBuf>,
+    /// Seconds an upload may go without a request before its token
+    /// expires. Defaults to 15 minutes.
+    pub token_idle_timeout_secs: Option<u64>,
+    /// Bytes of chunks that arrive ahead of their turn the server may hold
+    /// for all uploads together. Defaults to 512 MiB.
+    pub max_held_bytes: Option<usize>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ClientConfiguration {
+    /// Owner recorded for the client's files.
+    pub name: String,
+    pub api_key: Option<String>,
+    /// Subject common name of the client's certificate. Needs
+    /// `tls.client_ca_bundle`.
+    pub certificate_common_name: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct TlsConfiguration {
+    /// PEM file with the server certificate, followed by any intermediates.
+    pub certificate: PathBuf,
+    /// PEM file with the certificate's private key.
+    pub private_key: PathBuf,
+    /// PEM file with the CAs client certificates must be issued by. Without
+    /// it, clients can only log in with API keys.
+    pub client_ca_bundle: Option<PathBuf>,
 }
 
 pub trait ConfigReader {
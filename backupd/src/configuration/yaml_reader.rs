@@ -31,7 +31,7 @@ mod tests {
     use std::io::Cursor;
 
     use super::YamlReader;
-    use crate::configuration::{Configuration, ConfigReader};
+    use crate::configuration::{ClientConfiguration, Configuration, ConfigReader, TlsConfiguration};
 
     #[test]
     fn test_read_proper_config() {
@@ -41,6 +41,144 @@ mod tests {
         let config_result = config_reader.read_config();
         let config_should_be = Configuration {
             storage_path: "foo".into(),
+            tls: None,
+            clients: vec![],
+            storage_key_file: None,
+            token_idle_timeout_secs: None,
+            max_held_bytes: None,
+        };
+
+        assert_eq!(config_result.unwrap(), config_should_be);
+    }
+
+    #[test]
+    fn test_read_tls_config() {
+        let static_config = Cursor::new(r#"
+            storage_path: foo
+            tls:
+              certificate: server.pem
+              private_key: server.key
+        "#);
+        let mut config_reader = YamlReader::new(static_config);
+
+        let config_result = config_reader.read_config();
+        let config_should_be = Configuration {
+            storage_path: "foo".into(),
+            tls: Some(TlsConfiguration {
+                certificate: "server.pem".into(),
+                private_key: "server.key".into(),
+                client_ca_bundle: None,
+            }),
+            clients: vec![],
+            storage_key_file: None,
+            token_idle_timeout_secs: None,
+            max_held_bytes: None,
+        };
+
+        assert_eq!(config_result.unwrap(), config_should_be);
+    }
+
+    #[test]
+    fn test_read_clients_config() {
+        let static_config = Cursor::new(r#"
+            storage_path: foo
+            clients:
+              - name: laptop
+                api_key: secret1
+              - name: desktop
+                api_key: secret2
+              - name: server
+                certificate_common_name: server.example.com
+        "#);
+        let mut config_reader = YamlReader::new(static_config);
+
+        let config_result = config_reader.read_config();
+        let config_should_be = Configuration {
+            storage_path: "foo".into(),
+            tls: None,
+            clients: vec![
+                ClientConfiguration {
+                    name: "laptop".into(),
+                    api_key: Some("secret1".into()),
+                    certificate_common_name: None,
+                },
+                ClientConfiguration {
+                    name: "desktop".into(),
+                    api_key: Some("secret2".into()),
+                    certificate_common_name: None,
+                },
+                ClientConfiguration {
+                    name: "server".into(),
+                    api_key: None,
+                    certificate_common_name: Some("server.example.com".into()),
+                },
+            ],
+            storage_key_file: None,
+            token_idle_timeout_secs: None,
+            max_held_bytes: None,
+        };
+
+        assert_eq!(config_result.unwrap(), config_should_be);
+    }
+
+    #[test]
+    fn test_read_storage_key_config() {
+        let static_config = Cursor::new(r#"
+            storage_path: foo
+            storage_key_file: storage.key
+        "#);
+        let mut config_reader = YamlReader::new(static_config);
+
+        let config_result = config_reader.read_config();
+        let config_should_be = Configuration {
+            storage_path: "foo".into(),
+            tls: None,
+            clients: vec![],
+            storage_key_file: Some("storage.key".into()),
+            token_idle_timeout_secs: None,
+            max_held_bytes: None,
+        };
+
+        assert_eq!(config_result.unwrap(), config_should_be);
+    }
+
+    #[test]
+    fn test_read_token_idle_timeout_config() {
+        let static_config = Cursor::new(r#"
+            storage_path: foo
+            token_idle_timeout_secs: 300
+        "#);
+        let mut config_reader = YamlReader::new(static_config);
+
+        let config_result = config_reader.read_config();
+        let config_should_be = Configuration {
+            storage_path: "foo".into(),
+            tls: None,
+            clients: vec![],
+            storage_key_file: None,
+            token_idle_timeout_secs: Some(300),
+            max_held_bytes: None,
+        };
+
+        assert_eq!(config_result.unwrap(), config_should_be);
+    }
+
+    #[test]
+    fn test_read_max_held_bytes_config() {
+        let static_config = Cursor::new(r#"
+            storage_path: foo
+            max_held_bytes: 1048576
+        "#);
+        let mut config_reader = YamlReader::new(static_config);
+
+        let config_result = config_reader.read_config();
+        let config_should_be = Configuration {
+            storage_path: "foo".into(),
+            tls: None,
+            clients: vec![],
+            storage_key_file: None,
+            token_idle_timeout_secs: None,
+            max_held_bytes: Some(1048576),
         };
 
         assert_eq!(config_result.unwrap(), config_should_be);
@@ -1,17 +1,29 @@
 #[macro_use]
 extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
 
 mod server;
 mod storage;
 mod configuration;
 
 use std::env;
+use std::fs::{self, File};
+use std::path::Path;
+use std::process;
 use std::thread;
+use std::time::Duration;
 
+use backuplib::crypto::{Key, KEY_LEN};
 use backuplib::grpc::ServerBuilder;
 use backuplib::rpc::BaacupServer;
+use backuplib::tls::{ClientIdentities, TlsAcceptor, TlsAcceptorBuilder};
+use backuplib::tls_api::TlsAcceptorBuilder as _;
 
+use configuration::{ConfigReader, Configuration, TlsConfiguration};
+use configuration::yaml_reader::YamlReader;
 use server::BaacupImpl;
+use storage::sqlite_db::SqliteStorageManager;
 
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
@@ -19,11 +31,48 @@ fn main() {
     backuplib::print_hello();
     println!("backupd v{} using backuplib v{}", VERSION, backuplib::VERSION);
 
-    let filename = env::args().skip(1).next().unwrap_or("backup/".into());
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("rotate-storage-key") {
+        if args.len() != 3 {
+            eprintln!("usage: backupd rotate-storage-key <config file> <new key file>");
+            process::exit(2);
+        }
+        return rotate_storage_key(&args[1], Path::new(&args[2]));
+    }
+
+    let config_path = args.into_iter().next().unwrap_or("config.yml".into());
+    let config = read_config(&config_path);
+
+    let storage_key = config.storage_key_file.as_ref().map(|key_file| read_key(key_file));
+    let mut baacup_impl = BaacupImpl::new_from_db_path(&config.storage_path.to_string_lossy(), storage_key)
+        .unwrap_or_else(|err| {
+            eprintln!("Can't open storage: {}", err);
+            process::exit(1);
+        });
+    if let Some(idle_timeout) = config.token_idle_timeout_secs {
+        baacup_impl.set_token_idle_timeout(Duration::from_secs(idle_timeout));
+    }
+    if let Some(max_held_bytes) = config.max_held_bytes {
+        baacup_impl.set_max_server_held_bytes(max_held_bytes);
+    }
+    for client in &config.clients {
+        if let Some(ref api_key) = client.api_key {
+            baacup_impl.add_client(&client.name, api_key);
+        }
+        if let Some(ref common_name) = client.certificate_common_name {
+            baacup_impl.add_certificate_client(&client.name, common_name);
+        }
+    }
 
-    let mut server_builder = ServerBuilder::new_plain();
+    let mut server_builder = ServerBuilder::<TlsAcceptor>::new();
     server_builder.http.set_port(8000);
-    let baacup_impl = BaacupImpl::new_from_db_path(&filename);
+    if let Some(ref tls) = config.tls {
+        let (acceptor, client_identities) = load_tls_acceptor(tls);
+        server_builder.http.set_tls(acceptor);
+        if let Some(client_identities) = client_identities {
+            baacup_impl.accept_client_certificates(client_identities);
+        }
+    }
     server_builder.add_service(BaacupServer::new_service_def(baacup_impl));
     let _server = server_builder.build().unwrap();
 
@@ -31,3 +80,47 @@ fn main() {
         thread::park();
     }
 }
+
+/// Rewraps the data keys of everything stored encrypted at rest with the
+/// key in `new_key_file`. Opening the storage locks it, so this refuses to
+/// run while the server is up, and the server can't start meanwhile.
+fn rotate_storage_key(config_path: &str, new_key_file: &Path) {
+    let config = read_config(config_path);
+    let key_file = config.storage_key_file.as_ref().expect("storage_key_file isn't set");
+    let new_key = read_key(new_key_file);
+
+    let mut storage = SqliteStorageManager::new(&config.storage_path.to_string_lossy())
+        .unwrap_or_else(|err| {
+            eprintln!("Can't open storage: {}", err);
+            process::exit(1);
+        });
+    storage.encrypt_at_rest(read_key(key_file));
+    let rotated = storage.rotate_storage_key(new_key).expect("could not rotate storage key");
+    println!("Rewrapped {} data keys. Set storage_key_file to {} before starting the server.",
+             rotated, new_key_file.display());
+}
+
+fn read_config(config_path: &str) -> Configuration {
+    let config_file = File::open(config_path).expect("could not open config file");
+    YamlReader::new(config_file).read_config().expect("could not read config file")
+}
+
+fn read_key(key_file: &Path) -> Key {
+    let bytes = fs::read(key_file).expect("could not read storage key");
+    Key::from_slice(&bytes).unwrap_or_else(|| panic!("{} must hold exactly {} bytes", key_file.display(), KEY_LEN))
+}
+
+fn load_tls_acceptor(tls: &TlsConfiguration) -> (TlsAcceptor, Option<ClientIdentities>) {
+    let certificate = fs::read(&tls.certificate).expect("could not read TLS certificate");
+    let private_key = fs::read(&tls.private_key).expect("could not read TLS private key");
+
+    let mut builder = TlsAcceptorBuilder::from_pem(&certificate, &private_key)
+        .expect("invalid TLS certificate or private key");
+    let client_identities = tls.client_ca_bundle.as_ref().map(|client_ca_bundle| {
+        let client_ca_bundle = fs::read(client_ca_bundle).expect("could not read client CA bundle");
+        builder.verify_client_certificates(&client_ca_bundle).expect("invalid client CA bundle")
+    });
+    let acceptor = builder.build().expect("invalid TLS configuration");
+
+    (acceptor, client_identities)
+}
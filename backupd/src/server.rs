@@ -1,50 +1,581 @@
+use std::borrow::Cow;
 use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
-use std::io::{Seek, Write, SeekFrom};
-use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use futures::Stream;
 
 use backuplib::rpc::*;
+use backuplib::compression;
+use backuplib::crypto::Key;
+use backuplib::delta;
+use backuplib::hash::ContentHasher;
+use backuplib::tls::ClientIdentities;
 
-use crate::storage::{StorageManager, FileLen, FileSystem};
+use crate::storage::{StorageManager, StorageError, FileSystem, UploadSession};
 use crate::storage::sqlite_db::SqliteStorageManager;
 
+/// Largest chunk a client may request through `read_chunk` or
+/// `read_snapshot_chunk` at once.
+const MAX_READ_LEN: u64 = 1024 * 1024;
+
+/// How much of a stored file is read at once while hashing it.
+const HASH_READ_LEN: u64 = 64 * 1024;
+
+/// How many blocks are read at once while computing a signature.
+const SIGNATURE_READ_BLOCKS: u64 = 64;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Everything `BaacupImpl` offers on top of the basic protocol.
+const CAPABILITIES: &[Capability] = &[
+    Capability::Checksums,
+    Capability::ContentHash,
+    Capability::Restore,
+    Capability::PosixAttributes,
+    Capability::EntryTypes,
+    Capability::UploadStream,
+    Capability::Zstd,
+    Capability::Dedup,
+    Capability::Delta,
+    Capability::BatchStatus,
+    Capability::Snapshots,
+];
+
+/// How much data of an upload may arrive ahead of the chunks in front of it.
+/// Chunks beyond that get `BadOffset`, and the client sends them again.
+pub const MAX_HELD_BYTES: usize = 32 * 1024 * 1024;
+
+/// How much data all uploads together may hold by default, see
+/// `BaacupImpl::set_max_server_held_bytes`.
+const DEFAULT_MAX_SERVER_HELD_BYTES: usize = 512 * 1024 * 1024;
+
+/// How long an upload may go without a request before its token expires.
+const DEFAULT_TOKEN_IDLE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// How often an upload in use is saved to storage. A restart makes it look
+/// idle for at most this long.
+const SESSION_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
 macro_rules! try_future {
     ($x:expr) => {
         match $x {
             Ok(val) => val,
-            Err(err) => return BaacupFuture::new(Err(err)),
+            Err(err) => return BaacupFuture::new(Err(err.into())),
         }
     };
 }
 
-struct Context {
-    file_metadata: FileMetadata,
+/// An upload in progress. Requests for the same upload take turns on its
+/// lock, while other uploads go on.
+struct ActiveUpload {
+    session: UploadSession,
+    /// When `session` was last saved to storage.
+    saved: SystemTime,
+    /// Set once the last chunk is written. Requests that were waiting for
+    /// the lock then treat the upload as finished.
+    finished: bool,
+    /// Chunks that arrived before the ones in front of them, by offset.
+    held: BTreeMap<u64, FileChunk>,
+    /// Bytes held by all uploads, which `held` counts towards for as long
+    /// as it has them.
+    server_held_bytes: Arc<Mutex<usize>>,
 }
 
-impl Context {
-    fn new(file_metadata: FileMetadata) -> Context {
-        Context {
-            file_metadata: file_metadata,
+impl ActiveUpload {
+    fn new(session: UploadSession, server_held_bytes: Arc<Mutex<usize>>) -> Arc<Mutex<ActiveUpload>> {
+        Arc::new(Mutex::new(ActiveUpload {
+            session: session,
+            saved: SystemTime::now(),
+            finished: false,
+            held: BTreeMap::new(),
+            server_held_bytes: server_held_bytes,
+        }))
+    }
+
+    /// Bytes of data held for this upload.
+    fn held_bytes(&self) -> usize {
+        self.held.values()
+            .map(|held| held.data.len())
+            .sum()
+    }
+
+    /// Holds `chunk`, unless all uploads together would then hold more than
+    /// `max_server_held_bytes`.
+    fn hold(&mut self, chunk: FileChunk, max_server_held_bytes: usize) -> bool {
+        let mut server_held_bytes = self.server_held_bytes.lock().unwrap();
+        if *server_held_bytes + chunk.data.len() > max_server_held_bytes {
+            return false;
         }
+        *server_held_bytes += chunk.data.len();
+        self.held.insert(chunk.offset, chunk);
+        true
+    }
+
+    /// Takes the chunk held at `offset`, if any.
+    fn take_held(&mut self, offset: u64) -> Option<FileChunk> {
+        let chunk = self.held.remove(&offset)?;
+        *self.server_held_bytes.lock().unwrap() -= chunk.data.len();
+        Some(chunk)
+    }
+
+    /// Drops the held chunks that start before `offset`.
+    fn drop_held_before(&mut self, offset: u64) {
+        let kept = self.held.split_off(&offset);
+        self.drop_held();
+        self.held = kept;
+    }
+
+    /// Drops every held chunk.
+    fn drop_held(&mut self) {
+        *self.server_held_bytes.lock().unwrap() -= self.held_bytes();
+        self.held.clear();
     }
 }
 
+impl Drop for ActiveUpload {
+    fn drop(&mut self) {
+        self.drop_held();
+    }
+}
+
+/// Every copy shares the same state. The copies handed out by `authenticate`
+/// act on behalf of one client; calls on any other copy are rejected.
 pub struct BaacupImpl<S> {
-    next_token_mutex: Arc<Mutex<u32>>,
-    token_map_mutex: Arc<Mutex<HashMap<u32, Context>>>,
-    storage: S,
+    /// Uploads in progress. They're saved to storage when they start and
+    /// every `SESSION_SAVE_INTERVAL` while in use, so uploads survive a
+    /// restart. The map is only locked to look uploads up, never during
+    /// storage I/O.
+    token_map_mutex: Arc<Mutex<HashMap<UploadToken, Arc<Mutex<ActiveUpload>>>>>,
+    /// Uploads that finished recently, kept until they expire so a client
+    /// that missed the response to its last chunk can send it again.
+    finished_uploads_mutex: Arc<Mutex<HashMap<UploadToken, UploadSession>>>,
+    /// When `expire_uploads` last went through the uploads.
+    last_expiry_mutex: Arc<Mutex<SystemTime>>,
+    token_idle_timeout: Duration,
+    /// Bytes held by all uploads, and how many they may hold. Uploads hold
+    /// chunks that arrive early, and without a limit on all of them, many
+    /// uploads could hold more than the server has memory for.
+    server_held_bytes_mutex: Arc<Mutex<usize>>,
+    max_server_held_bytes: usize,
+    storage: Arc<S>,
+    /// Client names by the SHA-256 of their API key. Looking up the digest
+    /// instead of the key itself keeps the lookup time independent of how
+    /// close a guess is.
+    api_keys: Arc<HashMap<Vec<u8>, String>>,
+    /// Client names by the common name of their certificate.
+    certificate_clients: Arc<HashMap<String, String>>,
+    client_identities: Option<ClientIdentities>,
+    client: Option<String>,
 }
 
 impl<S> BaacupImpl<S> {
     pub fn new_from_storage(storage_manager: S) -> BaacupImpl<S> {
         BaacupImpl {
-            next_token_mutex: Arc::new(Mutex::new(0)),
             token_map_mutex: Arc::new(Mutex::new(HashMap::new())),
-            storage: storage_manager,
+            finished_uploads_mutex: Arc::new(Mutex::new(HashMap::new())),
+            last_expiry_mutex: Arc::new(Mutex::new(SystemTime::now())),
+            token_idle_timeout: DEFAULT_TOKEN_IDLE_TIMEOUT,
+            server_held_bytes_mutex: Arc::new(Mutex::new(0)),
+            max_server_held_bytes: DEFAULT_MAX_SERVER_HELD_BYTES,
+            storage: Arc::new(storage_manager),
+            api_keys: Arc::new(HashMap::new()),
+            certificate_clients: Arc::new(HashMap::new()),
+            client_identities: None,
+            client: None,
+        }
+    }
+
+    /// Lets the client called `name` in with `api_key`.
+    pub fn add_client(&mut self, name: &str, api_key: &str) {
+        Arc::make_mut(&mut self.api_keys).insert(api_key_digest(api_key), name.to_string());
+    }
+
+    /// Lets the client called `name` in with a client certificate whose
+    /// subject has `common_name`.
+    pub fn add_certificate_client(&mut self, name: &str, common_name: &str) {
+        Arc::make_mut(&mut self.certificate_clients).insert(common_name.to_string(), name.to_string());
+    }
+
+    /// Trusts the certificates the TLS acceptor verified, see
+    /// `TlsAcceptorBuilder::verify_client_certificates`.
+    pub fn accept_client_certificates(&mut self, client_identities: ClientIdentities) {
+        self.client_identities = Some(client_identities);
+    }
+
+    fn certificate_client(&self, channel_binding: &[u8]) -> Option<&String> {
+        let common_name = self.client_identities.as_ref()?.common_name(channel_binding)?;
+        self.certificate_clients.get(&common_name)
+    }
+
+    /// Expires upload tokens that go unused for `idle_timeout`.
+    pub fn set_token_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.token_idle_timeout = idle_timeout;
+    }
+
+    /// Limits how much data all uploads together may hold while it waits
+    /// for the chunks in front of it. Chunks beyond that get `BadOffset`.
+    pub fn set_max_server_held_bytes(&mut self, max_server_held_bytes: usize) {
+        self.max_server_held_bytes = max_server_held_bytes;
+    }
+
+    fn client(&self) -> Result<String, BaacupError> {
+        self.client.clone().ok_or(BaacupError::Unauthenticated)
+    }
+}
+
+impl<S> BaacupImpl<S>
+    where for<'a> S: StorageManager<'a>,
+{
+    /// Picks up the uploads that were in progress when the server last
+    /// stopped.
+    pub fn restore_uploads(&self) -> Result<(), StorageError> {
+        let uploads = self.storage.load_uploads()?;
+        let mut token_map = self.token_map_mutex.lock().unwrap();
+        token_map.extend(uploads.into_iter()
+            .map(|(token, session)| (token, ActiveUpload::new(session, self.server_held_bytes_mutex.clone()))));
+        Ok(())
+    }
+
+    /// Runs `f` on `client`'s upload with `token`, holding the upload's
+    /// lock, and marks the upload as used. Expired and finished uploads and
+    /// other clients' tokens don't exist for us.
+    fn with_upload<T, F>(&self, client: &str, token: &UploadToken, f: F) -> Result<T, BaacupError>
+        where F: FnOnce(&mut ActiveUpload) -> Result<T, BaacupError>,
+    {
+        let upload = self.token_map_mutex.lock().unwrap()
+            .get(token)
+            .cloned()
+            .ok_or(BaacupError::InvalidToken)?;
+        let mut upload = upload.lock().unwrap();
+
+        let now = SystemTime::now();
+        if upload.finished
+            || upload.session.owner != client
+            || upload.session.is_expired(now, self.token_idle_timeout)
+        {
+            return Err(BaacupError::InvalidToken);
+        }
+        upload.session.last_used = now;
+        if is_due(upload.saved, now, SESSION_SAVE_INTERVAL) {
+            self.storage.save_upload(token, &upload.session)?;
+            upload.saved = now;
+        }
+        f(&mut upload)
+    }
+
+    /// Looks up `client`'s finished upload with `token`, as long as it
+    /// hasn't expired.
+    fn finished_upload(&self, client: &str, token: &UploadToken) -> Result<UploadSession, BaacupError> {
+        let now = SystemTime::now();
+        let finished_uploads = self.finished_uploads_mutex.lock().unwrap();
+        finished_uploads.get(token)
+            .filter(|session| session.owner == client)
+            .filter(|session| !session.is_expired(now, self.token_idle_timeout))
+            .cloned()
+            .ok_or(BaacupError::InvalidToken)
+    }
+
+    /// Whether `data` is already stored at `offset`, i.e. the chunk was
+    /// resent after its response got lost.
+    fn is_stored(&self, client: &str, file_name: &str, offset: u64, chunk_data: &ChunkData) -> Result<bool, StorageError> {
+        let stored = self.storage.read(client, file_name, offset, chunk_data.len())?;
+        if stored.len() as u64 != chunk_data.len() {
+            return Ok(false);
+        }
+        match *chunk_data {
+            ChunkData::Own { ref data, .. } => Ok(checksum(&stored) == checksum(data)),
+            ChunkData::Copied { ref data } => Ok(checksum(&stored) == checksum(data)),
+            ChunkData::Shared { hash, .. } => {
+                let mut hasher = ContentHasher::new();
+                hasher.update(&stored);
+                Ok(hasher.finish() == hash)
+            }
+        }
+    }
+
+    /// Works out what `chunk`, headed for `offset` of the upload `session`,
+    /// adds to the file. Shared chunks that come with their data are added
+    /// to the chunk store.
+    fn decode_chunk<'c>(&self, chunk: &IncomingChunk<'c>, offset: u64, session: &UploadSession) -> Result<ChunkData<'c>, BaacupError> {
+        let file_size = session.file_metadata.file_size;
+        if let Some(copy) = chunk.copy {
+            return self.copy_previous(copy, chunk, offset, session);
+        }
+
+        let zstd_frame = match chunk.compression {
+            Compression::None => None,
+            Compression::Zstd => Some(chunk.contents),
+        };
+        let hash = match chunk.chunk_hash {
+            Some(hash) => hash,
+            None => return Ok(ChunkData::Own {
+                data: decompress_chunk(chunk.contents, chunk.compression, offset, file_size)?,
+                zstd_frame: zstd_frame,
+            }),
+        };
+
+        // Only a hash: the client sent us the chunk before
+        if chunk.contents.is_empty() {
+            let len = self.storage.chunk_len(&session.owner, hash)?.ok_or(BaacupError::NotFound)?;
+            if len > file_size.saturating_sub(offset) {
+                return Err(BaacupError::InvalidArgument("Chunk reaches past the end of the file".into()));
+            }
+            return Ok(ChunkData::Shared { hash: hash, len: len });
+        }
+
+        let data = decompress_chunk(chunk.contents, chunk.compression, offset, file_size)?;
+        let mut hasher = ContentHasher::new();
+        hasher.update(&data);
+        if hasher.finish() != hash {
+            return Err(BaacupError::InvalidArgument("Chunk data doesn't match its hash".into()));
+        }
+        self.storage.put_chunk(&session.owner, hash, &data, zstd_frame)?;
+        Ok(ChunkData::Shared { hash: hash, len: data.len() as u64 })
+    }
+
+    /// Reads what `chunk` copies from the previous version of the file of
+    /// the upload `session`.
+    fn copy_previous<'c>(&self, copy: CopyRange, chunk: &IncomingChunk<'c>, offset: u64, session: &UploadSession)
+        -> Result<ChunkData<'c>, BaacupError>
+    {
+        if !chunk.contents.is_empty() || chunk.chunk_hash.is_some() {
+            return Err(BaacupError::InvalidArgument("A chunk that copies can't have data of its own".into()));
+        }
+        if copy.len > MAX_COPY_LEN {
+            let message = format!("Can't copy more than {} bytes in one chunk", MAX_COPY_LEN);
+            return Err(BaacupError::InvalidArgument(message));
+        }
+        if copy.len > session.file_metadata.file_size.saturating_sub(offset) {
+            return Err(BaacupError::InvalidArgument("Chunk reaches past the end of the file".into()));
+        }
+
+        let data = self.storage.read_previous(&session.owner, &session.file_metadata.file_name, copy.offset, copy.len)?;
+        if data.len() as u64 != copy.len {
+            return Err(BaacupError::InvalidArgument("Copy reaches past the end of the previous version".into()));
+        }
+        Ok(ChunkData::Copied { data: data })
+    }
+
+    /// Writes `chunk` at `offset` of `client`'s upload with `token`. Returns
+    /// where the chunk ended, and the content hash once the upload is
+    /// complete and checked.
+    ///
+    /// Data that is already stored at `offset` is acknowledged without
+    /// writing it again.
+    fn append_to_upload(&self, client: &str, token: &UploadToken, offset: u64, chunk: &IncomingChunk)
+        -> Result<StreamResult, BaacupError>
+    {
+        let result = self.with_upload(client, token, |upload| self.append_to_active_upload(client, token, offset, chunk, upload));
+        match result {
+            Err(BaacupError::InvalidToken) => self.append_to_finished_upload(client, token, offset, chunk),
+            result => result,
+        }
+    }
+
+    /// Writes `chunk` like `append_to_upload`. Pipelined `upload_chunk`
+    /// calls can arrive out of order, so a chunk that starts past the head
+    /// is held until the chunks before it are written, as long as no more
+    /// than `MAX_HELD_BYTES` of the upload, and no more than the server-wide
+    /// limit of all uploads, are held. Acknowledging a held chunk only tells
+    /// the client it arrived intact.
+    fn append_or_hold(&self, client: &str, chunk: &FileChunk) -> Result<(), BaacupError> {
+        let incoming = IncomingChunk::from_file_chunk(chunk);
+        let result = self.with_upload(client, &chunk.token, |upload| {
+            let file_len = self.storage.get_head(client, &upload.session.file_metadata.file_name)?;
+            if chunk.offset <= file_len {
+                return self.append_to_active_upload(client, &chunk.token, chunk.offset, &incoming, upload)
+                    .map(|_| ());
+            }
+
+            // Sent again before its turn came
+            if upload.held.contains_key(&chunk.offset) {
+                return Ok(());
+            }
+            if upload.held_bytes() + chunk.data.len() > MAX_HELD_BYTES
+                || !upload.hold(chunk.clone(), self.max_server_held_bytes)
+            {
+                return Err(BaacupError::BadOffset { expected: file_len });
+            }
+            Ok(())
+        });
+        match result {
+            Err(BaacupError::InvalidToken) => self.append_to_finished_upload(client, &chunk.token, chunk.offset, &incoming)
+                .map(|_| ()),
+            result => result,
+        }
+    }
+
+    /// Acknowledges `chunk` if it's already stored at `offset` of `client`'s
+    /// finished upload with `token`, i.e. it was resent after the response
+    /// to it got lost.
+    fn append_to_finished_upload(&self, client: &str, token: &UploadToken, offset: u64, chunk: &IncomingChunk)
+        -> Result<StreamResult, BaacupError>
+    {
+        let session = self.finished_upload(client, token)?;
+        let file_name = &session.file_metadata.file_name;
+        let chunk_data = self.decode_chunk(chunk, offset, &session)?;
+        if self.is_stored(client, file_name, offset, &chunk_data)? {
+            return Ok(StreamResult {
+                offset: offset + chunk_data.len(),
+                content_hash: self.storage.content_hash(client, file_name)?,
+            });
+        }
+        Err(BaacupError::BadOffset { expected: session.file_metadata.file_size })
+    }
+
+    /// Does the work of `append_to_upload` for an `upload` that is still
+    /// in progress.
+    fn append_to_active_upload(&self, client: &str, token: &UploadToken, offset: u64, chunk: &IncomingChunk, upload: &mut ActiveUpload)
+        -> Result<StreamResult, BaacupError>
+    {
+        let chunk_data = self.decode_chunk(chunk, offset, &upload.session)?;
+        let mut end = offset + chunk_data.len();
+
+        // Double-check len
+        let file_name = upload.session.file_metadata.file_name.clone();
+        let file_len = self.storage.get_head(client, &file_name)?;
+        if file_len != offset {
+            if end <= file_len && self.is_stored(client, &file_name, offset, &chunk_data)? {
+                return Ok(StreamResult {
+                    offset: end,
+                    content_hash: None,
+                });
+            }
+            return Err(BaacupError::BadOffset { expected: file_len });
+        }
+        self.write_chunk(client, &file_name, &chunk_data)?;
+
+        // Chunks that arrived ahead of this one can go on now
+        while let Some(held) = upload.take_held(end) {
+            // The held chunk was acknowledged already, so if it's no good
+            // the client has to be sent back to it. This chunk is written.
+            let held_data = match self.decode_chunk(&IncomingChunk::from_file_chunk(&held), end, &upload.session) {
+                Ok(held_data) => held_data,
+                Err(_) => return Err(BaacupError::BadOffset { expected: end }),
+            };
+            self.write_chunk(client, &file_name, &held_data)?;
+            end += held_data.len();
+        }
+        upload.drop_held_before(end);
+
+        // Check if we're done
+        if end != upload.session.file_metadata.file_size {
+            return Ok(StreamResult {
+                offset: end,
+                content_hash: None,
+            });
+        }
+        // Make sure we ended up with the file the client has. If not, the
+        // upload starts over, and the client finds out with `get_head`.
+        let content_hash = hash_stored_file(&*self.storage, client, &file_name)?;
+        if let Some(ref client_hash) = upload.session.file_metadata.content_hash {
+            if *client_hash != content_hash {
+                self.storage.discard(client, &file_name)?;
+                upload.drop_held();
+                return Err(BaacupError::ContentHashMismatch);
+            }
+        }
+
+        println!("File upload finished.");
+        upload.finished = true;
+        upload.drop_held();
+        self.token_map_mutex.lock().unwrap().remove(token);
+        self.storage.remove_upload(token)?;
+        let session = upload.session.clone();
+        self.storage.finish(client, &session.file_metadata, &content_hash)?;
+        self.finished_uploads_mutex.lock().unwrap().insert(*token, session);
+        Ok(StreamResult {
+            offset: end,
+            content_hash: Some(content_hash),
+        })
+    }
+
+    /// Appends `chunk_data` to `client`'s file, keeping what the client
+    /// compressed compressed.
+    fn write_chunk(&self, client: &str, file_name: &str, chunk_data: &ChunkData) -> Result<(), StorageError> {
+        match *chunk_data {
+            ChunkData::Own { ref data, zstd_frame: None } => self.storage.append(client, file_name, data),
+            ChunkData::Own { ref data, zstd_frame: Some(zstd_frame) } => self.storage.append_zstd(client, file_name, data, zstd_frame),
+            ChunkData::Shared { hash, .. } => self.storage.append_chunk(client, file_name, hash),
+            // Copies tend to be big, so they're worth compressing
+            ChunkData::Copied { ref data } => match compression::compress(data.clone(), Compression::Zstd) {
+                (zstd_frame, Compression::Zstd) => self.storage.append_zstd(client, file_name, data, &zstd_frame),
+                _ => self.storage.append(client, file_name, data),
+            },
+        }
+    }
+
+    /// Forgets abandoned uploads. Uploads are only gone through once per
+    /// `SESSION_SAVE_INTERVAL`, or idle timeout if that's shorter, since
+    /// `with_upload` rejects expired ones anyway.
+    fn expire_uploads(&self) -> Result<(), StorageError> {
+        let now = SystemTime::now();
+        {
+            let mut last_expiry = self.last_expiry_mutex.lock().unwrap();
+            let interval = self.token_idle_timeout.min(SESSION_SAVE_INTERVAL);
+            if !is_due(*last_expiry, now, interval) {
+                return Ok(());
+            }
+            *last_expiry = now;
+        }
+        let expired: Vec<UploadToken> = {
+            let mut token_map = self.token_map_mutex.lock().unwrap();
+            // An upload whose lock is taken is in use
+            let expired: Vec<UploadToken> = token_map.iter()
+                .filter(|&(_, upload)| upload.try_lock()
+                    .map(|upload| upload.session.is_expired(now, self.token_idle_timeout))
+                    .unwrap_or(false))
+                .map(|(token, _)| *token)
+                .collect();
+            for token in &expired {
+                token_map.remove(token);
+            }
+            expired
+        };
+        for token in expired {
+            self.storage.remove_upload(&token)?;
         }
+
+        let mut finished_uploads = self.finished_uploads_mutex.lock().unwrap();
+        finished_uploads.retain(|_, session| !session.is_expired(now, self.token_idle_timeout));
+        Ok(())
     }
 }
 
+impl<S> Clone for BaacupImpl<S> {
+    fn clone(&self) -> BaacupImpl<S> {
+        BaacupImpl {
+            token_map_mutex: self.token_map_mutex.clone(),
+            finished_uploads_mutex: self.finished_uploads_mutex.clone(),
+            last_expiry_mutex: self.last_expiry_mutex.clone(),
+            token_idle_timeout: self.token_idle_timeout,
+            server_held_bytes_mutex: self.server_held_bytes_mutex.clone(),
+            max_server_held_bytes: self.max_server_held_bytes,
+            storage: self.storage.clone(),
+            api_keys: self.api_keys.clone(),
+            certificate_clients: self.certificate_clients.clone(),
+            client_identities: self.client_identities.clone(),
+            client: self.client.clone(),
+        }
+    }
+}
+
+/// Whether `interval` has passed between `last` and `now`.
+fn is_due(last: SystemTime, now: SystemTime, interval: Duration) -> bool {
+    // A clock going backwards doesn't make anything due
+    now.duration_since(last)
+        .map(|elapsed| elapsed >= interval)
+        .unwrap_or(false)
+}
+
+fn api_key_digest(api_key: &str) -> Vec<u8> {
+    let mut hasher = ContentHasher::new();
+    hasher.update(api_key.as_bytes());
+    hasher.finish()
+}
+
 impl BaacupImpl<FileSystem> {
     pub fn new_from_path<P>(path: P) -> BaacupImpl<FileSystem>
         where P: Into<PathBuf>,
@@ -55,79 +586,365 @@ impl BaacupImpl<FileSystem> {
 }
 
 impl BaacupImpl<SqliteStorageManager> {
-    pub fn new_from_db_path(path: &str) -> BaacupImpl<SqliteStorageManager> {
-        let fs = SqliteStorageManager::new(path);
-        Self::new_from_storage(fs)
+    /// Stores files encrypted at rest if there's a `storage_key`.
+    pub fn new_from_db_path(path: &str, storage_key: Option<Key>) -> Result<BaacupImpl<SqliteStorageManager>, StorageError> {
+        let mut fs = SqliteStorageManager::new(path)?;
+        if let Some(storage_key) = storage_key {
+            fs.encrypt_at_rest(storage_key);
+            let sealed = fs.seal_plaintext()?;
+            if sealed > 0 {
+                println!("Encrypted {} data files and chunks stored before encryption at rest.", sealed);
+            }
+        }
+        let baacup_impl = Self::new_from_storage(fs);
+        baacup_impl.restore_uploads()?;
+        Ok(baacup_impl)
+    }
+}
+
+impl<S> Authenticate for BaacupImpl<S>
+    where for<'a> S: StorageManager<'a>,
+          S: Send + Sync + 'static,
+{
+    type Session = BaacupImpl<S>;
+
+    /// An API key takes precedence over a client certificate, so a wrong
+    /// key is rejected even over a connection with a valid certificate.
+    fn authenticate(&self, credentials: &Credentials) -> Result<BaacupImpl<S>, BaacupError> {
+        let client = match (&credentials.api_key, &credentials.channel_binding) {
+            (Some(api_key), _) => self.api_keys.get(&api_key_digest(api_key)),
+            (None, Some(channel_binding)) => self.certificate_client(channel_binding),
+            (None, None) => None,
+        }.ok_or(BaacupError::Unauthenticated)?;
+
+        let mut session = self.clone();
+        session.client = Some(client.clone());
+        Ok(session)
     }
 }
 
 impl<S> Baacup for BaacupImpl<S>
     where for<'a> S: StorageManager<'a>,
+          S: Send + Sync + 'static,
 {
-    fn init_upload(&self, metadata: FileMetadata) -> BaacupFuture<u32> {
-        try_future!(self.storage.create(&metadata)
-            .map_err(|e| e.to_string()));
+    fn hello(&self, hello: Hello) -> BaacupFuture<Hello> {
+        try_future!(hello.check_compatible());
+        BaacupFuture::new(Ok(Hello::new(VERSION, CAPABILITIES)))
+    }
+
+    fn init_compressed_upload(&self, metadata: FileMetadata, compression: Compression) -> BaacupFuture<Upload> {
+        let client = try_future!(self.client());
+        if !metadata.entry_type.has_contents() && metadata.file_size != 0 {
+            let message = format!("{} has no contents but a size of {} bytes", metadata.file_name, metadata.file_size);
+            return BaacupFuture::new(Err(BaacupError::InvalidArgument(message)));
+        }
+        try_future!(self.storage.create(&client, &metadata));
+
+        // Nothing left to upload, so the token is never used
+        if !metadata.entry_type.has_contents() {
+            try_future!(self.storage.finish(&client, &metadata, &ContentHasher::new().finish()));
+            return BaacupFuture::new(Ok(Upload {
+                token: UploadToken::new(rand::random()),
+                compression: Compression::None,
+            }));
+        }
 
-        // Get a token and increment token counter
-        // (Bad for security)
-        let mut next_token = self.next_token_mutex.lock().unwrap();
-        let token = *next_token;
-        *next_token += 1;
+        try_future!(self.expire_uploads());
+
+        // Get a random token
+        let token = UploadToken::new(rand::random());
 
         // Insert token into map
-        let context = Context::new(metadata);
-        let mut token_map = self.token_map_mutex.lock().unwrap();
-        token_map.insert(token, context);
+        let session = UploadSession::new(client, metadata);
+        try_future!(self.storage.save_upload(&token, &session));
+        self.token_map_mutex.lock().unwrap().insert(token, ActiveUpload::new(session, self.server_held_bytes_mutex.clone()));
 
-        BaacupFuture::new(Ok(token))
+        // We take every compression there is
+        BaacupFuture::new(Ok(Upload {
+            token: token,
+            compression: compression,
+        }))
     }
 
-    fn get_head(&self, token: u32) -> BaacupFuture<u64> {
+    fn get_head(&self, token: UploadToken) -> BaacupFuture<u64> {
+        let client = try_future!(self.client());
+
         // Get path from map
-        let token_map = self.token_map_mutex.lock().unwrap();
-        let context = try_future!(token_map.get(&token)
-            .ok_or("Invalid token".to_string()));
+        let file_name = match self.with_upload(&client, &token, |upload| Ok(upload.session.file_metadata.file_name.clone())) {
+            Ok(file_name) => file_name,
+            // The client may have missed that its upload finished
+            Err(BaacupError::InvalidToken) => try_future!(self.finished_upload(&client, &token)).file_metadata.file_name,
+            Err(error) => return BaacupFuture::new(Err(error)),
+        };
 
         // Get file length
         BaacupFuture::new(self.storage
-            .get_head(&context.file_metadata.file_name)
-            .map_err(|e| e.to_string()))
+            .get_head(&client, &file_name)
+            .map_err(BaacupError::from))
     }
 
     fn upload_chunk(&self, chunk: FileChunk) -> BaacupFuture<u32> {
+        let client = try_future!(self.client());
         println!("Got chunk with token {} offset {} data.len() {}", chunk.token, chunk.offset, chunk.data.len());
 
-        // Get metadata
-        let mut token_map = self.token_map_mutex.lock().unwrap();
-        let context = try_future!(token_map.get(&chunk.token)
-            .ok_or("Invalid token".to_string()));
+        // Make sure the data wasn't corrupted in transit
+        if !chunk.checksum_is_valid() {
+            return BaacupFuture::new(Err(BaacupError::ChecksumMismatch));
+        }
 
-        // Double-check len
-        let file_len = try_future!(self.storage
-            .get_head(&context.file_metadata.file_name)
-            .map_err(|e| e.to_string()));
-        if file_len != chunk.offset {
-            return BaacupFuture::new(Err("Bad offset".to_string()));
+        try_future!(self.append_or_hold(&client, &chunk));
+
+        // Return checksum of the data we wrote
+        BaacupFuture::new(Ok(checksum(&chunk.data)))
+    }
+
+    fn upload_stream(&self, header: StreamHeader, chunks: BaacupStream<DataChunk>) -> BaacupFuture<StreamResult> {
+        let client = try_future!(self.client());
+
+        // Fail before the client sends any data if it can't be written
+        try_future!(self.with_upload(&client, &header.token, |upload| {
+            let file_len = self.storage.get_head(&client, &upload.session.file_metadata.file_name)?;
+            if file_len != header.offset {
+                return Err(BaacupError::BadOffset { expected: file_len });
+            }
+            Ok(())
+        }));
+
+        let server = self.clone();
+        let start = StreamResult {
+            offset: header.offset,
+            content_hash: None,
+        };
+        BaacupFuture::new(chunks.fold(start, move |result, chunk| {
+            if !chunk.checksum_is_valid() {
+                return Err(BaacupError::ChecksumMismatch);
+            }
+            let incoming = IncomingChunk {
+                contents: &chunk.data,
+                compression: chunk.compression,
+                chunk_hash: chunk.chunk_hash.as_deref(),
+                copy: chunk.copy,
+            };
+            server.append_to_upload(&client, &header.token, result.offset, &incoming)
+        }))
+    }
+
+    fn file_is_uploaded(&self, metadata: FileMetadata) -> BaacupFuture<UploadStatus> {
+        let client = try_future!(self.client());
+        let is_uploaded = !try_future!(self.storage.storage_outdated(&client, &metadata));
+        let content_hash = try_future!(self.storage.content_hash(&client, &metadata.file_name));
+
+        BaacupFuture::new(Ok(UploadStatus {
+            is_uploaded: is_uploaded,
+            content_hash: content_hash,
+        }))
+    }
+
+    fn files_are_uploaded(&self, files: Vec<FileMetadata>) -> BaacupFuture<Vec<UploadStatus>> {
+        let client = try_future!(self.client());
+        if files.len() > MAX_FILES_ARE_UPLOADED {
+            let message = format!("Can't ask about more than {} files at once", MAX_FILES_ARE_UPLOADED);
+            return BaacupFuture::new(Err(BaacupError::InvalidArgument(message)));
         }
 
-        // Write data
-        try_future!(self.storage
-            .append(&context.file_metadata.file_name, &chunk.data)
-            .map_err(|e| e.to_string()));
+        BaacupFuture::new(self.storage.upload_statuses(&client, &files)
+            .map_err(BaacupError::from))
+    }
 
-        // Check if we're done
-        println!("{} {}", chunk.offset + chunk.data.len() as u64, context.file_metadata.file_size);
-        if chunk.offset + chunk.data.len() as u64 == context.file_metadata.file_size {
-            println!("File upload finished.");
-            token_map.remove(&chunk.token);
+    fn has_chunks(&self, chunk_hashes: Vec<Vec<u8>>) -> BaacupFuture<Vec<bool>> {
+        let client = try_future!(self.client());
+        if chunk_hashes.len() > MAX_HAS_CHUNKS {
+            let message = format!("Can't ask about more than {} chunks at once", MAX_HAS_CHUNKS);
+            return BaacupFuture::new(Err(BaacupError::InvalidArgument(message)));
+        }
+
+        BaacupFuture::new(chunk_hashes.iter()
+            .map(|hash| Ok(self.storage.chunk_len(&client, hash)?.is_some()))
+            .collect::<Result<Vec<bool>, StorageError>>()
+            .map_err(BaacupError::from))
+    }
+
+    fn get_signature(&self, file_name: String) -> BaacupFuture<Signature> {
+        let client = try_future!(self.client());
+
+        let file_len = try_future!(self.storage.get_head(&client, &file_name));
+        let block_size = delta::block_size(file_len);
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+        while offset < file_len {
+            let data = try_future!(self.storage.read(&client, &file_name, offset, block_size as u64 * SIGNATURE_READ_BLOCKS));
+            if data.is_empty() {
+                break;
+            }
+            offset += data.len() as u64;
+            blocks.extend(data.chunks(block_size as usize).map(delta::block_signature));
         }
 
-        // Return checksum
-        // TODO: Actually return checksum
-        BaacupFuture::new(Ok(0))
+        BaacupFuture::new(Ok(Signature {
+            block_size: block_size,
+            blocks: blocks,
+        }))
+    }
+
+    fn list_files(&self) -> BaacupFuture<Vec<FileMetadata>> {
+        let client = try_future!(self.client());
+        BaacupFuture::new(self.storage.list(&client).map_err(BaacupError::from))
     }
 
-    fn file_is_uploaded(&self, metadata: FileMetadata) -> BaacupFuture<bool> {
-        BaacupFuture::new(self.storage.storage_outdated(&metadata).map(|b| !b))
+    fn read_chunk(&self, file_name: String, offset: u64, len: u64) -> BaacupFuture<Vec<u8>> {
+        let client = try_future!(self.client());
+        if len > MAX_READ_LEN {
+            let message = format!("Chunk length exceeds {} bytes", MAX_READ_LEN);
+            return BaacupFuture::new(Err(BaacupError::InvalidArgument(message)));
+        }
+
+        BaacupFuture::new(self.storage.read(&client, &file_name, offset, len).map_err(BaacupError::from))
+    }
+
+    fn begin_snapshot(&self, host: String) -> BaacupFuture<SnapshotId> {
+        let client = try_future!(self.client());
+
+        let snapshot = Snapshot {
+            id: SnapshotId::new(rand::random()),
+            host: host,
+            started: SystemTime::now().into(),
+            finished: None,
+            file_count: 0,
+        };
+        try_future!(self.storage.begin_snapshot(&client, &snapshot));
+        BaacupFuture::new(Ok(snapshot.id))
+    }
+
+    fn add_to_snapshot(&self, snapshot_id: SnapshotId, file_names: Vec<String>) -> BaacupFuture<()> {
+        let client = try_future!(self.client());
+        if file_names.len() > MAX_ADD_TO_SNAPSHOT {
+            let message = format!("Can't add more than {} files to a snapshot at once", MAX_ADD_TO_SNAPSHOT);
+            return BaacupFuture::new(Err(BaacupError::InvalidArgument(message)));
+        }
+
+        // A committed snapshot stays as it was committed
+        let snapshot = try_future!(self.storage.snapshot(&client, &snapshot_id));
+        if snapshot.is_committed() {
+            let message = format!("Snapshot {} is committed already", snapshot_id);
+            return BaacupFuture::new(Err(BaacupError::InvalidArgument(message)));
+        }
+
+        BaacupFuture::new(self.storage.add_to_snapshot(&client, &snapshot_id, &file_names)
+            .map_err(BaacupError::from))
+    }
+
+    fn commit_snapshot(&self, snapshot_id: SnapshotId) -> BaacupFuture<Snapshot> {
+        let client = try_future!(self.client());
+
+        // The client may have missed that it committed the snapshot already
+        let snapshot = try_future!(self.storage.snapshot(&client, &snapshot_id));
+        if snapshot.is_committed() {
+            return BaacupFuture::new(Ok(snapshot));
+        }
+
+        BaacupFuture::new(self.storage.commit_snapshot(&client, &snapshot_id, SystemTime::now().into())
+            .map_err(BaacupError::from))
+    }
+
+    fn list_snapshots(&self) -> BaacupFuture<Vec<Snapshot>> {
+        let client = try_future!(self.client());
+
+        BaacupFuture::new(self.storage.snapshots(&client)
+            .map(|snapshots| snapshots.into_iter()
+                .filter(Snapshot::is_committed)
+                .collect())
+            .map_err(BaacupError::from))
+    }
+
+    fn list_snapshot_files(&self, snapshot_id: SnapshotId) -> BaacupFuture<Vec<FileMetadata>> {
+        let client = try_future!(self.client());
+
+        // Snapshots that aren't committed don't exist for restores
+        let snapshot = try_future!(self.storage.snapshot(&client, &snapshot_id));
+        if !snapshot.is_committed() {
+            return BaacupFuture::new(Err(BaacupError::NotFound));
+        }
+
+        BaacupFuture::new(self.storage.snapshot_files(&client, &snapshot_id)
+            .map_err(BaacupError::from))
+    }
+
+    fn read_snapshot_chunk(&self, snapshot_id: SnapshotId, file_name: String, offset: u64, len: u64) -> BaacupFuture<Vec<u8>> {
+        let client = try_future!(self.client());
+        if len > MAX_READ_LEN {
+            let message = format!("Chunk length exceeds {} bytes", MAX_READ_LEN);
+            return BaacupFuture::new(Err(BaacupError::InvalidArgument(message)));
+        }
+
+        let snapshot = try_future!(self.storage.snapshot(&client, &snapshot_id));
+        if !snapshot.is_committed() {
+            return BaacupFuture::new(Err(BaacupError::NotFound));
+        }
+
+        BaacupFuture::new(self.storage.read_snapshot(&client, &snapshot_id, &file_name, offset, len)
+            .map_err(BaacupError::from))
+    }
+}
+
+/// A chunk as it came in through `upload_chunk` or `upload_stream`.
+struct IncomingChunk<'a> {
+    contents: &'a [u8],
+    compression: Compression,
+    chunk_hash: Option<&'a [u8]>,
+    copy: Option<CopyRange>,
+}
+
+impl<'a> IncomingChunk<'a> {
+    fn from_file_chunk(chunk: &'a FileChunk) -> IncomingChunk<'a> {
+        IncomingChunk {
+            contents: &chunk.data,
+            compression: chunk.compression,
+            chunk_hash: chunk.chunk_hash.as_deref(),
+            copy: chunk.copy,
+        }
+    }
+}
+
+/// What a chunk adds to a file.
+enum ChunkData<'a> {
+    /// Data of this file alone, and the zstd frame it arrived as.
+    Own { data: Cow<'a, [u8]>, zstd_frame: Option<&'a [u8]> },
+    /// A chunk from the chunk store.
+    Shared { hash: &'a [u8], len: u64 },
+    /// Data from the previous version of the file.
+    Copied { data: Vec<u8> },
+}
+
+impl<'a> ChunkData<'a> {
+    fn len(&self) -> u64 {
+        match *self {
+            ChunkData::Own { ref data, .. } => data.len() as u64,
+            ChunkData::Shared { len, .. } => len,
+            ChunkData::Copied { ref data } => data.len() as u64,
+        }
+    }
+}
+
+/// Decompresses chunk `contents` headed for `offset` of a file of
+/// `file_size` bytes. Chunks never reach past the end of the file.
+fn decompress_chunk(contents: &[u8], compression: Compression, offset: u64, file_size: u64)
+    -> Result<Cow<'_, [u8]>, BaacupError>
+{
+    compression::decompress(contents, compression, file_size.saturating_sub(offset))
+        .map_err(|err| BaacupError::InvalidArgument(format!("Invalid chunk data: {}", err)))
+}
+
+fn hash_stored_file<'a, S>(storage: &'a S, owner: &str, filename: &str) -> Result<Vec<u8>, StorageError>
+    where S: StorageManager<'a>,
+{
+    let mut hasher = ContentHasher::new();
+    let mut offset = 0;
+    loop {
+        let data = storage.read(owner, filename, offset, HASH_READ_LEN)?;
+        if data.is_empty() {
+            return Ok(hasher.finish());
+        }
+        offset += data.len() as u64;
+        hasher.update(&data);
     }
 }
@@ -0,0 +1,62 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind};
+
+use backuplib::rpc::BaacupError;
+use diesel::result::Error as DieselError;
+
+#[derive(Debug)]
+pub enum StorageError {
+    NotFound,
+    StorageFull,
+    PermissionDenied,
+    Io(IoError),
+    Database(DieselError),
+    Other(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StorageError::NotFound => write!(f, "File not found"),
+            StorageError::StorageFull => write!(f, "Storage full"),
+            StorageError::PermissionDenied => write!(f, "Permission denied"),
+            StorageError::Io(ref e) => write!(f, "I/O error: {}", e),
+            StorageError::Database(ref e) => write!(f, "Database error: {}", e),
+            StorageError::Other(ref message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for StorageError {}
+
+impl From<IoError> for StorageError {
+    fn from(error: IoError) -> StorageError {
+        match error.kind() {
+            ErrorKind::NotFound => StorageError::NotFound,
+            ErrorKind::PermissionDenied => StorageError::PermissionDenied,
+            ErrorKind::StorageFull => StorageError::StorageFull,
+            _ => StorageError::Io(error),
+        }
+    }
+}
+
+impl From<DieselError> for StorageError {
+    fn from(error: DieselError) -> StorageError {
+        match error {
+            DieselError::NotFound => StorageError::NotFound,
+            _ => StorageError::Database(error),
+        }
+    }
+}
+
+impl From<StorageError> for BaacupError {
+    fn from(error: StorageError) -> BaacupError {
+        match error {
+            StorageError::NotFound => BaacupError::NotFound,
+            StorageError::StorageFull => BaacupError::StorageFull,
+            StorageError::PermissionDenied => BaacupError::PermissionDenied,
+            error => BaacupError::Internal(error.to_string()),
+        }
+    }
+}
@@ -1,10 +1,15 @@
 pub mod sqlite_db;
+mod error;
 
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write, Seek};
-use std::path::PathBuf;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
-use backuplib::rpc::FileMetadata;
+use backuplib::hex::to_hex;
+use backuplib::rpc::{EntryType, FileMetadata, Snapshot, SnapshotId, Timestamp, UploadStatus, UploadToken};
+
+pub use self::error::StorageError;
 
 pub trait FileLen {
     fn len(&self) -> Result<u64, String>;
@@ -18,11 +23,121 @@ impl FileLen for File {
     }
 }
 
+/// An upload in progress, from `init_upload` until its last chunk.
+#[derive(Clone, Debug)]
+pub struct UploadSession {
+    pub owner: String,
+    pub file_metadata: FileMetadata,
+    pub last_used: SystemTime,
+}
+
+impl UploadSession {
+    pub fn new(owner: String, file_metadata: FileMetadata) -> UploadSession {
+        UploadSession {
+            owner: owner,
+            file_metadata: file_metadata,
+            last_used: SystemTime::now(),
+        }
+    }
+
+    pub fn is_expired(&self, now: SystemTime, idle_timeout: Duration) -> bool {
+        // A clock going backwards doesn't make a session any older
+        now.duration_since(self.last_used)
+            .map(|idle| idle >= idle_timeout)
+            .unwrap_or(false)
+    }
+}
+
+/// Every file belongs to the client that uploaded it. `owner` is that
+/// client's name, and file names are only unique per owner.
 pub trait StorageManager<'a> {
-    fn create(&'a self, metadata: &FileMetadata) -> Result<(), String>;
-    fn append(&'a self, filename: &str, data: &[u8]) -> Result<(), String>;
-    fn storage_outdated(&'a self, metadata: &FileMetadata) -> Result<bool, String>;
-    fn get_head(&'a self, filename: &str) -> Result<u64, String>;
+    /// Starts a new, empty version of a file. The version it replaces stays
+    /// readable with `read_previous` until `finish`, and with
+    /// `read_snapshot` for as long as a snapshot has it.
+    fn create(&'a self, owner: &str, metadata: &FileMetadata) -> Result<(), StorageError>;
+    fn append(&'a self, owner: &str, filename: &str, data: &[u8]) -> Result<(), StorageError>;
+    /// Appends `data`, which arrived compressed as `zstd_frame`. Storage that
+    /// keeps blobs compressed can store the frame as it is.
+    fn append_zstd(&'a self, owner: &str, filename: &str, data: &[u8], _zstd_frame: &[u8]) -> Result<(), StorageError> {
+        self.append(owner, filename, data)
+    }
+    /// Gets the length of the shared chunk with the SHA-256 `hash`, if
+    /// `owner` has uploaded its data. Chunks are stored once for all
+    /// clients, but a client only gets to refer to those it could send.
+    fn chunk_len(&'a self, owner: &str, hash: &[u8]) -> Result<Option<u64>, StorageError>;
+    /// Stores `data`, which `owner` uploaded, as the shared chunk with
+    /// `hash`, unless it's there already. `zstd_frame` is `data` compressed,
+    /// if it arrived that way.
+    fn put_chunk(&'a self, owner: &str, hash: &[u8], data: &[u8], zstd_frame: Option<&[u8]>) -> Result<(), StorageError>;
+    /// Appends the shared chunk with `hash` to a file. Fails with
+    /// `NotFound` unless `owner` has uploaded the chunk's data.
+    fn append_chunk(&'a self, owner: &str, filename: &str, hash: &[u8]) -> Result<(), StorageError>;
+    fn storage_outdated(&'a self, owner: &str, metadata: &FileMetadata) -> Result<bool, StorageError>;
+    fn get_head(&'a self, owner: &str, filename: &str) -> Result<u64, StorageError>;
+    fn read(&'a self, owner: &str, filename: &str, offset: u64, len: u64) -> Result<Vec<u8>, StorageError>;
+    /// Reads like `read` from the version of a file that `create` replaced,
+    /// which delta uploads copy from.
+    fn read_previous(&'a self, owner: &str, filename: &str, offset: u64, len: u64) -> Result<Vec<u8>, StorageError>;
+    /// Reads like `read` from a file as `add_to_snapshot` recorded it in the
+    /// snapshot with `id`, whatever was uploaded since.
+    fn read_snapshot(&'a self, owner: &str, id: &SnapshotId, filename: &str, offset: u64, len: u64) -> Result<Vec<u8>, StorageError>;
+    fn list(&'a self, owner: &str) -> Result<Vec<FileMetadata>, StorageError>;
+    /// Records the metadata and content hash of a file whose upload
+    /// finished, and drops the version it replaced. Until then, the file
+    /// keeps the metadata of that version.
+    fn finish(&'a self, owner: &str, metadata: &FileMetadata, content_hash: &[u8]) -> Result<(), StorageError>;
+    /// Throws away what was appended to a file since `create`, so an upload
+    /// that went wrong can start over. The version `create` replaced stays.
+    fn discard(&'a self, owner: &str, filename: &str) -> Result<(), StorageError>;
+    /// Gets the content hash recorded by `finish`, if any.
+    fn content_hash(&'a self, owner: &str, filename: &str) -> Result<Option<Vec<u8>>, StorageError>;
+    /// Tells for each of `files` whether it's stored up to date, and its
+    /// content hash. Storage that can look up many files at once should.
+    fn upload_statuses(&'a self, owner: &str, files: &[FileMetadata]) -> Result<Vec<UploadStatus>, StorageError> {
+        files.iter()
+            .map(|metadata| Ok(UploadStatus {
+                is_uploaded: !self.storage_outdated(owner, metadata)?,
+                content_hash: self.content_hash(owner, &metadata.file_name)?,
+            }))
+            .collect()
+    }
+    /// Stores `session`, replacing any earlier version of it.
+    fn save_upload(&'a self, token: &UploadToken, session: &UploadSession) -> Result<(), StorageError>;
+    fn remove_upload(&'a self, token: &UploadToken) -> Result<(), StorageError>;
+    /// Gets every upload saved with `save_upload` and not removed since.
+    fn load_uploads(&'a self) -> Result<Vec<(UploadToken, UploadSession)>, StorageError>;
+    /// Stores a new snapshot, which has no files and isn't committed yet.
+    fn begin_snapshot(&'a self, owner: &str, snapshot: &Snapshot) -> Result<(), StorageError>;
+    /// Gets a snapshot of `owner`'s, committed or not.
+    fn snapshot(&'a self, owner: &str, id: &SnapshotId) -> Result<Snapshot, StorageError>;
+    /// Gets all of `owner`'s snapshots, committed or not, oldest first.
+    fn snapshots(&'a self, owner: &str) -> Result<Vec<Snapshot>, StorageError>;
+    /// Records what's stored of each of `filenames` now in the snapshot
+    /// with `id`, replacing what it had for them. Fails with `NotFound`
+    /// unless every one of the files has finished uploading.
+    fn add_to_snapshot(&'a self, owner: &str, id: &SnapshotId, filenames: &[String]) -> Result<(), StorageError>;
+    /// Marks the snapshot with `id` as committed at `finished`, and counts
+    /// its files.
+    fn commit_snapshot(&'a self, owner: &str, id: &SnapshotId, finished: Timestamp) -> Result<Snapshot, StorageError>;
+    /// Lists the files of a snapshot as `add_to_snapshot` recorded them.
+    fn snapshot_files(&'a self, owner: &str, id: &SnapshotId) -> Result<Vec<FileMetadata>, StorageError>;
+}
+
+/// Reads up to `len` bytes starting at `offset`. Returns fewer bytes if the
+/// end of the file is reached first.
+pub fn read_at<R>(reader: &mut R, offset: u64, len: u64) -> Result<Vec<u8>, StorageError>
+    where R: Read + Seek,
+{
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::new();
+    reader.take(len)
+        .read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Name of the file the shared chunk with `hash` is stored in.
+pub fn chunk_file_name(hash: &[u8]) -> String {
+    to_hex(hash)
 }
 
 #[derive(Debug, Clone)]
@@ -38,32 +153,216 @@ impl FileSystem {
             base_path: base_path.into(),
         }
     }
+
+    /// Shared chunks are kept next to the clients' directories.
+    fn chunk_path(&self, hash: &[u8]) -> PathBuf {
+        self.base_path.join(".chunks").join(chunk_file_name(hash))
+    }
+
+    /// Marks that `owner` has uploaded the data of the shared chunk with
+    /// `hash`.
+    fn chunk_owner_path(&self, owner: &str, hash: &[u8]) -> PathBuf {
+        self.base_path.join(".chunk_owners").join(owner).join(chunk_file_name(hash))
+    }
+
+    /// Where the version of a file that `create` replaced is kept.
+    fn previous_path(&self, owner: &str, filename: &str) -> PathBuf {
+        self.base_path.join(".previous").join(owner).join(filename)
+    }
 }
 
 impl<'a> StorageManager<'a> for FileSystem {
-    fn create(&self, metadata: &FileMetadata) -> Result<(), String> {
-        let full_path = self.base_path.join(&metadata.file_name);
-        File::create(full_path).map_err(|e| e.to_string())?;
+    fn create(&self, owner: &str, metadata: &FileMetadata) -> Result<(), StorageError> {
+        let owner_path = self.base_path.join(owner);
+        fs::create_dir_all(&owner_path)?;
+        let full_path = owner_path.join(&metadata.file_name);
+        if full_path.is_file() {
+            let previous_path = self.previous_path(owner, &metadata.file_name);
+            if let Some(previous_dir) = previous_path.parent() {
+                fs::create_dir_all(previous_dir)?;
+            }
+            fs::rename(&full_path, previous_path)?;
+        }
+        File::create(full_path)?;
         Ok(())
     }
 
-    fn append(&'a self, filename: &str, data: &[u8]) -> Result<(), String> {
-        let full_path = self.base_path.join(&filename);
+    fn append(&'a self, owner: &str, filename: &str, data: &[u8]) -> Result<(), StorageError> {
+        let full_path = self.base_path.join(owner).join(filename);
         let mut file = OpenOptions::new()
-            .write(true)
-            .open(full_path)
-            .map_err(|e| e.to_string())?;
+            .append(true)
+            .open(full_path)?;
         file.write_all(data)
-            .map_err(|e| e.to_string())
+            .map_err(StorageError::from)
     }
 
-    fn storage_outdated(&'a self, metadata: &FileMetadata) -> Result<bool, String> {
+    fn chunk_len(&'a self, owner: &str, hash: &[u8]) -> Result<Option<u64>, StorageError> {
+        if !self.chunk_owner_path(owner, hash).exists() {
+            return Ok(None);
+        }
+        match fs::metadata(self.chunk_path(hash)) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn put_chunk(&'a self, owner: &str, hash: &[u8], data: &[u8], _zstd_frame: Option<&[u8]>) -> Result<(), StorageError> {
+        let chunk_path = self.chunk_path(hash);
+        if !chunk_path.exists() {
+            // Only complete chunks get their final name
+            let partial_path = chunk_path.with_extension("partial");
+            fs::create_dir_all(self.base_path.join(".chunks"))?;
+            fs::write(&partial_path, data)?;
+            fs::rename(partial_path, chunk_path)?;
+        }
+
+        let owner_path = self.chunk_owner_path(owner, hash);
+        if let Some(owner_dir) = owner_path.parent() {
+            fs::create_dir_all(owner_dir)?;
+        }
+        File::create(owner_path)?;
+        Ok(())
+    }
+
+    fn append_chunk(&'a self, owner: &str, filename: &str, hash: &[u8]) -> Result<(), StorageError> {
+        if !self.chunk_owner_path(owner, hash).exists() {
+            return Err(StorageError::NotFound);
+        }
+        // Files stay plain copies here, so only uploads share chunks
+        let data = fs::read(self.chunk_path(hash))?;
+        self.append(owner, filename, &data)
+    }
+
+    fn storage_outdated(&'a self, _owner: &str, _metadata: &FileMetadata) -> Result<bool, StorageError> {
         // Dummy implementation
         Ok(true)
     }
 
-    fn get_head(&'a self, filename: &str) -> Result<u64, String> {
-        unimplemented!()
+    fn get_head(&'a self, owner: &str, filename: &str) -> Result<u64, StorageError> {
+        let file = File::open(self.base_path.join(owner).join(filename))?;
+        FileLen::len(&file)
+            .map_err(StorageError::Other)
+    }
+
+    fn read(&'a self, owner: &str, filename: &str, offset: u64, len: u64) -> Result<Vec<u8>, StorageError> {
+        let full_path = self.base_path.join(owner).join(filename);
+        let mut file = File::open(full_path)?;
+        read_at(&mut file, offset, len)
+    }
+
+    fn read_previous(&'a self, owner: &str, filename: &str, offset: u64, len: u64) -> Result<Vec<u8>, StorageError> {
+        let mut file = File::open(self.previous_path(owner, filename))?;
+        read_at(&mut file, offset, len)
+    }
+
+    fn read_snapshot(&'a self, _owner: &str, _id: &SnapshotId, _filename: &str, _offset: u64, _len: u64)
+        -> Result<Vec<u8>, StorageError>
+    {
+        // Dummy implementation
+        Err(StorageError::NotFound)
+    }
+
+    fn list(&'a self, owner: &str) -> Result<Vec<FileMetadata>, StorageError> {
+        let owner_path = self.base_path.join(owner);
+        let mut files = Vec::new();
+        if owner_path.is_dir() {
+            list_dir(&owner_path, &owner_path, &mut files)?;
+        }
+        Ok(files)
+    }
+
+    fn finish(&'a self, owner: &str, metadata: &FileMetadata, _content_hash: &[u8]) -> Result<(), StorageError> {
+        // Hashes aren't kept, but the previous version isn't needed anymore
+        match fs::remove_file(self.previous_path(owner, &metadata.file_name)) {
+            Ok(()) => Ok(()),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn discard(&'a self, owner: &str, filename: &str) -> Result<(), StorageError> {
+        File::create(self.base_path.join(owner).join(filename))?;
+        Ok(())
+    }
+
+    fn content_hash(&'a self, _owner: &str, _filename: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        // Dummy implementation
+        Ok(None)
+    }
+
+    fn save_upload(&'a self, _token: &UploadToken, _session: &UploadSession) -> Result<(), StorageError> {
+        // Dummy implementation
+        Ok(())
+    }
+
+    fn remove_upload(&'a self, _token: &UploadToken) -> Result<(), StorageError> {
+        // Dummy implementation
+        Ok(())
+    }
+
+    fn load_uploads(&'a self) -> Result<Vec<(UploadToken, UploadSession)>, StorageError> {
+        // Dummy implementation
+        Ok(Vec::new())
+    }
+
+    fn begin_snapshot(&'a self, _owner: &str, _snapshot: &Snapshot) -> Result<(), StorageError> {
+        // Dummy implementation
+        Err(snapshots_unsupported())
+    }
+
+    fn snapshot(&'a self, _owner: &str, _id: &SnapshotId) -> Result<Snapshot, StorageError> {
+        // Dummy implementation
+        Err(StorageError::NotFound)
+    }
+
+    fn snapshots(&'a self, _owner: &str) -> Result<Vec<Snapshot>, StorageError> {
+        // Dummy implementation
+        Ok(Vec::new())
+    }
+
+    fn add_to_snapshot(&'a self, _owner: &str, _id: &SnapshotId, _filenames: &[String]) -> Result<(), StorageError> {
+        // Dummy implementation
+        Err(snapshots_unsupported())
+    }
+
+    fn commit_snapshot(&'a self, _owner: &str, _id: &SnapshotId, _finished: Timestamp) -> Result<Snapshot, StorageError> {
+        // Dummy implementation
+        Err(snapshots_unsupported())
+    }
+
+    fn snapshot_files(&'a self, _owner: &str, _id: &SnapshotId) -> Result<Vec<FileMetadata>, StorageError> {
+        // Dummy implementation
+        Err(StorageError::NotFound)
     }
 }
 
+fn snapshots_unsupported() -> StorageError {
+    StorageError::Other("file system storage doesn't keep snapshots".into())
+}
+
+fn list_dir(base_path: &Path, dir: &Path, files: &mut Vec<FileMetadata>) -> Result<(), StorageError> {
+    for entry in dir.read_dir()? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            list_dir(base_path, &entry.path(), files)?;
+            continue;
+        }
+
+        let file_name = entry.path()
+            .strip_prefix(base_path)
+            .map_err(|e| StorageError::Other(e.to_string()))?
+            .to_string_lossy()
+            .into_owned();
+        files.push(FileMetadata {
+            file_name: file_name,
+            last_modified: metadata.modified()?.into(),
+            file_size: metadata.len(),
+            content_hash: None,
+            posix_attributes: None,
+            entry_type: EntryType::Regular,
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,126 @@
+//! Encryption at rest. Every file and every shared chunk gets its own
+//! random data key, which is stored wrapped by the master key from the
+//! configuration. Rotating the master key only rewraps the data keys.
+
+use std::convert::TryInto;
+
+use backuplib::crypto::{self, Key, NONCE_LEN};
+
+use crate::storage::{StorageError, chunk_file_name};
+
+/// What the key derived from the master key is for.
+const WRAPPING_PURPOSE: &str = "storage data keys";
+
+/// Encrypts `data_key` with `master_key`, bound to `id`, the file ID or
+/// chunk hash it belongs to.
+pub fn wrap_key(master_key: &Key, id: &[u8], data_key: &Key) -> Result<Vec<u8>, StorageError> {
+    let nonce = crypto::random_nonce();
+    let mut wrapped = nonce.to_vec();
+    wrapped.extend(crypto::seal(&master_key.derive(WRAPPING_PURPOSE), &nonce, id, data_key.as_bytes())?);
+    Ok(wrapped)
+}
+
+/// Undoes `wrap_key`. Fails if `master_key` isn't the key `wrapped` was
+/// wrapped with.
+pub fn unwrap_key(master_key: &Key, id: &[u8], wrapped: &[u8]) -> Result<Key, StorageError> {
+    if wrapped.len() < NONCE_LEN {
+        return Err(wrong_master_key());
+    }
+    let (nonce, sealed) = wrapped.split_at(NONCE_LEN);
+    let data_key = crypto::open(&master_key.derive(WRAPPING_PURPOSE), nonce.try_into().unwrap(), id, sealed)
+        .map_err(|_| wrong_master_key())?;
+    Key::from_slice(&data_key).ok_or_else(wrong_master_key)
+}
+
+/// Encrypts the blob at `file_offset` of the file with `file_id`. Returns
+/// the nonce to store with the blob, and the sealed blob.
+pub fn seal_blob(data_key: &Key, file_id: &str, file_offset: i64, stored: &[u8])
+    -> Result<(Vec<u8>, Vec<u8>), StorageError>
+{
+    let nonce = crypto::random_nonce();
+    let sealed = crypto::seal(data_key, &nonce, &blob_aad(file_id, file_offset), stored)?;
+    Ok((nonce.to_vec(), sealed))
+}
+
+/// Undoes `seal_blob`, failing if the blob was moved or tampered with.
+pub fn open_blob(data_key: &Key, file_id: &str, file_offset: i64, nonce: &[u8], sealed: &[u8])
+    -> Result<Vec<u8>, StorageError>
+{
+    let nonce = nonce.try_into()
+        .map_err(|_| StorageError::Other(format!("bad nonce for blob at {} of {}", file_offset, file_id)))?;
+    Ok(crypto::open(data_key, nonce, &blob_aad(file_id, file_offset), sealed)?)
+}
+
+/// Encrypts the shared chunk with `hash`. Returns the nonce to store with
+/// the chunk, and the sealed chunk.
+pub fn seal_chunk(data_key: &Key, hash: &[u8], stored: &[u8]) -> Result<(Vec<u8>, Vec<u8>), StorageError> {
+    let nonce = crypto::random_nonce();
+    let sealed = crypto::seal(data_key, &nonce, hash, stored)?;
+    Ok((nonce.to_vec(), sealed))
+}
+
+/// Undoes `seal_chunk`. Chunks sealed before they had nonces have none,
+/// and were sealed with an all-zero one instead.
+pub fn open_chunk(data_key: &Key, hash: &[u8], nonce: Option<&[u8]>, sealed: &[u8]) -> Result<Vec<u8>, StorageError> {
+    let nonce = match nonce {
+        Some(nonce) => nonce.try_into()
+            .map_err(|_| StorageError::Other(format!("bad nonce for chunk {}", chunk_file_name(hash))))?,
+        None => [0; NONCE_LEN],
+    };
+    Ok(crypto::open(data_key, &nonce, hash, sealed)?)
+}
+
+fn blob_aad(file_id: &str, file_offset: i64) -> Vec<u8> {
+    let mut aad = file_id.as_bytes().to_vec();
+    aad.extend_from_slice(&file_offset.to_be_bytes());
+    aad
+}
+
+fn wrong_master_key() -> StorageError {
+    StorageError::Other("data key doesn't unwrap with the storage key".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use backuplib::crypto::Key;
+
+    use super::{open_blob, open_chunk, seal_blob, seal_chunk, unwrap_key, wrap_key};
+
+    #[test]
+    fn test_wrap_round_trip() {
+        let master_key = Key::generate();
+        let data_key = Key::generate();
+
+        let wrapped = wrap_key(&master_key, b"file", &data_key).unwrap();
+        assert_eq!(unwrap_key(&master_key, b"file", &wrapped).unwrap(), data_key);
+        assert!(unwrap_key(&Key::generate(), b"file", &wrapped).is_err());
+        // A key can't be moved to another file
+        assert!(unwrap_key(&master_key, b"other file", &wrapped).is_err());
+        assert!(unwrap_key(&master_key, b"file", &wrapped[..10]).is_err());
+    }
+
+    #[test]
+    fn test_blob_bound_to_its_place() {
+        let data_key = Key::generate();
+        let (nonce, sealed) = seal_blob(&data_key, "file", 4096, b"blob data").unwrap();
+        assert!(!sealed.windows(9).any(|window| window == b"blob data"));
+
+        assert_eq!(open_blob(&data_key, "file", 4096, &nonce, &sealed).unwrap(), b"blob data");
+        assert!(open_blob(&data_key, "file", 0, &nonce, &sealed).is_err());
+        assert!(open_blob(&data_key, "other", 4096, &nonce, &sealed).is_err());
+        assert!(open_blob(&Key::generate(), "file", 4096, &nonce, &sealed).is_err());
+    }
+
+    #[test]
+    fn test_chunk_nonces_differ() {
+        let data_key = Key::generate();
+        let (nonce, sealed) = seal_chunk(&data_key, b"hash", b"chunk data").unwrap();
+        let (other_nonce, other_sealed) = seal_chunk(&data_key, b"hash", b"chunk data").unwrap();
+        assert_ne!(nonce, other_nonce);
+        assert_ne!(sealed, other_sealed);
+
+        assert_eq!(open_chunk(&data_key, b"hash", Some(&nonce), &sealed).unwrap(), b"chunk data");
+        assert!(open_chunk(&data_key, b"other hash", Some(&nonce), &sealed).is_err());
+        assert!(open_chunk(&data_key, b"hash", None, &sealed).is_err());
+    }
+}
@@ -1,126 +1,1464 @@
+mod encryption;
 mod model;
 mod schema;
 
-use std::fs::{File, OpenOptions};
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
 use std::sync::{Arc, Mutex};
-use std::io::Write;
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
 use diesel::prelude::*;
 use diesel::sqlite::SqliteConnection;
 use diesel::{Connection, RunQueryDsl};
 use uuid::Uuid;
-use backuplib::rpc::FileMetadata;
+use backuplib::compression;
+use backuplib::crypto::Key;
+use backuplib::rpc::{Compression, EntryType, ExtendedAttribute, FileMetadata, PosixAttributes, Snapshot, SnapshotId, Timestamp, UploadStatus, UploadToken};
 
-use crate::storage::StorageManager;
-use crate::storage::sqlite_db::model::DbFile;
-use crate::storage::sqlite_db::schema::files;
+use crate::storage::{StorageManager, StorageError, UploadSession, chunk_file_name, read_at};
+use crate::storage::sqlite_db::model::{DbChunk, DbFile, DbFileAttributes, DbFileBlob, DbFileVersion, DbSnapshot, DbSnapshotFile, DbUploadSession, DbXattr};
+use crate::storage::sqlite_db::schema::{chunk_owners, chunks, file_attributes, file_blobs, file_versions, file_xattrs, files, snapshot_files, snapshots, upload_sessions, version_attributes, version_blobs, version_xattrs};
+
+embed_migrations!();
+
+// Values of `files.entry_type`
+const REGULAR: i32 = 0;
+const DIRECTORY: i32 = 1;
+const SYMLINK: i32 = 2;
+const FIFO: i32 = 3;
+const CHAR_DEVICE: i32 = 4;
+const BLOCK_DEVICE: i32 = 5;
+
+// Values of `file_blobs.compression` and `chunks.compression`
+const UNCOMPRESSED: i32 = 0;
+const ZSTD: i32 = 1;
+
+/// Directory the shared chunks are stored in.
+const CHUNK_DIR: &str = "chunks";
 
 pub struct SqliteStorageManager {
     connection: Arc<Mutex<SqliteConnection>>,
+    /// Directory the data files and shared chunks are stored in.
+    data_dir: PathBuf,
+    /// Wraps the data keys of files and chunks encrypted at rest.
+    storage_key: Option<Key>,
+    /// Locked for as long as the storage is open, so no other process
+    /// changes it meanwhile.
+    _lock: File,
 }
 
 impl SqliteStorageManager {
-    pub fn new(filename: &str) -> SqliteStorageManager {
-        let connection = SqliteConnection::establish(filename).unwrap();
-        SqliteStorageManager {
+    /// Opens the database in `filename`, and keeps file data in the
+    /// current directory.
+    pub fn new(filename: &str) -> Result<SqliteStorageManager, StorageError> {
+        SqliteStorageManager::with_data_dir(filename, PathBuf::new())
+    }
+
+    /// Opens the database in `filename`, and keeps file data in `data_dir`.
+    pub fn with_data_dir<P>(filename: &str, data_dir: P) -> Result<SqliteStorageManager, StorageError>
+        where P: Into<PathBuf>,
+    {
+        let lock = lock_storage(filename)?;
+        let connection = SqliteConnection::establish(filename)
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        embedded_migrations::run(&connection)
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        Ok(SqliteStorageManager {
             connection: Arc::new(Mutex::new(connection)),
+            data_dir: data_dir.into(),
+            storage_key: None,
+            _lock: lock,
+        })
+    }
+
+    /// Encrypts everything stored from now on, with data keys wrapped by
+    /// `storage_key`. Reading anything stored encrypted needs it too. What's
+    /// stored already is left to `seal_plaintext`.
+    pub fn encrypt_at_rest(&mut self, storage_key: Key) {
+        self.storage_key = Some(storage_key);
+    }
+
+    /// Rewraps every data key with `new_key`, which is used from then on.
+    /// Either all of them are rewrapped or none are. Returns how many
+    /// there were.
+    pub fn rotate_storage_key(&mut self, new_key: Key) -> Result<usize, StorageError> {
+        let storage_key = self.storage_key.as_ref()
+            .ok_or_else(|| StorageError::Other("no storage key to rotate".into()))?;
+        let connection = self.connection.lock().unwrap();
+
+        let rotated = connection.transaction::<_, StorageError, _>(|| {
+            let file_keys = files::table
+                .select((files::id, files::data_key))
+                .filter(files::data_key.is_not_null())
+                .load::<(String, Option<Vec<u8>>)>(&*connection)?;
+            let chunk_keys = chunks::table
+                .select((chunks::hash, chunks::data_key))
+                .filter(chunks::data_key.is_not_null())
+                .load::<(Vec<u8>, Option<Vec<u8>>)>(&*connection)?;
+
+            for (id, wrapped) in &file_keys {
+                let data_key = encryption::unwrap_key(storage_key, id.as_bytes(), wrapped.as_ref().unwrap())?;
+                diesel::update(files::table.find(id))
+                    .set(files::data_key.eq(encryption::wrap_key(&new_key, id.as_bytes(), &data_key)?))
+                    .execute(&*connection)?;
+            }
+            for (hash, wrapped) in &chunk_keys {
+                let data_key = encryption::unwrap_key(storage_key, hash, wrapped.as_ref().unwrap())?;
+                diesel::update(chunks::table.find(hash))
+                    .set(chunks::data_key.eq(encryption::wrap_key(&new_key, hash, &data_key)?))
+                    .execute(&*connection)?;
+            }
+            Ok(file_keys.len() + chunk_keys.len())
+        })?;
+
+        drop(connection);
+        self.storage_key = Some(new_key);
+        Ok(rotated)
+    }
+
+    /// Data key of `file_row`, if it's encrypted at rest.
+    fn file_key(&self, file_row: &DbFile) -> Result<Option<Key>, StorageError> {
+        unwrap_data_key(self.storage_key.as_ref(), file_row.id.as_bytes(), file_row.data_key.as_ref())
+    }
+
+    /// Like `file_key`, but gives files stored before encryption at rest
+    /// was turned on a data key for what gets appended to them.
+    fn file_key_for_append(&self, connection: &SqliteConnection, file_row: &DbFile) -> Result<Option<Key>, StorageError> {
+        let storage_key = match (&self.storage_key, &file_row.data_key) {
+            (Some(storage_key), None) => storage_key,
+            _ => return self.file_key(file_row),
+        };
+        let data_key = Key::generate();
+        diesel::update(file_row)
+            .set(files::data_key.eq(encryption::wrap_key(storage_key, file_row.id.as_bytes(), &data_key)?))
+            .execute(connection)?;
+        Ok(Some(data_key))
+    }
+
+    /// Reads up to `len` bytes starting at `offset` from the current version
+    /// of `file_row`.
+    fn read_current(&self, connection: &SqliteConnection, file_row: &DbFile, offset: u64, len: u64)
+        -> Result<Vec<u8>, StorageError>
+    {
+        if last_blob(connection, &file_row.id)?.is_none() {
+            let mut file = File::open(self.data_dir.join(&file_row.id))?;
+            return read_at(&mut file, offset, len);
         }
+        let data_keys = DataKeys {
+            storage_key: self.storage_key.as_ref(),
+            file_key: self.file_key(file_row)?,
+        };
+        read_blobs(connection, &self.data_dir, &data_keys, &file_row.id, offset, len)
+    }
+
+    /// Encrypts everything stored before encryption at rest was turned on:
+    /// the plaintext blobs of every data file, and every plaintext chunk.
+    /// Each data file or chunk is rewritten next to the old one, and only
+    /// replaces it once the database points at the new one, so this can be
+    /// interrupted and run again. Returns how many were encrypted.
+    pub fn seal_plaintext(&self) -> Result<usize, StorageError> {
+        let storage_key = self.storage_key.as_ref()
+            .ok_or_else(|| StorageError::Other("no storage key to encrypt with".into()))?;
+        let connection = self.connection.lock().unwrap();
+        let mut sealed = 0;
+
+        for file_row in files::table.load::<DbFile>(&*connection)? {
+            let data_path = self.data_dir.join(&file_row.id);
+            if data_path.exists() {
+                // Files stored as they are become one plaintext blob first
+                blobs_end(&connection, &self.data_dir, &file_row.id)?;
+            }
+            let blobs = file_blobs::table
+                .filter(file_blobs::file_id.eq(&file_row.id))
+                .filter(file_blobs::chunk_hash.is_null())
+                .order(file_blobs::file_offset)
+                .load::<DbFileBlob>(&*connection)?;
+            if !finish_sealing(&data_path, &blobs)? {
+                let file_key = self.file_key_for_append(&connection, &file_row)?.unwrap();
+                let blobs = seal_blobs(&data_path, &file_row.id, &file_key, blobs)?;
+                connection.transaction::<_, StorageError, _>(|| {
+                    for blob in &blobs {
+                        diesel::update(file_blobs::table.find((&blob.file_id, blob.file_offset)))
+                            .set((
+                                file_blobs::stored_offset.eq(blob.stored_offset),
+                                file_blobs::stored_len.eq(blob.stored_len),
+                                file_blobs::nonce.eq(&blob.nonce),
+                            ))
+                            .execute(&*connection)?;
+                    }
+                    Ok(())
+                })?;
+                fs::rename(sealing_path(&data_path), &data_path)?;
+                sealed += 1;
+            }
+
+            // Adopts a previous version stored as it is
+            previous_version(&connection, &self.data_dir, &file_row)?;
+        }
+
+        for version_row in file_versions::table.load::<DbFileVersion>(&*connection)? {
+            let data_path = self.data_dir.join(&version_row.data_path);
+            let blobs = version_blobs::table
+                .filter(version_blobs::version_id.eq(&version_row.id))
+                .filter(version_blobs::chunk_hash.is_null())
+                .order(version_blobs::file_offset)
+                .load::<DbFileBlob>(&*connection)?;
+            if !finish_sealing(&data_path, &blobs)? {
+                // Versions keep the data key of their file
+                let file_row = files::table.find(&version_row.file_id).first::<DbFile>(&*connection)?;
+                let file_key = self.file_key_for_append(&connection, &file_row)?.unwrap();
+                let blobs = seal_blobs(&data_path, &file_row.id, &file_key, blobs)?;
+                connection.transaction::<_, StorageError, _>(|| {
+                    for blob in &blobs {
+                        diesel::update(version_blobs::table.find((&version_row.id, blob.file_offset)))
+                            .set((
+                                version_blobs::stored_offset.eq(blob.stored_offset),
+                                version_blobs::stored_len.eq(blob.stored_len),
+                                version_blobs::nonce.eq(&blob.nonce),
+                            ))
+                            .execute(&*connection)?;
+                    }
+                    Ok(())
+                })?;
+                fs::rename(sealing_path(&data_path), &data_path)?;
+                sealed += 1;
+            }
+        }
+
+        for chunk in chunks::table.load::<DbChunk>(&*connection)? {
+            let path = chunk_path(&self.data_dir, &chunk.hash);
+            let temp_path = sealing_path(&path);
+            if chunk.data_key.is_some() {
+                if temp_path.exists() {
+                    fs::rename(&temp_path, &path)?;
+                }
+                continue;
+            }
+
+            let data_key = Key::generate();
+            let (nonce, stored) = encryption::seal_chunk(&data_key, &chunk.hash, &fs::read(&path)?)?;
+            write_synced(&temp_path, &stored)?;
+            connection.transaction::<_, StorageError, _>(|| {
+                diesel::update(chunks::table.find(&chunk.hash))
+                    .set((
+                        chunks::stored_len.eq(stored.len() as i64),
+                        chunks::data_key.eq(encryption::wrap_key(storage_key, &chunk.hash, &data_key)?),
+                        chunks::nonce.eq(nonce),
+                    ))
+                    .execute(&*connection)?;
+                diesel::update(file_blobs::table.filter(file_blobs::chunk_hash.eq(&chunk.hash)))
+                    .set(file_blobs::stored_len.eq(stored.len() as i64))
+                    .execute(&*connection)?;
+                diesel::update(version_blobs::table.filter(version_blobs::chunk_hash.eq(&chunk.hash)))
+                    .set(version_blobs::stored_len.eq(stored.len() as i64))
+                    .execute(&*connection)?;
+                Ok(())
+            })?;
+            fs::rename(&temp_path, &path)?;
+            sealed += 1;
+        }
+
+        Ok(sealed)
     }
 }
 
 impl<'a> StorageManager<'a> for SqliteStorageManager {
-    fn create(&'a self, metadata: &FileMetadata) -> Result<(), String> {
+    fn create(&'a self, owner: &str, metadata: &FileMetadata) -> Result<(), StorageError> {
         let id = Uuid::new_v4().to_simple().to_string();
+        let (entry_type, symlink_target, device_major, device_minor) = entry_type_columns(&metadata.entry_type);
 
         let connection = self.connection.lock().unwrap();
         let file_row_result = files::table
+            .filter(files::owner.eq(owner))
             .filter(files::filename.eq(&metadata.file_name))
             .first::<DbFile>(&*connection);
 
         match file_row_result {
             Ok(file_row) => {
-                diesel::update(&file_row)
-                    .set(files::last_modified.eq(metadata.last_modified as i64))
-                    .execute(&*connection)
-                    .map_err(|e| e.to_string())?;
+                let data_path = self.data_dir.join(&file_row.id);
+                let retired_path = self.data_dir.join(format!("{}.{}", file_row.id, file_row.version_id));
+                // A create that didn't get to commit left the data file
+                // under the name of the version it was retiring
+                if retired_path.exists() {
+                    fs::rename(&retired_path, &data_path)?;
+                }
 
-                OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .truncate(true)
-                    .open(file_row.id)
-                    .map_err(|e| e.to_string())?;
+                // Delta uploads copy from the version we're replacing, and
+                // snapshots may have it. The metadata stays until `finish`,
+                // so an unfinished upload doesn't pass for the file.
+                drop_previous(&connection, &self.data_dir, &file_row)?;
+                connection.transaction::<_, StorageError, _>(|| {
+                    blobs_end(&connection, &self.data_dir, &file_row.id)?;
+                    let version_row = DbFileVersion {
+                        id: file_row.version_id.clone(),
+                        file_id: file_row.id.clone(),
+                        data_path: format!("{}.{}", file_row.id, file_row.version_id),
+                    };
+                    diesel::insert_into(file_versions::table)
+                        .values(&version_row)
+                        .execute(&*connection)?;
+                    let blobs = file_blobs::table
+                        .filter(file_blobs::file_id.eq(&file_row.id))
+                        .load::<DbFileBlob>(&*connection)?;
+                    for blob in blobs {
+                        diesel::insert_into(version_blobs::table)
+                            .values((
+                                version_blobs::version_id.eq(&version_row.id),
+                                version_blobs::file_offset.eq(blob.file_offset),
+                                version_blobs::len.eq(blob.len),
+                                version_blobs::stored_offset.eq(blob.stored_offset),
+                                version_blobs::stored_len.eq(blob.stored_len),
+                                version_blobs::compression.eq(blob.compression),
+                                version_blobs::chunk_hash.eq(blob.chunk_hash),
+                                version_blobs::nonce.eq(blob.nonce),
+                            ))
+                            .execute(&*connection)?;
+                    }
+                    diesel::delete(file_blobs::table.filter(file_blobs::file_id.eq(&file_row.id)))
+                        .execute(&*connection)?;
+                    // The old hash no longer describes the file once we truncate it
+                    diesel::update(&file_row)
+                        .set((
+                            files::content_hash.eq(None::<Vec<u8>>),
+                            files::version_id.eq(Uuid::new_v4().to_simple().to_string()),
+                            files::previous_version_id.eq(&version_row.id),
+                        ))
+                        .execute(&*connection)?;
 
-                Ok(())
+                    fs::rename(&data_path, &retired_path)?;
+                    OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .open(&data_path)?;
+                    Ok(())
+                })
             }
             Err(_) => {
-                let file = OpenOptions::new()
+                OpenOptions::new()
                     .write(true)
                     .create(true)
                     .truncate(true)
-                    .open(&id)
-                    .map_err(|e| e.to_string())?;
+                    .open(self.data_dir.join(&id))?;
 
                 let new_file = DbFile {
-                    id: id,
+                    id: id.clone(),
                     filename: metadata.file_name.clone(),
-                    last_modified: metadata.last_modified as i64,
+                    last_modified: metadata.last_modified.seconds,
+                    last_modified_nanos: metadata.last_modified.nanos as i32,
+                    content_hash: None,
+                    owner: owner.to_string(),
+                    entry_type: entry_type,
+                    symlink_target: symlink_target,
+                    device_major: device_major,
+                    device_minor: device_minor,
+                    data_key: None,
+                    version_id: Uuid::new_v4().to_simple().to_string(),
+                    previous_version_id: None,
                 };
 
                 diesel::insert_into(files::table)
                     .values(&new_file)
-                    .execute(&*connection)
-                    .map_err(|e| e.to_string())?;
+                    .execute(&*connection)?;
 
                 Ok(())
             }
         }
     }
 
-    fn append(&'a self, filename: &str, data: &[u8]) -> Result<(), String> {
+    fn append(&'a self, owner: &str, filename: &str, data: &[u8]) -> Result<(), StorageError> {
         let connection = self.connection.lock().unwrap();
 
         let file_row = files::table
+            .filter(files::owner.eq(owner))
             .filter(files::filename.eq(&filename))
-            .first::<DbFile>(&*connection)
-            .unwrap();
+            .first::<DbFile>(&*connection)?;
 
-        let mut file = OpenOptions::new()
-            .write(true)
-            .open(file_row.id)
-            .map_err(|e| e.to_string())?;
+        let data_key = self.file_key_for_append(&connection, &file_row)?;
+        append_blob(&connection, &self.data_dir, &file_row.id, data_key.as_ref(), data, data.len() as u64, UNCOMPRESSED)
+    }
+
+    fn append_zstd(&'a self, owner: &str, filename: &str, data: &[u8], zstd_frame: &[u8]) -> Result<(), StorageError> {
+        let connection = self.connection.lock().unwrap();
 
-        file.write_all(data)
-            .map_err(|e| e.to_string())
+        let file_row = files::table
+            .filter(files::owner.eq(owner))
+            .filter(files::filename.eq(&filename))
+            .first::<DbFile>(&*connection)?;
+
+        let data_key = self.file_key_for_append(&connection, &file_row)?;
+        append_blob(&connection, &self.data_dir, &file_row.id, data_key.as_ref(), zstd_frame, data.len() as u64, ZSTD)
     }
 
-    fn storage_outdated(&'a self, metadata: &FileMetadata) -> Result<bool, String> {
+    fn chunk_len(&'a self, owner: &str, hash: &[u8]) -> Result<Option<u64>, StorageError> {
         let connection = self.connection.lock().unwrap();
 
+        if !is_chunk_owner(&connection, owner, hash)? {
+            return Ok(None);
+        }
+        let len = chunks::table
+            .find(hash)
+            .select(chunks::len)
+            .first::<i64>(&*connection)
+            .optional()?;
+
+        Ok(len.map(|len| len as u64))
+    }
+
+    fn put_chunk(&'a self, owner: &str, hash: &[u8], data: &[u8], zstd_frame: Option<&[u8]>) -> Result<(), StorageError> {
+        let connection = self.connection.lock().unwrap();
+
+        let stored_chunk = chunks::table
+            .find(hash)
+            .first::<DbChunk>(&*connection)
+            .optional()?;
+        if stored_chunk.is_some() {
+            return add_chunk_owner(&connection, owner, hash);
+        }
+
+        let (stored, compression) = match zstd_frame {
+            Some(zstd_frame) => (zstd_frame, ZSTD),
+            None => (data, UNCOMPRESSED),
+        };
+        let (stored, data_key, nonce) = match self.storage_key {
+            Some(ref storage_key) => {
+                let data_key = Key::generate();
+                let (nonce, sealed) = encryption::seal_chunk(&data_key, hash, stored)?;
+                (sealed, Some(encryption::wrap_key(storage_key, hash, &data_key)?), Some(nonce))
+            }
+            None => (stored.to_vec(), None, None),
+        };
+        // The row only goes in once the whole chunk is on disk
+        fs::create_dir_all(self.data_dir.join(CHUNK_DIR))?;
+        write_replacing(&chunk_path(&self.data_dir, hash), &stored)?;
+
+        diesel::insert_into(chunks::table)
+            .values(&DbChunk {
+                hash: hash.to_vec(),
+                len: data.len() as i64,
+                stored_len: stored.len() as i64,
+                compression: compression,
+                data_key: data_key,
+                nonce: nonce,
+            })
+            .execute(&*connection)?;
+
+        add_chunk_owner(&connection, owner, hash)
+    }
+
+    fn append_chunk(&'a self, owner: &str, filename: &str, hash: &[u8]) -> Result<(), StorageError> {
+        let connection = self.connection.lock().unwrap();
+
+        if !is_chunk_owner(&connection, owner, hash)? {
+            return Err(StorageError::NotFound);
+        }
+
+        let file_row = files::table
+            .filter(files::owner.eq(owner))
+            .filter(files::filename.eq(&filename))
+            .first::<DbFile>(&*connection)?;
+        let chunk = chunks::table
+            .find(hash)
+            .first::<DbChunk>(&*connection)?;
+
+        let file_offset = blobs_end(&connection, &self.data_dir, &file_row.id)?;
+        diesel::insert_into(file_blobs::table)
+            .values(&DbFileBlob {
+                file_id: file_row.id,
+                file_offset: file_offset,
+                len: chunk.len,
+                stored_offset: 0,
+                stored_len: chunk.stored_len,
+                compression: chunk.compression,
+                chunk_hash: Some(chunk.hash),
+                // Encrypted with the chunk's own data key, if at all
+                nonce: None,
+            })
+            .execute(&*connection)?;
+
+        Ok(())
+    }
+
+    fn storage_outdated(&'a self, owner: &str, metadata: &FileMetadata) -> Result<bool, StorageError> {
+        let connection = self.connection.lock().unwrap();
+
+        // Unfinished uploads don't have a hash yet
         let file_is_updated = files::table
+            .filter(files::owner.eq(owner))
             .filter(files::filename.eq(&metadata.file_name))
-            .filter(files::last_modified.eq(metadata.last_modified as i64))
+            .filter(files::last_modified.eq(metadata.last_modified.seconds))
+            .filter(files::last_modified_nanos.eq(metadata.last_modified.nanos as i32))
+            .filter(files::content_hash.is_not_null())
             .first::<DbFile>(&*connection)
             .is_ok();
 
         Ok(!file_is_updated)
     }
 
-    fn get_head(&'a self, filename: &str) -> Result<u64, String> {
+    fn get_head(&'a self, owner: &str, filename: &str) -> Result<u64, StorageError> {
         let connection = self.connection.lock().unwrap();
 
         let file_row = files::table
+            .filter(files::owner.eq(owner))
             .filter(files::filename.eq(&filename))
-            .first::<DbFile>(&*connection)
-            .unwrap();
+            .first::<DbFile>(&*connection)?;
+
+        file_len(&connection, &self.data_dir, &file_row.id)
+    }
+
+    fn read(&'a self, owner: &str, filename: &str, offset: u64, len: u64) -> Result<Vec<u8>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+
+        let file_row = files::table
+            .filter(files::owner.eq(owner))
+            .filter(files::filename.eq(&filename))
+            .first::<DbFile>(&*connection)?;
+
+        self.read_current(&connection, &file_row, offset, len)
+    }
+
+    fn read_previous(&'a self, owner: &str, filename: &str, offset: u64, len: u64) -> Result<Vec<u8>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+
+        let file_row = files::table
+            .filter(files::owner.eq(owner))
+            .filter(files::filename.eq(&filename))
+            .first::<DbFile>(&*connection)?;
+
+        let version_id = previous_version(&connection, &self.data_dir, &file_row)?
+            .ok_or(StorageError::NotFound)?;
+        // Versions keep the data key of their file
+        let data_keys = DataKeys {
+            storage_key: self.storage_key.as_ref(),
+            file_key: self.file_key(&file_row)?,
+        };
+        read_version(&connection, &self.data_dir, &data_keys, &file_row.id, &version_id, offset, len)
+    }
+
+    fn read_snapshot(&'a self, owner: &str, id: &SnapshotId, filename: &str, offset: u64, len: u64)
+        -> Result<Vec<u8>, StorageError>
+    {
+        let connection = self.connection.lock().unwrap();
+
+        snapshot_row(&connection, owner, id)?;
+        let snapshot_file_row = snapshot_files::table
+            .filter(snapshot_files::snapshot_id.eq(id.as_bytes()))
+            .filter(snapshot_files::filename.eq(filename))
+            .first::<DbSnapshotFile>(&*connection)?;
+        let file_row = files::table
+            .find(&snapshot_file_row.file_id)
+            .first::<DbFile>(&*connection)?;
+
+        if snapshot_file_row.version_id == file_row.version_id {
+            return self.read_current(&connection, &file_row, offset, len);
+        }
+        let data_keys = DataKeys {
+            storage_key: self.storage_key.as_ref(),
+            file_key: self.file_key(&file_row)?,
+        };
+        read_version(&connection, &self.data_dir, &data_keys, &file_row.id, &snapshot_file_row.version_id, offset, len)
+    }
+
+    fn list(&'a self, owner: &str) -> Result<Vec<FileMetadata>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+
+        let file_rows = files::table
+            .filter(files::owner.eq(owner))
+            .load::<DbFile>(&*connection)?;
+
+        file_rows.into_iter()
+            .map(|file_row| {
+                let file_size = file_len(&connection, &self.data_dir, &file_row.id)?;
+                let posix_attributes = load_attributes(&connection, &file_row.id)?;
+                let entry_type = file_row_entry_type(&file_row)?;
+                Ok(FileMetadata {
+                    file_name: file_row.filename,
+                    last_modified: Timestamp::new(file_row.last_modified, file_row.last_modified_nanos as u32),
+                    file_size: file_size,
+                    content_hash: file_row.content_hash,
+                    posix_attributes: posix_attributes,
+                    entry_type: entry_type,
+                })
+            })
+            .collect()
+    }
+
+    fn finish(&'a self, owner: &str, metadata: &FileMetadata, content_hash: &[u8]) -> Result<(), StorageError> {
+        let (entry_type, symlink_target, device_major, device_minor) = entry_type_columns(&metadata.entry_type);
+        let connection = self.connection.lock().unwrap();
+
+        let file_row = files::table
+            .filter(files::owner.eq(owner))
+            .filter(files::filename.eq(&metadata.file_name))
+            .first::<DbFile>(&*connection)?;
+
+        connection.transaction::<_, StorageError, _>(|| {
+            diesel::update(&file_row)
+                .set((
+                    files::last_modified.eq(metadata.last_modified.seconds),
+                    files::last_modified_nanos.eq(metadata.last_modified.nanos as i32),
+                    files::content_hash.eq(content_hash),
+                    files::entry_type.eq(entry_type),
+                    files::symlink_target.eq(&symlink_target),
+                    files::device_major.eq(device_major),
+                    files::device_minor.eq(device_minor),
+                ))
+                .execute(&*connection)?;
+            save_attributes(&connection, &file_row.id, metadata.posix_attributes.as_ref())
+        })?;
+        drop_previous(&connection, &self.data_dir, &file_row)
+    }
+
+    fn discard(&'a self, owner: &str, filename: &str) -> Result<(), StorageError> {
+        let connection = self.connection.lock().unwrap();
+
+        let file_row = files::table
+            .filter(files::owner.eq(owner))
+            .filter(files::filename.eq(&filename))
+            .first::<DbFile>(&*connection)?;
+
+        diesel::delete(file_blobs::table.filter(file_blobs::file_id.eq(&file_row.id)))
+            .execute(&*connection)?;
+        // A file without blobs is stored as it is, so it has to be empty too
+        OpenOptions::new()
+            .write(true)
+            .open(self.data_dir.join(&file_row.id))?
+            .set_len(0)?;
+        Ok(())
+    }
+
+    fn content_hash(&'a self, owner: &str, filename: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+
+        let content_hash = files::table
+            .filter(files::owner.eq(owner))
+            .filter(files::filename.eq(&filename))
+            .select(files::content_hash)
+            .first::<Option<Vec<u8>>>(&*connection)
+            .optional()?;
+
+        Ok(content_hash.and_then(|hash| hash))
+    }
+
+    fn upload_statuses(&'a self, owner: &str, files: &[FileMetadata]) -> Result<Vec<UploadStatus>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+
+        let filenames: Vec<&str> = files.iter()
+            .map(|metadata| metadata.file_name.as_str())
+            .collect();
+        let stored: HashMap<String, (i64, i32, Option<Vec<u8>>)> = files::table
+            .filter(files::owner.eq(owner))
+            .filter(files::filename.eq_any(filenames))
+            .select((files::filename, files::last_modified, files::last_modified_nanos, files::content_hash))
+            .load::<(String, i64, i32, Option<Vec<u8>>)>(&*connection)?
+            .into_iter()
+            .map(|(filename, last_modified, last_modified_nanos, content_hash)| (filename, (last_modified, last_modified_nanos, content_hash)))
+            .collect();
+
+        // Same as `storage_outdated` and `content_hash` for each file
+        Ok(files.iter()
+            .map(|metadata| match stored.get(&metadata.file_name) {
+                Some(&(last_modified, last_modified_nanos, ref content_hash)) => UploadStatus {
+                    is_uploaded: content_hash.is_some()
+                        && last_modified == metadata.last_modified.seconds
+                        && last_modified_nanos == metadata.last_modified.nanos as i32,
+                    content_hash: content_hash.clone(),
+                },
+                None => UploadStatus {
+                    is_uploaded: false,
+                    content_hash: None,
+                },
+            })
+            .collect())
+    }
+
+    fn save_upload(&'a self, token: &UploadToken, session: &UploadSession) -> Result<(), StorageError> {
+        let connection = self.connection.lock().unwrap();
+
+        let last_used = session.last_used
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(|e| StorageError::Other(e.to_string()))?
+            .as_secs();
+        let metadata = &session.file_metadata;
+        let session_row = DbUploadSession {
+            token: token.as_bytes().to_vec(),
+            owner: session.owner.clone(),
+            filename: metadata.file_name.clone(),
+            last_modified: metadata.last_modified.seconds,
+            last_modified_nanos: metadata.last_modified.nanos as i32,
+            file_size: metadata.file_size as i64,
+            content_hash: metadata.content_hash.clone(),
+            last_used: last_used as i64,
+        };
+
+        diesel::replace_into(upload_sessions::table)
+            .values(&session_row)
+            .execute(&*connection)?;
+
+        Ok(())
+    }
+
+    fn remove_upload(&'a self, token: &UploadToken) -> Result<(), StorageError> {
+        let connection = self.connection.lock().unwrap();
+
+        diesel::delete(upload_sessions::table.filter(upload_sessions::token.eq(token.as_bytes())))
+            .execute(&*connection)?;
+
+        Ok(())
+    }
+
+    fn load_uploads(&'a self) -> Result<Vec<(UploadToken, UploadSession)>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+
+        let session_rows = upload_sessions::table
+            .load::<DbUploadSession>(&*connection)?;
+
+        session_rows.into_iter()
+            .map(|session_row| {
+                let token = UploadToken::from_slice(&session_row.token)
+                    .map_err(|e| StorageError::Other(e.to_string()))?;
+                let session = UploadSession {
+                    owner: session_row.owner,
+                    file_metadata: FileMetadata {
+                        file_name: session_row.filename,
+                        last_modified: Timestamp::new(session_row.last_modified, session_row.last_modified_nanos as u32),
+                        file_size: session_row.file_size as u64,
+                        content_hash: session_row.content_hash,
+                        // Saved with the file by `create`
+                        posix_attributes: None,
+                        // Nothing else has contents to upload
+                        entry_type: EntryType::Regular,
+                    },
+                    last_used: SystemTime::UNIX_EPOCH + Duration::from_secs(session_row.last_used as u64),
+                };
+                Ok((token, session))
+            })
+            .collect()
+    }
+
+    fn begin_snapshot(&'a self, owner: &str, snapshot: &Snapshot) -> Result<(), StorageError> {
+        let connection = self.connection.lock().unwrap();
+
+        let snapshot_row = DbSnapshot {
+            id: snapshot.id.as_bytes().to_vec(),
+            owner: owner.to_string(),
+            host: snapshot.host.clone(),
+            started: snapshot.started.seconds,
+            started_nanos: snapshot.started.nanos as i32,
+            finished: snapshot.finished.map(|finished| finished.seconds),
+            finished_nanos: snapshot.finished.map(|finished| finished.nanos as i32),
+            file_count: snapshot.file_count as i64,
+        };
+        diesel::insert_into(snapshots::table)
+            .values(&snapshot_row)
+            .execute(&*connection)?;
+
+        Ok(())
+    }
+
+    fn snapshot(&'a self, owner: &str, id: &SnapshotId) -> Result<Snapshot, StorageError> {
+        let connection = self.connection.lock().unwrap();
+        snapshot_from_row(snapshot_row(&connection, owner, id)?)
+    }
+
+    fn snapshots(&'a self, owner: &str) -> Result<Vec<Snapshot>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+
+        snapshots::table
+            .filter(snapshots::owner.eq(owner))
+            .order((snapshots::started, snapshots::started_nanos))
+            .load::<DbSnapshot>(&*connection)?
+            .into_iter()
+            .map(snapshot_from_row)
+            .collect()
+    }
+
+    fn add_to_snapshot(&'a self, owner: &str, id: &SnapshotId, filenames: &[String]) -> Result<(), StorageError> {
+        let connection = self.connection.lock().unwrap();
+
+        connection.transaction::<_, StorageError, _>(|| {
+            snapshot_row(&connection, owner, id)?;
+            let file_rows: HashMap<String, DbFile> = files::table
+                .filter(files::owner.eq(owner))
+                .filter(files::filename.eq_any(filenames))
+                .load::<DbFile>(&*connection)?
+                .into_iter()
+                .map(|file_row| (file_row.filename.clone(), file_row))
+                .collect();
+
+            for filename in filenames {
+                // Files without a content hash never finished uploading
+                let (file_row, content_hash) = match file_rows.get(filename) {
+                    Some(file_row) => match file_row.content_hash {
+                        Some(ref content_hash) => (file_row, content_hash),
+                        None => return Err(StorageError::NotFound),
+                    },
+                    None => return Err(StorageError::NotFound),
+                };
+                let snapshot_file_row = DbSnapshotFile {
+                    snapshot_id: id.as_bytes().to_vec(),
+                    filename: filename.clone(),
+                    file_id: file_row.id.clone(),
+                    last_modified: file_row.last_modified,
+                    last_modified_nanos: file_row.last_modified_nanos,
+                    file_size: file_len(&connection, &self.data_dir, &file_row.id)? as i64,
+                    content_hash: content_hash.clone(),
+                    entry_type: file_row.entry_type,
+                    symlink_target: file_row.symlink_target.clone(),
+                    device_major: file_row.device_major,
+                    device_minor: file_row.device_minor,
+                    version_id: file_row.version_id.clone(),
+                };
+                save_version_attributes(&connection, &file_row.id, &file_row.version_id)?;
+                diesel::replace_into(snapshot_files::table)
+                    .values(&snapshot_file_row)
+                    .execute(&*connection)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn commit_snapshot(&'a self, owner: &str, id: &SnapshotId, finished: Timestamp) -> Result<Snapshot, StorageError> {
+        let connection = self.connection.lock().unwrap();
+
+        connection.transaction::<_, StorageError, _>(|| {
+            let snapshot_row = snapshot_row(&connection, owner, id)?;
+            let file_count = snapshot_files::table
+                .filter(snapshot_files::snapshot_id.eq(id.as_bytes()))
+                .count()
+                .get_result::<i64>(&*connection)?;
+            diesel::update(snapshots::table.find(&snapshot_row.id))
+                .set((
+                    snapshots::finished.eq(finished.seconds),
+                    snapshots::finished_nanos.eq(finished.nanos as i32),
+                    snapshots::file_count.eq(file_count),
+                ))
+                .execute(&*connection)?;
+
+            snapshot_from_row(DbSnapshot {
+                finished: Some(finished.seconds),
+                finished_nanos: Some(finished.nanos as i32),
+                file_count: file_count,
+                ..snapshot_row
+            })
+        })
+    }
+
+    fn snapshot_files(&'a self, owner: &str, id: &SnapshotId) -> Result<Vec<FileMetadata>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+
+        snapshot_row(&connection, owner, id)?;
+        let snapshot_file_rows = snapshot_files::table
+            .filter(snapshot_files::snapshot_id.eq(id.as_bytes()))
+            .order(snapshot_files::filename)
+            .load::<DbSnapshotFile>(&*connection)?;
+
+        snapshot_file_rows.into_iter()
+            .map(|snapshot_file_row| {
+                let entry_type = column_entry_type(&snapshot_file_row.filename, snapshot_file_row.entry_type,
+                                                   snapshot_file_row.symlink_target.as_ref(),
+                                                   snapshot_file_row.device_major, snapshot_file_row.device_minor)?;
+                Ok(FileMetadata {
+                    posix_attributes: load_version_attributes(&connection, &snapshot_file_row.version_id)?,
+                    file_name: snapshot_file_row.filename,
+                    last_modified: Timestamp::new(snapshot_file_row.last_modified, snapshot_file_row.last_modified_nanos as u32),
+                    file_size: snapshot_file_row.file_size as u64,
+                    content_hash: Some(snapshot_file_row.content_hash),
+                    entry_type: entry_type,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Whether `owner` has uploaded the data of the shared chunk with `hash`.
+fn is_chunk_owner(connection: &SqliteConnection, owner: &str, hash: &[u8]) -> Result<bool, StorageError> {
+    let owner_row = chunk_owners::table
+        .find((hash, owner))
+        .select(chunk_owners::owner)
+        .first::<String>(connection)
+        .optional()?;
+    Ok(owner_row.is_some())
+}
+
+/// Records that `owner` has uploaded the data of the shared chunk with
+/// `hash`.
+fn add_chunk_owner(connection: &SqliteConnection, owner: &str, hash: &[u8]) -> Result<(), StorageError> {
+    diesel::replace_into(chunk_owners::table)
+        .values((
+            chunk_owners::hash.eq(hash),
+            chunk_owners::owner.eq(owner),
+        ))
+        .execute(connection)?;
+    Ok(())
+}
+
+/// Gets the blob at the end of the file with `file_id`. Files without blobs
+/// are stored as they are.
+fn last_blob(connection: &SqliteConnection, file_id: &str) -> Result<Option<DbFileBlob>, StorageError> {
+    let blob = file_blobs::table
+        .filter(file_blobs::file_id.eq(file_id))
+        .order(file_blobs::file_offset.desc())
+        .first::<DbFileBlob>(connection)
+        .optional()?;
+    Ok(blob)
+}
+
+/// Length of the uncompressed data of the file with `file_id`.
+fn file_len(connection: &SqliteConnection, data_dir: &Path, file_id: &str) -> Result<u64, StorageError> {
+    match last_blob(connection, file_id)? {
+        Some(blob) => Ok((blob.file_offset + blob.len) as u64),
+        None => Ok(fs::metadata(data_dir.join(file_id))?.len()),
+    }
+}
+
+/// Where the next blob of the file with `file_id` goes. A file that was
+/// stored as it is becomes one blob first.
+fn blobs_end(connection: &SqliteConnection, data_dir: &Path, file_id: &str) -> Result<i64, StorageError> {
+    if let Some(blob) = last_blob(connection, file_id)? {
+        return Ok(blob.file_offset + blob.len);
+    }
+
+    let stored_len = fs::metadata(data_dir.join(file_id))?.len() as i64;
+    if stored_len > 0 {
+        diesel::insert_into(file_blobs::table)
+            .values(&DbFileBlob {
+                file_id: file_id.to_string(),
+                file_offset: 0,
+                len: stored_len,
+                stored_offset: 0,
+                stored_len: stored_len,
+                compression: UNCOMPRESSED,
+                chunk_hash: None,
+                nonce: None,
+            })
+            .execute(connection)?;
+    }
+    Ok(stored_len)
+}
+
+/// Appends `stored`, which holds the next `len` bytes of the file with
+/// `file_id`, to its data file. Encrypts it first if there's a `data_key`.
+fn append_blob(connection: &SqliteConnection, data_dir: &Path, file_id: &str, data_key: Option<&Key>, stored: &[u8], len: u64,
+               compression: i32)
+    -> Result<(), StorageError>
+{
+    let file_offset = blobs_end(connection, data_dir, file_id)?;
+    let data_path = data_dir.join(file_id);
+    // Anything a failed append left behind isn't part of any blob
+    let stored_offset = fs::metadata(&data_path)?.len();
+
+    let (nonce, stored) = match data_key {
+        Some(data_key) => {
+            let (nonce, sealed) = encryption::seal_blob(data_key, file_id, file_offset, stored)?;
+            (Some(nonce), sealed)
+        }
+        None => (None, stored.to_vec()),
+    };
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(data_path)?;
+    file.write_all(&stored)?;
+
+    diesel::insert_into(file_blobs::table)
+        .values(&DbFileBlob {
+            file_id: file_id.to_string(),
+            file_offset: file_offset,
+            len: len as i64,
+            stored_offset: stored_offset as i64,
+            stored_len: stored.len() as i64,
+            compression: compression,
+            chunk_hash: None,
+            nonce: nonce,
+        })
+        .execute(connection)?;
+
+    Ok(())
+}
+
+/// Reads up to `len` bytes starting at `offset` from the blobs of the file
+/// with `file_id`.
+fn read_blobs(connection: &SqliteConnection, data_dir: &Path, data_keys: &DataKeys, file_id: &str, offset: u64, len: u64)
+    -> Result<Vec<u8>, StorageError>
+{
+    let end = blob_range_end(offset, len);
+    let blobs = file_blobs::table
+        .filter(file_blobs::file_id.eq(file_id))
+        .filter(file_blobs::file_offset.lt(end as i64))
+        .filter((file_blobs::file_offset + file_blobs::len).gt(offset as i64))
+        .order(file_blobs::file_offset)
+        .load::<DbFileBlob>(connection)?;
+    read_blob_data(connection, data_dir, data_keys, file_id, &data_dir.join(file_id), blobs, offset, end)
+}
+
+/// Where a read of `len` bytes from `offset` stops, as far as blob offsets
+/// go.
+fn blob_range_end(offset: u64, len: u64) -> u64 {
+    cmp::min(offset.saturating_add(len), i64::max_value() as u64)
+}
+
+/// Keys to decrypt the blobs of a file with.
+struct DataKeys<'a> {
+    /// Unwraps the keys of shared chunks.
+    storage_key: Option<&'a Key>,
+    file_key: Option<Key>,
+}
+
+/// Reads the part between `offset` and `end` of `blobs` of the file with
+/// `file_id`, which are stored in `data_path` unless they're shared chunks.
+fn read_blob_data(connection: &SqliteConnection, data_dir: &Path, data_keys: &DataKeys, file_id: &str, data_path: &Path,
+                  blobs: Vec<DbFileBlob>, offset: u64, end: u64)
+    -> Result<Vec<u8>, StorageError>
+{
+    let mut file = File::open(data_path)?;
+    let mut data = Vec::new();
+    for blob in blobs {
+        let stored = match blob.chunk_hash {
+            Some(ref hash) => {
+                let sealed = read_at(&mut File::open(chunk_path(data_dir, hash))?, blob.stored_offset as u64, blob.stored_len as u64)?;
+                let (wrapped, nonce) = chunks::table
+                    .find(hash)
+                    .select((chunks::data_key, chunks::nonce))
+                    .first::<(Option<Vec<u8>>, Option<Vec<u8>>)>(connection)?;
+                match unwrap_data_key(data_keys.storage_key, hash, wrapped.as_ref())? {
+                    Some(data_key) => encryption::open_chunk(&data_key, hash, nonce.as_deref(), &sealed)?,
+                    None => sealed,
+                }
+            }
+            None => {
+                let sealed = read_at(&mut file, blob.stored_offset as u64, blob.stored_len as u64)?;
+                match (&blob.nonce, &data_keys.file_key) {
+                    (Some(nonce), Some(file_key)) => encryption::open_blob(file_key, file_id, blob.file_offset, nonce, &sealed)?,
+                    (Some(_), None) => {
+                        return Err(StorageError::Other(format!("{} has encrypted blobs but no data key", file_id)));
+                    }
+                    (None, _) => sealed,
+                }
+            }
+        };
+        let blob_data = compression::decompress(&stored, blob_compression(blob.compression)?, blob.len as u64)?;
+
+        let start = offset.saturating_sub(blob.file_offset as u64) as usize;
+        let stop = cmp::min(blob_data.len() as u64, end - blob.file_offset as u64) as usize;
+        data.extend_from_slice(&blob_data[start..stop]);
+    }
+    Ok(data)
+}
+
+/// Reads up to `len` bytes starting at `offset` from the version with
+/// `version_id` of the file with `file_id`.
+fn read_version(connection: &SqliteConnection, data_dir: &Path, data_keys: &DataKeys, file_id: &str, version_id: &str,
+                offset: u64, len: u64)
+    -> Result<Vec<u8>, StorageError>
+{
+    let version_row = file_versions::table
+        .find(version_id)
+        .first::<DbFileVersion>(connection)?;
+    let end = blob_range_end(offset, len);
+    let blobs = version_blobs::table
+        .filter(version_blobs::version_id.eq(version_id))
+        .filter(version_blobs::file_offset.lt(end as i64))
+        .filter((version_blobs::file_offset + version_blobs::len).gt(offset as i64))
+        .order(version_blobs::file_offset)
+        .load::<DbFileBlob>(connection)?;
+    read_blob_data(connection, data_dir, data_keys, file_id, &data_dir.join(&version_row.data_path), blobs, offset, end)
+}
+
+/// Gets the id of the version of `file_row` that `create` replaced, if it's
+/// still there. One that was replaced before there were versions, and
+/// before the file had blobs, is stored as it is in `<id>.previous`; it
+/// becomes a version with one blob here.
+fn previous_version(connection: &SqliteConnection, data_dir: &Path, file_row: &DbFile) -> Result<Option<String>, StorageError> {
+    if file_row.previous_version_id.is_some() {
+        return Ok(file_row.previous_version_id.clone());
+    }
+    let data_path = format!("{}.previous", file_row.id);
+    let stored_len = match fs::metadata(data_dir.join(&data_path)) {
+        Ok(metadata) => metadata.len() as i64,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    let version_id = format!("previous-{}", file_row.id);
+    connection.transaction::<_, StorageError, _>(|| {
+        diesel::insert_into(file_versions::table)
+            .values(&DbFileVersion {
+                id: version_id.clone(),
+                file_id: file_row.id.clone(),
+                data_path: data_path,
+            })
+            .execute(connection)?;
+        if stored_len > 0 {
+            diesel::insert_into(version_blobs::table)
+                .values((
+                    version_blobs::version_id.eq(&version_id),
+                    version_blobs::file_offset.eq(0),
+                    version_blobs::len.eq(stored_len),
+                    version_blobs::stored_offset.eq(0),
+                    version_blobs::stored_len.eq(stored_len),
+                    version_blobs::compression.eq(UNCOMPRESSED),
+                ))
+                .execute(connection)?;
+        }
+        diesel::update(file_row)
+            .set(files::previous_version_id.eq(&version_id))
+            .execute(connection)?;
+        Ok(())
+    })?;
+    Ok(Some(version_id))
+}
+
+/// Forgets the version of `file_row` that `create` replaced, if it's still
+/// there. Its data stays while a snapshot has it.
+fn drop_previous(connection: &SqliteConnection, data_dir: &Path, file_row: &DbFile) -> Result<(), StorageError> {
+    let version_id = match previous_version(connection, data_dir, file_row)? {
+        Some(version_id) => version_id,
+        None => return Ok(()),
+    };
+    diesel::update(file_row)
+        .set(files::previous_version_id.eq(None::<String>))
+        .execute(connection)?;
+
+    let in_snapshot = snapshot_files::table
+        .filter(snapshot_files::version_id.eq(&version_id))
+        .first::<DbSnapshotFile>(connection)
+        .optional()?
+        .is_some();
+    if in_snapshot {
+        return Ok(());
+    }
+
+    let version_row = file_versions::table
+        .find(&version_id)
+        .first::<DbFileVersion>(connection)?;
+    diesel::delete(version_blobs::table.filter(version_blobs::version_id.eq(&version_id)))
+        .execute(connection)?;
+    diesel::delete(version_xattrs::table.filter(version_xattrs::version_id.eq(&version_id)))
+        .execute(connection)?;
+    diesel::delete(version_attributes::table.find(&version_id))
+        .execute(connection)?;
+    diesel::delete(file_versions::table.find(&version_id))
+        .execute(connection)?;
+    match fs::remove_file(data_dir.join(&version_row.data_path)) {
+        Ok(()) => Ok(()),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Locks the storage with the database in `filename` against any other
+/// process, which fails if one has it locked already. The lock goes away
+/// with the returned file.
+fn lock_storage(filename: &str) -> Result<File, StorageError> {
+    let lock_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(format!("{}.lock", filename))?;
+    if unsafe { libc::flock(lock_file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::WouldBlock {
+            return Err(StorageError::Other(format!("{} is in use by another backupd", filename)));
+        }
+        return Err(err.into());
+    }
+    Ok(lock_file)
+}
+
+/// Where `seal_plaintext` writes the encrypted copy of `path`.
+fn sealing_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".sealing");
+    path.with_file_name(file_name)
+}
+
+/// Whether the data file in `path` with `blobs` needs no more encrypting.
+/// Puts an encrypted copy left by an interrupted `seal_plaintext` in its
+/// place if the database already points at it, and drops it otherwise.
+fn finish_sealing(path: &Path, blobs: &[DbFileBlob]) -> Result<bool, StorageError> {
+    let done = blobs.iter().all(|blob| blob.nonce.is_some());
+    let temp_path = sealing_path(path);
+    if temp_path.exists() {
+        if done {
+            fs::rename(&temp_path, path)?;
+        }
+        else {
+            fs::remove_file(&temp_path)?;
+        }
+    }
+    Ok(done)
+}
+
+/// Copies `blobs` of the file with `file_id` from the data file in
+/// `data_path` to its `sealing_path`, encrypting those that aren't yet with
+/// `file_key`. Anything in the data
+/// file that isn't part of a blob is left out. Returns the blobs as they
+/// are stored in the copy.
+fn seal_blobs(data_path: &Path, file_id: &str, file_key: &Key, blobs: Vec<DbFileBlob>) -> Result<Vec<DbFileBlob>, StorageError> {
+    let mut file = File::open(data_path)?;
+    let mut copy = Vec::new();
+    let mut sealed_blobs = Vec::with_capacity(blobs.len());
+    for mut blob in blobs {
+        let stored = read_at(&mut file, blob.stored_offset as u64, blob.stored_len as u64)?;
+        let stored = match blob.nonce {
+            Some(_) => stored,
+            None => {
+                let (nonce, sealed) = encryption::seal_blob(file_key, file_id, blob.file_offset, &stored)?;
+                blob.nonce = Some(nonce);
+                sealed
+            }
+        };
+        blob.stored_offset = copy.len() as i64;
+        blob.stored_len = stored.len() as i64;
+        copy.extend_from_slice(&stored);
+        sealed_blobs.push(blob);
+    }
+    write_synced(&sealing_path(data_path), &copy)?;
+    Ok(sealed_blobs)
+}
+
+/// Writes `data` to a new file in `path`, and waits until it's on disk.
+fn write_synced(path: &Path, data: &[u8]) -> Result<(), StorageError> {
+    let mut file = File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Writes `data` to `path` through a file next to it, so that `path` never
+/// has only part of `data`.
+fn write_replacing(path: &Path, data: &[u8]) -> Result<(), StorageError> {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".tmp");
+    let temp_path = path.with_file_name(file_name);
+    write_synced(&temp_path, data)?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Unwraps the data key of the file or chunk with `id`, if it has one.
+fn unwrap_data_key(storage_key: Option<&Key>, id: &[u8], wrapped: Option<&Vec<u8>>) -> Result<Option<Key>, StorageError> {
+    match (storage_key, wrapped) {
+        (Some(storage_key), Some(wrapped)) => Ok(Some(encryption::unwrap_key(storage_key, id, wrapped)?)),
+        (None, Some(_)) => Err(StorageError::Other("data is encrypted at rest, but there's no storage key".into())),
+        (_, None) => Ok(None),
+    }
+}
+
+fn chunk_path(data_dir: &Path, hash: &[u8]) -> PathBuf {
+    data_dir.join(CHUNK_DIR).join(chunk_file_name(hash))
+}
+
+fn blob_compression(code: i32) -> Result<Compression, StorageError> {
+    match code {
+        UNCOMPRESSED => Ok(Compression::None),
+        ZSTD => Ok(Compression::Zstd),
+        code => Err(StorageError::Other(format!("unknown blob compression {}", code))),
+    }
+}
+
+/// Splits `entry_type` into the `entry_type`, `symlink_target`,
+/// `device_major` and `device_minor` columns.
+fn entry_type_columns(entry_type: &EntryType) -> (i32, Option<Vec<u8>>, i32, i32) {
+    match *entry_type {
+        EntryType::Regular => (REGULAR, None, 0, 0),
+        EntryType::Directory => (DIRECTORY, None, 0, 0),
+        EntryType::Symlink { ref target } => (SYMLINK, Some(target.clone()), 0, 0),
+        EntryType::Fifo => (FIFO, None, 0, 0),
+        EntryType::CharDevice { major, minor } => (CHAR_DEVICE, None, major as i32, minor as i32),
+        EntryType::BlockDevice { major, minor } => (BLOCK_DEVICE, None, major as i32, minor as i32),
+    }
+}
+
+fn file_row_entry_type(file_row: &DbFile) -> Result<EntryType, StorageError> {
+    column_entry_type(&file_row.filename, file_row.entry_type, file_row.symlink_target.as_ref(),
+                      file_row.device_major, file_row.device_minor)
+}
+
+/// Undoes `entry_type_columns`.
+fn column_entry_type(filename: &str, entry_type: i32, symlink_target: Option<&Vec<u8>>, device_major: i32, device_minor: i32)
+    -> Result<EntryType, StorageError>
+{
+    let major = device_major as u32;
+    let minor = device_minor as u32;
+    match entry_type {
+        REGULAR => Ok(EntryType::Regular),
+        DIRECTORY => Ok(EntryType::Directory),
+        SYMLINK => Ok(EntryType::Symlink { target: symlink_target.cloned().unwrap_or_default() }),
+        FIFO => Ok(EntryType::Fifo),
+        CHAR_DEVICE => Ok(EntryType::CharDevice { major: major, minor: minor }),
+        BLOCK_DEVICE => Ok(EntryType::BlockDevice { major: major, minor: minor }),
+        code => Err(StorageError::Other(format!("unknown entry type {} for {}", code, filename))),
+    }
+}
+
+fn snapshot_from_row(snapshot_row: DbSnapshot) -> Result<Snapshot, StorageError> {
+    let finished = match (snapshot_row.finished, snapshot_row.finished_nanos) {
+        (Some(finished), Some(finished_nanos)) => Some(Timestamp::new(finished, finished_nanos as u32)),
+        _ => None,
+    };
+    Ok(Snapshot {
+        id: SnapshotId::from_slice(&snapshot_row.id)
+            .map_err(|e| StorageError::Other(e.to_string()))?,
+        host: snapshot_row.host,
+        started: Timestamp::new(snapshot_row.started, snapshot_row.started_nanos as u32),
+        finished: finished,
+        file_count: snapshot_row.file_count as u64,
+    })
+}
+
+/// Gets `owner`'s snapshot with `id`.
+fn snapshot_row(connection: &SqliteConnection, owner: &str, id: &SnapshotId) -> Result<DbSnapshot, StorageError> {
+    let snapshot_row = snapshots::table
+        .filter(snapshots::owner.eq(owner))
+        .filter(snapshots::id.eq(id.as_bytes()))
+        .first::<DbSnapshot>(connection)?;
+    Ok(snapshot_row)
+}
+
+/// Replaces whatever attributes the file with `file_id` had.
+fn save_attributes(connection: &SqliteConnection, file_id: &str, attributes: Option<&PosixAttributes>)
+    -> Result<(), StorageError>
+{
+    diesel::delete(file_xattrs::table.filter(file_xattrs::file_id.eq(file_id)))
+        .execute(connection)?;
+    diesel::delete(file_attributes::table.filter(file_attributes::file_id.eq(file_id)))
+        .execute(connection)?;
+
+    let attributes = match attributes {
+        Some(attributes) => attributes,
+        None => return Ok(()),
+    };
+
+    let attributes_row = DbFileAttributes {
+        file_id: file_id.to_string(),
+        mode: attributes.mode as i32,
+        uid: attributes.uid as i64,
+        gid: attributes.gid as i64,
+        user_name: attributes.user_name.clone(),
+        group_name: attributes.group_name.clone(),
+        accessed: attributes.accessed.seconds,
+        accessed_nanos: attributes.accessed.nanos as i32,
+        changed: attributes.changed.seconds,
+        changed_nanos: attributes.changed.nanos as i32,
+    };
+    diesel::insert_into(file_attributes::table)
+        .values(&attributes_row)
+        .execute(connection)?;
+
+    let xattr_rows: Vec<DbXattr> = attributes.xattrs.iter()
+        .map(|xattr| DbXattr {
+            file_id: file_id.to_string(),
+            name: xattr.name.clone(),
+            value: if xattr.value.is_empty() { None } else { Some(xattr.value.clone()) },
+        })
+        .collect();
+    diesel::insert_into(file_xattrs::table)
+        .values(&xattr_rows)
+        .execute(connection)?;
+
+    Ok(())
+}
+
+/// Keeps the attributes the file with `file_id` has now as those of its
+/// version with `version_id`, unless that version has some already.
+fn save_version_attributes(connection: &SqliteConnection, file_id: &str, version_id: &str) -> Result<(), StorageError> {
+    let saved = version_attributes::table
+        .find(version_id)
+        .first::<DbFileAttributes>(connection)
+        .optional()?
+        .is_some();
+    let attributes_row = file_attributes::table
+        .filter(file_attributes::file_id.eq(file_id))
+        .first::<DbFileAttributes>(connection)
+        .optional()?;
+    let attributes_row = match attributes_row {
+        Some(attributes_row) if !saved => attributes_row,
+        _ => return Ok(()),
+    };
+
+    diesel::insert_into(version_attributes::table)
+        .values((
+            version_attributes::version_id.eq(version_id),
+            version_attributes::mode.eq(attributes_row.mode),
+            version_attributes::uid.eq(attributes_row.uid),
+            version_attributes::gid.eq(attributes_row.gid),
+            version_attributes::user_name.eq(attributes_row.user_name),
+            version_attributes::group_name.eq(attributes_row.group_name),
+            version_attributes::accessed.eq(attributes_row.accessed),
+            version_attributes::accessed_nanos.eq(attributes_row.accessed_nanos),
+            version_attributes::changed.eq(attributes_row.changed),
+            version_attributes::changed_nanos.eq(attributes_row.changed_nanos),
+        ))
+        .execute(connection)?;
+    let xattr_rows = file_xattrs::table
+        .filter(file_xattrs::file_id.eq(file_id))
+        .load::<DbXattr>(connection)?;
+    for xattr_row in xattr_rows {
+        diesel::insert_into(version_xattrs::table)
+            .values((
+                version_xattrs::version_id.eq(version_id),
+                version_xattrs::name.eq(xattr_row.name),
+                version_xattrs::value.eq(xattr_row.value),
+            ))
+            .execute(connection)?;
+    }
+
+    Ok(())
+}
+
+fn load_attributes(connection: &SqliteConnection, file_id: &str) -> Result<Option<PosixAttributes>, StorageError> {
+    let attributes_row = file_attributes::table
+        .filter(file_attributes::file_id.eq(file_id))
+        .first::<DbFileAttributes>(connection)
+        .optional()?;
+    let attributes_row = match attributes_row {
+        Some(attributes_row) => attributes_row,
+        None => return Ok(None),
+    };
+
+    let xattr_rows = file_xattrs::table
+        .filter(file_xattrs::file_id.eq(file_id))
+        .load::<DbXattr>(connection)?;
+    Ok(Some(attributes_from_rows(attributes_row, xattr_rows)))
+}
+
+/// Like `load_attributes`, for the version with `version_id`.
+fn load_version_attributes(connection: &SqliteConnection, version_id: &str) -> Result<Option<PosixAttributes>, StorageError> {
+    let attributes_row = version_attributes::table
+        .find(version_id)
+        .first::<DbFileAttributes>(connection)
+        .optional()?;
+    let attributes_row = match attributes_row {
+        Some(attributes_row) => attributes_row,
+        None => return Ok(None),
+    };
+
+    let xattr_rows = version_xattrs::table
+        .filter(version_xattrs::version_id.eq(version_id))
+        .load::<DbXattr>(connection)?;
+    Ok(Some(attributes_from_rows(attributes_row, xattr_rows)))
+}
 
-        let mut file = OpenOptions::new()
-            .read(true)
-            .open(file_row.id)
-            .map_err(|e| e.to_string())?;
+fn attributes_from_rows(attributes_row: DbFileAttributes, xattr_rows: Vec<DbXattr>) -> PosixAttributes {
+    let xattrs = xattr_rows.into_iter()
+        .map(|xattr_row| ExtendedAttribute {
+            name: xattr_row.name,
+            value: xattr_row.value.unwrap_or_default(),
+        })
+        .collect();
 
-        file.metadata()
-            .map_err(|e| e.to_string())
-            .map(|m| m.len())
+    PosixAttributes {
+        mode: attributes_row.mode as u32,
+        uid: attributes_row.uid as u32,
+        gid: attributes_row.gid as u32,
+        user_name: attributes_row.user_name,
+        group_name: attributes_row.group_name,
+        accessed: Timestamp::new(attributes_row.accessed, attributes_row.accessed_nanos as u32),
+        changed: Timestamp::new(attributes_row.changed, attributes_row.changed_nanos as u32),
+        xattrs: xattrs,
     }
 }
@@ -1,4 +1,4 @@
-use crate::storage::sqlite_db::schema::files;
+use crate::storage::sqlite_db::schema::{chunks, file_attributes, file_blobs, file_versions, file_xattrs, files, snapshot_files, snapshots, upload_sessions};
 
 #[derive(Queryable, Insertable, Identifiable)]
 #[table_name="files"]
@@ -7,4 +7,134 @@ pub struct DbFile {
     pub id: String,
     pub filename: String,
     pub last_modified: i64,
+    pub last_modified_nanos: i32,
+    pub content_hash: Option<Vec<u8>>,
+    pub owner: String,
+    pub entry_type: i32,
+    pub symlink_target: Option<Vec<u8>>,
+    pub device_major: i32,
+    pub device_minor: i32,
+    /// Wrapped by the storage key, if the file is encrypted at rest.
+    pub data_key: Option<Vec<u8>>,
+    /// Changes with every upload.
+    pub version_id: String,
+    /// The version the last upload replaced, until it finishes.
+    pub previous_version_id: Option<String>,
+}
+
+#[derive(Queryable, Insertable)]
+#[table_name="upload_sessions"]
+pub struct DbUploadSession {
+    pub token: Vec<u8>,
+    pub owner: String,
+    pub filename: String,
+    pub last_modified: i64,
+    pub last_modified_nanos: i32,
+    pub file_size: i64,
+    pub content_hash: Option<Vec<u8>>,
+    /// Seconds since the Unix epoch.
+    pub last_used: i64,
+}
+
+/// Also loaded from `version_attributes`, which has the same columns but
+/// the version's id in place of the file's.
+#[derive(Queryable, Insertable)]
+#[table_name="file_attributes"]
+pub struct DbFileAttributes {
+    pub file_id: String,
+    pub mode: i32,
+    pub uid: i64,
+    pub gid: i64,
+    pub user_name: Option<String>,
+    pub group_name: Option<String>,
+    pub accessed: i64,
+    pub accessed_nanos: i32,
+    pub changed: i64,
+    pub changed_nanos: i32,
+}
+
+/// Also loaded from `version_xattrs`, like `DbFileAttributes`.
+#[derive(Queryable, Insertable)]
+#[table_name="file_xattrs"]
+pub struct DbXattr {
+    pub file_id: String,
+    pub name: Vec<u8>,
+    /// `None` if empty: diesel trips over empty blobs.
+    pub value: Option<Vec<u8>>,
+}
+
+/// Also loaded from `version_blobs`, like `DbFileAttributes`.
+#[derive(Queryable, Insertable)]
+#[table_name="file_blobs"]
+pub struct DbFileBlob {
+    pub file_id: String,
+    /// Where the blob goes in the file, in uncompressed bytes.
+    pub file_offset: i64,
+    pub len: i64,
+    /// Where the blob is in the data file, or in the chunk's file.
+    pub stored_offset: i64,
+    pub stored_len: i64,
+    pub compression: i32,
+    /// Set if the blob is a shared chunk.
+    pub chunk_hash: Option<Vec<u8>>,
+    /// Set if the blob is encrypted with the file's data key.
+    pub nonce: Option<Vec<u8>>,
+}
+
+/// A version of a file that an upload replaced, kept while delta uploads
+/// may copy from it or a snapshot has it.
+#[derive(Queryable, Insertable)]
+#[table_name="file_versions"]
+pub struct DbFileVersion {
+    pub id: String,
+    pub file_id: String,
+    /// Name of its data file in the data directory.
+    pub data_path: String,
+}
+
+#[derive(Queryable, Insertable)]
+#[table_name="chunks"]
+pub struct DbChunk {
+    pub hash: Vec<u8>,
+    pub len: i64,
+    pub stored_len: i64,
+    pub compression: i32,
+    /// Wrapped by the storage key, if the chunk is encrypted at rest.
+    pub data_key: Option<Vec<u8>>,
+    /// What the chunk was sealed with, unless it was sealed before chunks
+    /// had nonces.
+    pub nonce: Option<Vec<u8>>,
+}
+
+#[derive(Queryable, Insertable)]
+#[table_name="snapshots"]
+pub struct DbSnapshot {
+    pub id: Vec<u8>,
+    pub owner: String,
+    pub host: String,
+    pub started: i64,
+    pub started_nanos: i32,
+    /// Set once the snapshot is committed.
+    pub finished: Option<i64>,
+    pub finished_nanos: Option<i32>,
+    pub file_count: i64,
+}
+
+/// A file as it was stored when it was added to a snapshot.
+#[derive(Queryable, Insertable)]
+#[table_name="snapshot_files"]
+pub struct DbSnapshotFile {
+    pub snapshot_id: Vec<u8>,
+    pub filename: String,
+    pub file_id: String,
+    pub last_modified: i64,
+    pub last_modified_nanos: i32,
+    pub file_size: i64,
+    pub content_hash: Vec<u8>,
+    pub entry_type: i32,
+    pub symlink_target: Option<Vec<u8>>,
+    pub device_major: i32,
+    pub device_minor: i32,
+    /// Its attributes are in `version_attributes`.
+    pub version_id: String,
 }
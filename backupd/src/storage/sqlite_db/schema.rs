@@ -3,5 +3,156 @@ table! {
         id -> Text,
         filename -> Text,
         last_modified -> BigInt,
+        last_modified_nanos -> Integer,
+        content_hash -> Nullable<Binary>,
+        owner -> Text,
+        entry_type -> Integer,
+        symlink_target -> Nullable<Binary>,
+        device_major -> Integer,
+        device_minor -> Integer,
+        data_key -> Nullable<Binary>,
+        version_id -> Text,
+        previous_version_id -> Nullable<Text>,
+    }
+}
+
+table! {
+    upload_sessions (token) {
+        token -> Binary,
+        owner -> Text,
+        filename -> Text,
+        last_modified -> BigInt,
+        last_modified_nanos -> Integer,
+        file_size -> BigInt,
+        content_hash -> Nullable<Binary>,
+        last_used -> BigInt,
+    }
+}
+
+table! {
+    file_attributes (file_id) {
+        file_id -> Text,
+        mode -> Integer,
+        uid -> BigInt,
+        gid -> BigInt,
+        user_name -> Nullable<Text>,
+        group_name -> Nullable<Text>,
+        accessed -> BigInt,
+        accessed_nanos -> Integer,
+        changed -> BigInt,
+        changed_nanos -> Integer,
+    }
+}
+
+table! {
+    file_xattrs (file_id, name) {
+        file_id -> Text,
+        name -> Binary,
+        value -> Nullable<Binary>,
+    }
+}
+
+table! {
+    file_blobs (file_id, file_offset) {
+        file_id -> Text,
+        file_offset -> BigInt,
+        len -> BigInt,
+        stored_offset -> BigInt,
+        stored_len -> BigInt,
+        compression -> Integer,
+        chunk_hash -> Nullable<Binary>,
+        nonce -> Nullable<Binary>,
+    }
+}
+
+table! {
+    chunks (hash) {
+        hash -> Binary,
+        len -> BigInt,
+        stored_len -> BigInt,
+        compression -> Integer,
+        data_key -> Nullable<Binary>,
+        nonce -> Nullable<Binary>,
+    }
+}
+
+table! {
+    chunk_owners (hash, owner) {
+        hash -> Binary,
+        owner -> Text,
+    }
+}
+
+table! {
+    file_versions (id) {
+        id -> Text,
+        file_id -> Text,
+        data_path -> Text,
+    }
+}
+
+table! {
+    version_blobs (version_id, file_offset) {
+        version_id -> Text,
+        file_offset -> BigInt,
+        len -> BigInt,
+        stored_offset -> BigInt,
+        stored_len -> BigInt,
+        compression -> Integer,
+        chunk_hash -> Nullable<Binary>,
+        nonce -> Nullable<Binary>,
+    }
+}
+
+table! {
+    version_attributes (version_id) {
+        version_id -> Text,
+        mode -> Integer,
+        uid -> BigInt,
+        gid -> BigInt,
+        user_name -> Nullable<Text>,
+        group_name -> Nullable<Text>,
+        accessed -> BigInt,
+        accessed_nanos -> Integer,
+        changed -> BigInt,
+        changed_nanos -> Integer,
+    }
+}
+
+table! {
+    version_xattrs (version_id, name) {
+        version_id -> Text,
+        name -> Binary,
+        value -> Nullable<Binary>,
+    }
+}
+
+table! {
+    snapshots (id) {
+        id -> Binary,
+        owner -> Text,
+        host -> Text,
+        started -> BigInt,
+        started_nanos -> Integer,
+        finished -> Nullable<BigInt>,
+        finished_nanos -> Nullable<Integer>,
+        file_count -> BigInt,
+    }
+}
+
+table! {
+    snapshot_files (snapshot_id, filename) {
+        snapshot_id -> Binary,
+        filename -> Text,
+        file_id -> Text,
+        last_modified -> BigInt,
+        last_modified_nanos -> Integer,
+        file_size -> BigInt,
+        content_hash -> Binary,
+        entry_type -> Integer,
+        symlink_target -> Nullable<Binary>,
+        device_major -> Integer,
+        device_minor -> Integer,
+        version_id -> Text,
     }
 }
@@ -0,0 +1,342 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use backupd::server::BaacupImpl;
+use backupd::storage::{StorageManager, StorageError, UploadSession};
+use backuplib::rpc::{Authenticate, Credentials, EntryType, FileMetadata, Snapshot, SnapshotId, Timestamp, UploadToken};
+
+pub const TEST_CLIENT: &str = "test_client";
+pub const TEST_API_KEY: &str = "test_key";
+
+/// Makes a server that knows `TEST_CLIENT` and returns its session for it.
+pub fn test_session<S>(storage_manager: S) -> BaacupImpl<S>
+    where for<'a> S: StorageManager<'a>,
+          S: Send + Sync + 'static,
+{
+    let mut server = BaacupImpl::new_from_storage(storage_manager);
+    server.add_client(TEST_CLIENT, TEST_API_KEY);
+    server.authenticate(&Credentials { api_key: Some(TEST_API_KEY.into()), ..Default::default() }).unwrap()
+}
+
+/// Files are keyed by owner and file name.
+type FileKey = (String, String);
+
+fn file_key(owner: &str, filename: &str) -> FileKey {
+    (owner.to_string(), filename.to_string())
+}
+
+/// A snapshot and the files recorded in it, by name, with a copy of their
+/// contents.
+#[derive(Debug, Clone)]
+struct StoredSnapshot {
+    owner: String,
+    snapshot: Snapshot,
+    files: BTreeMap<String, FileMetadata>,
+    contents: HashMap<String, Vec<u8>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct InMemoryStorage {
+    map_mutex: Arc<Mutex<HashMap<FileKey, Arc<Mutex<Vec<u8>>>>>>,
+    hash_map_mutex: Arc<Mutex<HashMap<FileKey, Vec<u8>>>>,
+    entry_type_map_mutex: Arc<Mutex<HashMap<FileKey, EntryType>>>,
+    upload_map_mutex: Arc<Mutex<HashMap<UploadToken, UploadSession>>>,
+    chunk_map_mutex: Arc<Mutex<HashMap<Vec<u8>, Vec<u8>>>>,
+    /// Who uploaded the data of each chunk, by owner and hash.
+    chunk_owner_set_mutex: Arc<Mutex<HashSet<(String, Vec<u8>)>>>,
+    previous_map_mutex: Arc<Mutex<HashMap<FileKey, Vec<u8>>>>,
+    snapshot_map_mutex: Arc<Mutex<HashMap<SnapshotId, StoredSnapshot>>>,
+}
+
+impl InMemoryStorage {
+    pub fn new() -> InMemoryStorage {
+        InMemoryStorage {
+            map_mutex: Arc::new(Mutex::new(HashMap::new())),
+            hash_map_mutex: Arc::new(Mutex::new(HashMap::new())),
+            entry_type_map_mutex: Arc::new(Mutex::new(HashMap::new())),
+            upload_map_mutex: Arc::new(Mutex::new(HashMap::new())),
+            chunk_map_mutex: Arc::new(Mutex::new(HashMap::new())),
+            chunk_owner_set_mutex: Arc::new(Mutex::new(HashSet::new())),
+            previous_map_mutex: Arc::new(Mutex::new(HashMap::new())),
+            snapshot_map_mutex: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn get_file_contents(&self, owner: &str, filename: &str) -> Result<Vec<u8>, String> {
+        let mut map = self.map_mutex.lock()
+            .map_err(|e| e.to_string())?;
+        let file_mutex = map.get_mut(&file_key(owner, filename))
+            .ok_or("bad filename".to_string())?;
+        let file = file_mutex.lock()
+            .map_err(|e| e.to_string())?;
+        Ok(file.clone())
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.chunk_map_mutex.lock().unwrap().len()
+    }
+
+    pub fn has_previous(&self, owner: &str, filename: &str) -> bool {
+        self.previous_map_mutex.lock().unwrap().contains_key(&file_key(owner, filename))
+    }
+}
+
+impl<'a> StorageManager<'a> for InMemoryStorage {
+    fn create(&self, owner: &str, metadata: &FileMetadata) -> Result<(), StorageError> {
+        let data = Vec::new();
+        let mut map = self.map_mutex.lock().unwrap();
+        let replaced = map.insert(file_key(owner, &metadata.file_name), Arc::new(Mutex::new(data)));
+        let mut previous_map = self.previous_map_mutex.lock().unwrap();
+        match replaced {
+            Some(file_mutex) => previous_map.insert(file_key(owner, &metadata.file_name), file_mutex.lock().unwrap().clone()),
+            None => previous_map.remove(&file_key(owner, &metadata.file_name)),
+        };
+        let mut hash_map = self.hash_map_mutex.lock().unwrap();
+        hash_map.remove(&file_key(owner, &metadata.file_name));
+        let mut entry_type_map = self.entry_type_map_mutex.lock().unwrap();
+        entry_type_map.insert(file_key(owner, &metadata.file_name), metadata.entry_type.clone());
+        Ok(())
+    }
+
+    fn append(&'a self, owner: &str, filename: &str, data: &[u8]) -> Result<(), StorageError> {
+        let mut map = self.map_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        let file_mutex = map.get_mut(&file_key(owner, filename))
+            .ok_or(StorageError::NotFound)?;
+        let mut file = file_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        file.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn chunk_len(&'a self, owner: &str, hash: &[u8]) -> Result<Option<u64>, StorageError> {
+        if !self.chunk_owner_set_mutex.lock().unwrap().contains(&(owner.to_string(), hash.to_vec())) {
+            return Ok(None);
+        }
+        let chunk_map = self.chunk_map_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        Ok(chunk_map.get(hash).map(|chunk| chunk.len() as u64))
+    }
+
+    fn put_chunk(&'a self, owner: &str, hash: &[u8], data: &[u8], _zstd_frame: Option<&[u8]>) -> Result<(), StorageError> {
+        let mut chunk_map = self.chunk_map_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        chunk_map.entry(hash.to_vec()).or_insert_with(|| data.to_vec());
+        self.chunk_owner_set_mutex.lock().unwrap().insert((owner.to_string(), hash.to_vec()));
+        Ok(())
+    }
+
+    fn append_chunk(&'a self, owner: &str, filename: &str, hash: &[u8]) -> Result<(), StorageError> {
+        if !self.chunk_owner_set_mutex.lock().unwrap().contains(&(owner.to_string(), hash.to_vec())) {
+            return Err(StorageError::NotFound);
+        }
+        let data = self.chunk_map_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?
+            .get(hash)
+            .cloned()
+            .ok_or(StorageError::NotFound)?;
+        self.append(owner, filename, &data)
+    }
+
+    fn storage_outdated(&'a self, _owner: &str, _metadata: &FileMetadata) -> Result<bool, StorageError> {
+        Ok(false)
+    }
+
+    fn get_head(&'a self, owner: &str, filename: &str) -> Result<u64, StorageError> {
+        let mut map = self.map_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        let file_mutex = map.get_mut(&file_key(owner, filename))
+            .ok_or(StorageError::NotFound)?;
+        let file = file_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        Ok(file.len() as u64)
+    }
+
+    fn read(&'a self, owner: &str, filename: &str, offset: u64, len: u64) -> Result<Vec<u8>, StorageError> {
+        let mut map = self.map_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        let file_mutex = map.get_mut(&file_key(owner, filename))
+            .ok_or(StorageError::NotFound)?;
+        let file = file_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        let start = cmp::min(offset, file.len() as u64) as usize;
+        let end = cmp::min(offset.saturating_add(len), file.len() as u64) as usize;
+        Ok(file[start..end].to_vec())
+    }
+
+    fn read_previous(&'a self, owner: &str, filename: &str, offset: u64, len: u64) -> Result<Vec<u8>, StorageError> {
+        let previous_map = self.previous_map_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        let file = previous_map.get(&file_key(owner, filename))
+            .ok_or(StorageError::NotFound)?;
+        let start = cmp::min(offset, file.len() as u64) as usize;
+        let end = cmp::min(offset.saturating_add(len), file.len() as u64) as usize;
+        Ok(file[start..end].to_vec())
+    }
+
+    fn list(&'a self, owner: &str) -> Result<Vec<FileMetadata>, StorageError> {
+        let map = self.map_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        let entry_type_map = self.entry_type_map_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        let mut files = Vec::new();
+        for (&(ref file_owner, ref filename), file_mutex) in map.iter() {
+            if file_owner != owner {
+                continue;
+            }
+            let file = file_mutex.lock()
+                .map_err(|e| StorageError::Other(e.to_string()))?;
+            files.push(FileMetadata {
+                file_name: filename.clone(),
+                last_modified: Timestamp::default(),
+                file_size: file.len() as u64,
+                content_hash: None,
+                posix_attributes: None,
+                entry_type: entry_type_map[&(file_owner.clone(), filename.clone())].clone(),
+            });
+        }
+        Ok(files)
+    }
+
+    fn finish(&'a self, owner: &str, metadata: &FileMetadata, content_hash: &[u8]) -> Result<(), StorageError> {
+        let mut hash_map = self.hash_map_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        hash_map.insert(file_key(owner, &metadata.file_name), content_hash.to_vec());
+        let mut previous_map = self.previous_map_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        previous_map.remove(&file_key(owner, &metadata.file_name));
+        Ok(())
+    }
+
+    fn discard(&'a self, owner: &str, filename: &str) -> Result<(), StorageError> {
+        let map = self.map_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        let file_mutex = map.get(&file_key(owner, filename))
+            .ok_or(StorageError::NotFound)?;
+        file_mutex.lock().unwrap().clear();
+        Ok(())
+    }
+
+    fn content_hash(&'a self, owner: &str, filename: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        let hash_map = self.hash_map_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        Ok(hash_map.get(&file_key(owner, filename)).cloned())
+    }
+
+    fn save_upload(&'a self, token: &UploadToken, session: &UploadSession) -> Result<(), StorageError> {
+        let mut upload_map = self.upload_map_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        upload_map.insert(*token, session.clone());
+        Ok(())
+    }
+
+    fn remove_upload(&'a self, token: &UploadToken) -> Result<(), StorageError> {
+        let mut upload_map = self.upload_map_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        upload_map.remove(token);
+        Ok(())
+    }
+
+    fn load_uploads(&'a self) -> Result<Vec<(UploadToken, UploadSession)>, StorageError> {
+        let upload_map = self.upload_map_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        Ok(upload_map.iter()
+            .map(|(token, session)| (*token, session.clone()))
+            .collect())
+    }
+
+    fn begin_snapshot(&'a self, owner: &str, snapshot: &Snapshot) -> Result<(), StorageError> {
+        let mut snapshot_map = self.snapshot_map_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        snapshot_map.insert(snapshot.id, StoredSnapshot {
+            owner: owner.to_string(),
+            snapshot: snapshot.clone(),
+            files: BTreeMap::new(),
+            contents: HashMap::new(),
+        });
+        Ok(())
+    }
+
+    fn snapshot(&'a self, owner: &str, id: &SnapshotId) -> Result<Snapshot, StorageError> {
+        let snapshot_map = self.snapshot_map_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        snapshot_map.get(id)
+            .filter(|stored| stored.owner == owner)
+            .map(|stored| stored.snapshot.clone())
+            .ok_or(StorageError::NotFound)
+    }
+
+    fn snapshots(&'a self, owner: &str) -> Result<Vec<Snapshot>, StorageError> {
+        let snapshot_map = self.snapshot_map_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        let mut snapshots: Vec<Snapshot> = snapshot_map.values()
+            .filter(|stored| stored.owner == owner)
+            .map(|stored| stored.snapshot.clone())
+            .collect();
+        snapshots.sort_by_key(|snapshot| snapshot.started);
+        Ok(snapshots)
+    }
+
+    fn add_to_snapshot(&'a self, owner: &str, id: &SnapshotId, filenames: &[String]) -> Result<(), StorageError> {
+        let mut recorded = Vec::new();
+        let mut contents = Vec::new();
+        for filename in filenames {
+            let content_hash = self.content_hash(owner, filename)?
+                .ok_or(StorageError::NotFound)?;
+            let entry_type = self.entry_type_map_mutex.lock().unwrap()[&file_key(owner, filename)].clone();
+            recorded.push(FileMetadata {
+                file_name: filename.clone(),
+                last_modified: Timestamp::default(),
+                file_size: self.get_head(owner, filename)?,
+                content_hash: Some(content_hash),
+                posix_attributes: None,
+                entry_type: entry_type,
+            });
+            contents.push(self.read(owner, filename, 0, u64::MAX)?);
+        }
+
+        let mut snapshot_map = self.snapshot_map_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        let stored = snapshot_map.get_mut(id)
+            .filter(|stored| stored.owner == owner)
+            .ok_or(StorageError::NotFound)?;
+        for (metadata, data) in recorded.into_iter().zip(contents) {
+            stored.contents.insert(metadata.file_name.clone(), data);
+            stored.files.insert(metadata.file_name.clone(), metadata);
+        }
+        Ok(())
+    }
+
+    fn commit_snapshot(&'a self, owner: &str, id: &SnapshotId, finished: Timestamp) -> Result<Snapshot, StorageError> {
+        let mut snapshot_map = self.snapshot_map_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        let stored = snapshot_map.get_mut(id)
+            .filter(|stored| stored.owner == owner)
+            .ok_or(StorageError::NotFound)?;
+        stored.snapshot.finished = Some(finished);
+        stored.snapshot.file_count = stored.files.len() as u64;
+        Ok(stored.snapshot.clone())
+    }
+
+    fn snapshot_files(&'a self, owner: &str, id: &SnapshotId) -> Result<Vec<FileMetadata>, StorageError> {
+        let snapshot_map = self.snapshot_map_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        snapshot_map.get(id)
+            .filter(|stored| stored.owner == owner)
+            .map(|stored| stored.files.values().cloned().collect())
+            .ok_or(StorageError::NotFound)
+    }
+
+    fn read_snapshot(&'a self, owner: &str, id: &SnapshotId, filename: &str, offset: u64, len: u64) -> Result<Vec<u8>, StorageError> {
+        let snapshot_map = self.snapshot_map_mutex.lock()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+        let file = snapshot_map.get(id)
+            .filter(|stored| stored.owner == owner)
+            .and_then(|stored| stored.contents.get(filename))
+            .ok_or(StorageError::NotFound)?;
+        let start = cmp::min(offset, file.len() as u64) as usize;
+        let end = cmp::min(offset.saturating_add(len), file.len() as u64) as usize;
+        Ok(file[start..end].to_vec())
+    }
+}
@@ -1,70 +1,26 @@
-use std::cmp;
-use std::collections::{HashMap, HashSet};
-use std::io::{Read, Write, Seek, SeekFrom, Result as IoResult};
-use std::sync::{Arc, Mutex};
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::Cursor;
+use std::process;
+use std::thread;
+use std::time::Duration;
 
-use backupd::storage::{StorageManager, FileLen};
-use backupd::server::BaacupImpl;
+use backupd::storage::StorageManager;
+use backupd::server::{BaacupImpl, MAX_HELD_BYTES};
 use futures::future::{self, Future, Loop, Either};
+use futures::stream;
 
-use backuplib::rpc::{Baacup, FileMetadata, FileChunk};
+use backuplib::compression::compress;
+use backuplib::convergent;
+use backuplib::crypto::Key;
+use backuplib::delta::{self, DeltaOp};
+use backuplib::hash::content_hash;
+use backuplib::rpc::{Authenticate, Baacup, BaacupError, BaacupStream, Capability, Compression, CopyRange, Credentials, DataChunk, EntryType, FileMetadata, FileChunk, Hello, SnapshotId, StreamHeader, Timestamp, UploadStatus, UploadToken, MAX_ADD_TO_SNAPSHOT, MAX_FILES_ARE_UPLOADED, MAX_HAS_CHUNKS};
 
-#[derive(Debug, Clone)]
-pub struct InMemoryStorage {
-    map_mutex: Arc<Mutex<HashMap<String, Arc<Mutex<Vec<u8>>>>>>,
-}
-
-impl InMemoryStorage {
-    pub fn new() -> InMemoryStorage {
-        InMemoryStorage {
-            map_mutex: Arc::new(Mutex::new(HashMap::new())),
-        }
-    }
-
-    pub fn get_file_contents(&self, filename: &str) -> Result<Vec<u8>, String> {
-        let mut map = self.map_mutex.lock()
-            .map_err(|e| e.to_string())?;
-        let file_mutex = map.get_mut(filename)
-            .ok_or("bad filename".to_string())?;
-        let file = file_mutex.lock()
-            .map_err(|e| e.to_string())?;
-        Ok(file.clone())
-    }
-}
-
-impl<'a> StorageManager<'a> for InMemoryStorage {
-    fn create(&self, metadata: &FileMetadata) -> Result<(), String> {
-        let data = Vec::new();
-        let mut map = self.map_mutex.lock().unwrap();
-        map.insert(metadata.file_name.clone(), Arc::new(Mutex::new(data)));
-        Ok(())
-    }
-
-    fn append(&'a self, filename: &str, data: &[u8]) -> Result<(), String> {
-        let mut map = self.map_mutex.lock()
-            .map_err(|e| e.to_string())?;
-        let file_mutex = map.get_mut(filename)
-            .ok_or("bad filename".to_string())?;
-        let mut file = file_mutex.lock()
-            .map_err(|e| e.to_string())?;
-        file.extend_from_slice(data);
-        Ok(())
-    }
-
-    fn storage_outdated(&'a self, metadata: &FileMetadata) -> Result<bool, String> {
-        Ok(false)
-    }
+mod common;
 
-    fn get_head(&'a self, filename: &str) -> Result<u64, String> {
-        let mut map = self.map_mutex.lock()
-            .map_err(|e| e.to_string())?;
-        let file_mutex = map.get_mut(filename)
-            .ok_or("bad filename".to_string())?;
-        let file = file_mutex.lock()
-            .map_err(|e| e.to_string())?;
-        Ok(file.len() as u64)
-    }
-}
+use common::{InMemoryStorage, TEST_API_KEY, TEST_CLIENT, test_session};
 
 #[test]
 fn test_unique_tokens() {
@@ -72,7 +28,7 @@ fn test_unique_tokens() {
     let storage_manager = InMemoryStorage::new();
 
     // Make a new server from the manager
-    let server = BaacupImpl::new_from_storage(storage_manager.clone());
+    let server = test_session(storage_manager.clone());
 
     let token_set = HashSet::new();
     let fut = future::loop_fn((server, token_set, 0), move |(server, mut token_set, count)| {
@@ -83,8 +39,11 @@ fn test_unique_tokens() {
             // Upload generated file to server
             let metadata = FileMetadata {
                 file_name: format!("file_{}", count),
-                last_modified: 0,
+                last_modified: Timestamp::default(),
                 file_size: 1,
+                content_hash: None,
+                posix_attributes: None,
+                entry_type: EntryType::Regular,
             };
             Either::B(server.init_upload(metadata)
                 .and_then(move |token| {
@@ -103,33 +62,32 @@ fn test_file_upload() {
     let storage_manager = InMemoryStorage::new();
 
     // Make a new server from the manager
-    let server = BaacupImpl::new_from_storage(storage_manager.clone());
+    let server = test_session(storage_manager.clone());
 
     // Upload generated file to server
     let metadata = FileMetadata {
         file_name: "test_file".into(),
-        last_modified: 0,
+        last_modified: Timestamp::default(),
         file_size: 2048,
+        content_hash: None,
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
     };
     let fut = server.init_upload(metadata).and_then(move |token| {
         server.get_head(token).and_then(move |offset| {
             assert_eq!(offset, 0);
-            let chunk = FileChunk {
-                token: token,
-                offset: offset,
-                data: (0..1024).map(|n| (n % 256) as u8).collect(),
-            };
+            let chunk = FileChunk::new(token, offset, (0..1024).map(|n| (n % 256) as u8).collect());
             server.upload_chunk(chunk).and_then(move |_checksum| {
                 server.get_head(token).and_then(move |offset| {
                     assert_eq!(offset, 1024);
-                    let chunk = FileChunk {
-                        token: token,
-                        offset: offset,
-                        data: (0..1024).map(|n| (n % 256) as u8).collect(),
-                    };
-                    server.upload_chunk(chunk).and_then(move |_checksum| {
+                    let chunk = FileChunk::new(token, offset, (0..1024).map(|n| (n % 256) as u8).collect());
+                    let expected_checksum = chunk.checksum;
+                    server.upload_chunk(chunk).and_then(move |checksum| {
+                        // Did the server checksum the same data?
+                        assert_eq!(checksum, expected_checksum);
+
                         // Get file from storage manager
-                        let mut buf = storage_manager.get_file_contents("test_file".into()).unwrap();
+                        let buf = storage_manager.get_file_contents(TEST_CLIENT, "test_file").unwrap();
 
                         // Was it the right length?
                         assert_eq!(buf.len(), 2048);
@@ -146,3 +104,1077 @@ fn test_file_upload() {
     });
     tokio::run(fut.map_err(|err| panic!("Error: {}", err)));
 }
+
+#[test]
+fn test_file_download() {
+    // Make manager
+    let storage_manager = InMemoryStorage::new();
+
+    // Make a new server from the manager
+    let server = test_session(storage_manager.clone());
+
+    // Upload generated file to server
+    let metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: 1500,
+        content_hash: None,
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    let fut = server.init_upload(metadata).and_then(move |token| {
+        let chunk = FileChunk::new(token, 0, (0..1500).map(|n| (n % 256) as u8).collect());
+        server.upload_chunk(chunk).and_then(move |_checksum| {
+            server.list_files().and_then(move |files| {
+                // Is the file listed with its full size?
+                assert_eq!(files.len(), 1);
+                assert_eq!(files[0].file_name, "test_file");
+                assert_eq!(files[0].file_size, 1500);
+
+                server.read_chunk("test_file".into(), 1024, 1024).and_then(move |data| {
+                    // Was the read cut short at the end of the file?
+                    assert_eq!(data.len(), 476);
+
+                    // Does it have the right contents?
+                    for (idx, byte) in data.iter().enumerate() {
+                        assert_eq!(*byte, ((idx + 1024) % 256) as u8);
+                    }
+
+                    server.read_chunk("missing_file".into(), 0, 1024).then(|result| {
+                        assert_eq!(result.unwrap_err(), BaacupError::NotFound);
+                        Ok(())
+                    })
+                })
+            })
+        })
+    });
+    tokio::run(fut.map_err(|err| panic!("Error: {}", err)));
+}
+
+#[test]
+fn test_corrupted_chunk_rejected() {
+    // Make manager
+    let storage_manager = InMemoryStorage::new();
+
+    // Make a new server from the manager
+    let server = test_session(storage_manager.clone());
+
+    let metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: 1024,
+        content_hash: None,
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    let fut = server.init_upload(metadata).and_then(move |token| {
+        // Flip a bit after the checksum was computed
+        let mut chunk = FileChunk::new(token, 0, vec![0x55; 1024]);
+        chunk.data[512] ^= 1;
+        server.upload_chunk(chunk).then(move |result| {
+            assert_eq!(result.unwrap_err(), BaacupError::ChecksumMismatch);
+
+            // Nothing should have been written
+            server.get_head(token).and_then(move |offset| {
+                assert_eq!(offset, 0);
+
+                // Resending the intact chunk succeeds
+                let chunk = FileChunk::new(token, 0, vec![0x55; 1024]);
+                let expected_checksum = chunk.checksum;
+                server.upload_chunk(chunk).and_then(move |checksum| {
+                    assert_eq!(checksum, expected_checksum);
+                    Ok(())
+                })
+            })
+        })
+    });
+    tokio::run(fut.map_err(|err| panic!("Error: {}", err)));
+}
+
+#[test]
+fn test_content_hash_recorded() {
+    // Make manager
+    let storage_manager = InMemoryStorage::new();
+
+    // Make a new server from the manager
+    let server = test_session(storage_manager.clone());
+
+    let data: Vec<u8> = (0..2000).map(|n| (n % 256) as u8).collect();
+    let expected_hash = content_hash(&data[..]).unwrap();
+    let metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: 2000,
+        content_hash: Some(expected_hash.clone()),
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    let fut = server.init_upload(metadata.clone()).and_then(move |token| {
+        // No hash while the upload is unfinished
+        server.file_is_uploaded(metadata.clone()).and_then(move |status| {
+            assert_eq!(status.content_hash, None);
+
+            let chunk = FileChunk::new(token, 0, data[..1000].to_vec());
+            server.upload_chunk(chunk).and_then(move |_checksum| {
+                let chunk = FileChunk::new(token, 1000, data[1000..].to_vec());
+                server.upload_chunk(chunk).and_then(move |_checksum| {
+                    server.file_is_uploaded(metadata).and_then(move |status| {
+                        assert_eq!(status.content_hash, Some(expected_hash));
+                        Ok(())
+                    })
+                })
+            })
+        })
+    });
+    tokio::run(fut.map_err(|err| panic!("Error: {}", err)));
+}
+
+#[test]
+fn test_content_hash_mismatch_rejected() {
+    // Make manager
+    let storage_manager = InMemoryStorage::new();
+
+    // Make a new server from the manager
+    let server = test_session(storage_manager.clone());
+
+    let metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: 1024,
+        content_hash: Some(content_hash(&[0; 1024][..]).unwrap()),
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    let fut = server.init_upload(metadata.clone()).and_then(move |token| {
+        let chunk = FileChunk::new(token, 0, vec![0x55; 1024]);
+        server.upload_chunk(chunk).then(move |result| {
+            assert_eq!(result.unwrap_err(), BaacupError::ContentHashMismatch);
+
+            // The server shouldn't remember a hash for the bad upload
+            server.file_is_uploaded(metadata).and_then(move |status| {
+                assert_eq!(status.content_hash, None);
+
+                // The upload starts over with the same token
+                server.get_head(token).and_then(move |head| {
+                    assert_eq!(head, 0);
+                    let chunk = FileChunk::new(token, 0, vec![0; 1024]);
+                    server.upload_chunk(chunk)
+                })
+            })
+        })
+    });
+    fut.wait().unwrap();
+}
+
+#[test]
+fn test_bad_offset_reports_head() {
+    let storage_manager = InMemoryStorage::new();
+    let server = test_session(storage_manager.clone());
+
+    let metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: 2048 + MAX_HELD_BYTES as u64,
+        content_hash: None,
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    let token = server.init_upload(metadata).wait().unwrap();
+    server.upload_chunk(FileChunk::new(token, 0, vec![0x55; 1024])).wait().unwrap();
+
+    // Skip further ahead of the server's copy than it holds chunks for
+    let chunk = FileChunk::new(token, 1536, vec![0x55; MAX_HELD_BYTES + 1]);
+    let error = server.upload_chunk(chunk).wait().unwrap_err();
+    assert_eq!(error, BaacupError::BadOffset { expected: 1024 });
+    assert!(error.is_retryable());
+
+    let error = server.get_head(UploadToken::new([0; UploadToken::LEN])).wait().unwrap_err();
+    assert_eq!(error, BaacupError::InvalidToken);
+}
+
+#[test]
+fn test_reordered_chunks_written_in_order() {
+    let storage_manager = InMemoryStorage::new();
+    let server = test_session(storage_manager.clone());
+
+    let data: Vec<u8> = (0..4000).map(|n| (n % 251) as u8).collect();
+    let metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: 4000,
+        content_hash: Some(content_hash(&data[..]).unwrap()),
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    let token = server.init_upload(metadata.clone()).wait().unwrap();
+
+    // A window of four chunks, delivered in the wrong order, one twice
+    for &offset in &[3000, 1000, 2000, 1000] {
+        let chunk = FileChunk::new(token, offset, data[offset as usize..offset as usize + 1000].to_vec());
+        server.upload_chunk(chunk).wait().unwrap();
+    }
+    assert_eq!(server.get_head(token).wait().unwrap(), 0);
+    assert_eq!(server.file_is_uploaded(metadata.clone()).wait().unwrap().content_hash, None);
+
+    // The chunk at the head lets the held ones follow it
+    server.upload_chunk(FileChunk::new(token, 0, data[..1000].to_vec())).wait().unwrap();
+    assert_eq!(storage_manager.get_file_contents(TEST_CLIENT, "test_file").unwrap(), data);
+    let status = server.file_is_uploaded(metadata).wait().unwrap();
+    assert_eq!(status.content_hash, Some(content_hash(&data[..]).unwrap()));
+}
+
+#[test]
+fn test_held_chunks_limited_across_uploads() {
+    let mut server = BaacupImpl::new_from_storage(InMemoryStorage::new());
+    server.add_client(TEST_CLIENT, TEST_API_KEY);
+    server.set_max_server_held_bytes(1500);
+    let session = server.authenticate(&Credentials { api_key: Some(TEST_API_KEY.into()), ..Default::default() }).unwrap();
+
+    let metadata = |file_name: &str| FileMetadata {
+        file_name: file_name.into(),
+        last_modified: Timestamp::default(),
+        file_size: 2000,
+        content_hash: None,
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    let first = session.init_upload(metadata("first")).wait().unwrap();
+    let second = session.init_upload(metadata("second")).wait().unwrap();
+    session.upload_chunk(FileChunk::new(first, 1000, vec![0x55; 1000])).wait().unwrap();
+
+    // Each upload is within its own limit, but not both together
+    let error = session.upload_chunk(FileChunk::new(second, 1000, vec![0x55; 1000])).wait().unwrap_err();
+    assert_eq!(error, BaacupError::BadOffset { expected: 0 });
+
+    // Writing the held chunk frees its share
+    session.upload_chunk(FileChunk::new(first, 0, vec![0x55; 1000])).wait().unwrap();
+    session.upload_chunk(FileChunk::new(second, 1000, vec![0x55; 1000])).wait().unwrap();
+}
+
+#[test]
+fn test_bad_held_chunk_sent_again() {
+    let storage_manager = InMemoryStorage::new();
+    let server = test_session(storage_manager.clone());
+
+    let data = vec![0x55; 2000];
+    let metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: 2000,
+        content_hash: Some(content_hash(&data[..]).unwrap()),
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    let token = server.init_upload(metadata).wait().unwrap();
+
+    // A chunk the server doesn't have only turns out bad once its turn comes
+    let unknown = FileChunk::new(token, 1000, Vec::new()).with_chunk_hash(vec![0; 32]);
+    server.upload_chunk(unknown).wait().unwrap();
+    let error = server.upload_chunk(FileChunk::new(token, 0, data[..1000].to_vec())).wait().unwrap_err();
+    assert_eq!(error, BaacupError::BadOffset { expected: 1000 });
+    assert_eq!(server.get_head(token).wait().unwrap(), 1000);
+
+    server.upload_chunk(FileChunk::new(token, 1000, data[1000..].to_vec())).wait().unwrap();
+    assert_eq!(storage_manager.get_file_contents(TEST_CLIENT, "test_file").unwrap(), data);
+}
+
+#[test]
+fn test_resent_chunk_acknowledged() {
+    let storage_manager = InMemoryStorage::new();
+    let server = test_session(storage_manager.clone());
+
+    let data: Vec<u8> = (0..2048).map(|n| (n % 256) as u8).collect();
+    let metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: 2048,
+        content_hash: Some(content_hash(&data[..]).unwrap()),
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    let token = server.init_upload(metadata).wait().unwrap();
+
+    // The response to the first chunk got lost
+    let first = FileChunk::new(token, 0, data[..1024].to_vec());
+    let first_checksum = first.checksum;
+    assert_eq!(server.upload_chunk(first.clone()).wait(), Ok(first_checksum));
+    assert_eq!(server.upload_chunk(first).wait(), Ok(first_checksum));
+    assert_eq!(server.get_head(token).wait(), Ok(1024));
+
+    // And so did the one to the last chunk
+    let last = FileChunk::new(token, 1024, data[1024..].to_vec());
+    let last_checksum = last.checksum;
+    assert_eq!(server.upload_chunk(last.clone()).wait(), Ok(last_checksum));
+    assert_eq!(server.upload_chunk(last).wait(), Ok(last_checksum));
+    assert_eq!(server.get_head(token).wait(), Ok(2048));
+
+    assert_eq!(storage_manager.get_file_contents(TEST_CLIENT, "test_file").unwrap(), data);
+}
+
+#[test]
+fn test_resent_chunk_with_other_data_rejected() {
+    let storage_manager = InMemoryStorage::new();
+    let server = test_session(storage_manager.clone());
+
+    let metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: 2048,
+        content_hash: None,
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    let token = server.init_upload(metadata).wait().unwrap();
+    server.upload_chunk(FileChunk::new(token, 0, vec![0x55; 1024])).wait().unwrap();
+
+    // Same offset, different data
+    let result = server.upload_chunk(FileChunk::new(token, 0, vec![0xaa; 1024])).wait();
+    assert_eq!(result, Err(BaacupError::BadOffset { expected: 1024 }));
+
+    // Reaches past the head
+    let result = server.upload_chunk(FileChunk::new(token, 512, vec![0x55; 1024])).wait();
+    assert_eq!(result, Err(BaacupError::BadOffset { expected: 1024 }));
+
+    // Finished uploads only take back what they stored
+    server.upload_chunk(FileChunk::new(token, 1024, vec![0x55; 1024])).wait().unwrap();
+    let result = server.upload_chunk(FileChunk::new(token, 1024, vec![0xaa; 1024])).wait();
+    assert_eq!(result, Err(BaacupError::BadOffset { expected: 2048 }));
+}
+
+#[test]
+fn test_unauthenticated_calls_rejected() {
+    let mut server = BaacupImpl::new_from_storage(InMemoryStorage::new());
+    server.add_client(TEST_CLIENT, TEST_API_KEY);
+
+    // Unknown or missing keys don't get a session
+    let wrong_key = Credentials { api_key: Some("wrong_key".into()), ..Default::default() };
+    assert_eq!(server.authenticate(&wrong_key).err(), Some(BaacupError::Unauthenticated));
+    assert_eq!(server.authenticate(&Credentials::default()).err(), Some(BaacupError::Unauthenticated));
+
+    // The server itself acts for nobody
+    assert_eq!(server.list_files().wait().unwrap_err(), BaacupError::Unauthenticated);
+}
+
+#[test]
+fn test_files_belong_to_their_client() {
+    let storage_manager = InMemoryStorage::new();
+    let mut server = BaacupImpl::new_from_storage(storage_manager.clone());
+    server.add_client("alice", "alice_key");
+    server.add_client("bob", "bob_key");
+    let alice = server.authenticate(&Credentials { api_key: Some("alice_key".into()), ..Default::default() }).unwrap();
+    let bob = server.authenticate(&Credentials { api_key: Some("bob_key".into()), ..Default::default() }).unwrap();
+
+    let metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: 4,
+        content_hash: None,
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+
+    // Bob can't write to Alice's upload
+    let token = alice.init_upload(metadata.clone()).wait().unwrap();
+    let result = bob.upload_chunk(FileChunk::new(token, 0, b"his!".to_vec())).wait();
+    assert_eq!(result.unwrap_err(), BaacupError::InvalidToken);
+    alice.upload_chunk(FileChunk::new(token, 0, b"hers".to_vec())).wait().unwrap();
+
+    // A file with the same name from Bob is a different file
+    let token = bob.init_upload(metadata).wait().unwrap();
+    bob.upload_chunk(FileChunk::new(token, 0, b"his!".to_vec())).wait().unwrap();
+
+    assert_eq!(storage_manager.get_file_contents("alice", "test_file").unwrap(), b"hers");
+    assert_eq!(storage_manager.get_file_contents("bob", "test_file").unwrap(), b"his!");
+    assert_eq!(alice.list_files().wait().unwrap().len(), 1);
+}
+
+#[test]
+fn test_idle_token_expires() {
+    let mut server = BaacupImpl::new_from_storage(InMemoryStorage::new());
+    server.add_client(TEST_CLIENT, TEST_API_KEY);
+    server.set_token_idle_timeout(Duration::from_millis(200));
+    let session = server.authenticate(&Credentials { api_key: Some(TEST_API_KEY.into()), ..Default::default() }).unwrap();
+
+    let metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: 4,
+        content_hash: None,
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    let token = session.init_upload(metadata).wait().unwrap();
+
+    // Using the token keeps it alive
+    for _ in 0..3 {
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(session.get_head(token).wait().unwrap(), 0);
+    }
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(session.get_head(token).wait().unwrap_err(), BaacupError::InvalidToken);
+    let result = session.upload_chunk(FileChunk::new(token, 0, b"data".to_vec())).wait();
+    assert_eq!(result.unwrap_err(), BaacupError::InvalidToken);
+}
+
+#[test]
+fn test_upload_resumes_after_restart() {
+    let storage_manager = InMemoryStorage::new();
+    let server = test_session(storage_manager.clone());
+
+    let metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: 8,
+        content_hash: Some(content_hash(&b"abcdefgh"[..]).unwrap()),
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    let token = server.init_upload(metadata).wait().unwrap();
+    server.upload_chunk(FileChunk::new(token, 0, b"abcd".to_vec())).wait().unwrap();
+    drop(server);
+
+    // A new server on the same storage knows the upload
+    let restarted = test_session(storage_manager.clone());
+    restarted.restore_uploads().unwrap();
+    assert_eq!(restarted.get_head(token).wait().unwrap(), 4);
+    restarted.upload_chunk(FileChunk::new(token, 4, b"efgh".to_vec())).wait().unwrap();
+
+    // Finished uploads aren't restored
+    assert_eq!(storage_manager.get_file_contents(TEST_CLIENT, "test_file").unwrap(), b"abcdefgh");
+    let restarted = test_session(storage_manager);
+    restarted.restore_uploads().unwrap();
+    assert_eq!(restarted.get_head(token).wait().unwrap_err(), BaacupError::InvalidToken);
+}
+
+#[test]
+fn test_upload_saved_at_checkpoints() {
+    let storage_manager = InMemoryStorage::new();
+    let server = test_session(storage_manager.clone());
+
+    let metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: 12,
+        content_hash: None,
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    let token = server.init_upload(metadata).wait().unwrap();
+    let saved = storage_manager.load_uploads().unwrap();
+
+    // Chunks soon after the upload started don't save it again
+    server.upload_chunk(FileChunk::new(token, 0, b"abcd".to_vec())).wait().unwrap();
+    server.upload_chunk(FileChunk::new(token, 4, b"efgh".to_vec())).wait().unwrap();
+    let uploads = storage_manager.load_uploads().unwrap();
+    assert_eq!(uploads.len(), 1);
+    assert_eq!(uploads[0].0, token);
+    assert_eq!(uploads[0].1.last_used, saved[0].1.last_used);
+}
+
+fn stream_chunks(chunks: Vec<DataChunk>) -> BaacupStream<DataChunk> {
+    BaacupStream::new(stream::iter_ok(chunks))
+}
+
+#[test]
+fn test_upload_stream() {
+    let storage_manager = InMemoryStorage::new();
+    let server = test_session(storage_manager.clone());
+
+    let data: Vec<u8> = (0..3000).map(|n| (n % 256) as u8).collect();
+    let metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: 3000,
+        content_hash: Some(content_hash(&data[..]).unwrap()),
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    let token = server.init_upload(metadata).wait().unwrap();
+
+    // Start out the old way, then stream the rest
+    server.upload_chunk(FileChunk::new(token, 0, data[..1000].to_vec())).wait().unwrap();
+    let chunks = data[1000..].chunks(700).map(|chunk| DataChunk::new(chunk.to_vec())).collect();
+    let result = server.upload_stream(StreamHeader::new(token, 1000), stream_chunks(chunks)).wait().unwrap();
+
+    assert_eq!(result.offset, 3000);
+    assert_eq!(result.content_hash, Some(content_hash(&data[..]).unwrap()));
+    assert_eq!(storage_manager.get_file_contents(TEST_CLIENT, "test_file").unwrap(), data);
+}
+
+#[test]
+fn test_compressed_upload() {
+    let storage_manager = InMemoryStorage::new();
+    let server = test_session(storage_manager.clone());
+
+    let data: Vec<u8> = (0..30_000).map(|n| (n % 10) as u8).collect();
+    let metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: 30_000,
+        content_hash: Some(content_hash(&data[..]).unwrap()),
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    let upload = server.init_compressed_upload(metadata, Compression::Zstd).wait().unwrap();
+    assert_eq!(upload.compression, Compression::Zstd);
+
+    // Offsets count uncompressed bytes, also after compressed chunks
+    let (compressed, compression) = compress(data[..10_000].to_vec(), Compression::Zstd);
+    assert_eq!(compression, Compression::Zstd);
+    let chunk = FileChunk::compressed(upload.token, 0, compressed, compression);
+    let chunk_checksum = chunk.checksum;
+    assert_eq!(server.upload_chunk(chunk.clone()).wait(), Ok(chunk_checksum));
+    assert_eq!(server.upload_chunk(chunk).wait(), Ok(chunk_checksum));
+    assert_eq!(server.get_head(upload.token).wait(), Ok(10_000));
+
+    let chunks = vec![
+        DataChunk::new(data[10_000..20_000].to_vec()),
+        DataChunk::compressed(compress(data[20_000..].to_vec(), Compression::Zstd).0, Compression::Zstd),
+    ];
+    let result = server.upload_stream(StreamHeader::new(upload.token, 10_000), stream_chunks(chunks)).wait().unwrap();
+
+    assert_eq!(result.offset, 30_000);
+    assert_eq!(result.content_hash, Some(content_hash(&data[..]).unwrap()));
+    assert_eq!(storage_manager.get_file_contents(TEST_CLIENT, "test_file").unwrap(), data);
+}
+
+#[test]
+fn test_compressed_chunk_past_end_rejected() {
+    let storage_manager = InMemoryStorage::new();
+    let server = test_session(storage_manager.clone());
+
+    let metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: 1000,
+        content_hash: None,
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    let token = server.init_compressed_upload(metadata, Compression::Zstd).wait().unwrap().token;
+
+    let (compressed, compression) = compress(vec![0; 1_000_000], Compression::Zstd);
+    let result = server.upload_chunk(FileChunk::compressed(token, 0, compressed, compression)).wait();
+    match result {
+        Err(BaacupError::InvalidArgument(_)) => {}
+        result => panic!("expected InvalidArgument, got {:?}", result),
+    }
+
+    let result = server.upload_chunk(FileChunk::compressed(token, 0, b"not zstd".to_vec(), Compression::Zstd)).wait();
+    match result {
+        Err(BaacupError::InvalidArgument(_)) => {}
+        result => panic!("expected InvalidArgument, got {:?}", result),
+    }
+    assert_eq!(server.get_head(token).wait(), Ok(0));
+}
+
+#[test]
+fn test_chunks_shared_between_clients() {
+    let storage_manager = InMemoryStorage::new();
+    let mut server = BaacupImpl::new_from_storage(storage_manager.clone());
+    server.add_client("alice", "alice_key");
+    server.add_client("bob", "bob_key");
+    let alice = server.authenticate(&Credentials { api_key: Some("alice_key".into()), ..Default::default() }).unwrap();
+    let bob = server.authenticate(&Credentials { api_key: Some("bob_key".into()), ..Default::default() }).unwrap();
+
+    let shared = vec![0x55; 1024];
+    let shared_hash = content_hash(&shared[..]).unwrap();
+    let metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: 2048,
+        content_hash: None,
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    assert_eq!(alice.has_chunks(vec![shared_hash.clone()]).wait(), Ok(vec![false]));
+
+    // Alice sends the chunk along with its hash, and once more as a reference
+    let token = alice.init_upload(metadata.clone()).wait().unwrap();
+    alice.upload_chunk(FileChunk::new(token, 0, shared.clone()).with_chunk_hash(shared_hash.clone())).wait().unwrap();
+    alice.upload_chunk(FileChunk::new(token, 1024, Vec::new()).with_chunk_hash(shared_hash.clone())).wait().unwrap();
+    assert_eq!(storage_manager.chunk_count(), 1);
+
+    // Bob can't refer to it or even learn it's there without its data
+    assert_eq!(bob.has_chunks(vec![shared_hash.clone(), vec![0; 32]]).wait(), Ok(vec![false, false]));
+    let token = bob.init_upload(metadata.clone()).wait().unwrap();
+    let chunk = FileChunk::new(token, 0, Vec::new()).with_chunk_hash(shared_hash.clone());
+    assert_eq!(bob.upload_chunk(chunk).wait(), Err(BaacupError::NotFound));
+
+    // Sending it shares the stored chunk, and then he may refer to it
+    let chunks = vec![
+        DataChunk::new(shared.clone()).with_chunk_hash(shared_hash.clone()),
+        DataChunk::new(vec![0x66; 1024]),
+    ];
+    let result = bob.upload_stream(StreamHeader::new(token, 0), stream_chunks(chunks)).wait().unwrap();
+    assert_eq!(result.offset, 2048);
+    assert_eq!(bob.has_chunks(vec![shared_hash.clone()]).wait(), Ok(vec![true]));
+    let token = bob.init_upload(FileMetadata { file_name: "other_file".into(), file_size: 1024, ..metadata }).wait().unwrap();
+    bob.upload_chunk(FileChunk::new(token, 0, Vec::new()).with_chunk_hash(shared_hash.clone())).wait().unwrap();
+    assert_eq!(storage_manager.get_file_contents("bob", "other_file").unwrap(), shared);
+
+    assert_eq!(storage_manager.get_file_contents("alice", "test_file").unwrap(), vec![0x55; 2048]);
+    let mut expected = shared.clone();
+    expected.extend_from_slice(&[0x66; 1024]);
+    assert_eq!(storage_manager.get_file_contents("bob", "test_file").unwrap(), expected);
+    assert_eq!(storage_manager.chunk_count(), 1);
+}
+
+#[test]
+fn test_convergent_chunks_shared_between_clients() {
+    let storage_manager = InMemoryStorage::new();
+    let mut server = BaacupImpl::new_from_storage(storage_manager.clone());
+    server.add_client("alice", "alice_key");
+    server.add_client("bob", "bob_key");
+    let alice = server.authenticate(&Credentials { api_key: Some("alice_key".into()), ..Default::default() }).unwrap();
+    let bob = server.authenticate(&Credentials { api_key: Some("bob_key".into()), ..Default::default() }).unwrap();
+
+    // Both have their own key, but the same repository secret
+    let repository_secret = Key::generate();
+    let data: Vec<u8> = (0..10_000u32).map(|n| (n % 251) as u8).collect();
+    let upload = |session: &BaacupImpl<InMemoryStorage>, key: &Key| {
+        let mut chunks = Vec::new();
+        let mut entries = Vec::new();
+        for chunk in data.chunks(4096) {
+            let (sealed, entry) = convergent::seal_chunk(&repository_secret, chunk).unwrap();
+            let sealed_hash = content_hash(&sealed[..]).unwrap();
+            chunks.push(DataChunk::new(sealed).with_chunk_hash(sealed_hash));
+            entries.push(entry);
+        }
+        let manifest = convergent::seal_manifest(key, b"test_file", &entries).unwrap();
+        let file_size = chunks.iter().map(|chunk| chunk.data.len() as u64).sum::<u64>() + manifest.len() as u64;
+        chunks.push(DataChunk::new(manifest));
+
+        let metadata = FileMetadata {
+            file_name: "test_file".into(),
+            last_modified: Timestamp::default(),
+            file_size: file_size,
+            content_hash: None,
+            posix_attributes: None,
+            entry_type: EntryType::Regular,
+        };
+        let token = session.init_upload(metadata).wait().unwrap();
+        session.upload_stream(StreamHeader::new(token, 0), stream_chunks(chunks)).wait().unwrap();
+    };
+    let alice_key = Key::generate();
+    let bob_key = Key::generate();
+    upload(&alice, &alice_key);
+    assert_eq!(storage_manager.chunk_count(), 3);
+    upload(&bob, &bob_key);
+    assert_eq!(storage_manager.chunk_count(), 3);
+
+    // Each can only read their own copy back
+    let alice_file = storage_manager.get_file_contents("alice", "test_file").unwrap();
+    let bob_file = storage_manager.get_file_contents("bob", "test_file").unwrap();
+    let mut decrypted = Vec::new();
+    convergent::decrypt_file(Cursor::new(&bob_file), &mut decrypted, &bob_key, &repository_secret, b"test_file").unwrap();
+    assert_eq!(decrypted, data);
+    assert!(convergent::decrypt_file(Cursor::new(&alice_file), Vec::new(), &bob_key, &repository_secret, b"test_file").is_err());
+}
+
+#[test]
+fn test_bad_chunk_hash_rejected() {
+    let storage_manager = InMemoryStorage::new();
+    let server = test_session(storage_manager.clone());
+
+    let metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: 1024,
+        content_hash: None,
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    let token = server.init_upload(metadata).wait().unwrap();
+
+    // Data that doesn't match its hash isn't stored under it
+    let chunk = FileChunk::new(token, 0, vec![0x55; 1024]).with_chunk_hash(vec![0; 32]);
+    match server.upload_chunk(chunk).wait() {
+        Err(BaacupError::InvalidArgument(_)) => {}
+        result => panic!("expected InvalidArgument, got {:?}", result),
+    }
+    assert_eq!(storage_manager.chunk_count(), 0);
+
+    // Nor can a client refer to a chunk nobody uploaded
+    let chunk = FileChunk::new(token, 0, Vec::new()).with_chunk_hash(vec![0; 32]);
+    assert_eq!(server.upload_chunk(chunk).wait(), Err(BaacupError::NotFound));
+    assert_eq!(server.get_head(token).wait(), Ok(0));
+}
+
+#[test]
+fn test_files_are_uploaded() {
+    let server = test_session(InMemoryStorage::new());
+
+    let data = vec![0x55; 1024];
+    let metadata = |file_name: &str| FileMetadata {
+        file_name: file_name.into(),
+        last_modified: Timestamp::default(),
+        file_size: 1024,
+        content_hash: Some(content_hash(&data[..]).unwrap()),
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    let token = server.init_upload(metadata("finished")).wait().unwrap();
+    server.upload_chunk(FileChunk::new(token, 0, data.clone())).wait().unwrap();
+    server.init_upload(metadata("unfinished")).wait().unwrap();
+
+    // The batch answers the same as asking about each file
+    let files = vec![metadata("unfinished"), metadata("missing"), metadata("finished")];
+    let statuses = server.files_are_uploaded(files.clone()).wait().unwrap();
+    let one_by_one: Vec<UploadStatus> = files.into_iter()
+        .map(|metadata| server.file_is_uploaded(metadata).wait().unwrap())
+        .collect();
+    assert_eq!(statuses, one_by_one);
+    assert_eq!(statuses[2].content_hash, Some(content_hash(&data[..]).unwrap()));
+    assert_eq!(statuses[0].content_hash, None);
+
+    match server.files_are_uploaded(vec![metadata("finished"); MAX_FILES_ARE_UPLOADED + 1]).wait() {
+        Err(BaacupError::InvalidArgument(_)) => {}
+        result => panic!("expected InvalidArgument, got {:?}", result),
+    }
+}
+
+#[test]
+fn test_has_chunks_limited() {
+    let server = test_session(InMemoryStorage::new());
+
+    let hashes = vec![vec![0; 32]; MAX_HAS_CHUNKS + 1];
+    match server.has_chunks(hashes).wait() {
+        Err(BaacupError::InvalidArgument(_)) => {}
+        result => panic!("expected InvalidArgument, got {:?}", result),
+    }
+}
+
+#[test]
+fn test_delta_upload() {
+    let storage_manager = InMemoryStorage::new();
+    let server = test_session(storage_manager.clone());
+
+    let old: Vec<u8> = (0..20_000u32).map(|n| (n * n % 251) as u8).collect();
+    let mut metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: old.len() as u64,
+        content_hash: None,
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    let token = server.init_upload(metadata.clone()).wait().unwrap();
+    server.upload_chunk(FileChunk::new(token, 0, old.clone())).wait().unwrap();
+
+    // Change the file in place, then send only what changed
+    let mut new = old.clone();
+    new[10_000..10_100].copy_from_slice(&[0x55; 100]);
+    new.extend_from_slice(b"appended");
+    let signature = server.get_signature("test_file".into()).wait().unwrap();
+    let ops = delta::diff(&new[..], &signature).unwrap();
+    assert!(ops.len() > 1);
+
+    metadata.file_size = new.len() as u64;
+    metadata.content_hash = Some(content_hash(&new[..]).unwrap());
+    let token = server.init_upload(metadata).wait().unwrap();
+    let mut offset = 0;
+    let mut chunks = Vec::new();
+    for op in ops {
+        match op {
+            DeltaOp::Copy { offset: copy_offset, len } => {
+                chunks.push(DataChunk::copy(CopyRange { offset: copy_offset, len: len }));
+                offset += len;
+            }
+            DeltaOp::Literal { len } => {
+                chunks.push(DataChunk::new(new[offset as usize..(offset + len) as usize].to_vec()));
+                offset += len;
+            }
+        }
+    }
+    let result = server.upload_stream(StreamHeader::new(token, 0), stream_chunks(chunks)).wait().unwrap();
+
+    assert_eq!(result.content_hash, Some(content_hash(&new[..]).unwrap()));
+    assert_eq!(storage_manager.get_file_contents(TEST_CLIENT, "test_file").unwrap(), new);
+    assert!(!storage_manager.has_previous(TEST_CLIENT, "test_file"));
+}
+
+#[test]
+fn test_bad_copy_rejected() {
+    let server = test_session(InMemoryStorage::new());
+
+    let metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: 2048,
+        content_hash: None,
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    let token = server.init_upload(metadata.clone()).wait().unwrap();
+
+    // There's nothing to copy from yet
+    let copy = FileChunk::copy(token, 0, CopyRange { offset: 0, len: 1024 });
+    assert_eq!(server.upload_chunk(copy).wait(), Err(BaacupError::NotFound));
+    server.upload_chunk(FileChunk::new(token, 0, vec![0x55; 2048])).wait().unwrap();
+
+    let token = server.init_upload(metadata).wait().unwrap();
+    let mut with_data = FileChunk::new(token, 0, vec![0x55; 1024]);
+    with_data.copy = Some(CopyRange { offset: 0, len: 1024 });
+    let bad_copies = vec![
+        FileChunk::copy(token, 0, CopyRange { offset: 1024, len: 2048 }),
+        with_data,
+    ];
+    for bad_copy in bad_copies {
+        match server.upload_chunk(bad_copy).wait() {
+            Err(BaacupError::InvalidArgument(_)) => {}
+            result => panic!("expected InvalidArgument, got {:?}", result),
+        }
+    }
+    assert_eq!(server.get_head(token).wait(), Ok(0));
+}
+
+#[test]
+fn test_upload_stream_bad_offset() {
+    let server = test_session(InMemoryStorage::new());
+
+    let metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: 2048,
+        content_hash: None,
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    let token = server.init_upload(metadata).wait().unwrap();
+
+    let chunks = vec![DataChunk::new(vec![0x55; 1024])];
+    let result = server.upload_stream(StreamHeader::new(token, 1024), stream_chunks(chunks)).wait();
+    assert_eq!(result.unwrap_err(), BaacupError::BadOffset { expected: 0 });
+}
+
+#[test]
+fn test_upload_stream_corrupted_chunk() {
+    let storage_manager = InMemoryStorage::new();
+    let server = test_session(storage_manager.clone());
+
+    let metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: 3072,
+        content_hash: None,
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    let token = server.init_upload(metadata).wait().unwrap();
+
+    let mut corrupted = DataChunk::new(vec![0x66; 1024]);
+    corrupted.data[100] ^= 1;
+    let chunks = vec![DataChunk::new(vec![0x55; 1024]), corrupted, DataChunk::new(vec![0x77; 1024])];
+    let result = server.upload_stream(StreamHeader::new(token, 0), stream_chunks(chunks)).wait();
+    assert_eq!(result.unwrap_err(), BaacupError::ChecksumMismatch);
+
+    // Everything before the bad chunk was kept
+    assert_eq!(server.get_head(token).wait().unwrap(), 1024);
+    let chunks = vec![DataChunk::new(vec![0x66; 1024]), DataChunk::new(vec![0x77; 1024])];
+    let result = server.upload_stream(StreamHeader::new(token, 1024), stream_chunks(chunks)).wait().unwrap();
+    assert_eq!(result.offset, 3072);
+    assert!(result.content_hash.is_some());
+    assert_eq!(storage_manager.get_file_contents(TEST_CLIENT, "test_file").unwrap().len(), 3072);
+}
+
+#[test]
+fn test_entries_without_contents() {
+    let server = test_session(InMemoryStorage::new());
+
+    let entry_types = vec![
+        EntryType::Directory,
+        EntryType::Symlink { target: b"test_dir".to_vec() },
+        EntryType::Fifo,
+        EntryType::CharDevice { major: 1, minor: 3 },
+    ];
+    for (n, entry_type) in entry_types.iter().enumerate() {
+        let metadata = FileMetadata {
+            file_name: format!("test_entry_{}", n),
+            last_modified: Timestamp::default(),
+            file_size: 0,
+            content_hash: None,
+            posix_attributes: None,
+            entry_type: entry_type.clone(),
+        };
+        server.init_upload(metadata.clone()).wait().unwrap();
+
+        // Done without a single chunk
+        let status = server.file_is_uploaded(metadata).wait().unwrap();
+        assert_eq!(status.content_hash, Some(content_hash(&b""[..]).unwrap()));
+    }
+
+    let mut files = server.list_files().wait().unwrap();
+    files.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+    let listed: Vec<EntryType> = files.into_iter().map(|file| file.entry_type).collect();
+    assert_eq!(listed, entry_types);
+}
+
+#[test]
+fn test_entry_without_contents_rejects_size() {
+    let server = test_session(InMemoryStorage::new());
+
+    let metadata = FileMetadata {
+        file_name: "test_link".into(),
+        last_modified: Timestamp::default(),
+        file_size: 6,
+        content_hash: None,
+        posix_attributes: None,
+        entry_type: EntryType::Symlink { target: b"target".to_vec() },
+    };
+    match server.init_upload(metadata).wait() {
+        Err(BaacupError::InvalidArgument(_)) => {}
+        result => panic!("expected InvalidArgument, got {:?}", result),
+    }
+}
+
+#[test]
+fn test_snapshot() {
+    let server = test_session(InMemoryStorage::new());
+
+    let data = vec![0x66; 100];
+    let metadata = |file_name: &str, entry_type: EntryType| FileMetadata {
+        file_name: file_name.into(),
+        last_modified: Timestamp::default(),
+        file_size: if entry_type.has_contents() { 100 } else { 0 },
+        content_hash: None,
+        posix_attributes: None,
+        entry_type: entry_type,
+    };
+    let token = server.init_upload(metadata("test_file", EntryType::Regular)).wait().unwrap();
+    server.upload_chunk(FileChunk::new(token, 0, data.clone())).wait().unwrap();
+    server.init_upload(metadata("test_dir", EntryType::Directory)).wait().unwrap();
+
+    let snapshot_id = server.begin_snapshot("test_host".into()).wait().unwrap();
+    server.add_to_snapshot(snapshot_id, vec!["test_file".into(), "test_dir".into()]).wait().unwrap();
+    // Adding a file again replaces what was recorded
+    server.add_to_snapshot(snapshot_id, vec!["test_file".into()]).wait().unwrap();
+
+    // Invisible until committed
+    assert_eq!(server.list_snapshots().wait().unwrap(), Vec::new());
+    match server.list_snapshot_files(snapshot_id).wait() {
+        Err(BaacupError::NotFound) => {}
+        result => panic!("expected NotFound, got {:?}", result),
+    }
+    match server.read_snapshot_chunk(snapshot_id, "test_file".into(), 0, 100).wait() {
+        Err(BaacupError::NotFound) => {}
+        result => panic!("expected NotFound, got {:?}", result),
+    }
+
+    let snapshot = server.commit_snapshot(snapshot_id).wait().unwrap();
+    assert_eq!(snapshot.id, snapshot_id);
+    assert_eq!(snapshot.host, "test_host");
+    assert_eq!(snapshot.file_count, 2);
+    assert!(snapshot.finished.unwrap() >= snapshot.started);
+    // Committing again is harmless
+    assert_eq!(server.commit_snapshot(snapshot_id).wait().unwrap(), snapshot);
+    assert_eq!(server.list_snapshots().wait().unwrap(), vec![snapshot]);
+
+    let files = server.list_snapshot_files(snapshot_id).wait().unwrap();
+    let names: Vec<&str> = files.iter().map(|file| file.file_name.as_str()).collect();
+    assert_eq!(names, vec!["test_dir", "test_file"]);
+    assert_eq!(files[1].file_size, 100);
+    assert_eq!(files[1].content_hash, Some(content_hash(&data[..]).unwrap()));
+
+    // It reads as it was, whatever was uploaded since
+    let token = server.init_upload(metadata("test_file", EntryType::Regular)).wait().unwrap();
+    server.upload_chunk(FileChunk::new(token, 0, vec![0x77; 100])).wait().unwrap();
+    assert_eq!(server.read_snapshot_chunk(snapshot_id, "test_file".into(), 0, 1000).wait().unwrap(), data);
+    assert_eq!(server.read_chunk("test_file".into(), 0, 1000).wait().unwrap(), vec![0x77; 100]);
+
+    // A committed snapshot can't change
+    match server.add_to_snapshot(snapshot_id, vec!["test_file".into()]).wait() {
+        Err(BaacupError::InvalidArgument(_)) => {}
+        result => panic!("expected InvalidArgument, got {:?}", result),
+    }
+}
+
+#[test]
+fn test_snapshot_rejects_unfinished_files() {
+    let server = test_session(InMemoryStorage::new());
+
+    let metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: 100,
+        content_hash: None,
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    server.init_upload(metadata).wait().unwrap();
+
+    let snapshot_id = server.begin_snapshot("test_host".into()).wait().unwrap();
+    for &file_name in &["test_file", "missing_file"] {
+        match server.add_to_snapshot(snapshot_id, vec![file_name.into()]).wait() {
+            Err(BaacupError::NotFound) => {}
+            result => panic!("expected NotFound, got {:?}", result),
+        }
+    }
+    match server.add_to_snapshot(snapshot_id, vec!["test_file".into(); MAX_ADD_TO_SNAPSHOT + 1]).wait() {
+        Err(BaacupError::InvalidArgument(_)) => {}
+        result => panic!("expected InvalidArgument, got {:?}", result),
+    }
+    match server.add_to_snapshot(SnapshotId::new([0; SnapshotId::LEN]), Vec::new()).wait() {
+        Err(BaacupError::NotFound) => {}
+        result => panic!("expected NotFound, got {:?}", result),
+    }
+}
+
+#[test]
+fn test_hello() {
+    let server = test_session(InMemoryStorage::new());
+
+    let server_hello = server.hello(Hello::new("test", Capability::ALL)).wait().unwrap();
+    assert!(server_hello.check_compatible().is_ok());
+    assert!(server_hello.supports(Capability::ContentHash));
+    assert!(server_hello.supports(Capability::Restore));
+    assert!(server_hello.supports(Capability::PosixAttributes));
+    assert!(server_hello.supports(Capability::Zstd));
+    assert!(server_hello.supports(Capability::Dedup));
+    assert!(server_hello.supports(Capability::Delta));
+    assert!(server_hello.supports(Capability::BatchStatus));
+    assert!(server_hello.supports(Capability::Snapshots));
+}
+
+#[test]
+fn test_hello_rejects_old_protocol() {
+    let server = test_session(InMemoryStorage::new());
+
+    let mut hello = Hello::new("test", &[]);
+    hello.protocol_version = 0;
+    match server.hello(hello).wait() {
+        Err(BaacupError::IncompatibleVersion(_)) => {}
+        result => panic!("Expected IncompatibleVersion, got {:?}", result),
+    }
+}
+
+#[test]
+fn test_file_system_storage() {
+    let path = env::temp_dir().join(format!("backupd-file-system-{}", process::id()));
+    let _ = fs::remove_dir_all(&path);
+    let mut server = BaacupImpl::new_from_path(&path);
+    server.add_client(TEST_CLIENT, TEST_API_KEY);
+    let server = server.authenticate(&Credentials { api_key: Some(TEST_API_KEY.into()), ..Default::default() }).unwrap();
+
+    let data = vec![0x66; 100];
+    let metadata = FileMetadata {
+        file_name: "test_file".into(),
+        last_modified: Timestamp::default(),
+        file_size: 100,
+        content_hash: None,
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    };
+    let token = server.init_upload(metadata).wait().unwrap();
+    server.upload_chunk(FileChunk::new(token, 0, data.clone())).wait().unwrap();
+
+    // Files are kept in a directory of their owner's
+    assert_eq!(server.read_chunk("test_file".into(), 10, 1000).wait().unwrap(), &data[10..]);
+    let files = server.list_files().wait().unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].file_name, "test_file");
+    assert_eq!(files[0].file_size, 100);
+    assert!(path.join(TEST_CLIENT).join("test_file").is_file());
+    fs::remove_dir_all(&path).unwrap();
+}
@@ -0,0 +1,497 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+use diesel::{Connection, SqliteConnection};
+use futures::{stream, Future};
+
+use backupd::server::BaacupImpl;
+use backupd::storage::StorageManager;
+use backupd::storage::sqlite_db::SqliteStorageManager;
+use backuplib::compression::compress;
+use backuplib::crypto::Key;
+use backuplib::delta::{self, DeltaOp};
+use backuplib::hash::content_hash;
+use backuplib::rpc::{Authenticate, Baacup, BaacupError, BaacupStream, Compression, CopyRange, Credentials, DataChunk, EntryType, ExtendedAttribute, FileChunk, FileMetadata, PosixAttributes, StreamHeader, Timestamp};
+
+#[allow(dead_code)]
+mod common;
+
+use common::test_session;
+
+/// A directory of a test's own for a database and its data files, removed
+/// when the test is done.
+struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    fn new(name: &str) -> TestDir {
+        let path = env::temp_dir().join(format!("backupd-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TestDir {
+            path: path,
+        }
+    }
+
+    fn database(&self) -> String {
+        self.path.join("database.sqlite").to_string_lossy().into_owned()
+    }
+
+    /// Opens the storage in the directory, as a server starting up does.
+    fn storage(&self) -> SqliteStorageManager {
+        SqliteStorageManager::with_data_dir(&self.database(), &self.path).unwrap()
+    }
+}
+
+impl TestDir {
+    /// Number of shared chunks stored.
+    fn chunk_files(&self) -> usize {
+        match fs::read_dir(self.path.join("chunks")) {
+            Ok(entries) => entries.count(),
+            Err(_) => 0,
+        }
+    }
+
+    /// Bytes in the data files of the files stored, which doesn't count
+    /// the database or shared chunks.
+    fn data_file_bytes(&self) -> u64 {
+        fs::read_dir(&self.path).unwrap()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| !entry.file_name().to_string_lossy().starts_with("database.sqlite"))
+            .map(|entry| entry.metadata().unwrap())
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+fn regular_file(file_name: &str, data: &[u8]) -> FileMetadata {
+    FileMetadata {
+        file_name: file_name.into(),
+        last_modified: Timestamp::default(),
+        file_size: data.len() as u64,
+        content_hash: Some(content_hash(data).unwrap()),
+        posix_attributes: None,
+        entry_type: EntryType::Regular,
+    }
+}
+
+fn stream_chunks(chunks: Vec<DataChunk>) -> BaacupStream<DataChunk> {
+    BaacupStream::new(stream::iter_ok(chunks))
+}
+
+#[test]
+fn test_upload_resumes_after_restart() {
+    let dir = TestDir::new("restart");
+    let server = test_session(dir.storage());
+
+    let token = server.init_upload(regular_file("test_file", b"abcdefgh")).wait().unwrap();
+    server.upload_chunk(FileChunk::new(token, 0, b"abcd".to_vec())).wait().unwrap();
+    drop(server);
+
+    // A new server on the same database knows the upload
+    let restarted = test_session(dir.storage());
+    restarted.restore_uploads().unwrap();
+    assert_eq!(restarted.get_head(token).wait().unwrap(), 4);
+    restarted.upload_chunk(FileChunk::new(token, 4, b"efgh".to_vec())).wait().unwrap();
+    assert_eq!(restarted.read_chunk("test_file".into(), 0, 8).wait().unwrap(), b"abcdefgh");
+    drop(restarted);
+
+    // Finished uploads aren't restored
+    let restarted = test_session(dir.storage());
+    restarted.restore_uploads().unwrap();
+    assert_eq!(restarted.get_head(token).wait().unwrap_err(), BaacupError::InvalidToken);
+}
+
+#[test]
+fn test_mismatched_upload_starts_over() {
+    let dir = TestDir::new("mismatch");
+    let server = test_session(dir.storage());
+
+    let token = server.init_upload(regular_file("test_file", b"abcdefgh")).wait().unwrap();
+    server.upload_chunk(FileChunk::new(token, 0, b"abcd".to_vec())).wait().unwrap();
+    let result = server.upload_chunk(FileChunk::new(token, 4, b"wxyz".to_vec())).wait();
+    assert_eq!(result.unwrap_err(), BaacupError::ContentHashMismatch);
+
+    assert_eq!(server.get_head(token).wait().unwrap(), 0);
+    server.upload_chunk(FileChunk::new(token, 0, b"abcdefgh".to_vec())).wait().unwrap();
+    assert_eq!(server.read_chunk("test_file".into(), 0, 8).wait().unwrap(), b"abcdefgh");
+}
+
+#[test]
+fn test_migrations_keep_old_files() {
+    let dir = TestDir::new("migrations");
+
+    // The database as it was before migrations, with a file stored as it is
+    let file_id = "0123456789abcdef0123456789abcdef";
+    {
+        let connection = SqliteConnection::establish(&dir.database()).unwrap();
+        connection.execute("CREATE TABLE files (
+            id TEXT NOT NULL PRIMARY KEY,
+            filename TEXT NOT NULL,
+            last_modified BIGINT NOT NULL
+        )").unwrap();
+        connection.execute(&format!("INSERT INTO files VALUES ('{}', 'old_file', 1500000000)", file_id)).unwrap();
+    }
+    fs::write(dir.path.join(file_id), b"stored as it is").unwrap();
+
+    // Every migration runs, and files from before clients had names have none
+    let storage = dir.storage();
+    let files = storage.list("").unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].file_name, "old_file");
+    assert_eq!(files[0].last_modified, Timestamp::new(1500000000, 0));
+    assert_eq!(files[0].file_size, 15);
+    assert_eq!(files[0].content_hash, None);
+    assert_eq!(files[0].entry_type, EntryType::Regular);
+    assert!(files[0].posix_attributes.is_none());
+    assert_eq!(storage.read("", "old_file", 0, 100).unwrap(), b"stored as it is");
+
+    // Running them again on the migrated database changes nothing
+    drop(storage);
+    let storage = dir.storage();
+    assert_eq!(storage.read("", "old_file", 7, 2).unwrap(), b"as");
+    assert!(storage.snapshots("").unwrap().is_empty());
+}
+
+#[test]
+fn test_nanosecond_timestamps_stored() {
+    let dir = TestDir::new("timestamps");
+    let server = test_session(dir.storage());
+
+    let mut metadata = regular_file("test_file", b"abcd");
+    metadata.last_modified = Timestamp::new(1500000000, 123456789);
+    let token = server.init_upload(metadata.clone()).wait().unwrap();
+    server.upload_chunk(FileChunk::new(token, 0, b"abcd".to_vec())).wait().unwrap();
+
+    let files = server.list_files().wait().unwrap();
+    assert_eq!(files[0].last_modified, Timestamp::new(1500000000, 123456789));
+    assert!(server.file_is_uploaded(metadata.clone()).wait().unwrap().is_uploaded);
+
+    // A change within the same second is still a change
+    metadata.last_modified = Timestamp::new(1500000000, 123456790);
+    assert!(!server.file_is_uploaded(metadata).wait().unwrap().is_uploaded);
+}
+
+#[test]
+fn test_metadata_kept_until_upload_finishes() {
+    let dir = TestDir::new("unfinished-metadata");
+    let server = test_session(dir.storage());
+
+    let mut metadata = regular_file("test_file", b"abcd");
+    metadata.last_modified = Timestamp::new(1500000000, 0);
+    let token = server.init_upload(metadata.clone()).wait().unwrap();
+    server.upload_chunk(FileChunk::new(token, 0, b"abcd".to_vec())).wait().unwrap();
+
+    let mut changed = regular_file("test_file", b"efgh");
+    changed.last_modified = Timestamp::new(1600000000, 0);
+    let token = server.init_upload(changed.clone()).wait().unwrap();
+    server.upload_chunk(FileChunk::new(token, 0, b"ef".to_vec())).wait().unwrap();
+    assert_eq!(server.list_files().wait().unwrap()[0].last_modified, metadata.last_modified);
+    assert!(!server.file_is_uploaded(changed.clone()).wait().unwrap().is_uploaded);
+
+    server.upload_chunk(FileChunk::new(token, 2, b"gh".to_vec())).wait().unwrap();
+    assert_eq!(server.list_files().wait().unwrap()[0].last_modified, changed.last_modified);
+    assert!(server.file_is_uploaded(changed).wait().unwrap().is_uploaded);
+}
+
+#[test]
+fn test_compressed_blobs_stored_compressed() {
+    let dir = TestDir::new("compressed");
+    let server = test_session(dir.storage());
+
+    let data: Vec<u8> = (0..30_000).map(|n| (n % 10) as u8).collect();
+    let upload = server.init_compressed_upload(regular_file("test_file", &data), Compression::Zstd).wait().unwrap();
+    let (compressed, compression) = compress(data[..10_000].to_vec(), Compression::Zstd);
+    server.upload_chunk(FileChunk::compressed(upload.token, 0, compressed, compression)).wait().unwrap();
+    let chunks = vec![
+        DataChunk::new(data[10_000..20_000].to_vec()),
+        DataChunk::compressed(compress(data[20_000..].to_vec(), Compression::Zstd).0, Compression::Zstd),
+    ];
+    let result = server.upload_stream(StreamHeader::new(upload.token, 10_000), stream_chunks(chunks)).wait().unwrap();
+    assert_eq!(result.content_hash, Some(content_hash(&data[..]).unwrap()));
+
+    // Only the uncompressed chunk takes its full size
+    assert!(dir.data_file_bytes() < 11_000);
+    drop(server);
+
+    // Reads across blobs decompress each of them, also after a restart
+    let server = test_session(dir.storage());
+    assert_eq!(server.read_chunk("test_file".into(), 9_000, 12_000).wait().unwrap(), &data[9_000..21_000]);
+    assert_eq!(server.read_chunk("test_file".into(), 0, 30_000).wait().unwrap(), data);
+}
+
+#[test]
+fn test_chunks_shared_between_clients() {
+    let dir = TestDir::new("chunks");
+    let mut server = BaacupImpl::new_from_storage(dir.storage());
+    server.add_client("alice", "alice_key");
+    server.add_client("bob", "bob_key");
+    let alice = server.authenticate(&Credentials { api_key: Some("alice_key".into()), ..Default::default() }).unwrap();
+    let bob = server.authenticate(&Credentials { api_key: Some("bob_key".into()), ..Default::default() }).unwrap();
+
+    let shared: Vec<u8> = (0..5000).map(|n| (n % 7) as u8).collect();
+    let shared_hash = content_hash(&shared[..]).unwrap();
+    let mut alice_data = shared.clone();
+    alice_data.extend_from_slice(&shared);
+
+    // Alice sends the chunk compressed along with its hash, then refers to it
+    let upload = alice.init_compressed_upload(regular_file("test_file", &alice_data), Compression::Zstd).wait().unwrap();
+    let (compressed, compression) = compress(shared.clone(), Compression::Zstd);
+    let chunk = FileChunk::compressed(upload.token, 0, compressed, compression).with_chunk_hash(shared_hash.clone());
+    alice.upload_chunk(chunk).wait().unwrap();
+    alice.upload_chunk(FileChunk::new(upload.token, 5000, Vec::new()).with_chunk_hash(shared_hash.clone())).wait().unwrap();
+    assert_eq!(dir.chunk_files(), 1);
+
+    // Bob has to send it with data of his own around it, but it's only
+    // stored once
+    let mut bob_data = vec![0x66; 100];
+    bob_data.extend_from_slice(&shared);
+    bob_data.extend_from_slice(&[0x77; 100]);
+    let token = bob.init_upload(regular_file("bob_file", &bob_data)).wait().unwrap();
+    assert_eq!(bob.has_chunks(vec![shared_hash.clone()]).wait(), Ok(vec![false]));
+    let chunk = FileChunk::new(token, 0, Vec::new()).with_chunk_hash(shared_hash.clone());
+    assert_eq!(bob.upload_chunk(chunk).wait(), Err(BaacupError::NotFound));
+    let chunks = vec![
+        DataChunk::new(vec![0x66; 100]),
+        DataChunk::new(shared.clone()).with_chunk_hash(shared_hash.clone()),
+        DataChunk::new(vec![0x77; 100]),
+    ];
+    let result = bob.upload_stream(StreamHeader::new(token, 0), stream_chunks(chunks)).wait().unwrap();
+    assert_eq!(result.content_hash, Some(content_hash(&bob_data[..]).unwrap()));
+    assert_eq!(dir.chunk_files(), 1);
+    assert!(dir.data_file_bytes() < 300);
+    drop((server, alice, bob));
+
+    // The chunk is still there after a restart, and shared files read from it
+    let storage = dir.storage();
+    assert_eq!(storage.chunk_len("bob", &shared_hash).unwrap(), Some(5000));
+    assert_eq!(storage.chunk_len("carol", &shared_hash).unwrap(), None);
+    assert!(storage.append_chunk("carol", "bob_file", &shared_hash).is_err());
+    assert_eq!(storage.read("alice", "test_file", 4000, 2000).unwrap(), &alice_data[4000..6000]);
+    assert_eq!(storage.read("bob", "bob_file", 0, 6000).unwrap(), bob_data);
+}
+
+#[test]
+fn test_delta_upload_from_previous_blobs() {
+    let dir = TestDir::new("delta");
+    let server = test_session(dir.storage());
+
+    // The version deltas copy from is kept compressed
+    let old: Vec<u8> = (0..40_000u32).map(|n| (n / 100 % 251) as u8).collect();
+    let upload = server.init_compressed_upload(regular_file("test_file", &old), Compression::Zstd).wait().unwrap();
+    for offset in (0..40_000).step_by(10_000) {
+        let (compressed, compression) = compress(old[offset..offset + 10_000].to_vec(), Compression::Zstd);
+        server.upload_chunk(FileChunk::compressed(upload.token, offset as u64, compressed, compression)).wait().unwrap();
+    }
+
+    let mut new = old.clone();
+    new[20_000..20_100].copy_from_slice(&[0x55; 100]);
+    new.extend_from_slice(b"appended");
+    let signature = server.get_signature("test_file".into()).wait().unwrap();
+    let mut offset = 0;
+    let mut chunks = Vec::new();
+    for op in delta::diff(&new[..], &signature).unwrap() {
+        match op {
+            DeltaOp::Copy { offset: copy_offset, len } => {
+                chunks.push(DataChunk::copy(CopyRange { offset: copy_offset, len: len }));
+                offset += len;
+            }
+            DeltaOp::Literal { len } => {
+                chunks.push(DataChunk::new(new[offset as usize..(offset + len) as usize].to_vec()));
+                offset += len;
+            }
+        }
+    }
+    assert!(chunks.iter().any(|chunk| chunk.copy.is_some()));
+
+    // The previous version outlives a restart halfway through the upload
+    let token = server.init_upload(regular_file("test_file", &new)).wait().unwrap();
+    let rest = chunks.split_off(1);
+    let result = server.upload_stream(StreamHeader::new(token, 0), stream_chunks(chunks)).wait().unwrap();
+    drop(server);
+    let server = test_session(dir.storage());
+    server.restore_uploads().unwrap();
+    let result = server.upload_stream(StreamHeader::new(token, result.offset), stream_chunks(rest)).wait().unwrap();
+
+    assert_eq!(result.content_hash, Some(content_hash(&new[..]).unwrap()));
+    assert_eq!(server.read_chunk("test_file".into(), 0, new.len() as u64).wait().unwrap(), new);
+    // Nothing is left of the version it replaced
+    let data_files = fs::read_dir(&dir.path).unwrap()
+        .filter(|entry| !entry.as_ref().unwrap().file_name().to_string_lossy().starts_with("database.sqlite"))
+        .count();
+    assert_eq!(data_files, 1);
+}
+
+/// Uploads `data` as `metadata` in chunks of 10,000 bytes.
+fn upload<S>(server: &S, metadata: FileMetadata, data: &[u8])
+    where S: Baacup,
+{
+    let token = server.init_upload(metadata).wait().unwrap();
+    for (index, chunk) in data.chunks(10_000).enumerate() {
+        server.upload_chunk(FileChunk::new(token, index as u64 * 10_000, chunk.to_vec())).wait().unwrap();
+    }
+}
+
+#[test]
+fn test_snapshot_keeps_replaced_versions() {
+    let dir = TestDir::new("snapshot-versions");
+    let server = test_session(dir.storage());
+    let attributes = |mode: u32, xattr: &[u8]| PosixAttributes {
+        mode: mode,
+        uid: 1000,
+        gid: 1000,
+        user_name: Some("user".into()),
+        group_name: None,
+        accessed: Timestamp::new(1500000000, 0),
+        changed: Timestamp::new(1500000000, 0),
+        xattrs: vec![ExtendedAttribute { name: b"user.test".to_vec(), value: xattr.to_vec() }],
+    };
+
+    let old: Vec<u8> = (0..25_000u32).map(|n| (n % 251) as u8).collect();
+    let mut old_metadata = regular_file("test_file", &old);
+    old_metadata.posix_attributes = Some(attributes(0o100644, b"old"));
+    upload(&server, old_metadata, &old);
+    let snapshot_id = server.begin_snapshot("test_host".into()).wait().unwrap();
+    server.add_to_snapshot(snapshot_id, vec!["test_file".into()]).wait().unwrap();
+    server.commit_snapshot(snapshot_id).wait().unwrap();
+
+    // Uploaded twice since, so the snapshot's version isn't even the
+    // previous one any more
+    for new in &[vec![0x55; 12_000], vec![0x66; 3_000]] {
+        let mut new_metadata = regular_file("test_file", new);
+        new_metadata.posix_attributes = Some(attributes(0o100600, b"new"));
+        upload(&server, new_metadata, new);
+    }
+    assert_eq!(server.read_chunk("test_file".into(), 0, 30_000).wait().unwrap(), vec![0x66; 3_000]);
+
+    let assert_snapshot_read = |server: &dyn Baacup| {
+        assert_eq!(server.read_snapshot_chunk(snapshot_id, "test_file".into(), 0, 30_000).wait().unwrap(), old);
+        assert_eq!(server.read_snapshot_chunk(snapshot_id, "test_file".into(), 9_000, 2_000).wait().unwrap(), &old[9_000..11_000]);
+        let files = server.list_snapshot_files(snapshot_id).wait().unwrap();
+        assert_eq!(files[0].file_size, 25_000);
+        assert_eq!(files[0].content_hash, Some(content_hash(&old[..]).unwrap()));
+        assert_eq!(files[0].posix_attributes, Some(attributes(0o100644, b"old")));
+    };
+    assert_snapshot_read(&server);
+    drop(server);
+    let server = test_session(dir.storage());
+    assert_snapshot_read(&server);
+
+    // The version in between was dropped
+    let data_files = fs::read_dir(&dir.path).unwrap()
+        .filter(|entry| !entry.as_ref().unwrap().file_name().to_string_lossy().starts_with("database.sqlite"))
+        .count();
+    assert_eq!(data_files, 2);
+}
+
+impl TestDir {
+    /// Whether any data file or shared chunk holds `plaintext` as it is.
+    fn stores_plaintext(&self, plaintext: &[u8]) -> bool {
+        fs::read_dir(&self.path).unwrap()
+            .chain(fs::read_dir(self.path.join("chunks")).unwrap())
+            .map(|entry| entry.unwrap())
+            .filter(|entry| !entry.file_name().to_string_lossy().starts_with("database.sqlite"))
+            .filter(|entry| entry.metadata().unwrap().is_file())
+            .any(|entry| fs::read(entry.path()).unwrap().windows(plaintext.len()).any(|window| window == plaintext))
+    }
+}
+
+/// Stores `shared_file` made of a shared chunk, and `test_file` with a
+/// previous version. Everything in them ends in "secret".
+fn store_secrets(storage: &SqliteStorageManager) {
+    storage.create("owner", &regular_file("test_file", b"old secret")).unwrap();
+    storage.append("owner", "test_file", b"old secret").unwrap();
+    storage.create("owner", &regular_file("test_file", b"new secret")).unwrap();
+    storage.append("owner", "test_file", b"new secret").unwrap();
+
+    let hash = content_hash(&b"chunk secret"[..]).unwrap();
+    storage.put_chunk("owner", &hash, b"chunk secret", None).unwrap();
+    storage.create("owner", &regular_file("shared_file", b"chunk secret")).unwrap();
+    storage.append_chunk("owner", "shared_file", &hash).unwrap();
+}
+
+fn assert_secrets_read(storage: &SqliteStorageManager) {
+    assert_eq!(storage.read("owner", "test_file", 0, 100).unwrap(), b"new secret");
+    assert_eq!(storage.read_previous("owner", "test_file", 0, 100).unwrap(), b"old secret");
+    assert_eq!(storage.read("owner", "shared_file", 0, 100).unwrap(), b"chunk secret");
+}
+
+#[test]
+fn test_encrypted_at_rest_and_rotated() {
+    let dir = TestDir::new("encryption");
+    let old_key = Key::generate();
+    let new_key = Key::generate();
+
+    let mut storage = dir.storage();
+    storage.encrypt_at_rest(old_key.clone());
+    store_secrets(&storage);
+    assert!(!dir.stores_plaintext(b"secret"));
+    assert_secrets_read(&storage);
+
+    // One data key for the file, one for the chunk
+    assert_eq!(storage.rotate_storage_key(new_key.clone()).unwrap(), 2);
+    assert_secrets_read(&storage);
+    drop(storage);
+
+    // Only the new key reads anything after a restart
+    assert!(dir.storage().read("owner", "test_file", 0, 100).is_err());
+    let mut storage = dir.storage();
+    storage.encrypt_at_rest(old_key);
+    assert!(storage.read("owner", "test_file", 0, 100).is_err());
+    assert!(storage.read("owner", "shared_file", 0, 100).is_err());
+    drop(storage);
+    let mut storage = dir.storage();
+    storage.encrypt_at_rest(new_key);
+    assert_secrets_read(&storage);
+    assert!(!dir.stores_plaintext(b"secret"));
+}
+
+#[test]
+fn test_plaintext_sealed_once_encrypted_at_rest() {
+    let dir = TestDir::new("seal-plaintext");
+    store_secrets(&dir.storage());
+    assert!(dir.stores_plaintext(b"secret"));
+
+    let key = Key::generate();
+    let mut storage = dir.storage();
+    storage.encrypt_at_rest(key.clone());
+    // The file, its previous version and the chunk
+    assert_eq!(storage.seal_plaintext().unwrap(), 3);
+    assert!(!dir.stores_plaintext(b"secret"));
+    assert_secrets_read(&storage);
+    assert_eq!(storage.seal_plaintext().unwrap(), 0);
+    drop(storage);
+
+    // What's sealed reads after a restart, and appends still work
+    let mut storage = dir.storage();
+    storage.encrypt_at_rest(key);
+    assert_secrets_read(&storage);
+    storage.append("owner", "test_file", b" and more").unwrap();
+    assert_eq!(storage.read("owner", "test_file", 0, 100).unwrap(), b"new secret and more");
+    drop(storage);
+    assert!(dir.storage().read("owner", "test_file", 0, 100).is_err());
+}
+
+#[test]
+fn test_storage_locked_while_open() {
+    let dir = TestDir::new("lock");
+    let storage = dir.storage();
+
+    // Such as `rotate-storage-key` while the server is up
+    match SqliteStorageManager::with_data_dir(&dir.database(), &dir.path) {
+        Err(err) => assert!(err.to_string().contains("in use")),
+        Ok(_) => panic!("storage opened twice"),
+    }
+    drop(storage);
+    dir.storage();
+}
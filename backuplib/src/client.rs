@@ -1,50 +1,186 @@
-use grpc::RequestOptions;
-use futures::Future;
+use std::convert::TryFrom;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use grpc::{ClientConf, ClientStub, MetadataKey, RequestOptions, StreamingRequest};
+use futures::{stream, Future, Stream};
+use httpbis::ClientTlsOption;
 
 use crate::proto::baacup_grpc;
 use crate::proto::baacup_grpc::Baacup as GrpcBaacup;
 use crate::proto::baacup;
 use crate::rpc::*;
+use crate::error::response_error;
+use crate::tls::{ChannelBinding, TlsConnector};
+
+/// How long `new_tls` waits for the first handshake unless the `ClientConf`
+/// sets a connection timeout.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct BaacupClient {
+    inner: baacup_grpc::BaacupClient,
+    api_key: Option<String>,
+    channel_binding: Option<ChannelBinding>,
+}
 
-pub struct BaacupClient(baacup_grpc::BaacupClient);
+impl BaacupClient {
+    /// Connects to backupd over TLS. Unless `connector` pins the server's
+    /// certificate, the certificate must be issued for `host`.
+    ///
+    /// If `connector` has a client certificate, this waits for the handshake
+    /// so the server knows who the client is before the first request.
+    pub fn new_tls(host: &str, port: u16, connector: TlsConnector, conf: ClientConf) -> grpc::Result<BaacupClient> {
+        let addr = (host, port).to_socket_addrs()?.next()
+            .ok_or(grpc::Error::Other("server address did not resolve"))?;
+        let channel_binding = connector.channel_binding();
+        let handshake_timeout = conf.http.connection_timeout.unwrap_or(HANDSHAKE_TIMEOUT);
+        let tls = ClientTlsOption::Tls(host.to_owned(), Arc::new(connector));
+        let grpc_client = grpc::Client::new_expl(&addr, host, tls, conf)?;
+
+        if let Some(ref channel_binding) = channel_binding {
+            channel_binding.wait(handshake_timeout)
+                .map_err(|err| grpc::Error::Io(io::Error::new(io::ErrorKind::Other, err.into_inner())))?;
+        }
+
+        let mut client = BaacupClient::with_client(Arc::new(grpc_client));
+        client.channel_binding = channel_binding;
+        Ok(client)
+    }
+
+    /// Sends `api_key` with every request to identify this client.
+    pub fn with_api_key(mut self, api_key: String) -> BaacupClient {
+        self.api_key = Some(api_key);
+        self
+    }
+
+    /// Introduces this client to the server, offering every capability the
+    /// library has. Fails if the two have no protocol version in common;
+    /// otherwise returns what the server said about itself, so callers can
+    /// skip the features it lacks.
+    pub fn negotiate(&self, software_version: &str) -> BaacupFuture<Hello> {
+        let hello = Hello::new(software_version, Capability::ALL);
+        BaacupFuture::new(self.hello(hello)
+            .and_then(|server_hello| {
+                server_hello.check_compatible()?;
+                Ok(server_hello)
+            }))
+    }
+
+    fn request_options(&self) -> RequestOptions {
+        let mut options = RequestOptions::new();
+        if let Some(ref api_key) = self.api_key {
+            options.metadata.add(MetadataKey::from(API_KEY_METADATA), Bytes::from(api_key.as_str()));
+        }
+        if let Some(binding) = self.channel_binding.as_ref().and_then(ChannelBinding::get) {
+            options.metadata.add(MetadataKey::from(CHANNEL_BINDING_METADATA), Bytes::from(binding));
+        }
+        options
+    }
+}
+
+impl BaacupClient {
+    fn request_file_list(&self, list_files_request: baacup::ListFilesRequest) -> BaacupFuture<Vec<FileMetadata>> {
+        let list_files_resp = self.inner.list_files(self.request_options(), list_files_request);
+        BaacupFuture::new(list_files_resp.drop_metadata()
+            .then(|list_files_result|
+                list_files_result.map_err(BaacupError::from).and_then(|mut list_files|
+                    match list_files.get_status() {
+                        baacup::Status::SUCCESS => Ok(list_files.take_files()
+                            .into_iter()
+                            .map(FileMetadata::from)
+                            .collect()),
+                        baacup::Status::ERROR => Err(response_error(list_files.take_error(), list_files.take_error_message())),
+                    }
+                )
+            )
+        )
+    }
+
+    fn request_chunk(&self, download_chunk: baacup::DownloadChunkRequest) -> BaacupFuture<Vec<u8>> {
+        let data_resp = self.inner.download_chunk(self.request_options(), download_chunk);
+        BaacupFuture::new(data_resp.drop_metadata()
+            .then(|data_result|
+                data_result.map_err(BaacupError::from).and_then(|mut data|
+                    match data.get_status() {
+                        baacup::Status::SUCCESS => Ok(data.take_data()),
+                        baacup::Status::ERROR => Err(response_error(data.take_error(), data.take_error_message())),
+                    }
+                )
+            )
+        )
+    }
+}
 
 impl grpc::ClientStub for BaacupClient {
     fn with_client(grpc_client: ::std::sync::Arc<::grpc::Client>) -> Self {
-        BaacupClient(<baacup_grpc::BaacupClient as grpc::ClientStub>::with_client(grpc_client))
+        BaacupClient {
+            inner: <baacup_grpc::BaacupClient as grpc::ClientStub>::with_client(grpc_client),
+            api_key: None,
+            channel_binding: None,
+        }
+    }
+}
+
+/// Servers from before `hello` don't know the method at all.
+fn hello_error(error: grpc::Error) -> BaacupError {
+    match error {
+        grpc::Error::GrpcMessage(ref message) if message.grpc_status == grpc::GrpcStatus::Unimplemented as i32 => {
+            BaacupError::IncompatibleVersion("server predates protocol negotiation".into())
+        }
+        error => BaacupError::from(error),
     }
 }
 
 impl Baacup for BaacupClient {
-    fn init_upload(&self, metadata: FileMetadata) -> BaacupFuture<u32> {
-        let mut file_metadata = baacup::FileMetadata::new();
-        file_metadata.set_file_name(metadata.file_name.into());
-        file_metadata.set_last_modified(metadata.last_modified);
-        file_metadata.set_file_size(metadata.file_size);
+    fn hello(&self, hello: Hello) -> BaacupFuture<Hello> {
+        let hello_resp = self.inner.hello(self.request_options(), hello.into());
+        BaacupFuture::new(hello_resp.drop_metadata()
+            .then(|hello_result|
+                hello_result.map_err(hello_error).and_then(|mut hello|
+                    match hello.get_status() {
+                        baacup::Status::SUCCESS => Ok(Hello::from(&mut hello)),
+                        baacup::Status::ERROR => Err(response_error(hello.take_error(), hello.take_error_message())),
+                    }
+                )
+            )
+        )
+    }
+
+    fn init_compressed_upload(&self, metadata: FileMetadata, compression: Compression) -> BaacupFuture<Upload> {
+        let mut file_metadata = baacup::FileMetadata::from(metadata);
+        file_metadata.set_compression(compression.into());
 
-        let token_resp = self.0.init_upload(RequestOptions::new(), file_metadata);
+        let token_resp = self.inner.init_upload(self.request_options(), file_metadata);
         BaacupFuture::new(token_resp.drop_metadata()
             .then(|token_result|
-                token_result.map_err(|e| e.to_string()).and_then(|mut token|
+                token_result.map_err(BaacupError::from).and_then(|mut token|
                     match token.get_status() {
-                        baacup::Status::SUCCESS => Ok(token.get_token().get_token()),
-                        baacup::Status::ERROR => Err(token.take_error_message()),
+                        // Servers without compression leave it unset
+                        baacup::Status::SUCCESS => Ok(Upload {
+                            token: UploadToken::from_slice(token.get_token().get_token())?,
+                            compression: token.get_compression().into(),
+                        }),
+                        baacup::Status::ERROR => Err(response_error(token.take_error(), token.take_error_message())),
                     }
                 )
             )
         )
     }
 
-    fn get_head(&self, token: u32) -> BaacupFuture<u64> {
+    fn get_head(&self, token: UploadToken) -> BaacupFuture<u64> {
         let mut upload_token = baacup::UploadToken::new();
-        upload_token.set_token(token);
+        upload_token.set_token(token.as_bytes().to_vec());
 
-        let head_resp = self.0.get_head(RequestOptions::new(), upload_token);
+        let head_resp = self.inner.get_head(self.request_options(), upload_token);
         BaacupFuture::new(head_resp.drop_metadata()
             .then(|head_result|
-                head_result.map_err(|e| e.to_string()).and_then(|mut head|
+                head_result.map_err(BaacupError::from).and_then(|mut head|
                     match head.get_status() {
                         baacup::Status::SUCCESS => Ok(head.get_offset()),
-                        baacup::Status::ERROR => Err(head.take_error_message()),
+                        baacup::Status::ERROR => Err(response_error(head.take_error(), head.take_error_message())),
                     }
                 )
             )
@@ -53,39 +189,262 @@ impl Baacup for BaacupClient {
 
     fn upload_chunk(&self, chunk: FileChunk) -> BaacupFuture<u32> {
         let mut file_chunk = baacup::FileChunk::new();
-        file_chunk.set_token(chunk.token);
+        file_chunk.set_token(chunk.token.as_bytes().to_vec());
         file_chunk.set_offset(chunk.offset);
         file_chunk.set_data(chunk.data);
+        file_chunk.set_checksum(chunk.checksum);
+        file_chunk.set_compression(chunk.compression.into());
+        file_chunk.set_chunk_hash(chunk.chunk_hash.unwrap_or_default());
+        if let Some(copy) = chunk.copy {
+            file_chunk.set_copy_offset(copy.offset);
+            file_chunk.set_copy_len(copy.len);
+        }
 
-        let checksum_resp = self.0.upload_chunk(RequestOptions::new(), file_chunk);
+        let checksum_resp = self.inner.upload_chunk(self.request_options(), file_chunk);
         BaacupFuture::new(checksum_resp.drop_metadata()
             .then(|checksum_result|
-                checksum_result.map_err(|e| e.to_string()).and_then(|mut checksum|
+                checksum_result.map_err(BaacupError::from).and_then(|mut checksum|
                     match checksum.get_status() {
                         baacup::Status::SUCCESS => Ok(checksum.get_checksum()),
-                        baacup::Status::ERROR => Err(checksum.take_error_message()),
+                        baacup::Status::ERROR => Err(response_error(checksum.take_error(), checksum.take_error_message())),
                     }
                 )
             )
         )
     }
 
-    fn file_is_uploaded(&self, metadata: FileMetadata) -> BaacupFuture<bool> {
-        let mut file_metadata = baacup::FileMetadata::new();
-        file_metadata.set_file_name(metadata.file_name.into());
-        file_metadata.set_last_modified(metadata.last_modified);
-        file_metadata.set_file_size(metadata.file_size);
+    fn upload_stream(&self, header: StreamHeader, chunks: BaacupStream<DataChunk>) -> BaacupFuture<StreamResult> {
+        let mut header_request = baacup::UploadStreamRequest::new();
+        header_request.mut_header().set_token(header.token.as_bytes().to_vec());
+        header_request.mut_header().set_offset(header.offset);
+
+        // A failed request stream never gets a response, so end the stream
+        // at the first error and report that error once the server answers
+        let chunk_error = Arc::new(Mutex::new(None));
+        let chunk_error_copy = chunk_error.clone();
+        let chunk_requests = chunks
+            .then(move |chunk_result| match chunk_result {
+                Ok(chunk) => Ok(Some(chunk)),
+                Err(error) => {
+                    *chunk_error_copy.lock().unwrap() = Some(error);
+                    Ok(None)
+                }
+            })
+            .take_while(|chunk| Ok(chunk.is_some()))
+            .filter_map(|chunk| chunk)
+            .map(|chunk| {
+                let mut chunk_request = baacup::UploadStreamRequest::new();
+                chunk_request.mut_chunk().set_data(chunk.data);
+                chunk_request.mut_chunk().set_checksum(chunk.checksum);
+                chunk_request.mut_chunk().set_compression(chunk.compression.into());
+                chunk_request.mut_chunk().set_chunk_hash(chunk.chunk_hash.unwrap_or_default());
+                if let Some(copy) = chunk.copy {
+                    chunk_request.mut_chunk().set_copy_offset(copy.offset);
+                    chunk_request.mut_chunk().set_copy_len(copy.len);
+                }
+                chunk_request
+            });
+        let requests = stream::once(Ok(header_request)).chain(chunk_requests);
 
-        let is_uploaded_resp = self.0.file_is_uploaded(RequestOptions::new(), file_metadata);
+        let stream_resp = self.inner.upload_stream(self.request_options(), StreamingRequest::new(requests));
+        BaacupFuture::new(stream_resp.drop_metadata()
+            .then(move |stream_result| {
+                if let Some(error) = chunk_error.lock().unwrap().take() {
+                    return Err(error);
+                }
+                stream_result.map_err(BaacupError::from).and_then(|mut stream_response|
+                    match stream_response.get_status() {
+                        baacup::Status::SUCCESS => {
+                            let content_hash = stream_response.take_content_hash();
+                            Ok(StreamResult {
+                                offset: stream_response.get_offset(),
+                                content_hash: if content_hash.is_empty() { None } else { Some(content_hash) },
+                            })
+                        }
+                        baacup::Status::ERROR => Err(response_error(stream_response.take_error(), stream_response.take_error_message())),
+                    }
+                )
+            })
+        )
+    }
+
+    fn file_is_uploaded(&self, metadata: FileMetadata) -> BaacupFuture<UploadStatus> {
+        let is_uploaded_resp = self.inner.file_is_uploaded(self.request_options(), metadata.into());
         BaacupFuture::new(is_uploaded_resp.drop_metadata()
             .then(|is_uploaded_result|
-                is_uploaded_result.map_err(|e| e.to_string()).and_then(|mut is_uploaded|
+                is_uploaded_result.map_err(BaacupError::from).and_then(|mut is_uploaded|
                     match is_uploaded.get_status() {
-                        baacup::Status::SUCCESS => Ok(is_uploaded.get_file_is_uploaded()),
-                        baacup::Status::ERROR => Err(is_uploaded.take_error_message()),
+                        baacup::Status::SUCCESS => {
+                            let content_hash = is_uploaded.take_content_hash();
+                            Ok(UploadStatus {
+                                is_uploaded: is_uploaded.get_file_is_uploaded(),
+                                content_hash: if content_hash.is_empty() { None } else { Some(content_hash) },
+                            })
+                        }
+                        baacup::Status::ERROR => Err(response_error(is_uploaded.take_error(), is_uploaded.take_error_message())),
+                    }
+                )
+            )
+        )
+    }
+
+    fn files_are_uploaded(&self, files: Vec<FileMetadata>) -> BaacupFuture<Vec<UploadStatus>> {
+        let requested = files.len();
+        let mut files_are_uploaded_request = baacup::FilesAreUploadedRequest::new();
+        files_are_uploaded_request.set_files(files.into_iter().map(baacup::FileMetadata::from).collect());
+
+        let files_are_uploaded_resp = self.inner.files_are_uploaded(self.request_options(), files_are_uploaded_request);
+        BaacupFuture::new(files_are_uploaded_resp.drop_metadata()
+            .then(move |files_are_uploaded_result|
+                files_are_uploaded_result.map_err(BaacupError::from).and_then(|mut files_are_uploaded|
+                    match files_are_uploaded.get_status() {
+                        baacup::Status::SUCCESS if files_are_uploaded.get_statuses().len() != requested => {
+                            let message = format!("server answered for {} of {} files", files_are_uploaded.get_statuses().len(), requested);
+                            Err(BaacupError::Internal(message))
+                        }
+                        baacup::Status::SUCCESS => Ok(files_are_uploaded.take_statuses().into_iter().map(UploadStatus::from).collect()),
+                        baacup::Status::ERROR => Err(response_error(files_are_uploaded.take_error(), files_are_uploaded.take_error_message())),
                     }
                 )
             )
         )
     }
+
+    fn has_chunks(&self, chunk_hashes: Vec<Vec<u8>>) -> BaacupFuture<Vec<bool>> {
+        let requested = chunk_hashes.len();
+        let mut has_chunks_request = baacup::HasChunksRequest::new();
+        has_chunks_request.set_chunk_hashes(chunk_hashes.into());
+
+        let has_chunks_resp = self.inner.has_chunks(self.request_options(), has_chunks_request);
+        BaacupFuture::new(has_chunks_resp.drop_metadata()
+            .then(move |has_chunks_result|
+                has_chunks_result.map_err(BaacupError::from).and_then(|mut has_chunks|
+                    match has_chunks.get_status() {
+                        baacup::Status::SUCCESS if has_chunks.get_has_chunks().len() != requested => {
+                            let message = format!("server answered for {} of {} chunks", has_chunks.get_has_chunks().len(), requested);
+                            Err(BaacupError::Internal(message))
+                        }
+                        baacup::Status::SUCCESS => Ok(has_chunks.take_has_chunks()),
+                        baacup::Status::ERROR => Err(response_error(has_chunks.take_error(), has_chunks.take_error_message())),
+                    }
+                )
+            )
+        )
+    }
+
+    fn get_signature(&self, file_name: String) -> BaacupFuture<Signature> {
+        let mut signature_request = baacup::GetSignatureRequest::new();
+        signature_request.set_file_name(file_name);
+
+        let signature_resp = self.inner.get_signature(self.request_options(), signature_request);
+        BaacupFuture::new(signature_resp.drop_metadata()
+            .then(|signature_result|
+                signature_result.map_err(BaacupError::from).and_then(|mut signature|
+                    match signature.get_status() {
+                        baacup::Status::SUCCESS => Ok(Signature {
+                            block_size: signature.get_block_size(),
+                            blocks: signature.take_blocks().into_iter().map(BlockSignature::from).collect(),
+                        }),
+                        baacup::Status::ERROR => Err(response_error(signature.take_error(), signature.take_error_message())),
+                    }
+                )
+            )
+        )
+    }
+
+    fn list_files(&self) -> BaacupFuture<Vec<FileMetadata>> {
+        self.request_file_list(baacup::ListFilesRequest::new())
+    }
+
+    fn read_chunk(&self, file_name: String, offset: u64, len: u64) -> BaacupFuture<Vec<u8>> {
+        let mut download_chunk = baacup::DownloadChunkRequest::new();
+        download_chunk.set_file_name(file_name);
+        download_chunk.set_offset(offset);
+        download_chunk.set_len(len);
+        self.request_chunk(download_chunk)
+    }
+
+    fn begin_snapshot(&self, host: String) -> BaacupFuture<SnapshotId> {
+        let mut begin_snapshot_request = baacup::BeginSnapshotRequest::new();
+        begin_snapshot_request.set_host(host);
+
+        let begin_snapshot_resp = self.inner.begin_snapshot(self.request_options(), begin_snapshot_request);
+        BaacupFuture::new(begin_snapshot_resp.drop_metadata()
+            .then(|begin_snapshot_result|
+                begin_snapshot_result.map_err(BaacupError::from).and_then(|mut begin_snapshot|
+                    match begin_snapshot.get_status() {
+                        baacup::Status::SUCCESS => SnapshotId::from_slice(begin_snapshot.get_snapshot_id()),
+                        baacup::Status::ERROR => Err(response_error(begin_snapshot.take_error(), begin_snapshot.take_error_message())),
+                    }
+                )
+            )
+        )
+    }
+
+    fn add_to_snapshot(&self, snapshot_id: SnapshotId, file_names: Vec<String>) -> BaacupFuture<()> {
+        let mut add_to_snapshot_request = baacup::AddToSnapshotRequest::new();
+        add_to_snapshot_request.set_snapshot_id(snapshot_id.as_bytes().to_vec());
+        add_to_snapshot_request.set_file_names(file_names.into());
+
+        let add_to_snapshot_resp = self.inner.add_to_snapshot(self.request_options(), add_to_snapshot_request);
+        BaacupFuture::new(add_to_snapshot_resp.drop_metadata()
+            .then(|add_to_snapshot_result|
+                add_to_snapshot_result.map_err(BaacupError::from).and_then(|mut add_to_snapshot|
+                    match add_to_snapshot.get_status() {
+                        baacup::Status::SUCCESS => Ok(()),
+                        baacup::Status::ERROR => Err(response_error(add_to_snapshot.take_error(), add_to_snapshot.take_error_message())),
+                    }
+                )
+            )
+        )
+    }
+
+    fn commit_snapshot(&self, snapshot_id: SnapshotId) -> BaacupFuture<Snapshot> {
+        let mut commit_snapshot_request = baacup::CommitSnapshotRequest::new();
+        commit_snapshot_request.set_snapshot_id(snapshot_id.as_bytes().to_vec());
+
+        let commit_snapshot_resp = self.inner.commit_snapshot(self.request_options(), commit_snapshot_request);
+        BaacupFuture::new(commit_snapshot_resp.drop_metadata()
+            .then(|commit_snapshot_result|
+                commit_snapshot_result.map_err(BaacupError::from).and_then(|mut commit_snapshot|
+                    match commit_snapshot.get_status() {
+                        baacup::Status::SUCCESS => Snapshot::try_from(commit_snapshot.take_snapshot()),
+                        baacup::Status::ERROR => Err(response_error(commit_snapshot.take_error(), commit_snapshot.take_error_message())),
+                    }
+                )
+            )
+        )
+    }
+
+    fn list_snapshots(&self) -> BaacupFuture<Vec<Snapshot>> {
+        let list_snapshots_resp = self.inner.list_snapshots(self.request_options(), baacup::ListSnapshotsRequest::new());
+        BaacupFuture::new(list_snapshots_resp.drop_metadata()
+            .then(|list_snapshots_result|
+                list_snapshots_result.map_err(BaacupError::from).and_then(|mut list_snapshots|
+                    match list_snapshots.get_status() {
+                        baacup::Status::SUCCESS => list_snapshots.take_snapshots()
+                            .into_iter()
+                            .map(Snapshot::try_from)
+                            .collect(),
+                        baacup::Status::ERROR => Err(response_error(list_snapshots.take_error(), list_snapshots.take_error_message())),
+                    }
+                )
+            )
+        )
+    }
+
+    fn list_snapshot_files(&self, snapshot_id: SnapshotId) -> BaacupFuture<Vec<FileMetadata>> {
+        let mut list_files_request = baacup::ListFilesRequest::new();
+        list_files_request.set_snapshot_id(snapshot_id.as_bytes().to_vec());
+        self.request_file_list(list_files_request)
+    }
+
+    fn read_snapshot_chunk(&self, snapshot_id: SnapshotId, file_name: String, offset: u64, len: u64) -> BaacupFuture<Vec<u8>> {
+        let mut download_chunk = baacup::DownloadChunkRequest::new();
+        download_chunk.set_file_name(file_name);
+        download_chunk.set_offset(offset);
+        download_chunk.set_len(len);
+        download_chunk.set_snapshot_id(snapshot_id.as_bytes().to_vec());
+        self.request_chunk(download_chunk)
+    }
 }
@@ -0,0 +1,72 @@
+use std::borrow::Cow;
+use std::io::{self, Read};
+
+use crate::rpc::Compression;
+
+/// zstd level chunks are compressed at. Higher levels barely shrink typical
+/// files any further but cost a lot more time.
+pub const ZSTD_LEVEL: i32 = 3;
+
+/// Compresses `data` with `compression`, unless that doesn't make it any
+/// smaller. Returns the data to send along with how it ended up compressed.
+pub fn compress(data: Vec<u8>, compression: Compression) -> (Vec<u8>, Compression) {
+    match compression {
+        Compression::None => (data, Compression::None),
+        Compression::Zstd => match zstd::bulk::compress(&data, ZSTD_LEVEL) {
+            Ok(compressed) if compressed.len() < data.len() => (compressed, Compression::Zstd),
+            // Already compressed, or too small to gain anything
+            _ => (data, Compression::None),
+        },
+    }
+}
+
+/// Undoes `compress`. Fails if `data` is corrupt or holds more than
+/// `max_len` bytes, so a small chunk can't blow up into a huge one.
+pub fn decompress(data: &[u8], compression: Compression, max_len: u64) -> io::Result<Cow<'_, [u8]>> {
+    let decompressed = match compression {
+        Compression::None => Cow::Borrowed(data),
+        Compression::Zstd => {
+            let mut decompressed = Vec::new();
+            zstd::stream::read::Decoder::new(data)?
+                .take(max_len.saturating_add(1))
+                .read_to_end(&mut decompressed)?;
+            Cow::Owned(decompressed)
+        }
+    };
+    if decompressed.len() as u64 > max_len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("data exceeds {} bytes", max_len)));
+    }
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compress, decompress};
+    use crate::rpc::Compression;
+
+    #[test]
+    fn test_round_trip() {
+        let data: Vec<u8> = (0..100_000).map(|n| (n % 7) as u8).collect();
+
+        let (compressed, compression) = compress(data.clone(), Compression::Zstd);
+        assert_eq!(compression, Compression::Zstd);
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress(&compressed, compression, data.len() as u64).unwrap(), &data[..]);
+    }
+
+    #[test]
+    fn test_incompressible_data_sent_as_is() {
+        let data = b"abc".to_vec();
+
+        assert_eq!(compress(data.clone(), Compression::Zstd), (data, Compression::None));
+    }
+
+    #[test]
+    fn test_decompress_limits_len() {
+        let (compressed, compression) = compress(vec![0; 10_000], Compression::Zstd);
+
+        assert!(decompress(&compressed, compression, 9_999).is_err());
+        assert!(decompress(b"not zstd", Compression::Zstd, 10_000).is_err());
+        assert!(decompress(b"plain", Compression::None, 4).is_err());
+    }
+}
@@ -0,0 +1,224 @@
+//! Convergent encryption: every chunk is encrypted under a key derived from
+//! its own contents and a repository secret, so the same chunk encrypts the
+//! same way for every client with that secret, and the server can
+//! deduplicate the ciphertext.
+//!
+//! The price is that the server learns which chunks are equal: where a file
+//! repeats itself, what files have in common, and what clients with the
+//! same secret have in common, along with the size of every chunk. Anyone
+//! with the secret who can see what the server stores can also check
+//! whether a file they already have is among it. `crypto::encrypt_file`
+//! leaks none of that. Clients only get to refer by hash to chunks they
+//! sent the server themselves, so deduplication doesn't hand anyone another
+//! client's sealed chunks.
+//!
+//! A convergently encrypted file is its sealed chunks one after the other,
+//! then a manifest sealed with the client's own key that lists them, then
+//! the manifest's length and `TRAILER_MAGIC`.
+
+use std::convert::TryInto;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::crypto::{self, Key, NONCE_LEN};
+use crate::hash::ContentHasher;
+
+/// Ends every convergently encrypted file.
+const TRAILER_MAGIC: &[u8] = b"BCC1";
+const TRAILER_LEN: u64 = 8 + 4;
+
+/// Bytes per chunk in a manifest: sealed length, then plaintext hash.
+const ENTRY_LEN: usize = 8 + 32;
+
+// What the keys derived from the ones the user gives are for
+const CHUNK_KEY_PURPOSE: &str = "convergent chunks";
+const MANIFEST_PURPOSE: &str = "manifests";
+
+/// One chunk of a convergently encrypted file, as its manifest lists it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManifestEntry {
+    pub sealed_len: u64,
+    /// SHA-256 of the chunk's plaintext, which its key is derived from.
+    pub plaintext_hash: Vec<u8>,
+}
+
+/// Encrypts `chunk` under a key derived from `repository_secret` and the
+/// chunk's contents. Returns the sealed chunk along with its manifest entry.
+pub fn seal_chunk(repository_secret: &Key, chunk: &[u8]) -> io::Result<(Vec<u8>, ManifestEntry)> {
+    let mut hasher = ContentHasher::new();
+    hasher.update(chunk);
+    let plaintext_hash = hasher.finish();
+
+    // Each key only ever seals the one plaintext, so a fixed nonce is safe
+    let sealed = crypto::seal(&chunk_key(repository_secret, &plaintext_hash), &[0; NONCE_LEN], TRAILER_MAGIC, chunk)?;
+    let entry = ManifestEntry {
+        sealed_len: sealed.len() as u64,
+        plaintext_hash: plaintext_hash,
+    };
+    Ok((sealed, entry))
+}
+
+/// Seals the manifest listing `entries` with `key`, followed by the
+/// trailer. This goes right after the last sealed chunk. The manifest is
+/// bound to `name`, what the file is stored under, so files can't be
+/// swapped unnoticed.
+pub fn seal_manifest(key: &Key, name: &[u8], entries: &[ManifestEntry]) -> io::Result<Vec<u8>> {
+    let mut manifest = Vec::with_capacity(entries.len() * ENTRY_LEN);
+    for entry in entries {
+        manifest.extend_from_slice(&entry.sealed_len.to_be_bytes());
+        manifest.extend_from_slice(&entry.plaintext_hash);
+    }
+
+    let nonce = crypto::random_nonce();
+    let mut sealed = nonce.to_vec();
+    sealed.extend(crypto::seal(&key.derive(MANIFEST_PURPOSE), &nonce, &manifest_aad(name), &manifest)?);
+    let sealed_len = sealed.len() as u64;
+    sealed.extend_from_slice(&sealed_len.to_be_bytes());
+    sealed.extend_from_slice(TRAILER_MAGIC);
+    Ok(sealed)
+}
+
+/// Decrypts a whole convergently encrypted file from `reader` into
+/// `writer`. Fails with `InvalidData` if anything in it isn't what was
+/// encrypted with `key` and `repository_secret` under `name`. Returns how
+/// many bytes were written.
+pub fn decrypt_file<R, W>(mut reader: R, mut writer: W, key: &Key, repository_secret: &Key, name: &[u8]) -> io::Result<u64>
+    where R: Read + Seek,
+          W: Write,
+{
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    if file_len < TRAILER_LEN {
+        return Err(not_convergent());
+    }
+    let mut trailer = [0; TRAILER_LEN as usize];
+    reader.seek(SeekFrom::Start(file_len - TRAILER_LEN))?;
+    reader.read_exact(&mut trailer)?;
+    if &trailer[8..] != TRAILER_MAGIC {
+        return Err(not_convergent());
+    }
+    let manifest_len = u64::from_be_bytes(trailer[..8].try_into().unwrap());
+    let chunks_len = (file_len - TRAILER_LEN).checked_sub(manifest_len)
+        .filter(|_| manifest_len >= NONCE_LEN as u64)
+        .ok_or_else(corrupt)?;
+
+    let mut sealed_manifest = vec![0; manifest_len as usize];
+    reader.seek(SeekFrom::Start(chunks_len))?;
+    reader.read_exact(&mut sealed_manifest)?;
+    let (nonce, sealed) = sealed_manifest.split_at(NONCE_LEN);
+    let manifest = crypto::open(&key.derive(MANIFEST_PURPOSE), nonce.try_into().unwrap(), &manifest_aad(name), sealed)?;
+    if manifest.len() % ENTRY_LEN != 0 {
+        return Err(corrupt());
+    }
+    let entries: Vec<ManifestEntry> = manifest.chunks(ENTRY_LEN)
+        .map(|entry| ManifestEntry {
+            sealed_len: u64::from_be_bytes(entry[..8].try_into().unwrap()),
+            plaintext_hash: entry[8..].to_vec(),
+        })
+        .collect();
+    if entries.iter().map(|entry| entry.sealed_len).sum::<u64>() != chunks_len {
+        return Err(corrupt());
+    }
+
+    reader.seek(SeekFrom::Start(0))?;
+    let mut written = 0;
+    for entry in entries {
+        let mut sealed = vec![0; entry.sealed_len as usize];
+        reader.read_exact(&mut sealed)?;
+        let chunk_key = chunk_key(repository_secret, &entry.plaintext_hash);
+        let chunk = crypto::open(&chunk_key, &[0; NONCE_LEN], TRAILER_MAGIC, &sealed)?;
+        writer.write_all(&chunk)?;
+        written += chunk.len() as u64;
+    }
+    Ok(written)
+}
+
+fn manifest_aad(name: &[u8]) -> Vec<u8> {
+    [TRAILER_MAGIC, name].concat()
+}
+
+fn chunk_key(repository_secret: &Key, plaintext_hash: &[u8]) -> Key {
+    let mac = crypto::hmac_sha256(&repository_secret.derive(CHUNK_KEY_PURPOSE), plaintext_hash);
+    Key::from_slice(&mac).unwrap()
+}
+
+fn not_convergent() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "not a convergently encrypted file")
+}
+
+fn corrupt() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "corrupt manifest")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{decrypt_file, seal_chunk, seal_manifest};
+    use crate::crypto::Key;
+
+    /// Encrypts `data` in chunks of `chunk_len`, like a client would.
+    fn encrypt(data: &[u8], chunk_len: usize, key: &Key, repository_secret: &Key) -> (Vec<Vec<u8>>, Vec<u8>) {
+        let mut sealed_chunks = Vec::new();
+        let mut entries = Vec::new();
+        for chunk in data.chunks(chunk_len) {
+            let (sealed, entry) = seal_chunk(repository_secret, chunk).unwrap();
+            sealed_chunks.push(sealed);
+            entries.push(entry);
+        }
+        let mut file = sealed_chunks.concat();
+        file.extend(seal_manifest(key, b"name", &entries).unwrap());
+        (sealed_chunks, file)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let key = Key::generate();
+        let repository_secret = Key::generate();
+        for &len in &[0, 1, 10_000] {
+            let data: Vec<u8> = (0..len).map(|n| (n % 251) as u8).collect();
+
+            let (_, file) = encrypt(&data, 4096, &key, &repository_secret);
+            let mut decrypted = Vec::new();
+            decrypt_file(Cursor::new(&file), &mut decrypted, &key, &repository_secret, b"name").unwrap();
+            assert_eq!(decrypted, data);
+        }
+    }
+
+    #[test]
+    fn test_chunks_converge_across_clients() {
+        let repository_secret = Key::generate();
+        let data = vec![0x55; 10_000];
+
+        let (alice_chunks, alice_file) = encrypt(&data, 4096, &Key::generate(), &repository_secret);
+        let (bob_chunks, bob_file) = encrypt(&data, 4096, &Key::generate(), &repository_secret);
+        assert_eq!(alice_chunks, bob_chunks);
+        // Only the manifests differ
+        assert_ne!(alice_file, bob_file);
+        assert!(!alice_chunks[0].windows(16).any(|window| window == &data[..16]));
+
+        // Another repository's chunks don't match
+        let (other_chunks, _) = encrypt(&data, 4096, &Key::generate(), &Key::generate());
+        assert_ne!(alice_chunks, other_chunks);
+    }
+
+    #[test]
+    fn test_wrong_key_or_tampering_rejected() {
+        let key = Key::generate();
+        let repository_secret = Key::generate();
+        let data = vec![0x55; 10_000];
+        let (_, file) = encrypt(&data, 4096, &key, &repository_secret);
+        let decrypt = |file: &[u8], key: &Key, repository_secret: &Key| {
+            decrypt_file(Cursor::new(file), Vec::new(), key, repository_secret, b"name")
+        };
+
+        assert!(decrypt(&file, &Key::generate(), &repository_secret).is_err());
+        assert!(decrypt(&file, &key, &Key::generate()).is_err());
+        let mut flipped = file.clone();
+        flipped[100] ^= 1;
+        assert!(decrypt(&flipped, &key, &repository_secret).is_err());
+        assert!(decrypt(&file[..file.len() - 1], &key, &repository_secret).is_err());
+        assert!(decrypt(&file[4096..], &key, &repository_secret).is_err());
+
+        // The same file stored under another name
+        assert!(decrypt_file(Cursor::new(&file), Vec::new(), &key, &repository_secret, b"other name").is_err());
+    }
+}
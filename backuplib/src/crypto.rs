@@ -0,0 +1,487 @@
+use std::cmp;
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use argon2::{Algorithm, Argon2, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use protobuf::Message;
+
+use crate::proto::baacup;
+use crate::rpc::{ExtendedAttribute, PosixAttributes, Timestamp};
+
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 24;
+pub const TAG_LEN: usize = 16;
+
+/// Plaintext bytes per sealed segment of an encrypted file.
+pub const SEGMENT_LEN: usize = 64 * 1024;
+
+/// Starts every encrypted file, so it can't be mistaken for anything else.
+const FILE_MAGIC: &[u8] = b"BCE1";
+/// Random bytes after `FILE_MAGIC` that tell encrypted files apart.
+const FILE_ID_LEN: usize = 16;
+
+// What the keys derived from the one the user gives are for
+const FILE_DATA_PURPOSE: &str = "file data";
+const NAME_PURPOSE: &str = "names";
+const NAME_NONCE_PURPOSE: &str = "name nonces";
+const ATTRIBUTES_PURPOSE: &str = "attributes";
+
+/// Name of the one extended attribute `encrypt_attributes` leaves, which
+/// holds all the others sealed.
+pub const ENCRYPTED_ATTRIBUTES: &[u8] = b"baacup.encrypted-attributes";
+
+/// Argon2id cost, stored along with whatever it protects so it can be
+/// raised later without breaking what's there.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Argon2Params {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub lanes: u32,
+}
+
+impl Default for Argon2Params {
+    /// The second recommendation of RFC 9106: 64 MiB, 3 passes, 4 lanes.
+    fn default() -> Argon2Params {
+        Argon2Params {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            lanes: 4,
+        }
+    }
+}
+
+/// A 256-bit XChaCha20-Poly1305 key.
+#[derive(Clone, PartialEq)]
+pub struct Key([u8; KEY_LEN]);
+
+impl Key {
+    pub fn new(bytes: [u8; KEY_LEN]) -> Key {
+        Key(bytes)
+    }
+
+    /// Fails unless `bytes` is exactly `KEY_LEN` long.
+    pub fn from_slice(bytes: &[u8]) -> Option<Key> {
+        bytes.try_into().ok().map(Key)
+    }
+
+    pub fn generate() -> Key {
+        let mut bytes = [0; KEY_LEN];
+        openssl::rand::rand_bytes(&mut bytes).expect("no randomness for a key");
+        Key(bytes)
+    }
+
+    /// Stretches `passphrase` into a key with Argon2id. Fails with
+    /// `InvalidInput` if Argon2 doesn't take `params`.
+    pub fn from_passphrase(passphrase: &[u8], salt: &[u8], params: &Argon2Params) -> io::Result<Key> {
+        let invalid = |err: argon2::Error| io::Error::new(io::ErrorKind::InvalidInput, err.to_string());
+        let params = argon2::Params::new(params.memory_kib, params.iterations, params.lanes, Some(KEY_LEN))
+            .map_err(invalid)?;
+        let mut bytes = [0; KEY_LEN];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase, salt, &mut bytes)
+            .map_err(invalid)?;
+        Ok(Key(bytes))
+    }
+
+    /// Derives a key for one `purpose`, so no key is used for two things.
+    pub fn derive(&self, purpose: &str) -> Key {
+        let mac = hmac_sha256(self, purpose.as_bytes());
+        Key::from_slice(&mac).unwrap()
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Keys don't belong in logs
+        write!(f, "Key(..)")
+    }
+}
+
+pub fn random_nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    random_bytes(&mut nonce);
+    nonce
+}
+
+/// Fills `bytes` from the system's secure random number generator.
+pub fn random_bytes(bytes: &mut [u8]) {
+    openssl::rand::rand_bytes(bytes).expect("no randomness");
+}
+
+pub fn hmac_sha256(key: &Key, data: &[u8]) -> Vec<u8> {
+    let pkey = PKey::hmac(key.as_bytes()).unwrap();
+    let mut signer = Signer::new(MessageDigest::sha256(), &pkey).unwrap();
+    signer.update(data).unwrap();
+    signer.sign_to_vec().unwrap()
+}
+
+/// Encrypts and authenticates `plaintext` with XChaCha20-Poly1305, which
+/// also authenticates `aad`. Returns the ciphertext followed by the tag.
+pub fn seal(key: &Key, nonce: &[u8; NONCE_LEN], aad: &[u8], plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    let payload = Payload { msg: plaintext, aad: aad };
+    cipher(key).encrypt(XNonce::from_slice(nonce), payload)
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "encryption failed"))
+}
+
+/// Undoes `seal`. Fails with `InvalidData` if `sealed` or `aad` isn't what
+/// was sealed with `key` and `nonce`.
+pub fn open(key: &Key, nonce: &[u8; NONCE_LEN], aad: &[u8], sealed: &[u8]) -> io::Result<Vec<u8>> {
+    let payload = Payload { msg: sealed, aad: aad };
+    cipher(key).decrypt(XNonce::from_slice(nonce), payload)
+        .map_err(|_| authentication_failed())
+}
+
+fn cipher(key: &Key) -> XChaCha20Poly1305 {
+    XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key.as_bytes()))
+}
+
+/// Encrypts a name so that the same name always encrypts the same way, and
+/// can still be looked up by its encrypted form. Only equal names can be
+/// told apart from different ones.
+pub fn encrypt_name(key: &Key, name: &[u8]) -> io::Result<Vec<u8>> {
+    // Like SIV: the nonce is a MAC of the name, so it's only reused for
+    // the very same name
+    let mac = hmac_sha256(&key.derive(NAME_NONCE_PURPOSE), name);
+    let nonce: [u8; NONCE_LEN] = mac[..NONCE_LEN].try_into().unwrap();
+    let mut encrypted = nonce.to_vec();
+    encrypted.extend(seal(&key.derive(NAME_PURPOSE), &nonce, &[], name)?);
+    Ok(encrypted)
+}
+
+/// Undoes `encrypt_name`.
+pub fn decrypt_name(key: &Key, encrypted: &[u8]) -> io::Result<Vec<u8>> {
+    if encrypted.len() < NONCE_LEN {
+        return Err(authentication_failed());
+    }
+    let (nonce, sealed) = encrypted.split_at(NONCE_LEN);
+    open(&key.derive(NAME_PURPOSE), nonce.try_into().unwrap(), &[], sealed)
+}
+
+/// Seals all of `attributes` into a single extended attribute, so the
+/// server sees neither ownership, permissions and times nor any other
+/// extended attribute. Everything else is left zero.
+pub fn encrypt_attributes(key: &Key, attributes: PosixAttributes) -> io::Result<PosixAttributes> {
+    let plaintext = baacup::PosixAttributes::from(attributes).write_to_bytes()
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    let nonce = random_nonce();
+    let mut sealed = nonce.to_vec();
+    sealed.extend(seal(&key.derive(ATTRIBUTES_PURPOSE), &nonce, ENCRYPTED_ATTRIBUTES, &plaintext)?);
+    Ok(PosixAttributes {
+        mode: 0,
+        uid: 0,
+        gid: 0,
+        user_name: None,
+        group_name: None,
+        accessed: Timestamp::default(),
+        changed: Timestamp::default(),
+        xattrs: vec![ExtendedAttribute {
+            name: ENCRYPTED_ATTRIBUTES.to_vec(),
+            value: sealed,
+        }],
+    })
+}
+
+/// Undoes `encrypt_attributes`.
+pub fn decrypt_attributes(key: &Key, attributes: &PosixAttributes) -> io::Result<PosixAttributes> {
+    let sealed = attributes.xattrs.iter()
+        .find(|xattr| xattr.name == ENCRYPTED_ATTRIBUTES)
+        .map(|xattr| &xattr.value[..])
+        .filter(|sealed| sealed.len() >= NONCE_LEN)
+        .ok_or_else(authentication_failed)?;
+    let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+    let plaintext = open(&key.derive(ATTRIBUTES_PURPOSE), nonce.try_into().unwrap(), ENCRYPTED_ATTRIBUTES, sealed)?;
+    let attributes: baacup::PosixAttributes = protobuf::parse_from_bytes(&plaintext)
+        .map_err(|_| authentication_failed())?;
+    Ok(attributes.into())
+}
+
+/// How long `encrypt_file` makes a file of `plaintext_len` bytes.
+pub fn encrypted_len(plaintext_len: u64) -> u64 {
+    let segments = cmp::max(1, plaintext_len.div_ceil(SEGMENT_LEN as u64));
+    (FILE_MAGIC.len() + FILE_ID_LEN) as u64 + segments * (NONCE_LEN + TAG_LEN) as u64 + plaintext_len
+}
+
+/// Encrypts everything in `reader` into `writer`, in segments of
+/// `SEGMENT_LEN` sealed one by one under a fresh random nonce each. Every
+/// segment is bound to its place in the file and whether it's the last,
+/// so segments can't be reordered, dropped or cut off unnoticed. It's also
+/// bound to a random id in the file's header and to `name`, what the file
+/// is stored under, so segments can't be moved between files or versions
+/// of a file, nor files swapped. Returns how many bytes were written.
+pub fn encrypt_file<R, W>(mut reader: R, mut writer: W, key: &Key, name: &[u8]) -> io::Result<u64>
+    where R: Read,
+          W: Write,
+{
+    let key = key.derive(FILE_DATA_PURPOSE);
+    let mut file_id = [0; FILE_ID_LEN];
+    random_bytes(&mut file_id);
+    writer.write_all(FILE_MAGIC)?;
+    writer.write_all(&file_id)?;
+    let mut written = (FILE_MAGIC.len() + FILE_ID_LEN) as u64;
+
+    let mut segment = vec![0; SEGMENT_LEN];
+    let mut next = vec![0; SEGMENT_LEN];
+    let mut len = read_full(&mut reader, &mut segment)?;
+    let mut index = 0;
+    loop {
+        // Only reading on tells whether this segment is the last
+        let next_len = if len == SEGMENT_LEN { read_full(&mut reader, &mut next)? } else { 0 };
+        let last = next_len == 0;
+        let nonce = random_nonce();
+        let sealed = seal(&key, &nonce, &segment_aad(&file_id, name, index, last), &segment[..len])?;
+        writer.write_all(&nonce)?;
+        writer.write_all(&sealed)?;
+        written += (nonce.len() + sealed.len()) as u64;
+        if last {
+            return Ok(written);
+        }
+        std::mem::swap(&mut segment, &mut next);
+        len = next_len;
+        index += 1;
+    }
+}
+
+/// Undoes `encrypt_file`. Fails with `InvalidData` if anything in `reader`
+/// isn't what `encrypt_file` wrote with `key` and `name`, though segments
+/// before the bad one are written out already. Returns how many bytes were
+/// written.
+pub fn decrypt_file<R, W>(mut reader: R, mut writer: W, key: &Key, name: &[u8]) -> io::Result<u64>
+    where R: Read,
+          W: Write,
+{
+    let key = key.derive(FILE_DATA_PURPOSE);
+    let mut magic = [0; 4];
+    if read_full(&mut reader, &mut magic)? != FILE_MAGIC.len() || magic != FILE_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not an encrypted file"));
+    }
+    let mut file_id = [0; FILE_ID_LEN];
+    if read_full(&mut reader, &mut file_id)? != FILE_ID_LEN {
+        return Err(authentication_failed());
+    }
+
+    let sealed_len = NONCE_LEN + SEGMENT_LEN + TAG_LEN;
+    let mut segment = vec![0; sealed_len];
+    let mut next = vec![0; sealed_len];
+    let mut len = read_full(&mut reader, &mut segment)?;
+    let mut index = 0;
+    let mut written = 0;
+    loop {
+        let next_len = if len == sealed_len { read_full(&mut reader, &mut next)? } else { 0 };
+        let last = next_len == 0;
+        if len < NONCE_LEN {
+            return Err(authentication_failed());
+        }
+        let (nonce, sealed) = segment[..len].split_at(NONCE_LEN);
+        let plaintext = open(&key, nonce.try_into().unwrap(), &segment_aad(&file_id, name, index, last), sealed)?;
+        writer.write_all(&plaintext)?;
+        written += plaintext.len() as u64;
+        if last {
+            return Ok(written);
+        }
+        std::mem::swap(&mut segment, &mut next);
+        len = next_len;
+        index += 1;
+    }
+}
+
+/// The name goes last, so nothing else needs to know how long it is.
+fn segment_aad(file_id: &[u8; FILE_ID_LEN], name: &[u8], index: u64, last: bool) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(FILE_ID_LEN + 9 + name.len());
+    aad.extend_from_slice(file_id);
+    aad.extend_from_slice(&index.to_be_bytes());
+    aad.push(last as u8);
+    aad.extend_from_slice(name);
+    aad
+}
+
+/// Fills as much of `buffer` as `reader` has left.
+fn read_full<R>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize>
+    where R: Read,
+{
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(bytes) => filled += bytes,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+fn authentication_failed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "decryption failed: wrong key or corrupt data")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::rpc::{ExtendedAttribute, PosixAttributes, Timestamp};
+
+    use super::{decrypt_attributes, decrypt_file, decrypt_name, encrypt_attributes, encrypt_file, encrypt_name,
+                encrypted_len, open, random_nonce,
+                seal, Argon2Params, Key, FILE_ID_LEN, FILE_MAGIC, NONCE_LEN, SEGMENT_LEN};
+
+    fn test_data(len: usize) -> Vec<u8> {
+        (0..len).map(|n| (n * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_passphrase_keys() {
+        let params = Argon2Params {
+            memory_kib: 64,
+            iterations: 1,
+            lanes: 1,
+        };
+        let key = Key::from_passphrase(b"password", b"somesalt", &params).unwrap();
+        assert_eq!(Key::from_passphrase(b"password", b"somesalt", &params).unwrap(), key);
+        assert_ne!(Key::from_passphrase(b"password", b"othersalt", &params).unwrap(), key);
+        assert_ne!(Key::from_passphrase(b"other password", b"somesalt", &params).unwrap(), key);
+
+        let too_little_memory = Argon2Params {
+            memory_kib: 7,
+            ..params
+        };
+        assert!(Key::from_passphrase(b"password", b"somesalt", &too_little_memory).is_err());
+        assert!(Key::from_passphrase(b"password", b"short", &params).is_err());
+    }
+
+    #[test]
+    fn test_seal_round_trip() {
+        let key = Key::generate();
+        let nonce = random_nonce();
+
+        let sealed = seal(&key, &nonce, b"aad", b"secret").unwrap();
+        assert_eq!(open(&key, &nonce, b"aad", &sealed).unwrap(), b"secret");
+        assert!(open(&key, &nonce, b"other aad", &sealed).is_err());
+        assert!(open(&Key::generate(), &nonce, b"aad", &sealed).is_err());
+        let mut tampered = sealed.clone();
+        tampered[0] ^= 1;
+        assert!(open(&key, &nonce, b"aad", &tampered).is_err());
+    }
+
+    #[test]
+    fn test_names_are_deterministic() {
+        let key = Key::generate();
+
+        let encrypted = encrypt_name(&key, b"/home/foo/notes.txt").unwrap();
+        assert_eq!(encrypt_name(&key, b"/home/foo/notes.txt").unwrap(), encrypted);
+        assert_ne!(encrypt_name(&key, b"/home/foo/other.txt").unwrap(), encrypted);
+        assert_ne!(encrypt_name(&Key::generate(), b"/home/foo/notes.txt").unwrap(), encrypted);
+        assert_eq!(decrypt_name(&key, &encrypted).unwrap(), b"/home/foo/notes.txt");
+    }
+
+    #[test]
+    fn test_attributes_hidden() {
+        let key = Key::generate();
+        let attributes = PosixAttributes {
+            mode: 0o100640,
+            uid: 1000,
+            gid: 100,
+            user_name: Some("alice".into()),
+            group_name: Some("users".into()),
+            accessed: Timestamp { seconds: 1_500_000_000, nanos: 5 },
+            changed: Timestamp { seconds: 1_400_000_000, nanos: 0 },
+            xattrs: vec![ExtendedAttribute {
+                name: b"user.comment".to_vec(),
+                value: b"quarterly figures".to_vec(),
+            }],
+        };
+
+        let encrypted = encrypt_attributes(&key, attributes.clone()).unwrap();
+        assert_eq!((encrypted.mode, encrypted.uid, encrypted.gid), (0, 0, 0));
+        assert_eq!((encrypted.user_name.as_ref(), encrypted.group_name.as_ref()), (None, None));
+        assert_eq!(encrypted.accessed, Timestamp::default());
+        assert_eq!(encrypted.xattrs.len(), 1);
+        assert!(!encrypted.xattrs[0].value.windows(5).any(|window| window == b"alice"));
+
+        assert_eq!(decrypt_attributes(&key, &encrypted).unwrap(), attributes);
+        assert!(decrypt_attributes(&Key::generate(), &encrypted).is_err());
+        assert!(decrypt_attributes(&key, &attributes).is_err());
+    }
+
+    #[test]
+    fn test_file_round_trip() {
+        let key = Key::generate();
+        for &len in &[0, 1, SEGMENT_LEN, 2 * SEGMENT_LEN + 5] {
+            let data = test_data(len);
+
+            let mut encrypted = Vec::new();
+            let written = encrypt_file(Cursor::new(&data), &mut encrypted, &key, b"name").unwrap();
+            assert_eq!(written, encrypted.len() as u64);
+            assert_eq!(written, encrypted_len(len as u64));
+            assert!(!encrypted.windows(16).any(|window| len >= 16 && window == &data[..16]));
+
+            let mut decrypted = Vec::new();
+            decrypt_file(Cursor::new(&encrypted), &mut decrypted, &key, b"name").unwrap();
+            assert_eq!(decrypted, data);
+        }
+    }
+
+    #[test]
+    fn test_tampered_file_rejected() {
+        let key = Key::generate();
+        let data = test_data(2 * SEGMENT_LEN + 5);
+        let mut encrypted = Vec::new();
+        encrypt_file(Cursor::new(&data), &mut encrypted, &key, b"name").unwrap();
+        let decrypt = |encrypted: &[u8]| decrypt_file(Cursor::new(encrypted), Vec::new(), &key, b"name");
+        let header_len = FILE_MAGIC.len() + FILE_ID_LEN;
+
+        let mut flipped = encrypted.clone();
+        flipped[header_len + NONCE_LEN + 100] ^= 1;
+        assert!(decrypt(&flipped).is_err());
+
+        // Cut off after the first segment
+        let segment_len = NONCE_LEN + SEGMENT_LEN + 16;
+        let segment_end = header_len + segment_len;
+        assert!(decrypt(&encrypted[..segment_end]).is_err());
+
+        // Second segment swapped in for the first
+        let mut swapped = encrypted[..header_len].to_vec();
+        swapped.extend_from_slice(&encrypted[segment_end..segment_end + segment_len]);
+        swapped.extend_from_slice(&encrypted[header_len..segment_end]);
+        swapped.extend_from_slice(&encrypted[segment_end + segment_len..]);
+        assert!(decrypt(&swapped).is_err());
+
+        assert!(decrypt_file(Cursor::new(&encrypted), Vec::new(), &Key::generate(), b"name").is_err());
+    }
+
+    #[test]
+    fn test_file_bound_to_name_and_id() {
+        let key = Key::generate();
+        let data = test_data(SEGMENT_LEN + 5);
+        let encrypt = |name: &[u8]| {
+            let mut encrypted = Vec::new();
+            encrypt_file(Cursor::new(&data), &mut encrypted, &key, name).unwrap();
+            encrypted
+        };
+        let decrypt = |encrypted: &[u8], name: &[u8]| decrypt_file(Cursor::new(encrypted), Vec::new(), &key, name);
+        let first = encrypt(b"first");
+        let other_version = encrypt(b"first");
+        let header_len = FILE_MAGIC.len() + FILE_ID_LEN;
+        let segment_end = header_len + NONCE_LEN + SEGMENT_LEN + 16;
+
+        // A file stored under another name
+        assert!(decrypt(&encrypt(b"second"), b"first").is_err());
+        assert!(decrypt(&first, b"second").is_err());
+
+        // A segment from another version of the same file
+        let mut spliced = first[..segment_end].to_vec();
+        spliced.extend_from_slice(&other_version[segment_end..]);
+        assert!(decrypt(&spliced, b"first").is_err());
+        assert!(decrypt(&other_version, b"first").is_ok());
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufReader, Read};
+
+use crate::hash::ContentHasher;
+use crate::rpc::{BlockSignature, Signature};
+
+/// Smallest and largest block size `block_size` picks.
+pub const MIN_BLOCK_SIZE: u32 = 1024;
+pub const MAX_BLOCK_SIZE: u32 = 128 * 1024;
+
+/// Picks the block size for the signature of a file of `file_len` bytes.
+/// Like rsync, about the square root of the length, so bigger files get
+/// bigger blocks but not too many of them.
+pub fn block_size(file_len: u64) -> u32 {
+    let size = ((file_len as f64).sqrt() as u64 & !7) as u32;
+    size.clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+/// rsync's weak checksum: two 16-bit sums over a block, which can be slid
+/// along the data one byte at a time.
+#[derive(Clone, Copy, Debug)]
+pub struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    pub fn new(block: &[u8]) -> RollingChecksum {
+        let len = block.len() as u32;
+        let mut checksum = RollingChecksum { a: 0, b: 0, len: len };
+        for (n, &byte) in block.iter().enumerate() {
+            checksum.a = checksum.a.wrapping_add(byte as u32);
+            checksum.b = checksum.b.wrapping_add((len - n as u32).wrapping_mul(byte as u32));
+        }
+        checksum
+    }
+
+    /// Moves the block one byte on, dropping `old` and taking in `new`.
+    pub fn roll(&mut self, old: u8, new: u8) {
+        self.a = self.a.wrapping_sub(old as u32).wrapping_add(new as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(old as u32)).wrapping_add(self.a);
+    }
+
+    pub fn value(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+pub fn block_signature(block: &[u8]) -> BlockSignature {
+    BlockSignature {
+        weak: RollingChecksum::new(block).value(),
+        strong: strong_hash(block),
+    }
+}
+
+fn strong_hash(block: &[u8]) -> Vec<u8> {
+    let mut hasher = ContentHasher::new();
+    hasher.update(block);
+    hasher.finish()
+}
+
+/// One step of rebuilding a new version of a file from the old one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeltaOp {
+    /// `len` bytes from `offset` of the old version.
+    Copy { offset: u64, len: u64 },
+    /// The next `len` bytes of the new version, which the old one doesn't
+    /// have.
+    Literal { len: u64 },
+}
+
+impl DeltaOp {
+    /// How many bytes of the new version this makes.
+    pub fn output_len(&self) -> u64 {
+        match *self {
+            DeltaOp::Copy { len, .. } => len,
+            DeltaOp::Literal { len } => len,
+        }
+    }
+}
+
+/// Works out how to build everything in `reader` from the file `signature`
+/// describes. Literal data isn't kept, only where it is.
+pub fn diff<R>(reader: R, signature: &Signature) -> io::Result<Vec<DeltaOp>>
+    where R: Read,
+{
+    let block_size = signature.block_size as usize;
+    if block_size == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "signature has a block size of 0"));
+    }
+    let mut blocks_by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (n, block) in signature.blocks.iter().enumerate() {
+        blocks_by_weak.entry(block.weak).or_default().push(n);
+    }
+    // Weak checksums collide, so a match needs the strong hash too
+    let find_block = |window: &mut VecDeque<u8>, weak: u32| {
+        let candidates = blocks_by_weak.get(&weak)?;
+        let strong = strong_hash(window.make_contiguous());
+        candidates.iter()
+            .cloned()
+            .find(|&n| signature.blocks[n].strong == strong)
+    };
+
+    let mut ops = Vec::new();
+    let mut bytes = BufReader::new(reader).bytes();
+    let mut window = VecDeque::with_capacity(block_size);
+    let mut checksum: Option<RollingChecksum> = None;
+    loop {
+        if window.len() < block_size {
+            match bytes.next() {
+                Some(byte) => window.push_back(byte?),
+                None => break,
+            }
+            continue;
+        }
+
+        let weak = match checksum {
+            Some(checksum) => checksum.value(),
+            None => {
+                let new_checksum = RollingChecksum::new(window.make_contiguous());
+                checksum = Some(new_checksum);
+                new_checksum.value()
+            }
+        };
+        if let Some(n) = find_block(&mut window, weak) {
+            push_copy(&mut ops, (n * block_size) as u64, block_size as u64);
+            window.clear();
+            checksum = None;
+            continue;
+        }
+
+        // Nothing starts here, so the first byte is new
+        let old = window.pop_front().unwrap();
+        push_literal(&mut ops, 1);
+        match bytes.next() {
+            Some(byte) => {
+                let byte = byte?;
+                window.push_back(byte);
+                checksum.as_mut().unwrap().roll(old, byte);
+            }
+            None => break,
+        }
+    }
+
+    // Too short for a whole block, but it may still be the old last block
+    if !window.is_empty() {
+        let weak = RollingChecksum::new(window.make_contiguous()).value();
+        match find_block(&mut window, weak) {
+            Some(n) => push_copy(&mut ops, (n * block_size) as u64, window.len() as u64),
+            None => push_literal(&mut ops, window.len() as u64),
+        }
+    }
+    Ok(ops)
+}
+
+fn push_copy(ops: &mut Vec<DeltaOp>, offset: u64, len: u64) {
+    if let Some(&mut DeltaOp::Copy { offset: last_offset, len: ref mut last_len }) = ops.last_mut() {
+        if last_offset + *last_len == offset {
+            *last_len += len;
+            return;
+        }
+    }
+    ops.push(DeltaOp::Copy { offset: offset, len: len });
+}
+
+fn push_literal(ops: &mut Vec<DeltaOp>, len: u64) {
+    if let Some(&mut DeltaOp::Literal { len: ref mut last_len }) = ops.last_mut() {
+        *last_len += len;
+        return;
+    }
+    ops.push(DeltaOp::Literal { len: len });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{block_signature, diff, DeltaOp, RollingChecksum};
+    use crate::rpc::Signature;
+
+    fn test_data(len: usize) -> Vec<u8> {
+        let mut state: u32 = 54321;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect()
+    }
+
+    fn signature(data: &[u8], block_size: u32) -> Signature {
+        Signature {
+            block_size: block_size,
+            blocks: data.chunks(block_size as usize).map(block_signature).collect(),
+        }
+    }
+
+    #[test]
+    fn test_rolling_matches_fresh() {
+        let data = test_data(2000);
+
+        let mut checksum = RollingChecksum::new(&data[..1000]);
+        for n in 0..1000 {
+            checksum.roll(data[n], data[n + 1000]);
+            assert_eq!(checksum.value(), RollingChecksum::new(&data[n + 1..n + 1001]).value());
+        }
+    }
+
+    #[test]
+    fn test_unchanged_file_is_one_copy() {
+        let data = test_data(10_500);
+
+        let ops = diff(Cursor::new(&data), &signature(&data, 1024)).unwrap();
+        assert_eq!(ops, vec![DeltaOp::Copy { offset: 0, len: 10_500 }]);
+    }
+
+    #[test]
+    fn test_edit_in_place() {
+        let old = test_data(10_240);
+        let mut new = old.clone();
+        new[5000..5010].copy_from_slice(b"0123456789");
+        new.splice(8000..8000, b"inserted".iter().cloned());
+
+        let ops = diff(Cursor::new(&new), &signature(&old, 1024)).unwrap();
+        assert_eq!(ops, vec![
+            DeltaOp::Copy { offset: 0, len: 4096 },
+            DeltaOp::Literal { len: 1024 },
+            DeltaOp::Copy { offset: 5120, len: 2048 },
+            DeltaOp::Literal { len: 1032 },
+            DeltaOp::Copy { offset: 8192, len: 2048 },
+        ]);
+
+        // The ops rebuild the new version
+        let mut rebuilt = Vec::new();
+        let mut position = 0;
+        for op in ops {
+            match op {
+                DeltaOp::Copy { offset, len } => rebuilt.extend_from_slice(&old[offset as usize..(offset + len) as usize]),
+                DeltaOp::Literal { len } => rebuilt.extend_from_slice(&new[position..position + len as usize]),
+            }
+            position = rebuilt.len();
+        }
+        assert_eq!(rebuilt, new);
+    }
+
+    #[test]
+    fn test_nothing_in_common() {
+        let ops = diff(Cursor::new(test_data(3000)), &signature(&[0; 2048], 1024)).unwrap();
+        assert_eq!(ops, vec![DeltaOp::Literal { len: 3000 }]);
+
+        let ops = diff(Cursor::new(test_data(3000)), &Signature { block_size: 1024, blocks: Vec::new() }).unwrap();
+        assert_eq!(ops, vec![DeltaOp::Literal { len: 3000 }]);
+    }
+}
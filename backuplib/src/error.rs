@@ -0,0 +1,169 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::proto::baacup;
+
+/// Errors returned by the Baacup RPCs.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BaacupError {
+    /// The upload token is unknown or no longer valid.
+    InvalidToken,
+    /// The chunk didn't start where the server's copy of the file ends.
+    BadOffset { expected: u64 },
+    /// The chunk's data didn't match its checksum. Nothing was written.
+    ChecksumMismatch,
+    /// The finished file didn't match the hash given at `init_upload`. What
+    /// was uploaded is thrown away, and the upload starts over at offset 0.
+    ContentHashMismatch,
+    StorageFull,
+    NotFound,
+    PermissionDenied,
+    /// The request had no API key, or one the server doesn't know.
+    Unauthenticated,
+    /// Client and server have no protocol version in common.
+    IncompatibleVersion(String),
+    InvalidArgument(String),
+    Internal(String),
+    /// The request never got a response from the server.
+    Transport(String),
+}
+
+impl BaacupError {
+    /// Whether repeating the request, possibly after resyncing with
+    /// `get_head`, can be expected to succeed.
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            BaacupError::BadOffset { .. } => true,
+            BaacupError::ChecksumMismatch => true,
+            BaacupError::Transport(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for BaacupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BaacupError::InvalidToken => write!(f, "Invalid token"),
+            BaacupError::BadOffset { expected } => write!(f, "Bad offset, expected {}", expected),
+            BaacupError::ChecksumMismatch => write!(f, "Checksum mismatch"),
+            BaacupError::ContentHashMismatch => write!(f, "Content hash mismatch"),
+            BaacupError::StorageFull => write!(f, "Storage full"),
+            BaacupError::NotFound => write!(f, "Not found"),
+            BaacupError::PermissionDenied => write!(f, "Permission denied"),
+            BaacupError::Unauthenticated => write!(f, "Unauthenticated"),
+            BaacupError::IncompatibleVersion(ref message) => write!(f, "Incompatible version: {}", message),
+            BaacupError::InvalidArgument(ref message) => write!(f, "Invalid argument: {}", message),
+            BaacupError::Internal(ref message) => write!(f, "Internal error: {}", message),
+            BaacupError::Transport(ref message) => write!(f, "Transport error: {}", message),
+        }
+    }
+}
+
+impl Error for BaacupError {}
+
+/// Gets the error out of a failed response. Servers that predate the `error`
+/// field only send a message.
+pub(crate) fn response_error(error: baacup::Error, error_message: String) -> BaacupError {
+    if error.get_code() == baacup::ErrorCode::INTERNAL && error.get_message().is_empty() {
+        return BaacupError::Internal(error_message);
+    }
+    error.into()
+}
+
+impl From<grpc::Error> for BaacupError {
+    fn from(error: grpc::Error) -> BaacupError {
+        BaacupError::Transport(error.to_string())
+    }
+}
+
+impl From<BaacupError> for baacup::Error {
+    fn from(error: BaacupError) -> baacup::Error {
+        let mut p = baacup::Error::new();
+        p.set_message(error.to_string());
+        let code = match error {
+            BaacupError::InvalidToken => baacup::ErrorCode::INVALID_TOKEN,
+            BaacupError::BadOffset { expected } => {
+                p.set_expected_offset(expected);
+                baacup::ErrorCode::BAD_OFFSET
+            }
+            BaacupError::ChecksumMismatch => baacup::ErrorCode::CHECKSUM_MISMATCH,
+            BaacupError::ContentHashMismatch => baacup::ErrorCode::CONTENT_HASH_MISMATCH,
+            BaacupError::StorageFull => baacup::ErrorCode::STORAGE_FULL,
+            BaacupError::NotFound => baacup::ErrorCode::NOT_FOUND,
+            BaacupError::PermissionDenied => baacup::ErrorCode::PERMISSION_DENIED,
+            BaacupError::Unauthenticated => baacup::ErrorCode::UNAUTHENTICATED,
+            BaacupError::IncompatibleVersion(message) => {
+                p.set_message(message);
+                baacup::ErrorCode::INCOMPATIBLE_VERSION
+            }
+            BaacupError::InvalidArgument(message) => {
+                p.set_message(message);
+                baacup::ErrorCode::INVALID_ARGUMENT
+            }
+            BaacupError::Internal(message) | BaacupError::Transport(message) => {
+                p.set_message(message);
+                baacup::ErrorCode::INTERNAL
+            }
+        };
+        p.set_code(code);
+        p
+    }
+}
+
+impl From<baacup::Error> for BaacupError {
+    fn from(mut p: baacup::Error) -> BaacupError {
+        match p.get_code() {
+            baacup::ErrorCode::INVALID_TOKEN => BaacupError::InvalidToken,
+            baacup::ErrorCode::BAD_OFFSET => BaacupError::BadOffset { expected: p.get_expected_offset() },
+            baacup::ErrorCode::CHECKSUM_MISMATCH => BaacupError::ChecksumMismatch,
+            baacup::ErrorCode::CONTENT_HASH_MISMATCH => BaacupError::ContentHashMismatch,
+            baacup::ErrorCode::STORAGE_FULL => BaacupError::StorageFull,
+            baacup::ErrorCode::NOT_FOUND => BaacupError::NotFound,
+            baacup::ErrorCode::PERMISSION_DENIED => BaacupError::PermissionDenied,
+            baacup::ErrorCode::UNAUTHENTICATED => BaacupError::Unauthenticated,
+            baacup::ErrorCode::INCOMPATIBLE_VERSION => BaacupError::IncompatibleVersion(p.take_message()),
+            baacup::ErrorCode::INVALID_ARGUMENT => BaacupError::InvalidArgument(p.take_message()),
+            baacup::ErrorCode::INTERNAL => BaacupError::Internal(p.take_message()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BaacupError, response_error};
+    use crate::proto::baacup;
+
+    #[test]
+    fn test_wire_round_trip() {
+        let errors = vec![
+            BaacupError::InvalidToken,
+            BaacupError::BadOffset { expected: 4096 },
+            BaacupError::ChecksumMismatch,
+            BaacupError::StorageFull,
+            BaacupError::Unauthenticated,
+            BaacupError::IncompatibleVersion("protocol 0".into()),
+            BaacupError::InvalidArgument("too long".into()),
+            BaacupError::Internal("disk on fire".into()),
+        ];
+
+        for error in errors {
+            let p: baacup::Error = error.clone().into();
+            assert_eq!(BaacupError::from(p), error);
+        }
+    }
+
+    #[test]
+    fn test_transport_error_arrives_as_internal() {
+        let p: baacup::Error = BaacupError::Transport("reset".into()).into();
+
+        assert_eq!(BaacupError::from(p), BaacupError::Internal("reset".into()));
+    }
+
+    #[test]
+    fn test_message_only_response() {
+        let error = response_error(baacup::Error::new(), "Bad offset".into());
+
+        assert_eq!(error, BaacupError::Internal("Bad offset".into()));
+    }
+}
@@ -0,0 +1,62 @@
+use std::io::{Read, Result as IoResult};
+
+use sha2::{Digest, Sha256};
+
+/// Incrementally computes the SHA-256 content hash of a file.
+pub struct ContentHasher(Sha256);
+
+impl ContentHasher {
+    pub fn new() -> ContentHasher {
+        ContentHasher(Sha256::new())
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.input(data);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.0.result().to_vec()
+    }
+}
+
+/// Hashes everything left in `reader`.
+pub fn content_hash<R>(mut reader: R) -> IoResult<Vec<u8>>
+    where R: Read,
+{
+    let mut hasher = ContentHasher::new();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let bytes = reader.read(&mut buffer)?;
+        if bytes == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.update(&buffer[..bytes]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{content_hash, ContentHasher};
+
+    #[test]
+    fn test_incremental_matches_reader() {
+        let data: Vec<u8> = (0..200_000).map(|n| (n % 251) as u8).collect();
+
+        let mut hasher = ContentHasher::new();
+        for block in data.chunks(1000) {
+            hasher.update(block);
+        }
+
+        assert_eq!(hasher.finish(), content_hash(Cursor::new(&data)).unwrap());
+    }
+
+    #[test]
+    fn test_empty_hash() {
+        let hash = content_hash(Cursor::new(Vec::new())).unwrap();
+
+        assert_eq!(hash[..4], [0xe3, 0xb0, 0xc4, 0x42]);
+        assert_eq!(hash.len(), 32);
+    }
+}
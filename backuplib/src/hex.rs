@@ -0,0 +1,29 @@
+/// Lowercase hex of `bytes`, two digits a byte.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Undoes `to_hex`. Takes either case, but fails on anything else.
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|n| u8::from_str_radix(hex.get(n..n + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_hex, to_hex};
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0x00, 0xab, 0x10]), "00ab10");
+        assert_eq!(from_hex("00ab10"), Some(vec![0x00, 0xab, 0x10]));
+        assert_eq!(from_hex("00a"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+}
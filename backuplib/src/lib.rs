@@ -1,8 +1,17 @@
 pub mod client;
+pub mod compression;
+pub mod convergent;
+pub mod crypto;
+pub mod delta;
+pub mod error;
+pub mod hash;
+pub mod hex;
 pub mod rpc;
+pub mod tls;
 mod proto;
 
 pub use grpc;
+pub use tls_api;
 
 pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
@@ -1,30 +1,798 @@
-use futures::{IntoFuture, Future, Poll};
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use futures::{IntoFuture, Future, Poll, Stream};
 
 use crate::proto::baacup;
 use crate::proto::baacup_grpc;
 pub use crate::proto::baacup_grpc::BaacupServer;
+pub use crate::error::BaacupError;
+
+/// Version of the Baacup protocol this library speaks. Bumped whenever a
+/// change breaks older peers.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest protocol version this library still talks to. Version 1 sent
+/// `last_modified` as 32-bit seconds.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// Optional features a peer may or may not support.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// Chunks carry a CRC32C checksum that the server verifies.
+    Checksums,
+    /// The server checks finished uploads against their SHA-256.
+    ContentHash,
+    /// Stored files can be read back with `read_chunk`.
+    Restore,
+    /// The server keeps the `PosixAttributes` of files.
+    PosixAttributes,
+    /// The server keeps directories, symlinks and special files.
+    EntryTypes,
+    /// Uploads can be sent in one `upload_stream` instead of chunk by chunk.
+    UploadStream,
+    /// Chunks can be compressed with zstd, see `init_compressed_upload`.
+    Zstd,
+    /// Chunks can be shared between files, see `has_chunks`.
+    Dedup,
+    /// Chunks can copy from the version of a file stored before, see
+    /// `get_signature`.
+    Delta,
+    /// Many files can be checked at once, see `files_are_uploaded`.
+    BatchStatus,
+    /// Backup runs can be grouped into snapshots, see `begin_snapshot`, and
+    /// files read back as a snapshot has them with `read_snapshot_chunk`.
+    Snapshots,
+}
+
+impl Capability {
+    pub const ALL: &'static [Capability] = &[
+        Capability::Checksums,
+        Capability::ContentHash,
+        Capability::Restore,
+        Capability::PosixAttributes,
+        Capability::EntryTypes,
+        Capability::UploadStream,
+        Capability::Zstd,
+        Capability::Dedup,
+        Capability::Delta,
+        Capability::BatchStatus,
+        Capability::Snapshots,
+    ];
+
+    /// Name on the wire.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Capability::Checksums => "checksums",
+            Capability::ContentHash => "content_hash",
+            Capability::Restore => "restore",
+            Capability::PosixAttributes => "posix_attributes",
+            Capability::EntryTypes => "entry_types",
+            Capability::UploadStream => "upload_stream",
+            Capability::Zstd => "zstd",
+            Capability::Dedup => "dedup",
+            Capability::Delta => "delta",
+            Capability::BatchStatus => "batch_status",
+            Capability::Snapshots => "snapshots",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Capability> {
+        Capability::ALL.iter()
+            .find(|capability| capability.name() == name)
+            .cloned()
+    }
+}
+
+/// What a peer says about itself in `hello`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hello {
+    pub protocol_version: u32,
+    /// Version of the program on the other end, for humans.
+    pub software_version: String,
+    pub capabilities: Vec<Capability>,
+}
+
+impl Hello {
+    /// Describes this end of the connection.
+    pub fn new(software_version: &str, capabilities: &[Capability]) -> Hello {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            software_version: software_version.to_string(),
+            capabilities: capabilities.to_vec(),
+        }
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    /// Fails unless we can talk to the peer that sent this. A peer with a
+    /// newer protocol decides for itself whether it still speaks ours.
+    pub fn check_compatible(&self) -> Result<(), BaacupError> {
+        if self.protocol_version < MIN_PROTOCOL_VERSION {
+            let message = format!("peer speaks protocol {}, we need at least {}",
+                                  self.protocol_version, MIN_PROTOCOL_VERSION);
+            return Err(BaacupError::IncompatibleVersion(message));
+        }
+        Ok(())
+    }
+}
+
+fn capabilities_from_names(names: &[String]) -> Vec<Capability> {
+    // Capabilities from newer peers mean nothing to us
+    names.iter()
+        .filter_map(|name| Capability::from_name(name))
+        .collect()
+}
+
+fn capability_names(capabilities: &[Capability]) -> Vec<String> {
+    capabilities.iter()
+        .map(|capability| capability.name().to_string())
+        .collect()
+}
+
+impl From<baacup::HelloRequest> for Hello {
+    fn from(mut p: baacup::HelloRequest) -> Hello {
+        Hello {
+            protocol_version: p.get_protocol_version(),
+            software_version: p.take_software_version(),
+            capabilities: capabilities_from_names(p.get_capabilities()),
+        }
+    }
+}
+
+impl From<Hello> for baacup::HelloRequest {
+    fn from(hello: Hello) -> baacup::HelloRequest {
+        let mut hello_request = baacup::HelloRequest::new();
+        hello_request.set_protocol_version(hello.protocol_version);
+        hello_request.set_software_version(hello.software_version);
+        hello_request.set_capabilities(capability_names(&hello.capabilities).into());
+        hello_request
+    }
+}
+
+impl<'a> From<&'a mut baacup::HelloResponse> for Hello {
+    fn from(p: &'a mut baacup::HelloResponse) -> Hello {
+        Hello {
+            protocol_version: p.get_protocol_version(),
+            software_version: p.take_software_version(),
+            capabilities: capabilities_from_names(p.get_capabilities()),
+        }
+    }
+}
+
+/// A point in time with nanosecond precision, relative to the Unix epoch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp {
+    pub seconds: i64,
+    /// Always below one second, also before the epoch.
+    pub nanos: u32,
+}
+
+impl Timestamp {
+    pub fn new(seconds: i64, nanos: u32) -> Timestamp {
+        Timestamp {
+            seconds: seconds,
+            nanos: nanos,
+        }
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Timestamp {
+        match time.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(since_epoch) => Timestamp::new(since_epoch.as_secs() as i64, since_epoch.subsec_nanos()),
+            Err(err) => {
+                // Round down to the second before, and count nanos up from there
+                let before_epoch = err.duration();
+                let mut seconds = -(before_epoch.as_secs() as i64);
+                let mut nanos = before_epoch.subsec_nanos();
+                if nanos > 0 {
+                    seconds -= 1;
+                    nanos = 1_000_000_000 - nanos;
+                }
+                Timestamp::new(seconds, nanos)
+            }
+        }
+    }
+}
+
+impl From<Timestamp> for SystemTime {
+    fn from(timestamp: Timestamp) -> SystemTime {
+        let nanos = Duration::from_nanos(timestamp.nanos as u64);
+        if timestamp.seconds >= 0 {
+            SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp.seconds as u64) + nanos
+        }
+        else {
+            SystemTime::UNIX_EPOCH - Duration::from_secs(timestamp.seconds.unsigned_abs()) + nanos
+        }
+    }
+}
+
+impl From<baacup::Timestamp> for Timestamp {
+    fn from(p: baacup::Timestamp) -> Timestamp {
+        Timestamp::new(p.get_seconds(), p.get_nanos())
+    }
+}
+
+impl From<Timestamp> for baacup::Timestamp {
+    fn from(timestamp: Timestamp) -> baacup::Timestamp {
+        let mut p = baacup::Timestamp::new();
+        p.set_seconds(timestamp.seconds);
+        p.set_nanos(timestamp.nanos);
+        p
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExtendedAttribute {
+    pub name: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+/// Ownership, permissions and the like. POSIX ACLs are among the `xattrs`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PosixAttributes {
+    /// File type and permission bits, as in `st_mode`.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub user_name: Option<String>,
+    pub group_name: Option<String>,
+    pub accessed: Timestamp,
+    /// Last status change, as in `st_ctime`.
+    pub changed: Timestamp,
+    pub xattrs: Vec<ExtendedAttribute>,
+}
+
+fn non_empty(string: String) -> Option<String> {
+    if string.is_empty() { None } else { Some(string) }
+}
+
+fn non_empty_bytes(bytes: Vec<u8>) -> Option<Vec<u8>> {
+    if bytes.is_empty() { None } else { Some(bytes) }
+}
+
+fn copy_range(offset: u64, len: u64) -> Option<CopyRange> {
+    if len == 0 { None } else { Some(CopyRange { offset: offset, len: len }) }
+}
+
+impl From<baacup::PosixAttributes> for PosixAttributes {
+    fn from(mut p: baacup::PosixAttributes) -> PosixAttributes {
+        PosixAttributes {
+            mode: p.get_mode(),
+            uid: p.get_uid(),
+            gid: p.get_gid(),
+            user_name: non_empty(p.take_user_name()),
+            group_name: non_empty(p.take_group_name()),
+            accessed: p.take_accessed().into(),
+            changed: p.take_changed().into(),
+            xattrs: p.take_xattrs()
+                .into_iter()
+                .map(|mut xattr| ExtendedAttribute {
+                    name: xattr.take_name(),
+                    value: xattr.take_value(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<PosixAttributes> for baacup::PosixAttributes {
+    fn from(attributes: PosixAttributes) -> baacup::PosixAttributes {
+        let mut p = baacup::PosixAttributes::new();
+        p.set_mode(attributes.mode);
+        p.set_uid(attributes.uid);
+        p.set_gid(attributes.gid);
+        p.set_user_name(attributes.user_name.unwrap_or_default());
+        p.set_group_name(attributes.group_name.unwrap_or_default());
+        p.set_accessed(attributes.accessed.into());
+        p.set_changed(attributes.changed.into());
+        p.set_xattrs(attributes.xattrs
+            .into_iter()
+            .map(|xattr| {
+                let mut p = baacup::ExtendedAttribute::new();
+                p.set_name(xattr.name);
+                p.set_value(xattr.value);
+                p
+            })
+            .collect());
+        p
+    }
+}
+
+/// What kind of file system entry a `FileMetadata` describes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EntryType {
+    Regular,
+    Directory,
+    /// `target` is the raw path the link points to, which need not exist.
+    Symlink { target: Vec<u8> },
+    Fifo,
+    CharDevice { major: u32, minor: u32 },
+    BlockDevice { major: u32, minor: u32 },
+}
+
+impl EntryType {
+    /// Only regular files have contents to upload.
+    pub fn has_contents(&self) -> bool {
+        *self == EntryType::Regular
+    }
+}
+
+impl Default for EntryType {
+    fn default() -> EntryType {
+        EntryType::Regular
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct FileMetadata {
     pub file_name: String,
-    pub last_modified: u32,
+    pub last_modified: Timestamp,
     pub file_size: u64,
+    pub content_hash: Option<Vec<u8>>,
+    pub posix_attributes: Option<PosixAttributes>,
+    pub entry_type: EntryType,
+}
+
+impl From<baacup::FileMetadata> for FileMetadata {
+    fn from(mut p: baacup::FileMetadata) -> FileMetadata {
+        let content_hash = p.take_content_hash();
+        let posix_attributes = if p.has_posix_attributes() {
+            Some(p.take_posix_attributes().into())
+        }
+        else {
+            None
+        };
+        let entry_type = match p.get_entry_type() {
+            baacup::EntryType::REGULAR => EntryType::Regular,
+            baacup::EntryType::DIRECTORY => EntryType::Directory,
+            baacup::EntryType::SYMLINK => EntryType::Symlink { target: p.take_symlink_target() },
+            baacup::EntryType::FIFO => EntryType::Fifo,
+            baacup::EntryType::CHAR_DEVICE => EntryType::CharDevice {
+                major: p.get_device_major(),
+                minor: p.get_device_minor(),
+            },
+            baacup::EntryType::BLOCK_DEVICE => EntryType::BlockDevice {
+                major: p.get_device_major(),
+                minor: p.get_device_minor(),
+            },
+        };
+        FileMetadata {
+            file_name: p.take_file_name(),
+            last_modified: p.take_last_modified().into(),
+            file_size: p.get_file_size(),
+            content_hash: if content_hash.is_empty() { None } else { Some(content_hash) },
+            posix_attributes: posix_attributes,
+            entry_type: entry_type,
+        }
+    }
+}
+
+impl From<FileMetadata> for baacup::FileMetadata {
+    fn from(metadata: FileMetadata) -> baacup::FileMetadata {
+        let mut file_metadata = baacup::FileMetadata::new();
+        file_metadata.set_file_name(metadata.file_name);
+        file_metadata.set_last_modified(metadata.last_modified.into());
+        file_metadata.set_file_size(metadata.file_size);
+        file_metadata.set_content_hash(metadata.content_hash.unwrap_or_default());
+        if let Some(posix_attributes) = metadata.posix_attributes {
+            file_metadata.set_posix_attributes(posix_attributes.into());
+        }
+        match metadata.entry_type {
+            EntryType::Regular => file_metadata.set_entry_type(baacup::EntryType::REGULAR),
+            EntryType::Directory => file_metadata.set_entry_type(baacup::EntryType::DIRECTORY),
+            EntryType::Symlink { target } => {
+                file_metadata.set_entry_type(baacup::EntryType::SYMLINK);
+                file_metadata.set_symlink_target(target);
+            }
+            EntryType::Fifo => file_metadata.set_entry_type(baacup::EntryType::FIFO),
+            EntryType::CharDevice { major, minor } => {
+                file_metadata.set_entry_type(baacup::EntryType::CHAR_DEVICE);
+                file_metadata.set_device_major(major);
+                file_metadata.set_device_minor(minor);
+            }
+            EntryType::BlockDevice { major, minor } => {
+                file_metadata.set_entry_type(baacup::EntryType::BLOCK_DEVICE);
+                file_metadata.set_device_major(major);
+                file_metadata.set_device_minor(minor);
+            }
+        }
+        file_metadata
+    }
+}
+
+/// What the server knows about a previously uploaded file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UploadStatus {
+    pub is_uploaded: bool,
+    /// Hash recorded when the last upload of the file finished.
+    pub content_hash: Option<Vec<u8>>,
+}
+
+impl From<baacup::FileUploadStatus> for UploadStatus {
+    fn from(mut p: baacup::FileUploadStatus) -> UploadStatus {
+        UploadStatus {
+            is_uploaded: p.get_file_is_uploaded(),
+            content_hash: non_empty_bytes(p.take_content_hash()),
+        }
+    }
+}
+
+impl From<UploadStatus> for baacup::FileUploadStatus {
+    fn from(status: UploadStatus) -> baacup::FileUploadStatus {
+        let mut p = baacup::FileUploadStatus::new();
+        p.set_file_is_uploaded(status.is_uploaded);
+        p.set_content_hash(status.content_hash.unwrap_or_default());
+        p
+    }
+}
+
+/// Identifies an upload started with `init_upload`. Tokens are random, so
+/// they can't be guessed, and only work for the client that got them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct UploadToken([u8; UploadToken::LEN]);
+
+impl UploadToken {
+    pub const LEN: usize = 16;
+
+    pub fn new(bytes: [u8; UploadToken::LEN]) -> UploadToken {
+        UploadToken(bytes)
+    }
+
+    /// Reads a token off the wire.
+    pub fn from_slice(bytes: &[u8]) -> Result<UploadToken, BaacupError> {
+        if bytes.len() != UploadToken::LEN {
+            return Err(BaacupError::InvalidToken);
+        }
+        let mut token = [0; UploadToken::LEN];
+        token.copy_from_slice(bytes);
+        Ok(UploadToken(token))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for UploadToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Identifies a snapshot started with `begin_snapshot`. Like upload tokens,
+/// IDs are random and only work for the client that got them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SnapshotId([u8; SnapshotId::LEN]);
+
+impl SnapshotId {
+    pub const LEN: usize = 16;
+
+    pub fn new(bytes: [u8; SnapshotId::LEN]) -> SnapshotId {
+        SnapshotId(bytes)
+    }
+
+    /// Reads an ID off the wire.
+    pub fn from_slice(bytes: &[u8]) -> Result<SnapshotId, BaacupError> {
+        if bytes.len() != SnapshotId::LEN {
+            return Err(BaacupError::InvalidArgument(format!("Snapshot IDs are {} bytes", SnapshotId::LEN)));
+        }
+        let mut id = [0; SnapshotId::LEN];
+        id.copy_from_slice(bytes);
+        Ok(SnapshotId(id))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for SnapshotId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// The files of one backup run, as recorded with `add_to_snapshot`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub id: SnapshotId,
+    /// Host the backup was taken of.
+    pub host: String,
+    pub started: Timestamp,
+    /// Set once the snapshot is committed.
+    pub finished: Option<Timestamp>,
+    pub file_count: u64,
+}
+
+impl Snapshot {
+    pub fn is_committed(&self) -> bool {
+        self.finished.is_some()
+    }
+}
+
+impl TryFrom<baacup::Snapshot> for Snapshot {
+    type Error = BaacupError;
+
+    fn try_from(mut p: baacup::Snapshot) -> Result<Snapshot, BaacupError> {
+        let finished = if p.has_finished() {
+            Some(p.take_finished().into())
+        }
+        else {
+            None
+        };
+        Ok(Snapshot {
+            id: SnapshotId::from_slice(p.get_id())?,
+            host: p.take_host(),
+            started: p.take_started().into(),
+            finished: finished,
+            file_count: p.get_file_count(),
+        })
+    }
+}
+
+impl From<Snapshot> for baacup::Snapshot {
+    fn from(snapshot: Snapshot) -> baacup::Snapshot {
+        let mut p = baacup::Snapshot::new();
+        p.set_id(snapshot.id.as_bytes().to_vec());
+        p.set_host(snapshot.host);
+        p.set_started(snapshot.started.into());
+        if let Some(finished) = snapshot.finished {
+            p.set_finished(finished.into());
+        }
+        p.set_file_count(snapshot.file_count);
+        p
+    }
+}
+
+/// How chunk data is compressed. Offsets always count uncompressed bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Zstd,
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::None
+    }
+}
+
+impl From<baacup::Compression> for Compression {
+    fn from(p: baacup::Compression) -> Compression {
+        match p {
+            baacup::Compression::NONE => Compression::None,
+            baacup::Compression::ZSTD => Compression::Zstd,
+        }
+    }
+}
+
+impl From<Compression> for baacup::Compression {
+    fn from(compression: Compression) -> baacup::Compression {
+        match compression {
+            Compression::None => baacup::Compression::NONE,
+            Compression::Zstd => baacup::Compression::ZSTD,
+        }
+    }
+}
+
+/// An upload started with `init_compressed_upload`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Upload {
+    pub token: UploadToken,
+    /// What the server accepts for the chunks of this upload. Chunks may
+    /// always be sent uncompressed.
+    pub compression: Compression,
+}
+
+/// Most chunk hashes one `has_chunks` call may ask about.
+pub const MAX_HAS_CHUNKS: usize = 1024;
+
+/// Most files one `files_are_uploaded` call may ask about.
+pub const MAX_FILES_ARE_UPLOADED: usize = 512;
+
+/// Most files one `add_to_snapshot` call may add.
+pub const MAX_ADD_TO_SNAPSHOT: usize = 512;
+
+/// Most bytes one chunk may copy, see `CopyRange`.
+pub const MAX_COPY_LEN: u64 = 4 * 1024 * 1024;
+
+/// Data of the version of a file stored before the current upload began,
+/// which a chunk copies instead of carrying data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CopyRange {
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Checksums of the blocks of a stored file, from `get_signature`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Signature {
+    /// Every block is this long, except maybe the last one.
+    pub block_size: u32,
+    pub blocks: Vec<BlockSignature>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockSignature {
+    /// Rolling checksum, see `delta::RollingChecksum`.
+    pub weak: u32,
+    /// SHA-256 of the block.
+    pub strong: Vec<u8>,
+}
+
+impl From<baacup::BlockSignature> for BlockSignature {
+    fn from(mut p: baacup::BlockSignature) -> BlockSignature {
+        BlockSignature {
+            weak: p.get_weak(),
+            strong: p.take_strong(),
+        }
+    }
+}
+
+impl From<BlockSignature> for baacup::BlockSignature {
+    fn from(block: BlockSignature) -> baacup::BlockSignature {
+        let mut p = baacup::BlockSignature::new();
+        p.set_weak(block.weak);
+        p.set_strong(block.strong);
+        p
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct FileChunk {
-    pub token: u32,
+    pub token: UploadToken,
+    /// Where the uncompressed data goes.
+    pub offset: u64,
+    pub data: Vec<u8>,
+    /// CRC32C of `data`, as sent.
+    pub checksum: u32,
+    pub compression: Compression,
+    /// SHA-256 of the uncompressed data, to share it with other files. With
+    /// empty `data`, stands for the chunk the server has under this hash.
+    pub chunk_hash: Option<Vec<u8>>,
+    /// Set instead of `data` to copy from the previous version of the file.
+    pub copy: Option<CopyRange>,
+}
+
+impl FileChunk {
+    /// Makes a chunk with the checksum computed from `data`.
+    pub fn new(token: UploadToken, offset: u64, data: Vec<u8>) -> FileChunk {
+        FileChunk::compressed(token, offset, data, Compression::None)
+    }
+
+    /// Makes a chunk of `data` compressed with `compression`.
+    pub fn compressed(token: UploadToken, offset: u64, data: Vec<u8>, compression: Compression) -> FileChunk {
+        let checksum = checksum(&data);
+        FileChunk {
+            token: token,
+            offset: offset,
+            data: data,
+            checksum: checksum,
+            compression: compression,
+            chunk_hash: None,
+            copy: None,
+        }
+    }
+
+    /// Makes a chunk that copies `copy` from the previous version of the
+    /// file.
+    pub fn copy(token: UploadToken, offset: u64, copy: CopyRange) -> FileChunk {
+        FileChunk {
+            copy: Some(copy),
+            ..FileChunk::new(token, offset, Vec::new())
+        }
+    }
+
+    /// Shares the chunk with other files under `chunk_hash`.
+    pub fn with_chunk_hash(mut self, chunk_hash: Vec<u8>) -> FileChunk {
+        self.chunk_hash = Some(chunk_hash);
+        self
+    }
+
+    pub fn checksum_is_valid(&self) -> bool {
+        checksum(&self.data) == self.checksum
+    }
+}
+
+/// Starts an `upload_stream`. The chunks that follow continue the upload
+/// with `token` at `offset`, which must be the server's head.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StreamHeader {
+    pub token: UploadToken,
     pub offset: u64,
+}
+
+impl StreamHeader {
+    pub fn new(token: UploadToken, offset: u64) -> StreamHeader {
+        StreamHeader {
+            token: token,
+            offset: offset,
+        }
+    }
+}
+
+/// Data sent in an `upload_stream`. It goes right after the chunk before.
+#[derive(Clone, Debug)]
+pub struct DataChunk {
     pub data: Vec<u8>,
+    /// CRC32C of `data`, as sent.
+    pub checksum: u32,
+    pub compression: Compression,
+    /// As in `FileChunk`.
+    pub chunk_hash: Option<Vec<u8>>,
+    pub copy: Option<CopyRange>,
 }
 
-pub struct BaacupFuture<T: Send + 'static>(Box<dyn Future<Item = T, Error = String> + Send>);
+impl DataChunk {
+    /// Makes a chunk with the checksum computed from `data`.
+    pub fn new(data: Vec<u8>) -> DataChunk {
+        DataChunk::compressed(data, Compression::None)
+    }
+
+    /// Makes a chunk of `data` compressed with `compression`.
+    pub fn compressed(data: Vec<u8>, compression: Compression) -> DataChunk {
+        let checksum = checksum(&data);
+        DataChunk {
+            data: data,
+            checksum: checksum,
+            compression: compression,
+            chunk_hash: None,
+            copy: None,
+        }
+    }
+
+    /// Makes a chunk that copies `copy` from the previous version of the
+    /// file.
+    pub fn copy(copy: CopyRange) -> DataChunk {
+        DataChunk {
+            copy: Some(copy),
+            ..DataChunk::new(Vec::new())
+        }
+    }
+
+    /// Shares the chunk with other files under `chunk_hash`.
+    pub fn with_chunk_hash(mut self, chunk_hash: Vec<u8>) -> DataChunk {
+        self.chunk_hash = Some(chunk_hash);
+        self
+    }
+
+    pub fn checksum_is_valid(&self) -> bool {
+        checksum(&self.data) == self.checksum
+    }
+}
+
+/// Where an `upload_stream` left the upload.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamResult {
+    /// Length of the server's copy of the file.
+    pub offset: u64,
+    /// Set if the stream finished the upload.
+    pub content_hash: Option<Vec<u8>>,
+}
+
+/// Computes the CRC32C checksum used for chunk data.
+pub fn checksum(data: &[u8]) -> u32 {
+    crc32c::crc32c(data)
+}
+
+pub struct BaacupFuture<T: Send + 'static>(Box<dyn Future<Item = T, Error = BaacupError> + Send>);
 
 impl<T> BaacupFuture<T>
     where T: Send + 'static,
 {
     pub fn new<F>(future: F) -> BaacupFuture<T>
-        where F: IntoFuture<Item = T, Error = String>,
+        where F: IntoFuture<Item = T, Error = BaacupError>,
               F::Future: Send + 'static,
     {
         BaacupFuture(Box::new(future.into_future()))
@@ -35,43 +803,178 @@ impl<T> Future for BaacupFuture<T>
     where T: Send,
 {
     type Item = T;
-    type Error = String;
+    type Error = BaacupError;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         self.0.poll()
     }
 }
 
+pub struct BaacupStream<T: Send + 'static>(Box<dyn Stream<Item = T, Error = BaacupError> + Send>);
+
+impl<T> BaacupStream<T>
+    where T: Send + 'static,
+{
+    pub fn new<S>(stream: S) -> BaacupStream<T>
+        where S: Stream<Item = T, Error = BaacupError> + Send + 'static,
+    {
+        BaacupStream(Box::new(stream))
+    }
+}
+
+impl<T> Stream for BaacupStream<T>
+    where T: Send,
+{
+    type Item = T;
+    type Error = BaacupError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.0.poll()
+    }
+}
+
+/// gRPC metadata key a client's API key is sent under.
+pub const API_KEY_METADATA: &str = "x-api-key";
+
+/// gRPC metadata key a client sends the channel binding of its TLS
+/// connection under, see `tls::ChannelBinding`.
+pub const CHANNEL_BINDING_METADATA: &str = "x-channel-binding-bin";
+
+/// What a caller sent to identify itself.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Credentials {
+    pub api_key: Option<String>,
+    pub channel_binding: Option<Vec<u8>>,
+}
+
+impl<'a> From<&'a grpc::Metadata> for Credentials {
+    fn from(metadata: &'a grpc::Metadata) -> Credentials {
+        Credentials {
+            api_key: metadata.get(API_KEY_METADATA)
+                .and_then(|api_key| String::from_utf8(api_key.to_vec()).ok()),
+            channel_binding: metadata.get(CHANNEL_BINDING_METADATA)
+                .map(|binding| binding.to_vec()),
+        }
+    }
+}
+
 pub trait Baacup {
-    fn init_upload(&self, metadata: FileMetadata) -> BaacupFuture<u32>;
-    fn get_head(&self, token: u32) -> BaacupFuture<u64>;
+    /// Tells the other end who we are and learns the same about it.
+    fn hello(&self, hello: Hello) -> BaacupFuture<Hello>;
+    fn init_upload(&self, metadata: FileMetadata) -> BaacupFuture<UploadToken> {
+        BaacupFuture::new(self.init_compressed_upload(metadata, Compression::None)
+            .map(|upload| upload.token))
+    }
+    /// Starts an upload whose chunks the client would like to compress with
+    /// `compression`. The server answers with what it accepts.
+    fn init_compressed_upload(&self, metadata: FileMetadata, compression: Compression) -> BaacupFuture<Upload>;
+    fn get_head(&self, token: UploadToken) -> BaacupFuture<u64>;
     fn upload_chunk(&self, chunk: FileChunk) -> BaacupFuture<u32>;
-    fn file_is_uploaded(&self, metadata: FileMetadata) -> BaacupFuture<bool>;
+    /// Appends all of `chunks` to an upload at once. If this fails part of
+    /// the way, `get_head` tells where to go on from.
+    fn upload_stream(&self, header: StreamHeader, chunks: BaacupStream<DataChunk>) -> BaacupFuture<StreamResult>;
+    fn file_is_uploaded(&self, metadata: FileMetadata) -> BaacupFuture<UploadStatus>;
+    /// `file_is_uploaded` for each of `files`, in one round-trip. At most
+    /// `MAX_FILES_ARE_UPLOADED`.
+    fn files_are_uploaded(&self, files: Vec<FileMetadata>) -> BaacupFuture<Vec<UploadStatus>>;
+    /// Tells for each of the SHA-256 `chunk_hashes` whether this client sent
+    /// the server that chunk before, so it needn't be sent again. Chunks
+    /// only other clients sent don't count. At most `MAX_HAS_CHUNKS`.
+    fn has_chunks(&self, chunk_hashes: Vec<Vec<u8>>) -> BaacupFuture<Vec<bool>>;
+    /// Gets the block checksums of the stored version of `file_name`, for
+    /// an upload of a new version that copies what hasn't changed.
+    fn get_signature(&self, file_name: String) -> BaacupFuture<Signature>;
+    fn list_files(&self) -> BaacupFuture<Vec<FileMetadata>>;
+    fn read_chunk(&self, file_name: String, offset: u64, len: u64) -> BaacupFuture<Vec<u8>>;
+    /// Starts a snapshot of `host`. Nothing in it is visible until
+    /// `commit_snapshot`.
+    fn begin_snapshot(&self, host: String) -> BaacupFuture<SnapshotId>;
+    /// Records the stored version of each of `file_names` in a snapshot
+    /// that isn't committed yet. The files must have finished uploading.
+    /// At most `MAX_ADD_TO_SNAPSHOT`.
+    fn add_to_snapshot(&self, snapshot_id: SnapshotId, file_names: Vec<String>) -> BaacupFuture<()>;
+    /// Ends a snapshot, which makes it visible to restores.
+    fn commit_snapshot(&self, snapshot_id: SnapshotId) -> BaacupFuture<Snapshot>;
+    /// Gets the committed snapshots, oldest first.
+    fn list_snapshots(&self) -> BaacupFuture<Vec<Snapshot>>;
+    /// Like `list_files`, but lists the files as a committed snapshot
+    /// recorded them.
+    fn list_snapshot_files(&self, snapshot_id: SnapshotId) -> BaacupFuture<Vec<FileMetadata>>;
+    /// Like `read_chunk`, but reads a file as a committed snapshot recorded
+    /// it.
+    fn read_snapshot_chunk(&self, snapshot_id: SnapshotId, file_name: String, offset: u64, len: u64) -> BaacupFuture<Vec<u8>>;
+}
+
+/// Server side entry point: each request is checked before it reaches the
+/// `Baacup` implementation.
+pub trait Authenticate {
+    /// The service as seen by one authenticated client. Streaming calls
+    /// carry it along until the stream ends.
+    type Session: Baacup + Send + 'static;
+
+    fn authenticate(&self, credentials: &Credentials) -> Result<Self::Session, BaacupError>;
+}
+
+/// Authenticates the request and hands the resulting session to `call`.
+fn authenticated<T, R, F>(service: &T, options: &grpc::RequestOptions, call: F) -> BaacupFuture<R>
+    where T: Authenticate,
+          R: Send + 'static,
+          F: FnOnce(T::Session) -> BaacupFuture<R>,
+{
+    match service.authenticate(&Credentials::from(&options.metadata)) {
+        Ok(session) => call(session),
+        Err(error) => BaacupFuture::new(Err(error)),
+    }
 }
 
 impl<T> baacup_grpc::Baacup for T
-    where T: Baacup
+    where T: Authenticate
 {
-    fn init_upload(&self, _o: grpc::RequestOptions, mut p: baacup::FileMetadata) -> grpc::SingleResponse<baacup::InitUploadResponse> {
-        let metadata = FileMetadata {
-            file_name: p.take_file_name(),
-            last_modified: p.get_last_modified(),
-            file_size: p.get_file_size(),
-        };
+    fn hello(&self, o: grpc::RequestOptions, p: baacup::HelloRequest) -> grpc::SingleResponse<baacup::HelloResponse> {
+        let hello = Hello::from(p);
+
+        grpc::SingleResponse::no_metadata(authenticated(self, &o, |session| Baacup::hello(&session, hello))
+            .then(|future_result| {
+                match future_result {
+                    Ok(hello) => {
+                        let mut hello_response = baacup::HelloResponse::new();
+                        hello_response.set_status(baacup::Status::SUCCESS);
+                        hello_response.set_protocol_version(hello.protocol_version);
+                        hello_response.set_software_version(hello.software_version);
+                        hello_response.set_capabilities(capability_names(&hello.capabilities).into());
+                        Ok(hello_response)
+                    }
+                    Err(error) => {
+                        let mut hello_response = baacup::HelloResponse::new();
+                        hello_response.set_status(baacup::Status::ERROR);
+                        hello_response.set_error_message(error.to_string());
+                        hello_response.set_error(error.into());
+                        Ok(hello_response)
+                    }
+                }
+            })
+        )
+    }
+
+    fn init_upload(&self, o: grpc::RequestOptions, p: baacup::FileMetadata) -> grpc::SingleResponse<baacup::InitUploadResponse> {
+        let compression = Compression::from(p.get_compression());
+        let metadata = FileMetadata::from(p);
 
-        grpc::SingleResponse::no_metadata(Baacup::init_upload(self, metadata)
+        grpc::SingleResponse::no_metadata(authenticated(self, &o, |session| Baacup::init_compressed_upload(&session, metadata, compression))
             .then(|future_result| {
                 match future_result {
-                    Ok(token) => {
+                    Ok(upload) => {
                         let mut init_upload_response = baacup::InitUploadResponse::new();
                         init_upload_response.set_status(baacup::Status::SUCCESS);
-                        init_upload_response.mut_token().set_token(token);
+                        init_upload_response.mut_token().set_token(upload.token.as_bytes().to_vec());
+                        init_upload_response.set_compression(upload.compression.into());
                         Ok(init_upload_response)
                     }
                     Err(error) => {
                         let mut init_upload_response = baacup::InitUploadResponse::new();
                         init_upload_response.set_status(baacup::Status::ERROR);
-                        init_upload_response.set_error_message(error);
+                        init_upload_response.set_error_message(error.to_string());
+                        init_upload_response.set_error(error.into());
                         Ok(init_upload_response)
                     }
                 }
@@ -79,10 +982,13 @@ impl<T> baacup_grpc::Baacup for T
         )
     }
 
-    fn get_head(&self, _o: grpc::RequestOptions, p: baacup::UploadToken) -> grpc::SingleResponse<baacup::FileHead> {
-        let token = p.get_token();
+    fn get_head(&self, o: grpc::RequestOptions, p: baacup::UploadToken) -> grpc::SingleResponse<baacup::FileHead> {
+        let token = UploadToken::from_slice(p.get_token());
 
-        grpc::SingleResponse::no_metadata(Baacup::get_head(self, token)
+        grpc::SingleResponse::no_metadata(authenticated(self, &o, |session| match token {
+                Ok(token) => Baacup::get_head(&session, token),
+                Err(error) => BaacupFuture::new(Err(error)),
+            })
             .then(|future_result| {
                 match future_result {
                     Ok(offset) => {
@@ -94,7 +1000,8 @@ impl<T> baacup_grpc::Baacup for T
                     Err(error) => {
                         let mut file_head = baacup::FileHead::new();
                         file_head.set_status(baacup::Status::ERROR);
-                        file_head.set_error_message(error);
+                        file_head.set_error_message(error.to_string());
+                        file_head.set_error(error.into());
                         Ok(file_head)
                     }
                 }
@@ -102,14 +1009,21 @@ impl<T> baacup_grpc::Baacup for T
         )
     }
 
-    fn upload_chunk(&self, _o: grpc::RequestOptions, mut p: baacup::FileChunk) -> grpc::SingleResponse<baacup::UploadFileResponse> {
-        let file_chunk = FileChunk {
-            token: p.get_token(),
+    fn upload_chunk(&self, o: grpc::RequestOptions, mut p: baacup::FileChunk) -> grpc::SingleResponse<baacup::UploadFileResponse> {
+        let file_chunk = UploadToken::from_slice(p.get_token()).map(|token| FileChunk {
+            token: token,
             offset: p.get_offset(),
             data: p.take_data(),
-        };
+            checksum: p.get_checksum(),
+            compression: p.get_compression().into(),
+            chunk_hash: non_empty_bytes(p.take_chunk_hash()),
+            copy: copy_range(p.get_copy_offset(), p.get_copy_len()),
+        });
 
-        grpc::SingleResponse::no_metadata(Baacup::upload_chunk(self, file_chunk)
+        grpc::SingleResponse::no_metadata(authenticated(self, &o, |session| match file_chunk {
+                Ok(file_chunk) => Baacup::upload_chunk(&session, file_chunk),
+                Err(error) => BaacupFuture::new(Err(error)),
+            })
             .then(|future_result| {
                 match future_result {
                     Ok(checksum) => {
@@ -121,7 +1035,8 @@ impl<T> baacup_grpc::Baacup for T
                     Err(error) => {
                         let mut upload_file_response = baacup::UploadFileResponse::new();
                         upload_file_response.set_status(baacup::Status::ERROR);
-                        upload_file_response.set_error_message(error);
+                        upload_file_response.set_error_message(error.to_string());
+                        upload_file_response.set_error(error.into());
                         Ok(upload_file_response)
                     }
                 }
@@ -129,30 +1044,409 @@ impl<T> baacup_grpc::Baacup for T
         )
     }
 
-    fn file_is_uploaded(&self, _o: grpc::RequestOptions, mut p: baacup::FileMetadata) -> grpc::SingleResponse<baacup::FileIsUploadedResponse> {
-        let metadata = FileMetadata {
-            file_name: p.take_file_name(),
-            last_modified: p.get_last_modified(),
-            file_size: p.get_file_size(),
-        };
+    fn upload_stream(&self, o: grpc::RequestOptions, p: grpc::StreamingRequest<baacup::UploadStreamRequest>) -> grpc::SingleResponse<baacup::UploadStreamResponse> {
+        let requests = p.0.map_err(BaacupError::from);
+
+        grpc::SingleResponse::no_metadata(authenticated(self, &o, |session| {
+                // The header comes first, then nothing but chunks
+                BaacupFuture::new(requests.into_future()
+                    .map_err(|(error, _requests)| error)
+                    .and_then(move |(first, requests)| {
+                        let header = match first {
+                            Some(mut request) if request.has_header() => {
+                                let header = request.take_header();
+                                StreamHeader::new(UploadToken::from_slice(header.get_token())?, header.get_offset())
+                            }
+                            _ => return Err(BaacupError::InvalidArgument("Upload stream must start with a header".into())),
+                        };
+                        let chunks = requests.and_then(|mut request| {
+                            if !request.has_chunk() {
+                                return Err(BaacupError::InvalidArgument("Upload stream has more than one header".into()));
+                            }
+                            let mut chunk = request.take_chunk();
+                            Ok(DataChunk {
+                                data: chunk.take_data(),
+                                checksum: chunk.get_checksum(),
+                                compression: chunk.get_compression().into(),
+                                chunk_hash: non_empty_bytes(chunk.take_chunk_hash()),
+                                copy: copy_range(chunk.get_copy_offset(), chunk.get_copy_len()),
+                            })
+                        });
+                        Ok(Baacup::upload_stream(&session, header, BaacupStream::new(chunks)))
+                    })
+                    .flatten())
+            })
+            .then(|future_result| {
+                match future_result {
+                    Ok(stream_result) => {
+                        let mut upload_stream_response = baacup::UploadStreamResponse::new();
+                        upload_stream_response.set_status(baacup::Status::SUCCESS);
+                        upload_stream_response.set_offset(stream_result.offset);
+                        upload_stream_response.set_content_hash(stream_result.content_hash.unwrap_or_default());
+                        Ok(upload_stream_response)
+                    }
+                    Err(error) => {
+                        let mut upload_stream_response = baacup::UploadStreamResponse::new();
+                        upload_stream_response.set_status(baacup::Status::ERROR);
+                        upload_stream_response.set_error_message(error.to_string());
+                        upload_stream_response.set_error(error.into());
+                        Ok(upload_stream_response)
+                    }
+                }
+            })
+        )
+    }
+
+    fn file_is_uploaded(&self, o: grpc::RequestOptions, p: baacup::FileMetadata) -> grpc::SingleResponse<baacup::FileIsUploadedResponse> {
+        let metadata = FileMetadata::from(p);
 
-        grpc::SingleResponse::no_metadata(Baacup::file_is_uploaded(self, metadata)
+        grpc::SingleResponse::no_metadata(authenticated(self, &o, |session| Baacup::file_is_uploaded(&session, metadata))
             .then(|future_result| {
                 match future_result {
-                    Ok(is_uploaded) => {
+                    Ok(upload_status) => {
                         let mut file_is_uploaded_response = baacup::FileIsUploadedResponse::new();
                         file_is_uploaded_response.set_status(baacup::Status::SUCCESS);
-                        file_is_uploaded_response.set_file_is_uploaded(is_uploaded);
+                        file_is_uploaded_response.set_file_is_uploaded(upload_status.is_uploaded);
+                        file_is_uploaded_response.set_content_hash(upload_status.content_hash.unwrap_or_default());
                         Ok(file_is_uploaded_response)
                     }
                     Err(error) => {
                         let mut file_is_uploaded_response = baacup::FileIsUploadedResponse::new();
                         file_is_uploaded_response.set_status(baacup::Status::ERROR);
-                        file_is_uploaded_response.set_error_message(error);
+                        file_is_uploaded_response.set_error_message(error.to_string());
+                        file_is_uploaded_response.set_error(error.into());
                         Ok(file_is_uploaded_response)
                     }
                 }
             })
         )
     }
+
+    fn files_are_uploaded(&self, o: grpc::RequestOptions, mut p: baacup::FilesAreUploadedRequest) -> grpc::SingleResponse<baacup::FilesAreUploadedResponse> {
+        let files = p.take_files().into_iter().map(FileMetadata::from).collect();
+
+        grpc::SingleResponse::no_metadata(authenticated(self, &o, |session| Baacup::files_are_uploaded(&session, files))
+            .then(|future_result| {
+                match future_result {
+                    Ok(statuses) => {
+                        let mut files_are_uploaded_response = baacup::FilesAreUploadedResponse::new();
+                        files_are_uploaded_response.set_status(baacup::Status::SUCCESS);
+                        files_are_uploaded_response.set_statuses(statuses.into_iter().map(baacup::FileUploadStatus::from).collect());
+                        Ok(files_are_uploaded_response)
+                    }
+                    Err(error) => {
+                        let mut files_are_uploaded_response = baacup::FilesAreUploadedResponse::new();
+                        files_are_uploaded_response.set_status(baacup::Status::ERROR);
+                        files_are_uploaded_response.set_error_message(error.to_string());
+                        files_are_uploaded_response.set_error(error.into());
+                        Ok(files_are_uploaded_response)
+                    }
+                }
+            })
+        )
+    }
+
+    fn has_chunks(&self, o: grpc::RequestOptions, mut p: baacup::HasChunksRequest) -> grpc::SingleResponse<baacup::HasChunksResponse> {
+        let chunk_hashes = p.take_chunk_hashes().into_vec();
+
+        grpc::SingleResponse::no_metadata(authenticated(self, &o, |session| Baacup::has_chunks(&session, chunk_hashes))
+            .then(|future_result| {
+                match future_result {
+                    Ok(has_chunks) => {
+                        let mut has_chunks_response = baacup::HasChunksResponse::new();
+                        has_chunks_response.set_status(baacup::Status::SUCCESS);
+                        has_chunks_response.set_has_chunks(has_chunks);
+                        Ok(has_chunks_response)
+                    }
+                    Err(error) => {
+                        let mut has_chunks_response = baacup::HasChunksResponse::new();
+                        has_chunks_response.set_status(baacup::Status::ERROR);
+                        has_chunks_response.set_error_message(error.to_string());
+                        has_chunks_response.set_error(error.into());
+                        Ok(has_chunks_response)
+                    }
+                }
+            })
+        )
+    }
+
+    fn get_signature(&self, o: grpc::RequestOptions, mut p: baacup::GetSignatureRequest) -> grpc::SingleResponse<baacup::GetSignatureResponse> {
+        let file_name = p.take_file_name();
+
+        grpc::SingleResponse::no_metadata(authenticated(self, &o, |session| Baacup::get_signature(&session, file_name))
+            .then(|future_result| {
+                match future_result {
+                    Ok(signature) => {
+                        let mut signature_response = baacup::GetSignatureResponse::new();
+                        signature_response.set_status(baacup::Status::SUCCESS);
+                        signature_response.set_block_size(signature.block_size);
+                        signature_response.set_blocks(signature.blocks.into_iter().map(baacup::BlockSignature::from).collect());
+                        Ok(signature_response)
+                    }
+                    Err(error) => {
+                        let mut signature_response = baacup::GetSignatureResponse::new();
+                        signature_response.set_status(baacup::Status::ERROR);
+                        signature_response.set_error_message(error.to_string());
+                        signature_response.set_error(error.into());
+                        Ok(signature_response)
+                    }
+                }
+            })
+        )
+    }
+
+    fn list_files(&self, o: grpc::RequestOptions, p: baacup::ListFilesRequest) -> grpc::SingleResponse<baacup::ListFilesResponse> {
+        let snapshot_id = match p.get_snapshot_id() {
+            b"" => Ok(None),
+            snapshot_id => SnapshotId::from_slice(snapshot_id).map(Some),
+        };
+
+        grpc::SingleResponse::no_metadata(authenticated(self, &o, |session| match snapshot_id {
+                Ok(Some(snapshot_id)) => Baacup::list_snapshot_files(&session, snapshot_id),
+                Ok(None) => Baacup::list_files(&session),
+                Err(error) => BaacupFuture::new(Err(error)),
+            })
+            .then(|future_result| {
+                match future_result {
+                    Ok(files) => {
+                        let mut list_files_response = baacup::ListFilesResponse::new();
+                        list_files_response.set_status(baacup::Status::SUCCESS);
+                        for metadata in files {
+                            list_files_response.mut_files().push(metadata.into());
+                        }
+                        Ok(list_files_response)
+                    }
+                    Err(error) => {
+                        let mut list_files_response = baacup::ListFilesResponse::new();
+                        list_files_response.set_status(baacup::Status::ERROR);
+                        list_files_response.set_error_message(error.to_string());
+                        list_files_response.set_error(error.into());
+                        Ok(list_files_response)
+                    }
+                }
+            })
+        )
+    }
+
+    fn download_chunk(&self, o: grpc::RequestOptions, mut p: baacup::DownloadChunkRequest) -> grpc::SingleResponse<baacup::DownloadChunkResponse> {
+        let file_name = p.take_file_name();
+        let offset = p.get_offset();
+        let len = p.get_len();
+        let snapshot_id = match p.get_snapshot_id() {
+            b"" => Ok(None),
+            snapshot_id => SnapshotId::from_slice(snapshot_id).map(Some),
+        };
+
+        grpc::SingleResponse::no_metadata(authenticated(self, &o, |session| match snapshot_id {
+                Ok(Some(snapshot_id)) => Baacup::read_snapshot_chunk(&session, snapshot_id, file_name, offset, len),
+                Ok(None) => Baacup::read_chunk(&session, file_name, offset, len),
+                Err(error) => BaacupFuture::new(Err(error)),
+            })
+            .then(|future_result| {
+                match future_result {
+                    Ok(data) => {
+                        let mut download_chunk_response = baacup::DownloadChunkResponse::new();
+                        download_chunk_response.set_status(baacup::Status::SUCCESS);
+                        download_chunk_response.set_data(data);
+                        Ok(download_chunk_response)
+                    }
+                    Err(error) => {
+                        let mut download_chunk_response = baacup::DownloadChunkResponse::new();
+                        download_chunk_response.set_status(baacup::Status::ERROR);
+                        download_chunk_response.set_error_message(error.to_string());
+                        download_chunk_response.set_error(error.into());
+                        Ok(download_chunk_response)
+                    }
+                }
+            })
+        )
+    }
+
+    fn begin_snapshot(&self, o: grpc::RequestOptions, mut p: baacup::BeginSnapshotRequest) -> grpc::SingleResponse<baacup::BeginSnapshotResponse> {
+        let host = p.take_host();
+
+        grpc::SingleResponse::no_metadata(authenticated(self, &o, |session| Baacup::begin_snapshot(&session, host))
+            .then(|future_result| {
+                match future_result {
+                    Ok(snapshot_id) => {
+                        let mut begin_snapshot_response = baacup::BeginSnapshotResponse::new();
+                        begin_snapshot_response.set_status(baacup::Status::SUCCESS);
+                        begin_snapshot_response.set_snapshot_id(snapshot_id.as_bytes().to_vec());
+                        Ok(begin_snapshot_response)
+                    }
+                    Err(error) => {
+                        let mut begin_snapshot_response = baacup::BeginSnapshotResponse::new();
+                        begin_snapshot_response.set_status(baacup::Status::ERROR);
+                        begin_snapshot_response.set_error_message(error.to_string());
+                        begin_snapshot_response.set_error(error.into());
+                        Ok(begin_snapshot_response)
+                    }
+                }
+            })
+        )
+    }
+
+    fn add_to_snapshot(&self, o: grpc::RequestOptions, mut p: baacup::AddToSnapshotRequest) -> grpc::SingleResponse<baacup::AddToSnapshotResponse> {
+        let snapshot_id = SnapshotId::from_slice(p.get_snapshot_id());
+        let file_names = p.take_file_names().into_vec();
+
+        grpc::SingleResponse::no_metadata(authenticated(self, &o, |session| match snapshot_id {
+                Ok(snapshot_id) => Baacup::add_to_snapshot(&session, snapshot_id, file_names),
+                Err(error) => BaacupFuture::new(Err(error)),
+            })
+            .then(|future_result| {
+                match future_result {
+                    Ok(()) => {
+                        let mut add_to_snapshot_response = baacup::AddToSnapshotResponse::new();
+                        add_to_snapshot_response.set_status(baacup::Status::SUCCESS);
+                        Ok(add_to_snapshot_response)
+                    }
+                    Err(error) => {
+                        let mut add_to_snapshot_response = baacup::AddToSnapshotResponse::new();
+                        add_to_snapshot_response.set_status(baacup::Status::ERROR);
+                        add_to_snapshot_response.set_error_message(error.to_string());
+                        add_to_snapshot_response.set_error(error.into());
+                        Ok(add_to_snapshot_response)
+                    }
+                }
+            })
+        )
+    }
+
+    fn commit_snapshot(&self, o: grpc::RequestOptions, p: baacup::CommitSnapshotRequest) -> grpc::SingleResponse<baacup::CommitSnapshotResponse> {
+        let snapshot_id = SnapshotId::from_slice(p.get_snapshot_id());
+
+        grpc::SingleResponse::no_metadata(authenticated(self, &o, |session| match snapshot_id {
+                Ok(snapshot_id) => Baacup::commit_snapshot(&session, snapshot_id),
+                Err(error) => BaacupFuture::new(Err(error)),
+            })
+            .then(|future_result| {
+                match future_result {
+                    Ok(snapshot) => {
+                        let mut commit_snapshot_response = baacup::CommitSnapshotResponse::new();
+                        commit_snapshot_response.set_status(baacup::Status::SUCCESS);
+                        commit_snapshot_response.set_snapshot(snapshot.into());
+                        Ok(commit_snapshot_response)
+                    }
+                    Err(error) => {
+                        let mut commit_snapshot_response = baacup::CommitSnapshotResponse::new();
+                        commit_snapshot_response.set_status(baacup::Status::ERROR);
+                        commit_snapshot_response.set_error_message(error.to_string());
+                        commit_snapshot_response.set_error(error.into());
+                        Ok(commit_snapshot_response)
+                    }
+                }
+            })
+        )
+    }
+
+    fn list_snapshots(&self, o: grpc::RequestOptions, _p: baacup::ListSnapshotsRequest) -> grpc::SingleResponse<baacup::ListSnapshotsResponse> {
+        grpc::SingleResponse::no_metadata(authenticated(self, &o, |session| Baacup::list_snapshots(&session))
+            .then(|future_result| {
+                match future_result {
+                    Ok(snapshots) => {
+                        let mut list_snapshots_response = baacup::ListSnapshotsResponse::new();
+                        list_snapshots_response.set_status(baacup::Status::SUCCESS);
+                        list_snapshots_response.set_snapshots(snapshots.into_iter().map(baacup::Snapshot::from).collect());
+                        Ok(list_snapshots_response)
+                    }
+                    Err(error) => {
+                        let mut list_snapshots_response = baacup::ListSnapshotsResponse::new();
+                        list_snapshots_response.set_status(baacup::Status::ERROR);
+                        list_snapshots_response.set_error_message(error.to_string());
+                        list_snapshots_response.set_error(error.into());
+                        Ok(list_snapshots_response)
+                    }
+                }
+            })
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::proto::baacup;
+    use super::{EntryType, ExtendedAttribute, FileMetadata, PosixAttributes, Timestamp};
+
+    #[test]
+    fn test_timestamp_round_trip() {
+        let times = vec![
+            SystemTime::UNIX_EPOCH,
+            SystemTime::UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789),
+            SystemTime::UNIX_EPOCH + Duration::new(5_000_000_000, 1),
+            SystemTime::UNIX_EPOCH - Duration::new(10, 0),
+            SystemTime::UNIX_EPOCH - Duration::new(0, 250_000_000),
+        ];
+
+        for time in times {
+            assert_eq!(SystemTime::from(Timestamp::from(time)), time);
+        }
+    }
+
+    #[test]
+    fn test_timestamp_before_epoch() {
+        let timestamp = Timestamp::from(SystemTime::UNIX_EPOCH - Duration::new(1, 250_000_000));
+
+        assert_eq!(timestamp, Timestamp::new(-2, 750_000_000));
+        assert!(timestamp < Timestamp::default());
+    }
+
+    #[test]
+    fn test_posix_attributes_round_trip() {
+        let posix_attributes = PosixAttributes {
+            mode: 0o100644,
+            uid: 1000,
+            gid: 100,
+            user_name: Some("alice".into()),
+            group_name: None,
+            accessed: Timestamp::new(1_600_000_000, 5),
+            changed: Timestamp::new(1_500_000_000, 0),
+            xattrs: vec![ExtendedAttribute {
+                name: b"user.comment".to_vec(),
+                value: b"hi".to_vec(),
+            }],
+        };
+        let metadata = FileMetadata {
+            file_name: "test_file".into(),
+            last_modified: Timestamp::default(),
+            file_size: 0,
+            content_hash: None,
+            posix_attributes: Some(posix_attributes.clone()),
+            entry_type: EntryType::Regular,
+        };
+
+        let received = FileMetadata::from(baacup::FileMetadata::from(metadata.clone()));
+        assert_eq!(received.posix_attributes, Some(posix_attributes));
+
+        let without_attributes = FileMetadata { posix_attributes: None, ..metadata };
+        let received = FileMetadata::from(baacup::FileMetadata::from(without_attributes));
+        assert_eq!(received.posix_attributes, None);
+    }
+
+    #[test]
+    fn test_entry_type_round_trip() {
+        let entry_types = vec![
+            EntryType::Regular,
+            EntryType::Directory,
+            EntryType::Symlink { target: b"../target".to_vec() },
+            EntryType::Fifo,
+            EntryType::CharDevice { major: 1, minor: 3 },
+            EntryType::BlockDevice { major: 8, minor: 1 },
+        ];
+
+        for entry_type in entry_types {
+            let metadata = FileMetadata {
+                file_name: "test_entry".into(),
+                last_modified: Timestamp::default(),
+                file_size: 0,
+                content_hash: None,
+                posix_attributes: None,
+                entry_type: entry_type.clone(),
+            };
+            let received = FileMetadata::from(baacup::FileMetadata::from(metadata));
+            assert_eq!(received.entry_type, entry_type);
+        }
+    }
 }
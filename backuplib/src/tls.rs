@@ -0,0 +1,584 @@
+//! TLS between backup-cli and backupd.
+//!
+//! grpc talks to TLS libraries through the `tls_api` traits and only ships a
+//! stub implementation of them, so this module provides one backed by
+//! OpenSSL. `TlsAcceptor` is given to `ServerBuilder::http.set_tls` and
+//! `TlsConnector` to `BaacupClient::new_tls`.
+//!
+//! gRPC handlers never see the connection a request came in on, so a client
+//! certificate can't be tied to a request directly. Instead both ends derive
+//! a channel binding from the TLS session (RFC 5705 keying material), the
+//! server remembers whose certificate each binding belongs to, and the client
+//! sends its binding along with every request. Only the two ends of a
+//! session know its binding.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::str;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::ssl::{self, AlpnError, SslAcceptor, SslAcceptorBuilder, SslConnector,
+                   SslConnectorBuilder, SslMethod, SslRef, SslStream, SslVerifyMode};
+use openssl::x509::X509;
+use tls_api::{Certificate, Error, HandshakeError, MidHandshakeTlsStream,
+              MidHandshakeTlsStreamImpl, Result, TlsStream, TlsStreamImpl};
+
+/// gRPC only runs over HTTP/2.
+const ALPN_H2: &[u8] = b"h2";
+
+const BINDING_LABEL: &str = "EXPORTER-baacup-channel-binding";
+const BINDING_LEN: usize = 32;
+
+/// Encodes protocol names the way OpenSSL wants them: each one prefixed by
+/// its length.
+fn alpn_wire_format(protocols: &[&[u8]]) -> Vec<u8> {
+    let mut wire = Vec::new();
+    for protocol in protocols {
+        wire.push(protocol.len() as u8);
+        wire.extend_from_slice(protocol);
+    }
+    wire
+}
+
+/// Picks the first of the server's protocols the client offered. The answer
+/// has to borrow from the client's list, which rules out
+/// `ssl::select_next_proto`.
+fn select_protocol<'a>(server_protocols: &[Vec<u8>], client_protocols: &'a [u8])
+    -> ::std::result::Result<&'a [u8], AlpnError>
+{
+    for server_protocol in server_protocols {
+        let mut rest = client_protocols;
+        while let Some((&len, tail)) = rest.split_first() {
+            if tail.len() < len as usize {
+                break;
+            }
+            let (protocol, tail) = tail.split_at(len as usize);
+            if protocol == &server_protocol[..] {
+                return Ok(protocol);
+            }
+            rest = tail;
+        }
+    }
+    Err(AlpnError::NOACK)
+}
+
+/// Returns the SHA-256 fingerprint of a DER encoded certificate. This is
+/// the form `TlsConnectorBuilder::pin_certificate` expects.
+pub fn certificate_fingerprint(der: &[u8]) -> Result<Vec<u8>> {
+    let certificate = X509::from_der(der).map_err(Error::new)?;
+    let digest = certificate.digest(MessageDigest::sha256()).map_err(Error::new)?;
+    Ok(digest.to_vec())
+}
+
+/// Parses a SHA-256 fingerprint written in hex, optionally separated by
+/// colons like `openssl x509 -fingerprint -sha256` prints it.
+pub fn parse_fingerprint(fingerprint: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = fingerprint.bytes().filter(|&b| b != b':').collect();
+    if digits.len() != 64 {
+        return None;
+    }
+
+    digits.chunks(2)
+        .map(|pair| u8::from_str_radix(str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+fn export_binding(ssl: &SslRef) -> Option<Vec<u8>> {
+    let mut binding = vec![0; BINDING_LEN];
+    ssl.export_keying_material(&mut binding, BINDING_LABEL, None).ok()?;
+    Some(binding)
+}
+
+/// Common names of the verified client certificates on open connections,
+/// by channel binding.
+#[derive(Clone, Debug, Default)]
+pub struct ClientIdentities(Arc<Mutex<HashMap<Vec<u8>, String>>>);
+
+impl ClientIdentities {
+    /// Returns the common name of the certificate the client presented on
+    /// the connection with this binding.
+    pub fn common_name(&self, channel_binding: &[u8]) -> Option<String> {
+        self.0.lock().unwrap().get(channel_binding).cloned()
+    }
+
+    fn register(&self, ssl: &SslRef) -> Option<Registration> {
+        let certificate = ssl.peer_certificate()?;
+        let common_name = certificate.subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()?
+            .data()
+            .as_utf8()
+            .ok()?
+            .to_string();
+        let binding = export_binding(ssl)?;
+
+        self.0.lock().unwrap().insert(binding.clone(), common_name);
+        Some(Registration {
+            identities: self.clone(),
+            binding: binding,
+        })
+    }
+}
+
+/// Forgets a connection's client identity once the connection is gone.
+#[derive(Debug)]
+struct Registration {
+    identities: ClientIdentities,
+    binding: Vec<u8>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.identities.0.lock().unwrap().remove(&self.binding);
+    }
+}
+
+#[derive(Debug)]
+enum HandshakeState {
+    Pending,
+    Established(Vec<u8>),
+    Failed(String),
+}
+
+/// The channel binding of the client's current connection.
+#[derive(Clone, Debug)]
+pub struct ChannelBinding(Arc<(Mutex<HandshakeState>, Condvar)>);
+
+impl ChannelBinding {
+    fn new() -> ChannelBinding {
+        ChannelBinding(Arc::new((Mutex::new(HandshakeState::Pending), Condvar::new())))
+    }
+
+    /// Returns the binding if the last handshake succeeded.
+    pub fn get(&self) -> Option<Vec<u8>> {
+        match *(self.0).0.lock().unwrap() {
+            HandshakeState::Established(ref binding) => Some(binding.clone()),
+            _ => None,
+        }
+    }
+
+    /// Waits for the first handshake to finish.
+    pub fn wait(&self, timeout: Duration) -> Result<Vec<u8>> {
+        let deadline = Instant::now() + timeout;
+        let (ref state_mutex, ref condvar) = *self.0;
+        let mut state = state_mutex.lock().unwrap();
+        loop {
+            match *state {
+                HandshakeState::Established(ref binding) => return Ok(binding.clone()),
+                HandshakeState::Failed(ref message) => return Err(Error::new_other(message)),
+                HandshakeState::Pending => {}
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::new_other("timed out waiting for TLS handshake"));
+            }
+            state = condvar.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    fn set(&self, new_state: HandshakeState) {
+        let (ref state_mutex, ref condvar) = *self.0;
+        *state_mutex.lock().unwrap() = new_state;
+        condvar.notify_all();
+    }
+}
+
+/// What to do with a connection's channel binding once its handshake ends.
+#[derive(Clone, Debug)]
+enum BindingHook {
+    Ignore,
+    /// Server side: remember whose certificate the client presented.
+    Register(ClientIdentities),
+    /// Client side: hand the binding to `BaacupClient`.
+    Record(ChannelBinding),
+}
+
+#[derive(Debug)]
+struct OpensslStream<S> {
+    stream: SslStream<S>,
+    _registration: Option<Registration>,
+}
+
+impl<S> io::Read for OpensslStream<S>
+    where S: io::Read + io::Write,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl<S> io::Write for OpensslStream<S>
+    where S: io::Read + io::Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S> TlsStreamImpl<S> for OpensslStream<S>
+    where S: io::Read + io::Write + fmt::Debug + Send + Sync + 'static,
+{
+    fn get_alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.stream.ssl().selected_alpn_protocol().map(|protocol| protocol.to_vec())
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        match self.stream.shutdown() {
+            Ok(_) => Ok(()),
+            Err(err) => Err(err.into_io_error()
+                .unwrap_or_else(|err| io::Error::new(io::ErrorKind::Other, err))),
+        }
+    }
+
+    fn get_mut(&mut self) -> &mut S {
+        self.stream.get_mut()
+    }
+
+    fn get_ref(&self) -> &S {
+        self.stream.get_ref()
+    }
+}
+
+/// `MidHandshakeSslStream::handshake` consumes the stream but the `tls_api`
+/// trait only lends it, so it is taken out of the `Option` on each attempt.
+#[derive(Debug)]
+struct OpensslMidHandshake<S>(Option<ssl::MidHandshakeSslStream<S>>, BindingHook);
+
+impl<S> MidHandshakeTlsStreamImpl<S> for OpensslMidHandshake<S>
+    where S: io::Read + io::Write + fmt::Debug + Send + Sync + 'static,
+{
+    fn handshake(&mut self) -> ::std::result::Result<TlsStream<S>, HandshakeError<S>> {
+        match self.0.take() {
+            Some(stream) => map_handshake(stream.handshake(), self.1.clone()),
+            None => Err(HandshakeError::Failure(Error::new_other("handshake already finished"))),
+        }
+    }
+}
+
+fn map_handshake<S>(result: ::std::result::Result<SslStream<S>, ssl::HandshakeError<S>>, hook: BindingHook)
+    -> ::std::result::Result<TlsStream<S>, HandshakeError<S>>
+    where S: io::Read + io::Write + fmt::Debug + Send + Sync + 'static,
+{
+    let error = match result {
+        Ok(stream) => {
+            let registration = match hook {
+                BindingHook::Ignore => None,
+                BindingHook::Register(ref identities) => identities.register(stream.ssl()),
+                BindingHook::Record(ref channel_binding) => {
+                    match export_binding(stream.ssl()) {
+                        Some(binding) => channel_binding.set(HandshakeState::Established(binding)),
+                        None => channel_binding.set(HandshakeState::Failed("no channel binding".into())),
+                    }
+                    None
+                }
+            };
+            return Ok(TlsStream::new(OpensslStream {
+                stream: stream,
+                _registration: registration,
+            }));
+        }
+        Err(ssl::HandshakeError::WouldBlock(stream)) => {
+            return Err(HandshakeError::Interrupted(MidHandshakeTlsStream::new(OpensslMidHandshake(Some(stream), hook))));
+        }
+        Err(ssl::HandshakeError::SetupFailure(err)) => Error::new(err),
+        Err(ssl::HandshakeError::Failure(stream)) => Error::new(stream.into_error()),
+    };
+
+    if let BindingHook::Record(ref channel_binding) = hook {
+        channel_binding.set(HandshakeState::Failed(error.to_string()));
+    }
+    Err(HandshakeError::Failure(error))
+}
+
+/// Client side TLS configuration.
+///
+/// The server certificate is checked against the system roots plus any added
+/// with `add_root_certificates_pem`, unless a certificate is pinned.
+pub struct TlsConnectorBuilder {
+    builder: SslConnectorBuilder,
+    pinned_certificate: Option<Vec<u8>>,
+    channel_binding: Option<ChannelBinding>,
+}
+
+impl TlsConnectorBuilder {
+    /// Trusts every certificate in a PEM encoded CA bundle.
+    pub fn add_root_certificates_pem(&mut self, pem: &[u8]) -> Result<&mut Self> {
+        let certificates = X509::stack_from_pem(pem).map_err(Error::new)?;
+        if certificates.is_empty() {
+            return Err(Error::new_other("no certificates found in CA bundle"));
+        }
+
+        for certificate in certificates {
+            self.builder.cert_store_mut().add_cert(certificate).map_err(Error::new)?;
+        }
+        Ok(self)
+    }
+
+    /// Only accepts a server whose certificate has this SHA-256 fingerprint.
+    /// A pinned certificate is trusted on its own, so it may be self-signed
+    /// and does not have to match the host name.
+    pub fn pin_certificate(&mut self, fingerprint: Vec<u8>) -> &mut Self {
+        self.pinned_certificate = Some(fingerprint);
+        self
+    }
+
+    /// Identifies the client to the server with `certificate_chain`, a PEM
+    /// file holding the client certificate followed by any intermediates,
+    /// and its PEM encoded `private_key`.
+    pub fn set_client_certificate(&mut self, certificate_chain: &[u8], private_key: &[u8]) -> Result<&mut Self> {
+        let mut certificates = X509::stack_from_pem(certificate_chain).map_err(Error::new)?.into_iter();
+        let certificate = certificates.next()
+            .ok_or_else(|| Error::new_other("no certificate found in certificate chain"))?;
+        let private_key = PKey::private_key_from_pem(private_key).map_err(Error::new)?;
+
+        self.builder.set_certificate(&certificate).map_err(Error::new)?;
+        for intermediate in certificates {
+            self.builder.add_extra_chain_cert(intermediate).map_err(Error::new)?;
+        }
+        self.builder.set_private_key(&private_key).map_err(Error::new)?;
+        self.builder.check_private_key().map_err(Error::new)?;
+
+        self.channel_binding = Some(ChannelBinding::new());
+        Ok(self)
+    }
+}
+
+impl tls_api::TlsConnectorBuilder for TlsConnectorBuilder {
+    type Connector = TlsConnector;
+
+    type Underlying = SslConnectorBuilder;
+
+    fn underlying_mut(&mut self) -> &mut SslConnectorBuilder {
+        &mut self.builder
+    }
+
+    fn supports_alpn() -> bool {
+        true
+    }
+
+    fn set_alpn_protocols(&mut self, protocols: &[&[u8]]) -> Result<()> {
+        self.builder.set_alpn_protos(&alpn_wire_format(protocols)).map_err(Error::new)
+    }
+
+    fn add_root_certificate(&mut self, cert: Certificate) -> Result<&mut Self> {
+        let certificate = X509::from_der(&cert.into_der()).map_err(Error::new)?;
+        self.builder.cert_store_mut().add_cert(certificate).map_err(Error::new)?;
+        Ok(self)
+    }
+
+    fn build(mut self) -> Result<TlsConnector> {
+        if let Some(fingerprint) = self.pinned_certificate {
+            self.builder.set_verify_callback(SslVerifyMode::PEER, move |_preverified, context| {
+                // The rest of the chain doesn't matter once the leaf is pinned.
+                if context.error_depth() != 0 {
+                    return true;
+                }
+
+                context.current_cert()
+                    .and_then(|certificate| certificate.digest(MessageDigest::sha256()).ok())
+                    .map_or(false, |digest| *digest == *fingerprint)
+            });
+        }
+
+        Ok(TlsConnector {
+            connector: self.builder.build(),
+            channel_binding: self.channel_binding,
+        })
+    }
+}
+
+pub struct TlsConnector {
+    connector: SslConnector,
+    channel_binding: Option<ChannelBinding>,
+}
+
+impl TlsConnector {
+    /// The binding the server knows this client's certificate by. Only set
+    /// when the connector has a client certificate.
+    pub fn channel_binding(&self) -> Option<ChannelBinding> {
+        self.channel_binding.clone()
+    }
+
+    fn binding_hook(&self) -> BindingHook {
+        match self.channel_binding {
+            Some(ref channel_binding) => BindingHook::Record(channel_binding.clone()),
+            None => BindingHook::Ignore,
+        }
+    }
+}
+
+impl tls_api::TlsConnector for TlsConnector {
+    type Builder = TlsConnectorBuilder;
+
+    fn builder() -> Result<TlsConnectorBuilder> {
+        let mut builder = SslConnector::builder(SslMethod::tls()).map_err(Error::new)?;
+        builder.set_alpn_protos(&alpn_wire_format(&[ALPN_H2])).map_err(Error::new)?;
+
+        Ok(TlsConnectorBuilder {
+            builder: builder,
+            pinned_certificate: None,
+            channel_binding: None,
+        })
+    }
+
+    fn connect<S>(&self, domain: &str, stream: S)
+        -> ::std::result::Result<TlsStream<S>, HandshakeError<S>>
+        where S: io::Read + io::Write + fmt::Debug + Send + Sync + 'static,
+    {
+        let config = self.connector.configure().map_err(|err| HandshakeError::Failure(Error::new(err)))?;
+        map_handshake(config.connect(domain, stream), self.binding_hook())
+    }
+
+    fn danger_connect_without_providing_domain_for_certificate_verification_and_server_name_indication<S>(
+        &self,
+        stream: S)
+        -> ::std::result::Result<TlsStream<S>, HandshakeError<S>>
+        where S: io::Read + io::Write + fmt::Debug + Send + Sync + 'static,
+    {
+        let config = self.connector.configure().map_err(|err| HandshakeError::Failure(Error::new(err)))?
+            .use_server_name_indication(false)
+            .verify_hostname(false);
+        map_handshake(config.connect("", stream), self.binding_hook())
+    }
+}
+
+/// Server side TLS configuration.
+pub struct TlsAcceptorBuilder {
+    builder: SslAcceptorBuilder,
+    client_identities: Option<ClientIdentities>,
+}
+
+impl TlsAcceptorBuilder {
+    /// Serves `certificate_chain`, a PEM file holding the server certificate
+    /// followed by any intermediates, with the PEM encoded `private_key`.
+    pub fn from_pem(certificate_chain: &[u8], private_key: &[u8]) -> Result<TlsAcceptorBuilder> {
+        let mut certificates = X509::stack_from_pem(certificate_chain).map_err(Error::new)?.into_iter();
+        let certificate = certificates.next()
+            .ok_or_else(|| Error::new_other("no certificate found in certificate chain"))?;
+        let private_key = PKey::private_key_from_pem(private_key).map_err(Error::new)?;
+
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).map_err(Error::new)?;
+        builder.set_certificate(&certificate).map_err(Error::new)?;
+        for intermediate in certificates {
+            builder.add_extra_chain_cert(intermediate).map_err(Error::new)?;
+        }
+        builder.set_private_key(&private_key).map_err(Error::new)?;
+        builder.check_private_key().map_err(Error::new)?;
+
+        let mut builder = TlsAcceptorBuilder {
+            builder: builder,
+            client_identities: None,
+        };
+        tls_api::TlsAcceptorBuilder::set_alpn_protocols(&mut builder, &[ALPN_H2])?;
+        Ok(builder)
+    }
+
+    /// Asks clients for a certificate issued by one of the CAs in the PEM
+    /// encoded `ca_bundle`. Clients without a certificate may still connect.
+    /// The returned map tells who presented which certificate.
+    pub fn verify_client_certificates(&mut self, ca_bundle: &[u8]) -> Result<ClientIdentities> {
+        let certificates = X509::stack_from_pem(ca_bundle).map_err(Error::new)?;
+        if certificates.is_empty() {
+            return Err(Error::new_other("no certificates found in CA bundle"));
+        }
+
+        for certificate in certificates {
+            self.builder.add_client_ca(&certificate).map_err(Error::new)?;
+            self.builder.cert_store_mut().add_cert(certificate).map_err(Error::new)?;
+        }
+        self.builder.set_verify(SslVerifyMode::PEER);
+        // Resumed sessions must remember that the client was verified.
+        self.builder.set_session_id_context(b"backupd").map_err(Error::new)?;
+
+        let client_identities = ClientIdentities::default();
+        self.client_identities = Some(client_identities.clone());
+        Ok(client_identities)
+    }
+}
+
+impl tls_api::TlsAcceptorBuilder for TlsAcceptorBuilder {
+    type Acceptor = TlsAcceptor;
+
+    type Underlying = SslAcceptorBuilder;
+
+    fn supports_alpn() -> bool {
+        true
+    }
+
+    fn set_alpn_protocols(&mut self, protocols: &[&[u8]]) -> Result<()> {
+        let protocols: Vec<Vec<u8>> = protocols.iter().map(|protocol| protocol.to_vec()).collect();
+        self.builder.set_alpn_select_callback(move |_, client_protocols| select_protocol(&protocols, client_protocols));
+        Ok(())
+    }
+
+    fn underlying_mut(&mut self) -> &mut SslAcceptorBuilder {
+        &mut self.builder
+    }
+
+    fn build(self) -> Result<TlsAcceptor> {
+        Ok(TlsAcceptor {
+            acceptor: self.builder.build(),
+            client_identities: self.client_identities,
+        })
+    }
+}
+
+pub struct TlsAcceptor {
+    acceptor: SslAcceptor,
+    client_identities: Option<ClientIdentities>,
+}
+
+impl tls_api::TlsAcceptor for TlsAcceptor {
+    type Builder = TlsAcceptorBuilder;
+
+    fn accept<S>(&self, stream: S)
+        -> ::std::result::Result<TlsStream<S>, HandshakeError<S>>
+        where S: io::Read + io::Write + fmt::Debug + Send + Sync + 'static,
+    {
+        let hook = match self.client_identities {
+            Some(ref client_identities) => BindingHook::Register(client_identities.clone()),
+            None => BindingHook::Ignore,
+        };
+        map_handshake(self.acceptor.accept(stream), hook)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{alpn_wire_format, parse_fingerprint, select_protocol};
+
+    #[test]
+    fn test_alpn_wire_format() {
+        assert_eq!(alpn_wire_format(&[b"h2", b"http/1.1"]), b"\x02h2\x08http/1.1".to_vec());
+    }
+
+    #[test]
+    fn test_select_protocol() {
+        let server_protocols = vec![b"h2".to_vec()];
+        assert_eq!(select_protocol(&server_protocols, b"\x08http/1.1\x02h2").ok(), Some(&b"h2"[..]));
+        assert!(select_protocol(&server_protocols, b"\x08http/1.1").is_err());
+        assert!(select_protocol(&server_protocols, b"\x05h2").is_err());
+    }
+
+    #[test]
+    fn test_parse_fingerprint() {
+        let hex = "00:11:22:33:44:55:66:77:88:99:AA:BB:CC:DD:EE:FF:\
+                   00112233445566778899aabbccddeeff";
+        let fingerprint = parse_fingerprint(hex).unwrap();
+        assert_eq!(&fingerprint[..4], &[0x00, 0x11, 0x22, 0x33]);
+        assert_eq!(&fingerprint[28..], &[0xcc, 0xdd, 0xee, 0xff]);
+
+        assert_eq!(parse_fingerprint("0011"), None);
+        assert_eq!(parse_fingerprint(&"zz".repeat(32)), None);
+    }
+}